@@ -1,5 +1,5 @@
 use std::fs;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use ops_common::{AppInfo, ServiceStatus};
 use tracing::{debug, warn, error};
 use serde_json;
@@ -27,11 +27,9 @@ impl AppInfoCollector {
 
         match fs::read_dir(apps_path) {
             Ok(entries) => {
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        if let Some(app_info) = self.read_app_info(&entry.path()) {
-                            apps.push(app_info);
-                        }
+                for entry in entries.flatten() {
+                    if let Some(app_info) = self.read_app_info(&entry.path()) {
+                        apps.push(app_info);
                     }
                 }
             }
@@ -44,6 +42,29 @@ impl AppInfoCollector {
         apps
     }
 
+    /// 解析应用目录内的相对路径，解析符号链接后必须仍位于该应用目录内
+    pub fn resolve_app_file(&self, app_name: &str, relative: &str) -> Result<PathBuf, String> {
+        let is_plain = |path: &Path| path.components().all(|c| matches!(c, Component::Normal(_)));
+        if app_name.is_empty() || !is_plain(Path::new(app_name)) || app_name.contains('/') {
+            return Err(format!("无效的应用名: {}", app_name));
+        }
+        if relative.is_empty() || !is_plain(Path::new(relative)) {
+            return Err(format!("路径必须是应用目录内不含 .. 的相对路径: {}", relative));
+        }
+
+        let app_path = Path::new(&self.apps_dir).join(app_name);
+        if self.read_app_info(&app_path).is_none() {
+            return Err(format!("未知的应用: {}", app_name));
+        }
+        let app_path = fs::canonicalize(&app_path).map_err(|e| format!("应用目录不可用: {}", e))?;
+        let file = fs::canonicalize(app_path.join(relative))
+            .map_err(|e| format!("文件不可用 {}: {}", relative, e))?;
+        if !file.starts_with(&app_path) {
+            return Err(format!("路径不在应用目录中: {}", relative));
+        }
+        Ok(file)
+    }
+
     /// 读取单个应用的信息
     fn read_app_info(&self, app_path: &Path) -> Option<AppInfo> {
         // 只处理目录
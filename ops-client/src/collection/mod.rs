@@ -1,3 +1,2 @@
-pub mod system_info;
 pub mod version_collector;
 pub mod app_info;
\ No newline at end of file
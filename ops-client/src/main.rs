@@ -2,11 +2,12 @@ use std::time::Duration;
 use std::process;
 use tokio::spawn;
 use tracing::{info, error};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 use tracing_appender::{rolling, non_blocking};
 
 mod collection;
+mod policy;
 mod tcp_services;
 
 use crate::tcp_services::client;
@@ -64,17 +65,20 @@ struct Args {
     /// 心跳间隔（秒）
     #[arg(long, help = "心跳间隔秒数 (默认: 3)")]
     heartbeat_interval: Option<u64>,
-}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // 解析命令行参数
-    let args = Args::parse();
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    // 初始化日志配置
-    setup_logging();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 命令校验策略
+    #[command(subcommand)]
+    Policy(policy::PolicyCommand),
+}
 
-    // 加载配置，优先级：命令行参数 > 配置文件 > 环境变量 > 默认值
+// 加载配置，优先级：命令行参数 > 配置文件 > 环境变量 > 默认值
+fn load_config(args: &Args) -> ClientConfig {
     let mut config = if let Some(config_path) = &args.config {
         match ClientConfig::from_file(config_path) {
             Ok(config) => {
@@ -92,8 +96,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     // 命令行参数覆盖配置
-    if let Some(host) = args.host {
-        config.server_host = host;
+    if let Some(host) = &args.host {
+        config.server_host = host.clone();
     }
     if let Some(port) = args.port {
         config.server_port = port;
@@ -101,6 +105,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if let Some(interval) = args.heartbeat_interval {
         config.heartbeat_interval_secs = interval;
     }
+    config
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // 解析命令行参数
+    let mut args = Args::parse();
+
+    // 策略子命令只在本地校验，不启动客户端
+    if let Some(Command::Policy(command)) = args.command.take() {
+        let config = load_config(&args);
+        if let Err(e) = policy::run(command, &config) {
+            eprintln!("错误: {}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // 初始化日志配置
+    setup_logging();
+
+    let config = load_config(&args);
 
     info!("Client starting with config: server={}", config.server_address());
 
@@ -113,15 +139,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    // 启动心跳任务
-    let heartbeat_session = session.clone();
-    spawn(async move {
-        heartbeat_session.start_heartbeat().await;
-    });
-
-    // 启动命令监听任务
+    // 启动心跳任务；服务端消息由会话内部的连接任务接收
     spawn(async move {
-        session.start_message_listener().await;
+        session.start_heartbeat().await;
     });
 
     // 保持程序运行
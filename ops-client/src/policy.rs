@@ -0,0 +1,115 @@
+// 命令校验策略：加载配置的策略文件、应用服务端下发的策略，以及 ops-client policy 子命令（不连接服务端）
+
+use std::path::PathBuf;
+use clap::Subcommand;
+use ops_common::command_policy::{CommandPolicy, DEFAULT_POLICY};
+use ops_common::PolicyStatus;
+use ops_common::config::ClientConfig;
+use ops_common::security::{CommandValidator, ValidationResult};
+use tracing::info;
+
+#[derive(Subcommand, Debug)]
+pub enum PolicyCommand {
+    /// 按策略校验命令并说明每条命令的判断依据，不执行命令
+    Test {
+        /// 要校验的命令行
+        command: String,
+        /// 策略文件，默认使用配置中的 command_policy_file，未配置时使用内置策略
+        #[arg(long)]
+        policy: Option<PathBuf>,
+    },
+    /// 输出内置的默认策略，可作为自定义策略的模板
+    Default,
+}
+
+/// 按配置加载命令校验器；策略文件无效时返回错误，不回退到内置策略
+pub fn load_validator(config: &ClientConfig) -> Result<CommandValidator, String> {
+    let policy = match &config.command_policy_file {
+        Some(path) => {
+            info!("Loading command policy from {}", path);
+            CommandPolicy::load(path)?
+        }
+        None => CommandPolicy::builtin(),
+    };
+    CommandValidator::from_policy(policy)
+}
+
+/// 客户端执行的策略：收到服务端策略前使用本地策略文件（未配置时为内置策略）；
+/// 收到后以服务端策略为准，本地策略文件作为附加限制，命令必须同时通过两者
+pub struct EnforcedPolicy {
+    local: Option<CommandValidator>, // 本地策略文件
+    server_version: Option<String>,
+    validator: CommandValidator,
+}
+
+impl EnforcedPolicy {
+    pub fn load(config: &ClientConfig) -> Result<Self, String> {
+        let local = match config.command_policy_file {
+            Some(_) => Some(load_validator(config)?),
+            None => None,
+        };
+        let validator = local.clone().unwrap_or_default();
+        Ok(Self { local, server_version: None, validator })
+    }
+
+    /// 应用服务端下发的策略并返回其版本；策略无效时保留当前策略
+    pub fn apply_server_policy(&mut self, policy: CommandPolicy) -> Result<String, String> {
+        let version = policy.version();
+        let mut validator = CommandValidator::from_policy(policy)?;
+        if let Some(local) = &self.local {
+            validator = validator.restricted_by(local.clone());
+        }
+        self.validator = validator;
+        self.server_version = Some(version.clone());
+        Ok(version)
+    }
+
+    pub fn validator(&self) -> &CommandValidator {
+        &self.validator
+    }
+
+    pub fn status(&self) -> PolicyStatus {
+        PolicyStatus {
+            version: self.server_version.clone(),
+            local_version: self.local.as_ref().map(|local| local.policy().version()),
+        }
+    }
+}
+
+/// 执行 policy 子命令；命令被拒绝时返回错误
+pub fn run(command: PolicyCommand, config: &ClientConfig) -> Result<(), String> {
+    match command {
+        PolicyCommand::Test { command, policy } => {
+            let validator = match policy {
+                Some(path) => {
+                    println!("策略: {}", path.display());
+                    CommandValidator::from_policy(CommandPolicy::load(&path)?)?
+                }
+                None => {
+                    match &config.command_policy_file {
+                        Some(path) => println!("策略: {}", path),
+                        None => println!("策略: 内置默认策略"),
+                    }
+                    load_validator(config)?
+                }
+            };
+            let explanation = validator.explain(&command);
+            println!("命令: {}", command);
+            for decision in &explanation.decisions {
+                let verdict = if decision.allowed { "允许" } else { "拒绝" };
+                println!("  [{}] {} — {}", verdict, decision.command, decision.detail);
+            }
+            match explanation.result {
+                ValidationResult::Allowed => {
+                    println!("结果: 允许");
+                    Ok(())
+                }
+                ValidationResult::Blocked { reason } => Err(format!("命令被拒绝: {}", reason)),
+            }
+        }
+        PolicyCommand::Default => {
+            print!("{}", DEFAULT_POLICY);
+            Ok(())
+        }
+    }
+}
@@ -1,18 +1,27 @@
-use std::net::{ TcpStream, SocketAddr };
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::fs;
+use std::pin::Pin;
+use std::process::Stdio;
  
-use std::time::{ Duration, SystemTime };
+use std::time::{ Duration, Instant, SystemTime };
 use crate::collection::version_collector;
 use crate::collection::app_info::AppInfoCollector;
+use crate::policy::EnforcedPolicy;
 use crate::tcp_services::client;
-use socket2::{ Socket, Domain, Type, TcpKeepalive };
-use tokio::sync::Mutex;
-use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use crate::tcp_services::credential::CredentialFile;
+use crate::tcp_services::file_transfer::{IncomingFile, OutgoingFile};
+use crate::tcp_services::log_tail::{self, LogFollower};
+use crate::tcp_services::outbox::Outbox;
+use crate::tcp_services::terminal::{PtyShell, TerminalControl};
+use crate::tcp_services::worker_pool::WorkerPool;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{Mutex, mpsc, oneshot};
 use tokio::net::TcpStream as AsyncTcpStream;
-use ops_common::{ ClientInfo, HostInfo, config::ClientConfig, security::{CommandValidator, ValidationResult}, tcp_auth::{TcpAuthMessage, TcpAuthenticator} };
+use ops_common::{ ClientInfo, HostInfo, codec::{FrameCodec, FrameTooLarge, FramedStream}, command_signing::{CommandSignature, CommandVerifier, SignedRequest}, config::ClientConfig, protocol::{Capability, CommandOutputChunk, CommandResponse, Envelope, FetchRange, FilePushStart, Hello, HelloAck, OutputStream, ReconnectReport, ResumeRequest, command_policy_signed_content, command_signed_content, file_fetch_signed_content, log_tail_signed_content}, security::ValidationResult, tcp_auth::{TcpAuthMessage, TcpAuthenticator}, tls::{self, BoxedStream, TlsConnector} };
 use tracing::{info, error, warn, debug};
-use serde::{Deserialize, Serialize};
+
 pub fn get_or_create_client_id(config: &ClientConfig) -> Result<String, std::io::Error> {
     if let Ok(id) = fs::read_to_string(&config.client_id_file) {
         Ok(id.trim().to_string())
@@ -24,51 +33,67 @@ pub fn get_or_create_client_id(config: &ClientConfig) -> Result<String, std::io:
     }
 }
 
-/// 客户端消息类型，与服务器端对应
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "data_type")]
-enum ClientMessage {
-    #[serde(rename = "client_info")]
-    ClientInfo {
-        client_id: String,
-        system_info: ops_common::HostInfo,
-        version_info: Vec<ops_common::VersionInfo>,
-        app_info: Vec<ops_common::AppInfo>,
-        last_seen: SystemTime,
-    },
-    #[serde(rename = "command_response")]
-    CommandResponse {
-        command_id: String,
-        client_id: String,
-        command: String,
-        output: String,
-        error_output: String,
-        exit_code: i32,
-        executed_at: SystemTime,
-    },
-    #[serde(rename = "auth_response")]
-    AuthResponse {
-        client_id: String,
-        nonce: String,
-        response_hash: String,
-        timestamp: u64,
-    },
+/// 发送队列容量；心跳和输出分块在队列满时直接跳过，命令结果等待队列空出
+const OUTBOUND_QUEUE_CAPACITY: usize = 256;
+
+/// 命令被提前结束的原因
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Interruption {
+    Cancelled,
+    TimedOut,
+}
+
+/// 命令的执行结果，直接用于构建 CommandResponse
+#[derive(Debug)]
+struct ExecutionResult {
+    exit_code: i32,
+    stdout: String,
+    stderr: String,
+    cancelled: bool,
+    timed_out: bool,
+}
+
+impl ExecutionResult {
+    /// 命令没有运行起来（或被提前取消），只有一条说明
+    fn not_run(message: String, cancelled: bool) -> Self {
+        Self { exit_code: -1, stdout: String::new(), stderr: message, cancelled, timed_out: false }
+    }
 }
 
-/// 服务器消息类型
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "data_type")]
-enum ServerMessage {
-    #[serde(rename = "auth_challenge")]
-    AuthChallenge {
-        nonce: String,
-        timestamp: u64,
-    },
-    #[serde(rename = "auth_result")]
-    AuthResult {
-        success: bool,
-        message: String,
-    },
+/// 超时命令的退出码
+const TIMED_OUT_EXIT_CODE: i32 = 124;
+
+/// 每个终端会话控制队列的容量
+const TERMINAL_CONTROL_QUEUE: usize = 256;
+
+/// 向命令所在的进程组发送信号，进程组已经退出时忽略
+pub(crate) fn signal_process_group(pgid: i32, signal: libc::c_int) {
+    // SAFETY: kill 只读取参数，负的 pid 表示整个进程组
+    if unsafe { libc::kill(-pgid, signal) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            warn!("Failed to send signal {} to process group {}: {}", signal, pgid, err);
+        }
+    }
+}
+
+/// 可以完整解码的字节数：末尾被截断的多字节字符不计入，其他非法字节交给有损解码处理
+pub fn complete_utf8_len(bytes: &[u8]) -> usize {
+    // 一个字符最多 4 字节，只需检查末尾 3 个字节中的首字节
+    for back in 1..=bytes.len().min(3) {
+        let start = bytes.len() - back;
+        let byte = bytes[start];
+        if byte & 0xC0 != 0x80 {
+            let width = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if width > back { start } else { bytes.len() };
+        }
+    }
+    bytes.len()
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -77,46 +102,125 @@ enum ClientState {
     Authenticating, // 正在认证中
     Authenticated,  // 已认证
     AuthFailed,     // 认证失败
+    Reconnecting,   // 连接断开，正在重连
+}
+
+/// 第 attempt 次失败后的退避间隔
+fn retry_delay(config: &ClientConfig, attempt: u32) -> Duration {
+    let delay = config.retry_base_delay_secs
+        .checked_pow(attempt)
+        .unwrap_or(config.retry_max_delay_secs)
+        .min(config.retry_max_delay_secs);
+    Duration::from_secs(delay)
 }
 
 pub struct TcpSession {
-    stream: Arc<Mutex<AsyncTcpStream>>,
+    outbound: mpsc::Sender<Envelope>,
     addr: String,
+    tls_connector: Option<TlsConnector>,
     config: ClientConfig,
-    validator: CommandValidator,
+    policy: Arc<Mutex<EnforcedPolicy>>, // 命令校验策略，服务端下发后更新
+    command_verifier: Option<Arc<Mutex<CommandVerifier>>>, // 配置了签名公钥时校验命令签名
     state: Arc<Mutex<ClientState>>,
-    authenticator: Option<TcpAuthenticator>,
+    credential_file: Arc<CredentialFile>,
+    credential: Arc<Mutex<Option<String>>>, // 服务端签发的本机密钥，尚未注册时为 None
+    negotiated: Arc<Mutex<Option<HelloAck>>>,
+    resume_token: Arc<Mutex<Option<String>>>, // 服务端签发的续连令牌
+    outbox: Arc<Mutex<Outbox>>, // 等待服务端确认的命令结果
+    running: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>, // 执行中命令的取消信号
+    workers: Arc<WorkerPool>, // 限制同时执行的命令数
+    terminals: Arc<Mutex<HashMap<String, mpsc::Sender<TerminalControl>>>>, // 打开中的终端会话
+    incoming_files: Arc<Mutex<HashMap<String, IncomingFile>>>, // 正在接收的推送文件
+    log_tails: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>, // 跟踪中日志的停止信号
 }
 
 impl TcpSession {
     pub async fn new(config: ClientConfig) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
         let addr = config.server_address();
-        let stream = Self::connect_with_retry(&addr, &config).await?;
+        let tls_connector = Self::build_tls_connector(&config)?;
+        let command_verifier = match &config.command_signing_public_key {
+            Some(public_key) => {
+                // 已执行的请求ID保存在状态目录中，重启后仍拒绝有效期内的重放
+                let seen_file = std::path::Path::new(&config.state_dir).join("seen_requests.json");
+                Some(Arc::new(Mutex::new(CommandVerifier::new(public_key)?.persist_to(seen_file)?)))
+            }
+            None => {
+                warn!("Command signing public key not configured, commands are executed without signature check");
+                None
+            }
+        };
+        let policy = EnforcedPolicy::load(&config)?;
+        let outbox_dir = std::path::Path::new(&config.state_dir).join("outbox");
+        let outbox = Outbox::open(&outbox_dir, config.outbox_max_entries)
+            .map_err(|e| format!("打开发件箱 {} 失败: {}", outbox_dir.display(), e))?;
+        if !outbox.is_empty() {
+            info!("{} unacknowledged command result(s) in outbox", outbox.len());
+        }
+        let stream = Self::connect_with_retry(&addr, &config, tls_connector.as_ref()).await?;
+        let mut framed = FramedStream::new(stream, FrameCodec::new(config.max_frame_size));
+        let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE_CAPACITY);
         
-        // 检查是否启用TCP认证
-        let tcp_auth_secret = std::env::var("OPS_TCP_AUTH_SECRET")
-            .unwrap_or_else(|_| "default-tcp-secret-key".to_string());
-        let authenticator = Some(TcpAuthenticator::new(tcp_auth_secret));
+        let credential_file = CredentialFile::new(&config.state_dir);
+        let credential = credential_file.load()
+            .map_err(|e| format!("读取凭据 {} 失败: {}", credential_file.path().display(), e))?;
         
+        let workers = Arc::new(WorkerPool::new(config.max_concurrent_commands));
+
         let session = Self {
-            stream: Arc::new(Mutex::new(stream)),
+            outbound,
             addr,
+            tls_connector,
             config,
-            validator: CommandValidator::new(),
+            policy: Arc::new(Mutex::new(policy)),
+            command_verifier,
             state: Arc::new(Mutex::new(ClientState::Connected)),
-            authenticator,
+            credential_file: Arc::new(credential_file),
+            credential: Arc::new(Mutex::new(credential)),
+            negotiated: Arc::new(Mutex::new(None)),
+            resume_token: Arc::new(Mutex::new(None)),
+            outbox: Arc::new(Mutex::new(outbox)),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            workers,
+            terminals: Arc::new(Mutex::new(HashMap::new())),
+            incoming_files: Arc::new(Mutex::new(HashMap::new())),
+            log_tails: Arc::new(Mutex::new(HashMap::new())),
         };
         
-        // 启动认证流程
-        session.handle_initial_authentication().await?;
+        // 握手阶段独占连接，之后交给连接任务
+        session.handshake(&mut framed).await?;
+        session.replay_outbox(&mut framed).await?;
+
+        tokio::spawn(session.clone().run_connection(framed, outbound_rx));
         
         Ok(session)
     }
 
-    pub async fn connect_with_retry(addr: &str, config: &ClientConfig) -> Result<AsyncTcpStream, Box<dyn std::error::Error + Send + Sync>> {
+    /// 按配置构建 TLS 连接器，未启用 TLS 时返回 None
+    fn build_tls_connector(config: &ClientConfig) -> Result<Option<TlsConnector>, Box<dyn std::error::Error + Send + Sync>> {
+        if !config.tls_enabled {
+            warn!("TLS disabled, traffic to {} is unencrypted", config.server_address());
+            return Ok(None);
+        }
+
+        let ca_file = config.tls_ca_file.as_deref()
+            .ok_or("启用 TLS 时必须配置 tls_ca_file")?;
+        let client_identity = match (&config.tls_cert_file, &config.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                info!("Presenting client certificate {}", cert_file);
+                Some((cert_file.as_str(), key_file.as_str()))
+            }
+            (None, None) => None,
+            _ => return Err("tls_cert_file 和 tls_key_file 必须同时配置".into()),
+        };
+        let connector = tls::build_connector(ca_file, client_identity)?;
+        info!("TLS enabled, trusting CA from {}", ca_file);
+        Ok(Some(connector))
+    }
+
+    pub async fn connect_with_retry(addr: &str, config: &ClientConfig, tls_connector: Option<&TlsConnector>) -> Result<BoxedStream, Box<dyn std::error::Error + Send + Sync>> {
         let mut retry = 0;
         loop {
-            match Self::create_socket_async(addr).await {
+            match Self::create_socket_async(addr, config, tls_connector).await {
                 Ok(stream) => {
                     info!("Successfully connected to {}", addr);
                     return Ok(stream);
@@ -128,25 +232,86 @@ impl TcpSession {
                         return Err(e.into());
                     }
                     
-                    let delay = (config.retry_base_delay_secs.pow(retry)).min(config.retry_max_delay_secs);
-                    warn!("Connection failed (attempt {}/{}): {}, retrying in {}s", retry, config.retry_max_attempts, e, delay);
-                    tokio::time::sleep(Duration::from_secs(delay)).await;
+                    let delay = retry_delay(config, retry);
+                    warn!("Connection failed (attempt {}/{}): {}, retrying in {}s", retry, config.retry_max_attempts, e, delay.as_secs());
+                    tokio::time::sleep(delay).await;
                 }
             }
         }
     }
 
-    pub async fn create_socket_async(addr: &str) -> std::io::Result<AsyncTcpStream> {
-        AsyncTcpStream::connect(addr).await
+    pub async fn create_socket_async(addr: &str, config: &ClientConfig, tls_connector: Option<&TlsConnector>) -> std::io::Result<BoxedStream> {
+        let stream = AsyncTcpStream::connect(addr).await?;
+        let Some(connector) = tls_connector else {
+            return Ok(Box::new(stream));
+        };
+
+        let host = config.tls_server_name.as_deref().unwrap_or(&config.server_host);
+        let tls_stream = connector.connect(tls::server_name(host)?, stream).await?;
+        Ok(Box::new(tls_stream))
+    }
+
+    /// 新连接的握手：先协商协议版本，再完成认证；凭续连令牌恢复会话时跳过认证并返回 true
+    async fn handshake(&self, stream: &mut FramedStream<BoxedStream>) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        if self.negotiate_protocol(stream).await? {
+            info!("Session resumed, skipping authentication");
+            *self.state.lock().await = ClientState::Authenticated;
+            return Ok(true);
+        }
+        self.handle_initial_authentication(stream).await?;
+        Ok(false)
+    }
+
+    /// 发送 hello 并等待服务端的协商结果，返回会话是否已恢复
+    async fn negotiate_protocol(&self, stream: &mut FramedStream<BoxedStream>) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut hello = Hello::local();
+        if !self.config.terminal_enabled {
+            hello.capabilities.retain(|capability| *capability != Capability::Terminal);
+        }
+        if self.config.file_push_dirs.is_empty() {
+            hello.capabilities.retain(|capability| *capability != Capability::FileTransfer);
+        }
+        if self.config.file_fetch_dirs.is_empty() {
+            hello.capabilities.retain(|capability| *capability != Capability::FileFetch);
+        }
+        // 有续连令牌时请求恢复之前的会话
+        let token = self.resume_token.lock().await.clone();
+        if let Some(token) = token {
+            hello.resume = Some(ResumeRequest { client_id: self.get_client_id().await?, token });
+        }
+        let requested_resume = hello.resume.is_some();
+        stream.write_envelope(&Envelope::Hello(hello)).await?;
+
+        let server_msg = tokio::time::timeout(
+            Duration::from_secs(10),
+            stream.read_envelope()
+        ).await
+        .map_err(|_| "Protocol negotiation timeout")?
+        .map_err(|e| format!("Read error during protocol negotiation: {}", e))?;
+
+        match server_msg {
+            Envelope::HelloAck(ack) => {
+                info!("Negotiated protocol v{} with server, capabilities: {:?}", ack.protocol_version, ack.capabilities);
+                if requested_resume && !ack.resumed {
+                    // 令牌已失效，完成认证后服务端会签发新令牌
+                    info!("Server declined session resume, performing full authentication");
+                    *self.resume_token.lock().await = None;
+                }
+                let resumed = ack.resumed;
+                *self.negotiated.lock().await = Some(ack);
+                Ok(resumed)
+            }
+            Envelope::ConnectionRejected { reason } => {
+                error!("Server rejected protocol negotiation: {}", reason);
+                Err(format!("Server rejected connection: {}", reason).into())
+            }
+            other => Err(format!("Unexpected message during protocol negotiation: {:?}", other).into()),
+        }
     }
 
     /// 处理初始认证流程
-    async fn handle_initial_authentication(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let tcp_auth_enabled = std::env::var("OPS_TCP_AUTH_ENABLED")
-            .map(|v| v.to_lowercase() == "true" || v == "1")
-            .unwrap_or(false);
-        
-        if !tcp_auth_enabled {
+    async fn handle_initial_authentication(&self, stream: &mut FramedStream<BoxedStream>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if !self.config.tcp_auth_enabled {
             info!("TCP authentication disabled, skipping authentication");
             let mut state = self.state.lock().await;
             *state = ClientState::Authenticated;
@@ -156,31 +321,16 @@ impl TcpSession {
         info!("TCP authentication enabled, waiting for challenge");
         
         // 等待服务器的认证质询
-        let mut buf = vec![0u8; 4096];
-        let n = {
-            let mut stream = self.stream.lock().await;
-            tokio::time::timeout(
-                Duration::from_secs(10),
-                stream.read(&mut buf)
-            ).await
-            .map_err(|_| "Authentication timeout")?
-            .map_err(|e| format!("Read error during auth: {}", e))?
-        };
-        
-        if n == 0 {
-            return Err("Connection closed during authentication".into());
-        }
-        
-        buf.truncate(n);
-        let challenge_data = String::from_utf8_lossy(&buf);
-        debug!("Received potential challenge: {}", challenge_data);
-        
-        // 解析认证质询
-        let server_msg: ServerMessage = serde_json::from_slice(&buf)
-            .map_err(|e| format!("Failed to parse server message: {}", e))?;
+        let server_msg = tokio::time::timeout(
+            Duration::from_secs(10),
+            stream.read_envelope()
+        ).await
+        .map_err(|_| "Authentication timeout")?
+        .map_err(|e| format!("Read error during auth: {}", e))?;
+        debug!("Received potential challenge: {:?}", server_msg);
         
         match server_msg {
-            ServerMessage::AuthChallenge { nonce, timestamp } => {
+            Envelope::Auth(TcpAuthMessage::Challenge { nonce, timestamp }) => {
                 info!("Received authentication challenge");
                 {
                     let mut state = self.state.lock().await;
@@ -188,29 +338,17 @@ impl TcpSession {
                 }
                 
                 // 生成认证响应
-                if let Some(ref auth) = self.authenticator {
-                    let client_id = get_or_create_client_id(&self.config)?;
-                    let response = auth.generate_response(client_id.clone(), nonce, timestamp)?;
-                    
-                    if let TcpAuthMessage::Response { client_id, nonce, response_hash, timestamp } = response {
-                        let auth_msg = ClientMessage::AuthResponse {
-                            client_id,
-                            nonce,
-                            response_hash,
-                            timestamp,
-                        };
-                        
-                        // 发送认证响应
-                        self.send_message(&auth_msg).await?;
-                        
-                        // 等待认证结果
-                        self.wait_for_auth_result().await?;
-                    }
-                } else {
-                    return Err("Authenticator not available".into());
-                }
+                let client_id = get_or_create_client_id(&self.config)?;
+                let secret = self.auth_secret(stream, &client_id).await?;
+                let response = TcpAuthenticator::new(secret).generate_response(client_id, nonce, timestamp)?;
+                
+                // 发送认证响应
+                stream.write_envelope(&Envelope::Auth(response)).await?;
+                
+                // 等待认证结果
+                self.wait_for_auth_result(stream).await?;
             }
-            ServerMessage::AuthResult { success, message } => {
+            Envelope::Auth(TcpAuthMessage::AuthResult { success, message }) => {
                 if success {
                     info!("Authentication successful: {}", message);
                     let mut state = self.state.lock().await;
@@ -222,34 +360,81 @@ impl TcpSession {
                     return Err(format!("Authentication failed: {}", message).into());
                 }
             }
+            other => {
+                return Err(format!("Unexpected message during authentication: {:?}", other).into());
+            }
         }
         
         Ok(())
     }
     
-    /// 等待认证结果
-    async fn wait_for_auth_result(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let mut buf = vec![0u8; 4096];
-        let n = {
-            let mut stream = self.stream.lock().await;
-            tokio::time::timeout(
-                Duration::from_secs(10),
-                stream.read(&mut buf)
-            ).await
-            .map_err(|_| "Authentication result timeout")?
-            .map_err(|e| format!("Read error during auth result: {}", e))?
-        };
-        
-        if n == 0 {
-            return Err("Connection closed while waiting for auth result".into());
+    /// 认证使用的密钥：优先使用已保存的凭据，其次凭注册令牌向服务端申请，最后是旧版共享密钥
+    async fn auth_secret(&self, stream: &mut FramedStream<BoxedStream>, client_id: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(secret) = self.credential.lock().await.clone() {
+            return Ok(secret);
         }
-        
-        buf.truncate(n);
-        let server_msg: ServerMessage = serde_json::from_slice(&buf)
-            .map_err(|e| format!("Failed to parse auth result: {}", e))?;
+        if let Some(token) = &self.config.enrollment_token {
+            return self.enroll(stream, client_id, token).await;
+        }
+        self.config.tcp_auth_secret
+            .clone()
+            .ok_or_else(|| "没有可用的认证密钥，请设置 OPS_ENROLLMENT_TOKEN 完成注册".into())
+    }
+
+    /// 用一次性注册令牌换取本机密钥并保存到状态目录
+    async fn enroll(&self, stream: &mut FramedStream<BoxedStream>, client_id: &str, token: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        info!("No saved credential, enrolling with the server");
+        let request = TcpAuthMessage::Enroll { client_id: client_id.to_string(), token: token.to_string() };
+        stream.write_envelope(&Envelope::Auth(request)).await?;
+
+        let server_msg = tokio::time::timeout(
+            Duration::from_secs(10),
+            stream.read_envelope()
+        ).await
+        .map_err(|_| "Enrollment timeout")?
+        .map_err(|e| format!("Read error during enrollment: {}", e))?;
+
+        match server_msg {
+            Envelope::Auth(TcpAuthMessage::Enrolled { secret }) => {
+                self.credential_file.save(&secret)
+                    .map_err(|e| format!("保存凭据 {} 失败: {}", self.credential_file.path().display(), e))?;
+                info!("Enrolled with the server, credential saved to {}", self.credential_file.path().display());
+                *self.credential.lock().await = Some(secret.clone());
+                Ok(secret)
+            }
+            Envelope::Auth(TcpAuthMessage::AuthResult { message, .. }) => {
+                error!("Enrollment failed: {}", message);
+                *self.state.lock().await = ClientState::AuthFailed;
+                Err(format!("Enrollment failed: {}", message).into())
+            }
+            other => Err(format!("Unexpected message during enrollment: {:?}", other).into()),
+        }
+    }
+
+    /// 保存服务端轮换后的密钥并确认；保存失败时不确认，服务端继续接受旧密钥
+    async fn update_credential(&self, secret: String) {
+        if let Err(e) = self.credential_file.save(&secret) {
+            error!("Failed to save rotated credential to {}: {}", self.credential_file.path().display(), e);
+            return;
+        }
+        info!("Server rotated the credential, saved to {}", self.credential_file.path().display());
+        *self.credential.lock().await = Some(secret);
+        if let Err(e) = self.send_message(Envelope::CredentialUpdateAck).await {
+            warn!("Failed to acknowledge credential update: {}", e);
+        }
+    }
+
+    /// 等待认证结果
+    async fn wait_for_auth_result(&self, stream: &mut FramedStream<BoxedStream>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let server_msg = tokio::time::timeout(
+            Duration::from_secs(10),
+            stream.read_envelope()
+        ).await
+        .map_err(|_| "Authentication result timeout")?
+        .map_err(|e| format!("Read error during auth result: {}", e))?;
         
         match server_msg {
-            ServerMessage::AuthResult { success, message } => {
+            Envelope::Auth(TcpAuthMessage::AuthResult { success, message }) => {
                 if success {
                     info!("Authentication successful: {}", message);
                     let mut state = self.state.lock().await;
@@ -266,73 +451,34 @@ impl TcpSession {
         }
     }
     
-    /// 发送消息到服务器
-    async fn send_message(&self, message: &ClientMessage) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let json_data = serde_json::to_vec(message)?;
-        let mut stream = self.stream.lock().await;
-        stream.write_all(&json_data).await?;
-        stream.write_all(b"\n").await?;
-        stream.flush().await?;
-        debug!("Message sent: {} bytes", json_data.len());
+    /// 把消息放入发送队列，由连接任务写出；队列满时等待
+    async fn send_message(&self, message: Envelope) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.outbound.send(message).await
+            .map_err(|_| "Outbound queue closed")?;
         Ok(())
     }
     
+    /// 校验请求签名；未配置签名公钥时不校验
+    async fn verify_request(
+        &self,
+        kind: SignedRequest,
+        request_id: &str,
+        content: &str,
+        signature: Option<&CommandSignature>
+    ) -> Result<(), String> {
+        let Some(verifier) = &self.command_verifier else {
+            return Ok(());
+        };
+        let client_id = self.get_client_id().await.map_err(|e| format!("读取客户端ID失败: {}", e))?;
+        verifier.lock().await.verify_request(kind, request_id, &client_id, content, signature)
+    }
+
     /// 检查是否已认证
     pub async fn is_authenticated(&self) -> bool {
         let state = self.state.lock().await;
         *state == ClientState::Authenticated
     }
 
-    pub async fn create_socket(addr: &str) -> std::io::Result<TcpStream> {
-        let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
-        
-        let parsed_addr: SocketAddr = addr.parse()
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
-        
-        socket.connect(&parsed_addr.into())
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e))?;
-
-        // 设置TCP keepalive参数
-        let keepalive = TcpKeepalive::new()
-            .with_time(Duration::from_secs(10))
-            .with_interval(Duration::from_secs(5));
-        socket.set_tcp_keepalive(&keepalive)?;
-
-        Ok(socket.into())
-    }
-
-    pub async fn send_data(&self, data: &[u8]) -> std::io::Result<()> {
-        debug!("Sending data to server, size: {} bytes", data.len());
-        let mut guard = self.stream.lock().await;
-        
-        match guard.write_all(data).await {
-            Ok(_) => {
-                // 确保数据被发送
-                if let Err(e) = guard.flush().await {
-                    warn!("Failed to flush data: {}", e);
-                }
-                debug!("Data sent successfully");
-                Ok(())
-            },
-            Err(e) => {
-                warn!("Failed to send data, attempting reconnection: {}", e);
-                
-                // 尝试重新连接
-                match Self::connect_with_retry(&self.addr, &self.config).await {
-                    Ok(new_stream) => {
-                        *guard = new_stream;
-                        info!("Reconnected successfully, resending data");
-                        guard.write_all(data).await
-                    },
-                    Err(reconnect_err) => {
-                        error!("Reconnection failed: {}", reconnect_err);
-                        Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, format!("Reconnection failed: {}", reconnect_err)))
-                    }
-                }
-            }
-        }
-    }
-
     pub async fn start_heartbeat(&self) {
         let session = self.clone();
         let client_id = match client::get_or_create_client_id(&session.config) {
@@ -355,23 +501,8 @@ impl TcpSession {
                 heartbeat_count += 1;
                 debug!("Starting heartbeat #{}", heartbeat_count);
                 
-                // 收集系统信息
-                let sys_info = HostInfo::new();
-                let version_info = version_collector::read_app_versions(&session.config.apps_base_dir);
-                
-                // 收集应用信息
-                let app_collector = AppInfoCollector::new(session.config.apps_base_dir.clone());
-                let app_info = app_collector.collect_apps_info();
-                
-                let current_time = SystemTime::now();
-
-                let client_data = ClientInfo {
-                    client_id: client_id.clone(),
-                    system_info: sys_info,
-                    version_info,
-                    app_info,
-                    last_seen: current_time,
-                };
+                let client_data = session.collect_client_info(&client_id).await;
+                let current_time = client_data.last_seen;
 
                 // 检查是否已认证
                 if !session.is_authenticated().await {
@@ -380,23 +511,16 @@ impl TcpSession {
                     continue;
                 }
 
-                // 创建客户端消息格式
-                let message = ClientMessage::ClientInfo {
-                    client_id: client_data.client_id,
-                    system_info: client_data.system_info,
-                    version_info: client_data.version_info,
-                    app_info: client_data.app_info,
-                    last_seen: client_data.last_seen,
-                };
+                let message = Envelope::ClientInfo(client_data);
 
-                // 发送心跳数据
-                match session.send_message(&message).await {
+                // 发送心跳数据；队列满时跳过本次心跳，不阻塞等待
+                match session.outbound.try_send(message) {
                     Ok(()) => {
                         last_successful_heartbeat = current_time;
-                        debug!("Heartbeat #{} sent successfully", heartbeat_count);
+                        debug!("Heartbeat #{} queued successfully", heartbeat_count);
                     }
                     Err(e) => {
-                        error!("Failed to send heartbeat #{}: {}", heartbeat_count, e);
+                        error!("Failed to queue heartbeat #{}: {}", heartbeat_count, e);
                         
                         // 检查连接健康状态
                         let time_since_last = current_time.duration_since(last_successful_heartbeat)
@@ -414,199 +538,616 @@ impl TcpSession {
         });
     }
 
-    // 处理服务端消息
+    /// 按顺序重发发件箱中未被确认的命令结果，服务端按 command_id 去重
+    async fn replay_outbox(&self, stream: &mut FramedStream<BoxedStream>) -> std::io::Result<()> {
+        let pending = self.outbox.lock().await.pending();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        info!("Replaying {} unacknowledged command result(s)", pending.len());
+        for response in pending {
+            stream.write_envelope(&Envelope::CommandResponse(response)).await?;
+        }
+        Ok(())
+    }
+
+    /// 收集主机与应用信息，用于心跳和重连后的重新上报
+    async fn collect_client_info(&self, client_id: &str) -> ClientInfo {
+        // 收集系统信息
+        let system_info = HostInfo::new();
+        let version_info = version_collector::read_app_versions(&self.config.apps_base_dir);
+
+        // 收集应用信息
+        let app_collector = AppInfoCollector::new(self.config.apps_base_dir.clone());
+        let app_info = app_collector.collect_apps_info();
+
+        ClientInfo {
+            client_id: client_id.to_string(),
+            system_info,
+            version_info,
+            app_info,
+            last_seen: SystemTime::now(),
+            command_queue: self.workers.status(),
+            command_policy: self.policy.lock().await.status(),
+            apps_base_dir: Some(self.config.apps_base_dir.clone()),
+        }
+    }
+
+    // 处理服务端发来的一个完整帧
     async fn process_server_message(&self, data: &[u8]) {
-        let message = String::from_utf8_lossy(data);
-        debug!("Processing server message: {}", message);
-
-        // 处理不同类型的消息
-        let trimmed_message = message.trim();
-        if trimmed_message.starts_with("CMD:") {
-            // 新格式: CMD:command_id::command 或 旧格式: CMD:command
-            let command_part = trimmed_message.trim_start_matches("CMD:");
-            
-            if let Some((command_id, command)) = command_part.split_once("::") {
+        let envelope = match Envelope::decode(data) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("Failed to parse server message: {} ({})", e, String::from_utf8_lossy(data));
+                return;
+            }
+        };
+        debug!("Processing server message: {:?}", envelope);
+
+        match envelope {
+            Envelope::CommandRequest { command_id, command, timeout_secs, signature } => {
                 info!("Received command from server: {} (ID: {})", command, command_id);
-                self.handle_command_with_id(command_id.trim(), command.trim()).await;
-            } else {
-                // 兼容旧格式
-                info!("Received command from server (legacy): {}", command_part);
-                self.handle_command(command_part.trim()).await;
-            }
-        } else if trimmed_message.starts_with("BROADCAST::") {
-            // 处理广播消息
-            let broadcast_content = trimmed_message.trim_start_matches("BROADCAST::");
-            info!("Received broadcast message: {}", broadcast_content);
-            self.handle_broadcast_message(broadcast_content).await;
-        } else if trimmed_message.contains("ACK") {
-            debug!("Received ACK from server");
-        } else if !trimmed_message.is_empty() {
-            info!("Received message from server: {}", trimmed_message);
-        }
-    }
-
-    pub async fn receive(&self) -> std::io::Result<Vec<u8>> {
-        let mut guard = self.stream.lock().await;
-        let mut buf = [0; 1024];
-        
-        // 使用超时避免无限阻塞
-        let n = tokio::time::timeout(
-            Duration::from_secs(1), 
-            guard.read(&mut buf)
-        ).await
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "Read timeout"))?
-        .map_err(|e| {
-            debug!("Read error: {}", e);
-            e
-        })?;
-
-        if n > 0 {
-            debug!("Received {} bytes: {:?}", n, String::from_utf8_lossy(&buf[..n]));
-            Ok(buf[..n].to_vec())
-        } else {
-            debug!("Received 0 bytes (connection closed)");
-            Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Connection closed"))
+                // 签名在读任务中按到达顺序校验，重复的 command_id 一定会被识别
+                if let Err(reason) = self.verify_request(SignedRequest::Command, &command_id, &command_signed_content(&command, timeout_secs), signature.as_ref()).await {
+                    error!("Refusing command {}: {}", command_id, reason);
+                    let rejection = Envelope::CommandRejected { command_id, reason };
+                    if let Err(e) = self.send_message(rejection).await {
+                        error!("Failed to send rejection for command: {}", e);
+                    }
+                    return;
+                }
+                let timeout = self.config.command_timeout(timeout_secs);
+                // 取消信号在读任务中登记，保证随后到达的 cancel_command 一定能找到它
+                let (cancel_tx, cancel_rx) = oneshot::channel();
+                self.running.lock().await.insert(command_id.clone(), cancel_tx);
+
+                // 命令在独立任务中执行，读任务继续接收后续消息
+                let session = self.clone();
+                tokio::spawn(async move {
+                    session.handle_command_with_id(&command_id, &command, timeout, cancel_rx).await;
+                    session.running.lock().await.remove(&command_id);
+                });
+            }
+            Envelope::CancelCommand { command_id } => {
+                match self.running.lock().await.remove(&command_id) {
+                    Some(cancel) => {
+                        info!("Cancelling command {}", command_id);
+                        let _ = cancel.send(());
+                    }
+                    None => debug!("Ignoring cancel for command {} that is not running", command_id),
+                }
+            }
+            Envelope::Broadcast { message } => {
+                info!("Received broadcast message: {}", message);
+                // 通知依赖外部命令，放到独立任务中避免阻塞读任务
+                let session = self.clone();
+                tokio::spawn(async move {
+                    session.handle_broadcast_message(&message).await;
+                });
+            }
+            Envelope::TerminalOpen { session_id, cols, rows, signature } => {
+                if !self.config.terminal_enabled {
+                    warn!("Refusing terminal {}: terminal is disabled", session_id);
+                    let closed = Envelope::TerminalClosed { session_id, reason: Some("客户端未启用终端".to_string()) };
+                    let _ = self.outbound.try_send(closed);
+                    return;
+                }
+                if let Err(reason) = self.verify_request(SignedRequest::TerminalOpen, &session_id, "", signature.as_ref()).await {
+                    error!("Refusing terminal {}: {}", session_id, reason);
+                    let _ = self.outbound.try_send(Envelope::TerminalClosed { session_id, reason: Some(reason) });
+                    return;
+                }
+                // 控制队列在读任务中登记，保证随后到达的输入能找到会话
+                let (control_tx, control_rx) = mpsc::channel(TERMINAL_CONTROL_QUEUE);
+                self.terminals.lock().await.insert(session_id.clone(), control_tx);
+                let session = self.clone();
+                tokio::spawn(async move {
+                    session.run_terminal(session_id, cols, rows, control_rx).await;
+                });
+            }
+            Envelope::TerminalInput { session_id, data } => {
+                self.control_terminal(&session_id, TerminalControl::Input(data)).await;
+            }
+            Envelope::TerminalResize { session_id, cols, rows } => {
+                self.control_terminal(&session_id, TerminalControl::Resize { cols, rows }).await;
+            }
+            Envelope::TerminalClose { session_id } => {
+                self.control_terminal(&session_id, TerminalControl::Close).await;
+            }
+            Envelope::FilePushStart(start) => {
+                self.start_file_push(start).await;
+            }
+            Envelope::FilePushChunk { transfer_id, offset, data } => {
+                self.receive_file_chunk(&transfer_id, offset, &data).await;
+            }
+            Envelope::FileFetchRequest { fetch_id, path, range, signature } => {
+                info!("Server requested file {} ({:?}) for fetch {}", path, range, fetch_id);
+                let content = file_fetch_signed_content(&path, &range);
+                if let Err(reason) = self.verify_request(SignedRequest::FileFetch, &fetch_id, &content, signature.as_ref()).await {
+                    error!("Refusing file fetch {}: {}", fetch_id, reason);
+                    let _ = self.outbound.try_send(Envelope::FileFetchFailed { fetch_id, reason });
+                    return;
+                }
+                // 分块发送时等待发送队列，放到独立任务中避免阻塞读任务
+                let session = self.clone();
+                tokio::spawn(async move {
+                    if let Err(reason) = session.send_file(&fetch_id, &path, &range).await {
+                        warn!("File fetch {} failed: {}", fetch_id, reason);
+                        let failed = Envelope::FileFetchFailed { fetch_id: fetch_id.clone(), reason };
+                        if let Err(e) = session.send_message(failed).await {
+                            error!("Failed to report file fetch {}: {}", fetch_id, e);
+                        }
+                    }
+                });
+            }
+            Envelope::LogTailStart { tail_id, app, file, lines, signature } => {
+                let content = log_tail_signed_content(&app, &file, lines);
+                if let Err(reason) = self.verify_request(SignedRequest::LogTail, &tail_id, &content, signature.as_ref()).await {
+                    error!("Refusing log tail {}: {}", tail_id, reason);
+                    let _ = self.outbound.try_send(Envelope::LogTailEnded { tail_id, reason });
+                    return;
+                }
+                info!("Following log {} of app {} for tail {}", file, app, tail_id);
+                // 停止信号在读任务中登记，保证随后到达的 log_tail_stop 一定能找到它
+                let (stop_tx, stop_rx) = oneshot::channel();
+                self.log_tails.lock().await.insert(tail_id.clone(), stop_tx);
+                let session = self.clone();
+                tokio::spawn(async move {
+                    session.run_log_tail(tail_id, &app, &file, lines, stop_rx).await;
+                });
+            }
+            Envelope::LogTailStop { tail_id } => {
+                match self.log_tails.lock().await.remove(&tail_id) {
+                    Some(stop) => {
+                        let _ = stop.send(());
+                    }
+                    None => debug!("Ignoring stop for unknown log tail {}", tail_id),
+                }
+            }
+            Envelope::Ack => {
+                debug!("Received ACK from server");
+            }
+            Envelope::ResultAck { command_id } => {
+                debug!("Server acknowledged result for command {}", command_id);
+                if let Err(e) = self.outbox.lock().await.remove(&command_id) {
+                    warn!("Failed to remove command {} from outbox: {}", command_id, e);
+                }
+            }
+            Envelope::CredentialUpdate { secret } => {
+                self.update_credential(secret).await;
+            }
+            Envelope::CommandPolicy { policy_id, policy, signature } => {
+                // 策略只在签名可信时生效，否则冒充的服务端可以下发放行一切的策略
+                let content = command_policy_signed_content(&policy);
+                if let Err(reason) = self.verify_request(SignedRequest::CommandPolicy, &policy_id, &content, signature.as_ref()).await {
+                    error!("Ignoring command policy from server: {}", reason);
+                    return;
+                }
+                match self.policy.lock().await.apply_server_policy(policy) {
+                    Ok(version) => info!("Enforcing command policy {} from server", version),
+                    Err(e) => error!("Ignoring invalid command policy from server: {}", e),
+                }
+            }
+            Envelope::SessionToken { token, expires_in_secs } => {
+                debug!("Received resume token, valid for {}s after disconnect", expires_in_secs);
+                *self.resume_token.lock().await = Some(token);
+            }
+            Envelope::ConnectionRejected { reason } => {
+                warn!("Server rejected connection: {}", reason);
+            }
+            other => {
+                warn!("Received unexpected message from server: {:?}", other);
+            }
         }
     }
 
-    // 在TcpSession结构体中添加消息处理
-    pub async fn start_message_listener(&self) {
-        let session = self.clone();
+    /// 开始接收推送文件；空文件直接落盘
+    async fn start_file_push(&self, start: FilePushStart) {
+        info!("Receiving file {} ({} bytes) for transfer {}", start.path, start.size, start.transfer_id);
+        if self.config.file_push_dirs.is_empty() {
+            self.report_file_push(&start.transfer_id, Err("客户端未启用文件推送".to_string())).await;
+            return;
+        }
+        // 分块不单独签名：未通过校验的传输不会登记，分块内容由签名的校验和保护
+        let content = start.signed_content();
+        if let Err(reason) = self.verify_request(SignedRequest::FilePush, &start.transfer_id, &content, start.signature.as_ref()).await {
+            self.report_file_push(&start.transfer_id, Err(reason)).await;
+            return;
+        }
 
-        tokio::spawn(async move {
-            info!("消息监听器已启动");
-            loop {
-                debug!("等待接收服务端消息...");
-                match session.receive().await {
-                    Ok(data) => {
-                        if !data.is_empty() {
-                            let message = String::from_utf8_lossy(&data);
-                            debug!("收到服务器消息: {}", message);
-
-                            // 使用现有的消息处理逻辑
-                            session.process_server_message(&data).await;
-                        } else {
-                            debug!("接收到空数据");
+        match IncomingFile::create(&start, &self.config.file_push_dirs, self.config.max_push_file_size).await {
+            Ok(incoming) if incoming.is_complete() => {
+                let target = incoming.target().display().to_string();
+                let result = incoming.finish().await.map(|_| target);
+                self.report_file_push(&start.transfer_id, result).await;
+            }
+            Ok(incoming) => {
+                if let Some(previous) = self.incoming_files.lock().await.insert(start.transfer_id.clone(), incoming) {
+                    previous.discard().await;
+                }
+            }
+            Err(e) => self.report_file_push(&start.transfer_id, Err(e)).await,
+        }
+    }
+
+    /// 写入一个分块，收齐后校验并落盘
+    async fn receive_file_chunk(&self, transfer_id: &str, offset: u64, data: &[u8]) {
+        let mut incoming_files = self.incoming_files.lock().await;
+        let Some(incoming) = incoming_files.get_mut(transfer_id) else {
+            debug!("Ignoring chunk of unknown transfer {}", transfer_id);
+            return;
+        };
+
+        let result = incoming.write_chunk(offset, data).await;
+        if result.is_ok() && !incoming.is_complete() {
+            return;
+        }
+        let Some(incoming) = incoming_files.remove(transfer_id) else { return };
+        drop(incoming_files);
+
+        let result = match result {
+            Ok(()) => {
+                let target = incoming.target().display().to_string();
+                incoming.finish().await.map(|_| target)
+            }
+            Err(e) => {
+                incoming.discard().await;
+                Err(e)
+            }
+        };
+        self.report_file_push(transfer_id, result).await;
+    }
+
+    async fn report_file_push(&self, transfer_id: &str, result: Result<String, String>) {
+        let (success, message) = match result {
+            Ok(target) => {
+                info!("File transfer {} written to {}", transfer_id, target);
+                (true, format!("已写入 {}", target))
+            }
+            Err(e) => {
+                warn!("File transfer {} failed: {}", transfer_id, e);
+                (false, e)
+            }
+        };
+        let result = Envelope::FilePushResult { transfer_id: transfer_id.to_string(), success, message };
+        if let Err(e) = self.send_message(result).await {
+            error!("Failed to report file transfer {}: {}", transfer_id, e);
+        }
+    }
+
+    /// 按请求的范围分块回传文件，最后发送整段内容的校验和
+    async fn send_file(&self, fetch_id: &str, path: &str, range: &FetchRange) -> Result<(), String> {
+        if self.config.file_fetch_dirs.is_empty() {
+            return Err("客户端未启用文件读取".to_string());
+        }
+        let mut file = OutgoingFile::open(path, range, &self.config.file_fetch_dirs, self.config.max_fetch_file_size).await?;
+        self.send_message(Envelope::FileFetchStart {
+            fetch_id: fetch_id.to_string(),
+            file_size: file.file_size(),
+            offset: file.offset(),
+            length: file.length(),
+        }).await.map_err(|e| e.to_string())?;
+
+        while let Some((offset, data)) = file.next_chunk().await? {
+            self.send_message(Envelope::FileFetchChunk { fetch_id: fetch_id.to_string(), offset, data })
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        let sha256 = file.finish();
+        info!("File fetch {} sent {}", fetch_id, path);
+        self.send_message(Envelope::FileFetchEnd { fetch_id: fetch_id.to_string(), sha256 })
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn discard_incoming_files(&self) {
+        for (transfer_id, incoming) in self.incoming_files.lock().await.drain() {
+            debug!("Discarding incomplete transfer {} on disconnect", transfer_id);
+            incoming.discard().await;
+        }
+    }
+
+    /// 把控制消息交给终端会话，会话不存在时忽略
+    async fn control_terminal(&self, session_id: &str, control: TerminalControl) {
+        let Some(sender) = self.terminals.lock().await.get(session_id).cloned() else {
+            debug!("Ignoring message for unknown terminal {}", session_id);
+            return;
+        };
+        if sender.try_send(control).is_err() {
+            warn!("Terminal {} control queue full, dropping message", session_id);
+        }
+    }
+
+    async fn close_terminals(&self) {
+        for (session_id, sender) in self.terminals.lock().await.drain() {
+            debug!("Closing terminal {} on disconnect", session_id);
+            let _ = sender.try_send(TerminalControl::Close);
+        }
+    }
+
+    async fn stop_log_tails(&self) {
+        for (tail_id, stop) in self.log_tails.lock().await.drain() {
+            debug!("Stopping log tail {} on disconnect", tail_id);
+            let _ = stop.send(());
+        }
+    }
+
+    /// 跟踪应用日志直到服务端停止；读取失败时通知服务端
+    async fn run_log_tail(&self, tail_id: String, app: &str, file: &str, lines: u64, mut stop: oneshot::Receiver<()>) {
+        let result = self.follow_log(&tail_id, app, file, lines, &mut stop).await;
+        self.log_tails.lock().await.remove(&tail_id);
+        info!("Log tail {} stopped", tail_id);
+        if let Err(reason) = result {
+            warn!("Log tail {} failed: {}", tail_id, reason);
+            if let Err(e) = self.send_message(Envelope::LogTailEnded { tail_id, reason }).await {
+                warn!("Failed to report log tail end: {}", e);
+            }
+        }
+    }
+
+    async fn follow_log(&self, tail_id: &str, app: &str, file: &str, lines: u64, stop: &mut oneshot::Receiver<()>) -> Result<(), String> {
+        let path = AppInfoCollector::new(self.config.apps_base_dir.clone()).resolve_app_file(app, file)?;
+        let mut follower = LogFollower::open(path, lines)
+            .await
+            .map_err(|e| format!("打开日志失败: {}", e))?;
+
+        loop {
+            let data = follower.poll().await.map_err(|e| format!("读取日志失败: {}", e))?;
+            if !data.is_empty() {
+                self.send_message(Envelope::LogTailData { tail_id: tail_id.to_string(), data })
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            tokio::select! {
+                _ = &mut *stop => return Ok(()),
+                _ = tokio::time::sleep(log_tail::POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    /// 终端会话：启动 PTY shell 并转发输入输出，结束后通知服务端
+    async fn run_terminal(&self, session_id: String, cols: u16, rows: u16, mut control: mpsc::Receiver<TerminalControl>) {
+        info!("Opening terminal {} ({}x{})", session_id, cols, rows);
+        let reason = match PtyShell::spawn(&self.config.terminal_shell, cols, rows) {
+            Ok(mut shell) => {
+                let reason = self.pump_terminal(&session_id, &shell, &mut control).await;
+                shell.terminate(Duration::from_secs(self.config.cancel_grace_secs)).await;
+                reason
+            }
+            Err(e) => {
+                error!("Failed to start terminal {}: {}", session_id, e);
+                Some(format!("启动终端失败: {}", e))
+            }
+        };
+
+        self.terminals.lock().await.remove(&session_id);
+        info!("Terminal {} closed", session_id);
+        if let Err(e) = self.send_message(Envelope::TerminalClosed { session_id, reason }).await {
+            warn!("Failed to report terminal close: {}", e);
+        }
+    }
+
+    /// 转发终端输入输出，直到 shell 退出或服务端关闭终端；返回异常结束的原因
+    async fn pump_terminal(&self, session_id: &str, shell: &PtyShell, control: &mut mpsc::Receiver<TerminalControl>) -> Option<String> {
+        let mut buf = [0u8; 4096];
+        let mut pending = Vec::new();
+
+        loop {
+            tokio::select! {
+                read = shell.read(&mut buf) => {
+                    let n = match read {
+                        Ok(0) => return None,
+                        Ok(n) => n,
+                        Err(e) => return Some(format!("读取终端失败: {}", e)),
+                    };
+                    // 多字节字符可能被拆在两次读取之间，留到下次一起发送
+                    pending.extend_from_slice(&buf[..n]);
+                    let complete = complete_utf8_len(&pending);
+                    if complete == 0 {
+                        continue;
+                    }
+                    let data = String::from_utf8_lossy(&pending[..complete]).into_owned();
+                    pending.drain(..complete);
+                    let output = Envelope::TerminalOutput { session_id: session_id.to_string(), data };
+                    if self.send_message(output).await.is_err() {
+                        return Some("发送队列已关闭".to_string());
+                    }
+                }
+                message = control.recv() => match message {
+                    Some(TerminalControl::Input(data)) => {
+                        if let Err(e) = shell.write_all(data.as_bytes()).await {
+                            return Some(format!("写入终端失败: {}", e));
                         }
                     }
-                    Err(e) => {
-                        match e.kind() {
-                            std::io::ErrorKind::TimedOut => {
-                                // 超时是正常的，继续监听
-                                debug!("等待消息超时，继续监听...");
-                            }
-                            std::io::ErrorKind::UnexpectedEof => {
-                                warn!("连接断开，尝试重新连接...");
-                                // 尝试重新连接
-                                if let Ok(new_stream) = Self::connect_with_retry(&session.addr, &session.config).await {
-                                    let mut guard = session.stream.lock().await;
-                                    *guard = new_stream;
-                                    info!("重新连接成功");
-                                } else {
-                                    error!("重新连接失败，等待后重试");
-                                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                                }
-                            }
-                            _ => {
-                                error!("接收消息错误: {}, 等待后重试", e);
-                                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                    Some(TerminalControl::Resize { cols, rows }) => {
+                        if let Err(e) = shell.resize(cols, rows) {
+                            warn!("Failed to resize terminal {}: {}", session_id, e);
+                        }
+                    }
+                    Some(TerminalControl::Close) | None => return None,
+                },
+            }
+        }
+    }
+
+    /// 连接任务：读端交给独立的读任务，本任务按顺序写出发送队列中的消息；
+    /// 任一方向出错后重新连接，写失败的那条消息在新连接上重发
+    async fn run_connection(self, mut stream: FramedStream<BoxedStream>, mut outbound_rx: mpsc::Receiver<Envelope>) {
+        let mut unsent: Option<Envelope> = None;
+
+        loop {
+            let (mut reader, mut writer) = stream.split();
+
+            let session = self.clone();
+            let mut reader_task = tokio::spawn(async move {
+                loop {
+                    match reader.read_frame().await {
+                        Ok(data) => session.process_server_message(&data).await,
+                        Err(e) => {
+                            if FrameTooLarge::is_frame_too_large(&e) {
+                                // 超长帧之后的字节流已无法对齐，只能重建连接
+                                error!("服务端消息超过最大帧大小: {}", e);
+                            } else {
+                                warn!("读取服务端消息失败: {}", e);
                             }
+                            return e.to_string();
                         }
                     }
                 }
+            });
+
+            let reason;
+            loop {
+                let message = match unsent.take() {
+                    Some(message) => message,
+                    None => tokio::select! {
+                        message = outbound_rx.recv() => match message {
+                            Some(message) => message,
+                            None => {
+                                // 所有发送端都已释放，会话结束
+                                reader_task.abort();
+                                return;
+                            }
+                        },
+                        result = &mut reader_task => {
+                            reason = result.unwrap_or_else(|e| format!("读任务异常退出: {}", e));
+                            break;
+                        }
+                    },
+                };
+
+                if let Err(e) = writer.write_envelope(&message).await {
+                    warn!("发送消息失败: {}", e);
+                    reason = e.to_string();
+                    unsent = Some(message);
+                    break;
+                }
+                debug!("Message sent: {:?}", message);
             }
-        });
+
+            reader_task.abort();
+            // 终端和未收完的推送文件不随连接恢复，断开时全部结束
+            self.close_terminals().await;
+            self.discard_incoming_files().await;
+            self.stop_log_tails().await;
+            warn!("连接断开（{}），尝试重新连接...", reason);
+            stream = self.reconnect(&reason).await;
+        }
+    }
+
+    /// 重连状态机：建立连接 → 协商协议 → 认证 → 重新上报 ClientInfo 和重连报告，
+    /// 任一步失败都按退避间隔从头重试；返回后由连接任务继续发送排队的消息
+    async fn reconnect(&self, reason: &str) -> FramedStream<BoxedStream> {
+        *self.state.lock().await = ClientState::Reconnecting;
+        let started = Instant::now();
+        let mut attempts = 0u32;
+        let mut last_error: Option<String> = None;
+
+        loop {
+            attempts += 1;
+            info!("Reconnect attempt {} to {}", attempts, self.addr);
+
+            match self.try_reconnect(attempts, started, reason, last_error.clone()).await {
+                Ok(stream) => {
+                    info!("Reconnected to {} after {} attempt(s), offline for {:?}", self.addr, attempts, started.elapsed());
+                    return stream;
+                }
+                Err(e) => {
+                    let delay = retry_delay(&self.config, attempts);
+                    warn!("Reconnect attempt {} failed: {}, retrying in {}s", attempts, e, delay.as_secs());
+                    last_error = Some(e.to_string());
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// 执行一次完整的重连
+    async fn try_reconnect(
+        &self,
+        attempts: u32,
+        started: Instant,
+        reason: &str,
+        last_error: Option<String>
+    ) -> Result<FramedStream<BoxedStream>, Box<dyn std::error::Error + Send + Sync>> {
+        let stream = Self::create_socket_async(&self.addr, &self.config, self.tls_connector.as_ref()).await?;
+        let mut stream = FramedStream::new(stream, FrameCodec::new(self.config.max_frame_size));
+        let resumed = self.handshake(&mut stream).await?;
+
+        // 先于排队的消息重新上报主机信息，服务端据此重新登记连接
+        let client_id = self.get_client_id().await?;
+        stream.write_envelope(&Envelope::ClientInfo(self.collect_client_info(&client_id).await)).await?;
+        stream.write_envelope(&Envelope::ReconnectReport(ReconnectReport {
+            client_id,
+            attempts,
+            downtime_secs: started.elapsed().as_secs(),
+            reason: reason.to_string(),
+            last_error,
+            resumed,
+        })).await?;
+        self.replay_outbox(&mut stream).await?;
+
+        Ok(stream)
     }
 
     // 带命令ID的命令处理 - 会将结果返回给服务端
-    async fn handle_command_with_id(&self, command_id: &str, command: &str) {
+    async fn handle_command_with_id(&self, command_id: &str, command: &str, timeout: Duration, cancel: oneshot::Receiver<()>) {
         info!("Executing command with ID {}: {}", command_id, command);
 
-        // 1. 命令验证
-        let sanitized_command = self.validator.sanitize_command(command);
-        let validation_result = self.validator.validate(&sanitized_command);
+        // 1. 命令验证：按 shell 语法解析后逐条校验，通过后原样执行
+        let validation_result = self.policy.lock().await.validator().validate(command);
         
         // 2. 记录命令到日志
         if let Err(e) = self.log_command(command).await {
             error!("Failed to log command: {}", e);
         }
 
+        // 先告知服务端命令已送达：通过校验则确认接收，否则带原因拒绝，不再返回执行结果
         let execution_result = match validation_result {
             ValidationResult::Allowed => {
-                info!("Command validation passed: {}", sanitized_command);
-                self.execute_command(&sanitized_command).await
+                info!("Command validation passed: {}", command);
+                if let Err(e) = self.send_message(Envelope::CommandAccepted { command_id: command_id.to_string() }).await {
+                    error!("Failed to acknowledge command {}: {}", command_id, e);
+                }
+                self.execute_command(command_id, command, timeout, cancel).await
             }
             ValidationResult::Blocked { reason } => {
                 error!("Command blocked: {} (reason: {})", command, reason);
-                Err(format!("命令被阻止: {}", reason).into())
-            }
-        };
-
-        // 3. 准备结果数据
-        let (output, error_output, exit_code) = match execution_result {
-            Ok(result) => {
-                // 解析结果字符串以提取状态码和输出 - 使用安全的字符串操作
-                if let Some(status_marker) = result.find("状态码: ") {
-                    // 安全地跳过"状态码: "标记
-                    let status_part = &result[status_marker..];
-                    if let Some(colon_pos) = status_part.find(": ") {
-                        let after_colon = &status_part[colon_pos + 2..];
-                        if let Some(status_end) = after_colon.find('\n') {
-                            let status_str = &after_colon[..status_end];
-                            let exit_code = status_str.parse().unwrap_or(-1);
-                            
-                            if let Some(stdout_marker) = result.find("标准输出:\n") {
-                                let after_stdout_marker = &result[stdout_marker..];
-                                if let Some(newline_pos) = after_stdout_marker.find('\n') {
-                                    let stdout_part = &after_stdout_marker[newline_pos + 1..];
-                                    if let Some(stderr_marker_pos) = stdout_part.find("\n错误输出:\n") {
-                                        let stdout = stdout_part[..stderr_marker_pos].to_string();
-                                        let stderr_part = &stdout_part[stderr_marker_pos..];
-                                        if let Some(stderr_newline) = stderr_part.find('\n') {
-                                            let stderr = stderr_part[stderr_newline + 1..].to_string();
-                                            (stdout, stderr, exit_code)
-                                        } else {
-                                            (stdout, String::new(), exit_code)
-                                        }
-                                    } else {
-                                        (stdout_part.to_string(), String::new(), exit_code)
-                                    }
-                                } else {
-                                    (result, String::new(), exit_code)
-                                }
-                            } else {
-                                (result, String::new(), exit_code)
-                            }
-                        } else {
-                            (result, String::new(), 0)
-                        }
-                    } else {
-                        (result, String::new(), 0)
-                    }
-                } else {
-                    (result, String::new(), 0)
+                let rejection = Envelope::CommandRejected { command_id: command_id.to_string(), reason };
+                if let Err(e) = self.send_message(rejection).await {
+                    error!("Failed to send rejection for command {}: {}", command_id, e);
                 }
-            }
-            Err(e) => {
-                (String::new(), e.to_string(), -1)
+                return;
             }
         };
 
+        // 3. 执行失败时把错误作为错误输出返回
+        let result = execution_result.unwrap_or_else(|e| ExecutionResult::not_run(e.to_string(), false));
+
         // 4. 构建命令结果并发送回服务端
         let executed_at = SystemTime::now();
         let client_id = self.get_client_id().await.unwrap_or_default();
         
-        let command_result = ClientMessage::CommandResponse {
+        let mut response = CommandResponse {
             command_id: command_id.to_string(),
             client_id,
             command: command.to_string(),
-            output,
-            error_output,
-            exit_code,
+            output: result.stdout,
+            error_output: result.stderr,
+            exit_code: result.exit_code,
             executed_at,
+            cancelled: result.cancelled,
+            timed_out: result.timed_out,
         };
+        truncate_response_to_frame(&mut response, self.config.max_frame_size);
 
-        match self.send_message(&command_result).await {
+        // 先落盘，连接中断时结果不会丢失，收到 result_ack 后删除
+        if let Err(e) = self.outbox.lock().await.push(&response) {
+            error!("Failed to persist result for command {}: {}", command_id, e);
+        }
+
+        match self.send_message(Envelope::CommandResponse(response)).await {
             Ok(()) => {
                 info!("Command result sent for command ID: {}", command_id);
             }
@@ -616,50 +1157,6 @@ impl TcpSession {
         }
     }
 
-    // 处理命令 - 添加安全验证 (兼容旧接口)
-    async fn handle_command(&self, command: &str) {
-        info!("Received command: {}", command);
-
-        // 1. 命令验证
-        let sanitized_command = self.validator.sanitize_command(command);
-        match self.validator.validate(&sanitized_command) {
-            ValidationResult::Allowed => {
-                info!("Command validation passed: {}", sanitized_command);
-            }
-            ValidationResult::Blocked { reason } => {
-                error!("Command blocked: {} (reason: {})", command, reason);
-                
-                let error_response = format!("命令被阻止: {}", reason);
-                if let Err(e) = self.send_data(error_response.as_bytes()).await {
-                    error!("Failed to send error response: {}", e);
-                }
-                return;
-            }
-        }
-
-        // 2. 记录命令到日志
-        if let Err(e) = self.log_command(command).await {
-            error!("Failed to log command: {}", e);
-        }
-
-        // 3. 执行命令
-        match self.execute_command(&sanitized_command).await {
-            Ok(response) => {
-                info!("Command executed successfully");
-                if let Err(e) = self.send_data(response.as_bytes()).await {
-                    error!("Failed to send command response: {}", e);
-                }
-            }
-            Err(e) => {
-                error!("Command execution failed: {}", e);
-                let error_response = format!("命令执行失败: {}", e);
-                if let Err(e) = self.send_data(error_response.as_bytes()).await {
-                    error!("Failed to send error response: {}", e);
-                }
-            }
-        }
-    }
-
     // 记录命令到日志文件
     async fn log_command(&self, command: &str) -> std::io::Result<()> {
         use std::io::Write;
@@ -679,17 +1176,79 @@ impl TcpSession {
     }
 
     // 安全地执行命令
-    async fn execute_command(&self, command: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        info!("Executing command: {}", command);
+    /// 执行命令，返回退出码、两路输出以及命令是否被取消或超时
+    async fn execute_command(
+        &self,
+        command_id: &str,
+        command: &str,
+        timeout: Duration,
+        mut cancel: oneshot::Receiver<()>,
+    ) -> Result<ExecutionResult, Box<dyn std::error::Error + Send + Sync>> {
+        // 等待空闲执行槽，超时只计算实际执行的时间；排队期间被取消则不再执行
+        let _slot = tokio::select! {
+            slot = self.workers.acquire() => slot,
+            Ok(()) = &mut cancel => {
+                info!("Command {} cancelled while queued", command_id);
+                return Ok(ExecutionResult::not_run("命令在排队等待时被取消".to_string(), true));
+            }
+        };
+
+        info!("Executing command: {} (timeout {:?})", command, timeout);
 
-        let output = tokio::process::Command::new("sh")
+        // 命令放在独立的进程组中，取消或超时时连同它启动的子进程一起结束
+        let mut child = tokio::process::Command::new("sh")
             .arg("-c")
             .arg(command)
-            .output()
-            .await?;
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()?;
+        let pgid = child.id().ok_or("无法获取子进程 ID")? as i32;
+
+        let stdout = child.stdout.take().ok_or("无法获取标准输出")?;
+        let stderr = child.stderr.take().ok_or("无法获取错误输出")?;
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
+        // 服务端支持时边执行边回传输出，stdout 与 stderr 共用一个序号
+        let streaming = self.negotiated.lock().await
+            .as_ref()
+            .is_some_and(|ack| ack.supports(&Capability::StreamingOutput));
+        let seq = AtomicU64::new(0);
+
+        let run = async {
+            tokio::try_join!(
+                self.pump_output(command_id, stdout, OutputStream::Stdout, streaming, &seq),
+                self.pump_output(command_id, stderr, OutputStream::Stderr, streaming, &seq),
+                child.wait(),
+            )
+        };
+        tokio::pin!(run);
+
+        // 取消或超时时结束进程组，已产生的输出照常返回
+        let mut interruption = None;
+        let (stdout, stderr, status) = tokio::select! {
+            result = &mut run => result?,
+            Ok(()) = cancel => {
+                interruption = Some(Interruption::Cancelled);
+                self.stop_process_group(command_id, pgid, run.as_mut()).await?
+            }
+            _ = tokio::time::sleep(timeout) => {
+                warn!("Command {} exceeded its {:?} timeout", command_id, timeout);
+                interruption = Some(Interruption::TimedOut);
+                self.stop_process_group(command_id, pgid, run.as_mut()).await?
+            }
+        };
+
+        let stdout = String::from_utf8_lossy(&stdout).into_owned();
+        let mut stderr = String::from_utf8_lossy(&stderr).into_owned();
+        // 与 coreutils timeout 一致，超时的命令退出码为 124
+        let exit_code = if interruption == Some(Interruption::TimedOut) {
+            stderr.push_str(&format!("\n命令执行超过 {} 秒，已被终止", timeout.as_secs()));
+            TIMED_OUT_EXIT_CODE
+        } else {
+            status.code().unwrap_or(-1)
+        };
 
         if !stdout.is_empty() {
             info!("Command stdout: {}", stdout.trim());
@@ -699,14 +1258,70 @@ impl TcpSession {
             warn!("Command stderr: {}", stderr.trim());
         }
 
-        let response = format!(
-            "命令执行完成\n状态码: {}\n标准输出:\n{}\n错误输出:\n{}",
-            output.status.code().unwrap_or(-1),
+        Ok(ExecutionResult {
+            exit_code,
             stdout,
-            stderr
-        );
+            stderr,
+            cancelled: interruption == Some(Interruption::Cancelled),
+            timed_out: interruption == Some(Interruption::TimedOut),
+        })
+    }
 
-        Ok(response)
+    /// 结束命令所在的进程组：先 SIGTERM，宽限期内未退出再 SIGKILL，然后等待输出读完
+    async fn stop_process_group<F: Future>(&self, command_id: &str, pgid: i32, mut run: Pin<&mut F>) -> F::Output {
+        signal_process_group(pgid, libc::SIGTERM);
+        let grace = Duration::from_secs(self.config.cancel_grace_secs);
+        match tokio::time::timeout(grace, run.as_mut()).await {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("Command {} did not exit within {:?} after SIGTERM, sending SIGKILL", command_id, grace);
+                signal_process_group(pgid, libc::SIGKILL);
+                run.await
+            }
+        }
+    }
+
+    /// 读取子进程的一路输出并返回全部内容；streaming 为 true 时每读到一块就发给服务端
+    ///
+    /// 分块用 try_send 入队，发送队列满（例如正在重连）时丢弃该块而不是阻塞命令，完整输出仍随结果返回。
+    async fn pump_output<R: AsyncRead + Unpin>(
+        &self,
+        command_id: &str,
+        mut reader: R,
+        stream: OutputStream,
+        streaming: bool,
+        seq: &AtomicU64,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut collected = Vec::new();
+        // collected 中已经发送的长度
+        let mut sent = 0;
+        let mut buffer = [0u8; 8192];
+
+        loop {
+            let n = reader.read(&mut buffer).await?;
+            collected.extend_from_slice(&buffer[..n]);
+
+            if streaming {
+                // 末尾不完整的 UTF-8 字符留到下一块，读到 EOF 时全部发出
+                let end = if n == 0 { collected.len() } else { sent + complete_utf8_len(&collected[sent..]) };
+                if end > sent {
+                    let chunk = CommandOutputChunk {
+                        command_id: command_id.to_string(),
+                        seq: seq.fetch_add(1, Ordering::SeqCst),
+                        stream,
+                        data: String::from_utf8_lossy(&collected[sent..end]).into_owned(),
+                    };
+                    sent = end;
+                    if self.outbound.try_send(Envelope::CommandOutput(chunk)).is_err() {
+                        debug!("Outbound queue full or closed, dropping output chunk of command {}", command_id);
+                    }
+                }
+            }
+
+            if n == 0 {
+                return Ok(collected);
+            }
+        }
     }
 
     // 处理广播消息并发送系统通知
@@ -855,15 +1470,63 @@ impl TcpSession {
     }
 }
 
+/// 命令输出超过最大帧大小时截断，保证结果仍能送达服务端
+pub fn truncate_response_to_frame(response: &mut CommandResponse, max_frame_size: usize) {
+    const TRUNCATED_MARKER: &str = "\n...[输出过大，已截断]";
+
+    loop {
+        let encoded_len = Envelope::CommandResponse(response.clone())
+            .encode()
+            .map(|data| data.len())
+            .unwrap_or(usize::MAX);
+        if encoded_len <= max_frame_size {
+            return;
+        }
+
+        let target = if response.output.len() >= response.error_output.len() {
+            &mut response.output
+        } else {
+            &mut response.error_output
+        };
+        if target.ends_with(TRUNCATED_MARKER) {
+            let len = target.len() - TRUNCATED_MARKER.len();
+            target.truncate(len);
+        }
+        if target.is_empty() {
+            // 命令文本本身已超过帧大小，无法再缩减
+            warn!("Command response for {} cannot fit in a frame", response.command_id);
+            return;
+        }
+
+        let mut cut = target.len() / 2;
+        while !target.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        target.truncate(cut);
+        target.push_str(TRUNCATED_MARKER);
+    }
+}
+
 impl Clone for TcpSession {
     fn clone(&self) -> Self {
         Self {
-            stream: Arc::clone(&self.stream),
+            outbound: self.outbound.clone(),
             addr: self.addr.clone(),
+            tls_connector: self.tls_connector.clone(),
             config: self.config.clone(),
-            validator: self.validator.clone(),
+            policy: Arc::clone(&self.policy),
+            command_verifier: self.command_verifier.clone(),
             state: Arc::clone(&self.state),
-            authenticator: self.authenticator.clone(),
+            credential_file: Arc::clone(&self.credential_file),
+            credential: Arc::clone(&self.credential),
+            negotiated: Arc::clone(&self.negotiated),
+            resume_token: Arc::clone(&self.resume_token),
+            outbox: Arc::clone(&self.outbox),
+            running: Arc::clone(&self.running),
+            workers: Arc::clone(&self.workers),
+            terminals: Arc::clone(&self.terminals),
+            incoming_files: Arc::clone(&self.incoming_files),
+            log_tails: Arc::clone(&self.log_tails),
         }
     }
 }
@@ -0,0 +1,41 @@
+// 本机认证密钥：注册或轮换后由服务端签发，保存在状态目录中（权限 0600）
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub struct CredentialFile {
+    path: PathBuf,
+}
+
+impl CredentialFile {
+    pub fn new<P: AsRef<Path>>(state_dir: P) -> Self {
+        Self { path: state_dir.as_ref().join("credential") }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 读取已保存的密钥，尚未注册时返回 None
+    pub fn load(&self) -> io::Result<Option<String>> {
+        match fs::read_to_string(&self.path) {
+            Ok(secret) => Ok(Some(secret.trim().to_string()).filter(|secret| !secret.is_empty())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 写入临时文件后改名替换，避免写到一半时丢失密钥
+    pub fn save(&self, secret: &str) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = self.path.with_extension("tmp");
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp)?;
+        file.write_all(secret.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)
+    }
+}
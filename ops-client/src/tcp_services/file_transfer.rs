@@ -0,0 +1,275 @@
+// 客户端文件传输：接收服务端推送的文件（写入临时文件，校验 SHA-256 后设置权限并改名），
+// 以及按服务端请求分块读取允许目录中的文件
+
+use std::io::SeekFrom;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use ops_common::file_transfer::{Checksum, FILE_CHUNK_SIZE};
+use ops_common::protocol::{FetchRange, FilePushStart};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// 未指定权限时使用的文件权限
+const DEFAULT_FILE_MODE: u32 = 0o644;
+
+fn check_absolute(path: &Path) -> Result<(), String> {
+    if !path.is_absolute() || path.components().any(|c| matches!(c, Component::ParentDir)) {
+        return Err(format!("路径必须是不含 .. 的绝对路径: {}", path.display()));
+    }
+    Ok(())
+}
+
+/// 已解析符号链接的路径是否位于某个允许目录内
+fn within_allowed_dirs(path: &Path, allowed_dirs: &[String]) -> Result<(), String> {
+    let allowed = allowed_dirs
+        .iter()
+        .filter_map(|dir| std::fs::canonicalize(dir).ok())
+        .any(|dir| path.starts_with(dir));
+    if !allowed {
+        return Err(format!("路径不在允许的目录中。允许的目录: {:?}", allowed_dirs));
+    }
+    Ok(())
+}
+
+/// 把路径解析到允许的目录下：必须是不含 `..` 的绝对路径，父目录必须存在，
+/// 且解析符号链接后仍位于某个允许目录内
+pub fn resolve_allowed_path(path: &str, allowed_dirs: &[String]) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    check_absolute(path)?;
+    let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+        return Err(format!("路径缺少文件名: {}", path.display()));
+    };
+
+    let parent = std::fs::canonicalize(parent)
+        .map_err(|e| format!("目标目录不可用 {}: {}", parent.display(), e))?;
+    within_allowed_dirs(&parent, allowed_dirs)?;
+    Ok(parent.join(file_name))
+}
+
+/// 解析要读取的文件：文件本身也解析符号链接，防止通过链接读到允许目录之外的文件
+pub fn resolve_readable_path(path: &str, allowed_dirs: &[String]) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    check_absolute(path)?;
+    let resolved = std::fs::canonicalize(path)
+        .map_err(|e| format!("文件不可用 {}: {}", path.display(), e))?;
+    within_allowed_dirs(&resolved, allowed_dirs)?;
+    if !resolved.is_file() {
+        return Err(format!("不是普通文件: {}", path.display()));
+    }
+    Ok(resolved)
+}
+
+/// 正在接收的文件
+pub struct IncomingFile {
+    target: PathBuf,
+    temp: PathBuf,
+    file: File,
+    size: u64,
+    received: u64,
+    sha256: String,
+    mode: u32,
+    checksum: Checksum,
+}
+
+impl IncomingFile {
+    pub async fn create(start: &FilePushStart, allowed_dirs: &[String], max_size: u64) -> Result<Self, String> {
+        if start.size > max_size {
+            return Err(format!("文件大小 {} 超过上限 {}", start.size, max_size));
+        }
+        let mode = start.mode.unwrap_or(DEFAULT_FILE_MODE);
+        if mode > 0o7777 {
+            return Err(format!("无效的文件权限: {:o}", mode));
+        }
+
+        let target = resolve_allowed_path(&start.path, allowed_dirs)?;
+        if fs::symlink_metadata(&target).await.is_ok_and(|meta| meta.is_dir()) {
+            return Err(format!("目标是一个目录: {}", target.display()));
+        }
+        // 临时文件与目标在同一目录，保证改名是原子操作
+        let file_name = target.file_name().unwrap_or_default().to_string_lossy();
+        let temp = target.with_file_name(format!(".{}.{}.part", file_name, start.transfer_id));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp)
+            .await
+            .map_err(|e| format!("创建临时文件失败 {}: {}", temp.display(), e))?;
+
+        Ok(Self {
+            target,
+            temp,
+            file,
+            size: start.size,
+            received: 0,
+            sha256: start.sha256.to_lowercase(),
+            mode,
+            checksum: Checksum::new(),
+        })
+    }
+
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+
+    /// 所有内容都已收到
+    pub fn is_complete(&self) -> bool {
+        self.received == self.size
+    }
+
+    /// 按顺序写入一个分块
+    pub async fn write_chunk(&mut self, offset: u64, data: &[u8]) -> Result<(), String> {
+        if offset != self.received {
+            return Err(format!("分块顺序错误: 期望偏移 {}，收到 {}", self.received, offset));
+        }
+        if self.received + data.len() as u64 > self.size {
+            return Err(format!("收到的数据超过声明的大小 {}", self.size));
+        }
+        self.file.write_all(data).await.map_err(|e| format!("写入临时文件失败: {}", e))?;
+        self.checksum.update(data);
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// 校验内容并原子地替换目标文件；失败时删除临时文件
+    pub async fn finish(mut self) -> Result<(), String> {
+        let result = self.commit().await;
+        if result.is_err() {
+            let _ = fs::remove_file(&self.temp).await;
+        }
+        result
+    }
+
+    async fn commit(&mut self) -> Result<(), String> {
+        let checksum = std::mem::take(&mut self.checksum).finish();
+        if checksum != self.sha256 {
+            return Err(format!("SHA-256 校验失败: 期望 {}，实际 {}", self.sha256, checksum));
+        }
+        self.file.flush().await.map_err(|e| e.to_string())?;
+        self.file.sync_all().await.map_err(|e| format!("同步文件失败: {}", e))?;
+        fs::set_permissions(&self.temp, std::fs::Permissions::from_mode(self.mode))
+            .await
+            .map_err(|e| format!("设置文件权限失败: {}", e))?;
+        fs::rename(&self.temp, &self.target)
+            .await
+            .map_err(|e| format!("替换目标文件失败: {}", e))
+    }
+
+    /// 放弃接收，删除临时文件
+    pub async fn discard(self) {
+        drop(self.file);
+        let _ = fs::remove_file(&self.temp).await;
+    }
+}
+
+/// 按服务端请求读取的文件，只读取 [offset, offset + length) 区间
+pub struct OutgoingFile {
+    file: File,
+    file_size: u64,
+    offset: u64,
+    length: u64,
+    sent: u64,
+    checksum: Checksum,
+}
+
+impl OutgoingFile {
+    pub async fn open(path: &str, range: &FetchRange, allowed_dirs: &[String], max_size: u64) -> Result<Self, String> {
+        let path = resolve_readable_path(path, allowed_dirs)?;
+        let mut file = File::open(&path)
+            .await
+            .map_err(|e| format!("打开文件失败 {}: {}", path.display(), e))?;
+        let file_size = file.metadata().await.map_err(|e| e.to_string())?.len();
+
+        let (offset, length) = match *range {
+            FetchRange::Whole => {
+                if file_size > max_size {
+                    return Err(format!("文件大小 {} 超过上限 {}，请指定字节范围或行数", file_size, max_size));
+                }
+                (0, file_size)
+            }
+            FetchRange::Bytes { offset, length } => {
+                if offset > file_size {
+                    return Err(format!("起始位置 {} 超过文件大小 {}", offset, file_size));
+                }
+                let length = length.unwrap_or(u64::MAX).min(file_size - offset);
+                if length > max_size {
+                    return Err(format!("读取长度 {} 超过上限 {}", length, max_size));
+                }
+                (offset, length)
+            }
+            FetchRange::Tail { lines } => {
+                // 行数过多时只返回最后 max_size 字节
+                let start = tail_start(&mut file, file_size, lines, max_size)
+                    .await
+                    .map_err(|e| format!("读取文件失败: {}", e))?;
+                (start, file_size - start)
+            }
+        };
+
+        file.seek(SeekFrom::Start(offset)).await.map_err(|e| format!("读取文件失败: {}", e))?;
+        Ok(Self { file, file_size, offset, length, sent: 0, checksum: Checksum::new() })
+    }
+
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// 读取下一个分块，返回分块在文件中的位置；区间读完后返回 None
+    pub async fn next_chunk(&mut self) -> Result<Option<(u64, Vec<u8>)>, String> {
+        let remaining = self.length - self.sent;
+        if remaining == 0 {
+            return Ok(None);
+        }
+        let mut data = vec![0; remaining.min(FILE_CHUNK_SIZE as u64) as usize];
+        self.file.read_exact(&mut data).await.map_err(|e| match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => "文件在读取过程中被截断".to_string(),
+            _ => format!("读取文件失败: {}", e),
+        })?;
+        self.checksum.update(&data);
+        let offset = self.offset + self.sent;
+        self.sent += data.len() as u64;
+        Ok(Some((offset, data)))
+    }
+
+    /// 已读取内容的 SHA-256
+    pub fn finish(self) -> String {
+        self.checksum.finish()
+    }
+}
+
+/// 从文件末尾向前查找最后 lines 行的起始位置，最多向前查找 max_size 字节
+pub async fn tail_start(file: &mut File, file_size: u64, lines: u64, max_size: u64) -> std::io::Result<u64> {
+    if lines == 0 {
+        return Ok(file_size);
+    }
+    let limit = file_size.saturating_sub(max_size);
+    let mut buf = vec![0; FILE_CHUNK_SIZE];
+    let mut found = 0;
+    let mut end = file_size;
+    while end > limit {
+        let start = end.saturating_sub(FILE_CHUNK_SIZE as u64).max(limit);
+        let block = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start)).await?;
+        file.read_exact(block).await?;
+        for (index, byte) in block.iter().enumerate().rev() {
+            let position = start + index as u64;
+            // 文件末尾的换行只结束最后一行，不算作新的一行
+            if *byte == b'\n' && position + 1 != file_size {
+                found += 1;
+                if found == lines {
+                    return Ok(position + 1);
+                }
+            }
+        }
+        end = start;
+    }
+    Ok(limit)
+}
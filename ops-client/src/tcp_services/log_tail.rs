@@ -0,0 +1,77 @@
+// 应用日志跟踪：定时轮询日志文件的新增内容并按行返回，
+// 日志轮转（改名后新建文件或原地截断）后从新文件开头继续读取
+
+use std::io::{self, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use crate::tcp_services::file_transfer::tail_start;
+
+/// 检查日志新增内容的间隔
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 开始跟踪时回传的历史内容上限
+const MAX_BACKLOG: u64 = 256 * 1024;
+
+/// 每次轮询最多读取的字节数，积压更多时在后续轮询中继续读取
+const MAX_READ_PER_POLL: u64 = 256 * 1024;
+
+/// 没有换行的超长行达到该长度后直接回传
+const MAX_LINE: usize = 64 * 1024;
+
+/// 文件的设备号和 inode，用于识别日志是否已被轮转
+fn file_identity(meta: &std::fs::Metadata) -> (u64, u64) {
+    (meta.dev(), meta.ino())
+}
+
+pub struct LogFollower {
+    path: PathBuf,
+    file: File,
+    identity: (u64, u64),
+    position: u64,
+    partial: Vec<u8>,
+}
+
+impl LogFollower {
+    /// 打开日志并定位到最后 lines 行
+    pub async fn open(path: PathBuf, lines: u64) -> io::Result<Self> {
+        let mut file = File::open(&path).await?;
+        let meta = file.metadata().await?;
+        let position = tail_start(&mut file, meta.len(), lines, MAX_BACKLOG).await?;
+        file.seek(SeekFrom::Start(position)).await?;
+        Ok(Self { path, file, identity: file_identity(&meta), position, partial: Vec::new() })
+    }
+
+    /// 读取新增的完整行，没有新内容时返回空字符串
+    pub async fn poll(&mut self) -> io::Result<String> {
+        // 原地截断（copytruncate）后从头读取
+        if self.file.metadata().await?.len() < self.position {
+            self.file.seek(SeekFrom::Start(0)).await?;
+            self.position = 0;
+            self.partial.clear();
+        }
+
+        let mut data = std::mem::take(&mut self.partial);
+        let read = (&mut self.file).take(MAX_READ_PER_POLL).read_to_end(&mut data).await?;
+        self.position += read as u64;
+
+        // 旧文件读完后，如果路径已指向新文件则切换过去；新文件尚未创建时继续等待
+        if (read as u64) < MAX_READ_PER_POLL
+            && let Ok(meta) = fs::metadata(&self.path).await
+            && file_identity(&meta) != self.identity {
+            self.file = File::open(&self.path).await?;
+            self.identity = file_identity(&meta);
+            self.position = 0;
+        }
+
+        let complete = match data.iter().rposition(|byte| *byte == b'\n') {
+            Some(end) => end + 1,
+            None if data.len() >= MAX_LINE => data.len(),
+            None => 0,
+        };
+        self.partial = data.split_off(complete);
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+}
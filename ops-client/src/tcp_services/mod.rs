@@ -1 +1,7 @@
-pub mod client;
\ No newline at end of file
+pub mod client;
+pub mod credential;
+pub mod file_transfer;
+pub mod log_tail;
+pub mod outbox;
+pub mod terminal;
+pub mod worker_pool;
@@ -0,0 +1,118 @@
+// 命令结果发件箱：结果先落盘，收到服务端 result_ack 后删除，重连后按顺序重发
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use ops_common::protocol::CommandResponse;
+use tracing::warn;
+
+pub struct Outbox {
+    dir: PathBuf,
+    max_entries: usize,
+    // 按写入顺序排列的 (序号, command_id)，序号即文件名
+    entries: Vec<(u64, String)>,
+    next_seq: u64,
+}
+
+impl Outbox {
+    /// 打开（或创建）发件箱目录，加载上次运行遗留的结果
+    pub fn open<P: AsRef<Path>>(dir: P, max_entries: usize) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut outbox = Self {
+            dir,
+            max_entries: max_entries.max(1),
+            entries: Vec::new(),
+            next_seq: 0,
+        };
+
+        let mut seqs: Vec<u64> = fs::read_dir(&outbox.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                name.strip_suffix(".json")?.parse().ok()
+            })
+            .collect();
+        seqs.sort_unstable();
+
+        for seq in seqs {
+            match outbox.read_entry(seq) {
+                Ok(response) => outbox.entries.push((seq, response.command_id)),
+                Err(e) => {
+                    warn!("Discarding unreadable outbox entry {}: {}", seq, e);
+                    let _ = fs::remove_file(outbox.path(seq));
+                }
+            }
+            outbox.next_seq = seq + 1;
+        }
+
+        Ok(outbox)
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.json", seq))
+    }
+
+    fn read_entry(&self, seq: u64) -> io::Result<CommandResponse> {
+        let data = fs::read(self.path(seq))?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 保存一个结果；同一命令只保留最新的一份，超过上限时丢弃最旧的结果
+    pub fn push(&mut self, response: &CommandResponse) -> io::Result<()> {
+        self.remove(&response.command_id)?;
+
+        while self.entries.len() >= self.max_entries {
+            let (seq, command_id) = self.entries.remove(0);
+            warn!("Outbox full, dropping oldest result for command {}", command_id);
+            let _ = fs::remove_file(self.path(seq));
+        }
+
+        let seq = self.next_seq;
+        let data = serde_json::to_vec(response)?;
+        // 先写临时文件再改名，避免进程中途退出留下半个文件
+        let tmp = self.dir.join(format!("{:020}.tmp", seq));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, self.path(seq))?;
+
+        self.next_seq += 1;
+        self.entries.push((seq, response.command_id.clone()));
+        Ok(())
+    }
+
+    /// 服务端确认收到后删除结果，返回是否存在
+    pub fn remove(&mut self, command_id: &str) -> io::Result<bool> {
+        let Some(index) = self.entries.iter().position(|(_, id)| id == command_id) else {
+            return Ok(false);
+        };
+        let (seq, _) = self.entries.remove(index);
+        match fs::remove_file(self.path(seq)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 按写入顺序返回所有未确认的结果
+    pub fn pending(&self) -> Vec<CommandResponse> {
+        self.entries
+            .iter()
+            .filter_map(|(seq, command_id)| match self.read_entry(*seq) {
+                Ok(response) => Some(response),
+                Err(e) => {
+                    warn!("Failed to read outbox entry for command {}: {}", command_id, e);
+                    None
+                }
+            })
+            .collect()
+    }
+}
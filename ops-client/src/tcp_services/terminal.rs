@@ -0,0 +1,125 @@
+// 交互式终端：为服务端打开的 Web 终端启动一个挂在伪终端（PTY）上的 shell
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::process::{Child, Command};
+use crate::tcp_services::client::signal_process_group;
+
+/// 服务端发给终端会话的控制消息
+#[derive(Debug)]
+pub enum TerminalControl {
+    Input(String),
+    Resize { cols: u16, rows: u16 },
+    Close,
+}
+
+fn winsize(cols: u16, rows: u16) -> libc::winsize {
+    libc::winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 }
+}
+
+fn set_fd_flag(fd: RawFd, get: libc::c_int, set: libc::c_int, flag: libc::c_int) -> io::Result<()> {
+    // SAFETY: fcntl 只读写描述符标志，fd 在调用期间有效
+    let flags = unsafe { libc::fcntl(fd, get) };
+    if flags < 0 || unsafe { libc::fcntl(fd, set, flags | flag) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// 运行在 PTY 上的 shell，shell 是新会话的首进程，结束时按进程组发信号
+pub struct PtyShell {
+    child: Child,
+    master: AsyncFd<File>,
+}
+
+impl PtyShell {
+    pub fn spawn(shell: &str, cols: u16, rows: u16) -> io::Result<Self> {
+        let (mut master, mut slave) = (-1, -1);
+        let size = winsize(cols, rows);
+        // SAFETY: openpty 只写入两个描述符，name 和 termios 传空指针表示不需要
+        if unsafe { libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), &size) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openpty 成功后两个描述符归本进程所有
+        let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        // shell 只通过标准输入输出持有从设备，主设备不能泄漏给子进程
+        set_fd_flag(master.as_raw_fd(), libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+        set_fd_flag(slave.as_raw_fd(), libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+        set_fd_flag(master.as_raw_fd(), libc::F_GETFL, libc::F_SETFL, libc::O_NONBLOCK)?;
+
+        let mut command = Command::new(shell);
+        command
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave))
+            .env("TERM", "xterm-256color")
+            .kill_on_drop(true);
+        // SAFETY: 子进程 exec 前只调用 async-signal-safe 的 setsid 和 ioctl
+        unsafe {
+            command.pre_exec(|| {
+                // 新会话并把 PTY 设为控制终端，shell 才能使用作业控制和 Ctrl-C
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        // 关闭父进程中的从设备，shell 退出后读主设备才会返回 EIO
+        drop(command);
+
+        Ok(Self { child, master: AsyncFd::new(File::from(master))? })
+    }
+
+    /// 读取终端输出；shell 及其子进程全部退出后返回 Ok(0)
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.master.readable().await?;
+            match guard.try_io(|master| master.get_ref().read(buf)) {
+                Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Ok(0),
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub async fn write_all(&self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let mut guard = self.master.writable().await?;
+            match guard.try_io(|master| master.get_ref().write(data)) {
+                Ok(Ok(written)) => data = &data[written..],
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn resize(&self, cols: u16, rows: u16) -> io::Result<()> {
+        let size = winsize(cols, rows);
+        // SAFETY: TIOCSWINSZ 只读取 size，主设备描述符由 self 持有
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &size) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// 结束 shell：先向进程组发送 SIGHUP，grace 内未退出再 SIGKILL
+    pub async fn terminate(&mut self, grace: Duration) {
+        if matches!(self.child.try_wait(), Ok(Some(_))) {
+            return;
+        }
+        if let Some(pid) = self.child.id() {
+            signal_process_group(pid as i32, libc::SIGHUP);
+            if tokio::time::timeout(grace, self.child.wait()).await.is_ok() {
+                return;
+            }
+            signal_process_group(pid as i32, libc::SIGKILL);
+        }
+        let _ = self.child.wait().await;
+    }
+}
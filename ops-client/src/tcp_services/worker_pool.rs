@@ -0,0 +1,73 @@
+// 命令执行池：限制同时执行的命令数，超出的命令排队等待执行槽
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use ops_common::CommandQueueStatus;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub struct WorkerPool {
+    permits: Arc<Semaphore>,
+    max_concurrent: usize,
+    queued: Arc<AtomicUsize>,
+    running: Arc<AtomicUsize>,
+}
+
+/// 占用中的执行槽，释放时归还给执行池
+pub struct WorkerSlot {
+    _permit: OwnedSemaphorePermit,
+    running: Arc<AtomicUsize>,
+}
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// 排队计数守卫，等待被取消时同样会减少计数
+struct QueuedGuard(Arc<AtomicUsize>);
+
+impl Drop for QueuedGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl WorkerPool {
+    pub fn new(max_concurrent: usize) -> Self {
+        let max_concurrent = max_concurrent.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            queued: Arc::new(AtomicUsize::new(0)),
+            running: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// 等待一个空闲执行槽
+    pub async fn acquire(&self) -> WorkerSlot {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let queued = QueuedGuard(Arc::clone(&self.queued));
+
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("worker pool semaphore is never closed");
+
+        self.running.fetch_add(1, Ordering::SeqCst);
+        drop(queued);
+        WorkerSlot {
+            _permit: permit,
+            running: Arc::clone(&self.running),
+        }
+    }
+
+    /// 当前执行与排队情况
+    pub fn status(&self) -> CommandQueueStatus {
+        CommandQueueStatus {
+            running: self.running.load(Ordering::SeqCst),
+            queued: self.queued.load(Ordering::SeqCst),
+            max_concurrent: self.max_concurrent,
+        }
+    }
+}
@@ -1,175 +1,1522 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
-    use std::path::Path;
-    use tempfile::tempdir;
-    use ops_common::{
-        config::ClientConfig,
-        security::{CommandValidator, ValidationResult}
-    };
-
-    #[test]
-    fn test_client_config_from_env() {
+use std::fs;
+use tempfile::tempdir;
+use ops_common::{
+    config::ClientConfig,
+    security::{CommandValidator, ValidationResult}
+};
+
+#[test]
+fn test_client_config_from_env() {
+    // SAFETY: 测试中修改环境变量，仅影响本测试读取的配置项
+    unsafe {
         std::env::set_var("OPS_SERVER_HOST", "test-server");
         std::env::set_var("OPS_SERVER_PORT", "9999");
-        
-        let config = ClientConfig::from_env();
-        assert_eq!(config.server_host, "test-server");
-        assert_eq!(config.server_port, 9999);
-        
-        // 清理环境变量
+    }
+    
+    let config = ClientConfig::from_env();
+    assert_eq!(config.server_host, "test-server");
+    assert_eq!(config.server_port, 9999);
+    
+    // 清理环境变量
+    unsafe {
         std::env::remove_var("OPS_SERVER_HOST");
         std::env::remove_var("OPS_SERVER_PORT");
     }
+}
+
+#[test]
+fn test_client_id_creation_and_retrieval() {
+    let temp_dir = tempdir().unwrap();
+    let client_id_file = temp_dir.path().join("test_client_id.txt");
+    
+    let config = ClientConfig {
+        client_id_file: client_id_file.to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+
+    // 测试创建新的客户端ID
+    let id1 = crate::tcp_services::client::get_or_create_client_id(&config).unwrap();
+    assert!(!id1.is_empty());
+    assert!(client_id_file.exists());
+
+    // 测试读取现有的客户端ID
+    let id2 = crate::tcp_services::client::get_or_create_client_id(&config).unwrap();
+    assert_eq!(id1, id2);
+}
+
+#[test]
+fn test_command_validator_allowed_commands() {
+    let validator = CommandValidator::new();
+    
+    match validator.validate("ps aux") {
+        ValidationResult::Allowed => {},
+        ValidationResult::Blocked { reason } => panic!("Should be allowed: {}", reason),
+    }
+
+    match validator.validate("ls -la") {
+        ValidationResult::Allowed => {},
+        ValidationResult::Blocked { reason } => panic!("Should be allowed: {}", reason),
+    }
+}
+
+#[test]
+fn test_command_validator_dangerous_commands() {
+    let validator = CommandValidator::new();
+    
+    match validator.validate("rm -rf /") {
+        ValidationResult::Blocked { reason } => {
+            assert!(reason.contains("危险模式") || reason.contains("dangerous"));
+        },
+        ValidationResult::Allowed => panic!("Should be blocked"),
+    }
+
+    match validator.validate("shutdown now") {
+        ValidationResult::Blocked { reason } => {
+            assert!(reason.contains("危险模式") || reason.contains("dangerous"));
+        },
+        ValidationResult::Allowed => panic!("Should be blocked"),
+    }
+}
+
+#[test]
+fn test_command_validator_unknown_commands() {
+    let validator = CommandValidator::new();
+    
+    match validator.validate("malicious_unknown_command") {
+        ValidationResult::Blocked { reason } => {
+            assert!(reason.contains("不在允许列表中") || reason.contains("not allowed"));
+        },
+        ValidationResult::Allowed => panic!("Should be blocked"),
+    }
+}
+
+#[test]
+fn test_command_validator_empty_command() {
+    let validator = CommandValidator::new();
+    
+    match validator.validate("") {
+        ValidationResult::Blocked { .. } => {},
+        ValidationResult::Allowed => panic!("Empty command should be blocked"),
+    }
+
+    match validator.validate("   ") {
+        ValidationResult::Blocked { .. } => {},
+        ValidationResult::Allowed => panic!("Whitespace-only command should be blocked"),
+    }
+}
+
+#[test]
+fn test_command_validator_long_command() {
+    let validator = CommandValidator::new();
+    let long_command = "a".repeat(2000); // 超过默认的1000字符限制
+    
+    match validator.validate(&long_command) {
+        ValidationResult::Blocked { reason } => {
+            assert!(reason.contains("长度超过限制") || reason.contains("too long"));
+        },
+        ValidationResult::Allowed => panic!("Long command should be blocked"),
+    }
+}
+
+#[test]
+fn test_command_sequences_validated_as_a_whole() {
+    let validator = CommandValidator::new();
+
+    // 分隔符不再被替换掉，序列中的每条命令都要通过校验
+    match validator.validate("ps aux; rm -rf /; echo hello") {
+        ValidationResult::Blocked { reason } => assert!(reason.contains("危险模式")),
+        ValidationResult::Allowed => panic!("Should be blocked"),
+    }
+    match validator.validate("cat /etc/hosts | sh") {
+        ValidationResult::Blocked { .. } => {},
+        ValidationResult::Allowed => panic!("Piping into a shell should be blocked"),
+    }
+    match validator.validate("ps aux | grep ops && echo done") {
+        ValidationResult::Allowed => {},
+        ValidationResult::Blocked { reason } => panic!("Should be allowed: {}", reason),
+    }
+}
+
+#[test]
+fn test_command_policy_file_loaded() {
+    let temp_dir = tempdir().unwrap();
+    let policy_file = temp_dir.path().join("policy.toml");
+    std::fs::write(&policy_file, r#"
+        [[rules]]
+        action = "allow"
+        programs = ["uptime", "systemctl"]
+
+        [[rules]]
+        action = "deny"
+        programs = ["systemctl"]
+        subcommands = ["stop"]
+    "#).unwrap();
+    let config = ClientConfig {
+        command_policy_file: Some(policy_file.to_str().unwrap().to_string()),
+        ..ClientConfig::default()
+    };
+
+    let validator = crate::policy::load_validator(&config).unwrap();
+    assert!(matches!(validator.validate("uptime"), ValidationResult::Allowed));
+    assert!(matches!(validator.validate("systemctl status nginx"), ValidationResult::Allowed));
+    assert!(matches!(validator.validate("systemctl stop nginx"), ValidationResult::Blocked { .. }));
+    // 策略中没有的命令即使在内置策略中允许也被拒绝
+    assert!(matches!(validator.validate("ps aux"), ValidationResult::Blocked { .. }));
+
+    // 无效的策略文件不会回退到内置策略
+    std::fs::write(&policy_file, "[[rules]]\naction = \"allow\"\nprograms = [\"ls\"]\nargs = [\"(\"]").unwrap();
+    assert!(crate::policy::load_validator(&config).is_err());
+}
+
+#[test]
+fn test_version_collector_empty_directory() {
+    let temp_dir = tempdir().unwrap();
+    let versions = crate::collection::version_collector::read_app_versions(
+        temp_dir.path().to_str().unwrap()
+    );
+    assert!(versions.is_empty());
+}
+
+#[test]
+fn test_version_collector_with_valid_version_file() {
+    let temp_dir = tempdir().unwrap();
+    let app_dir = temp_dir.path().join("test_app");
+    fs::create_dir(&app_dir).unwrap();
+    
+    let version_file = app_dir.join("version.txt");
+    let version_data = r#"{"app":"test_app","created_time":"2024-01-01T00:00:00Z"}"#;
+    fs::write(&version_file, version_data).unwrap();
+    
+    let versions = crate::collection::version_collector::read_app_versions(
+        temp_dir.path().to_str().unwrap()
+    );
+    
+    assert_eq!(versions.len(), 1);
+    assert_eq!(versions[0].app, "test_app");
+    assert_eq!(versions[0].created_time, "2024-01-01T00:00:00Z");
+}
+
+#[test]
+fn test_version_collector_with_invalid_json() {
+    let temp_dir = tempdir().unwrap();
+    let app_dir = temp_dir.path().join("bad_app");
+    fs::create_dir(&app_dir).unwrap();
+    
+    let version_file = app_dir.join("version.txt");
+    fs::write(&version_file, "invalid json").unwrap();
+    
+    let versions = crate::collection::version_collector::read_app_versions(
+        temp_dir.path().to_str().unwrap()
+    );
+    
+    // 应该忽略无效的JSON文件
+    assert!(versions.is_empty());
+}
+
+#[test]
+fn test_truncate_response_to_frame() {
+    use ops_common::protocol::{CommandResponse, Envelope};
+
+    let mut response = CommandResponse {
+        command_id: "cmd-1".to_string(),
+        client_id: "client-1".to_string(),
+        command: "cat big.log".to_string(),
+        output: "日志".repeat(10_000),
+        error_output: String::new(),
+        exit_code: 0,
+        executed_at: std::time::SystemTime::now(),
+        cancelled: false,
+        timed_out: false,
+    };
+
+    crate::tcp_services::client::truncate_response_to_frame(&mut response, 4096);
+
+    let encoded = Envelope::CommandResponse(response.clone()).encode().unwrap();
+    assert!(encoded.len() <= 4096);
+    assert!(response.output.ends_with("...[输出过大，已截断]"));
+    assert_eq!(response.output.matches("已截断").count(), 1);
+}
+
+// 模拟服务端：绑定本地端口，客户端配置指向该端口，状态文件放在临时目录
+async fn stub_server(temp_dir: &std::path::Path) -> (tokio::net::TcpListener, ClientConfig) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = ClientConfig {
+        server_host: "127.0.0.1".to_string(),
+        server_port: listener.local_addr().unwrap().port(),
+        client_id_file: temp_dir.join("client_id.txt").to_str().unwrap().to_string(),
+        apps_base_dir: temp_dir.to_str().unwrap().to_string(),
+        state_dir: temp_dir.join("state").to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+    fs::write(&config.client_id_file, "client-1").unwrap();
+    (listener, config)
+}
+
+// 模拟服务端的握手：读取 hello 并回复协商结果
+async fn accept_hello(
+    listener: &tokio::net::TcpListener,
+) -> (ops_common::codec::FramedStream<tokio::net::TcpStream>, ops_common::protocol::Hello) {
+    use ops_common::codec::{FrameCodec, FramedStream};
+    use ops_common::protocol::{Envelope, Hello};
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut framed = FramedStream::new(stream, FrameCodec::default());
+    let Envelope::Hello(hello) = framed.read_envelope().await.unwrap() else {
+        panic!("expected hello");
+    };
+    let mut ack = Hello::local().negotiate(&hello).unwrap();
+    ack.resumed = hello.resume.is_some();
+    framed.write_envelope(&Envelope::HelloAck(ack)).await.unwrap();
+    (framed, hello)
+}
+
+#[tokio::test]
+async fn test_reconnect_reannounces_client_info() {
+    use ops_common::protocol::Envelope;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+
+    let (session, (first, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    // 服务端断开后，客户端应重新握手并先上报 ClientInfo 和重连报告
+    drop(first);
+    let (mut second, _) = tokio::time::timeout(std::time::Duration::from_secs(5), accept_hello(&listener))
+        .await
+        .expect("client should reconnect");
+
+    match second.read_envelope().await.unwrap() {
+        Envelope::ClientInfo(info) => assert_eq!(info.client_id, "client-1"),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+    match second.read_envelope().await.unwrap() {
+        Envelope::ReconnectReport(report) => {
+            assert_eq!(report.client_id, "client-1");
+            assert_eq!(report.attempts, 1);
+            assert!(report.last_error.is_none());
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_reconnect_resumes_session_with_token() {
+    use ops_common::protocol::Envelope;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+
+    // 第一次连接：完成握手并签发续连令牌，然后断开
+    let server = async {
+        let (mut framed, hello) = accept_hello(&listener).await;
+        assert!(hello.resume.is_none());
+        framed.write_envelope(&Envelope::SessionToken { token: "token-1".to_string(), expires_in_secs: 60 }).await.unwrap();
+    };
+    let (session, ()) = tokio::join!(crate::tcp_services::client::TcpSession::new(config), server);
+    let _session = session.unwrap();
+
+    // 重连时 hello 携带令牌，服务端确认恢复
+    let (mut framed, hello) = tokio::time::timeout(std::time::Duration::from_secs(5), accept_hello(&listener))
+        .await
+        .expect("client should reconnect");
+    let resume = hello.resume.expect("hello should carry the resume token");
+    assert_eq!(resume.client_id, "client-1");
+    assert_eq!(resume.token, "token-1");
+
+    assert!(matches!(framed.read_envelope().await.unwrap(), Envelope::ClientInfo(_)));
+    match framed.read_envelope().await.unwrap() {
+        Envelope::ReconnectReport(report) => assert!(report.resumed),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+}
+
+fn sample_response(command_id: &str) -> ops_common::protocol::CommandResponse {
+    ops_common::protocol::CommandResponse {
+        command_id: command_id.to_string(),
+        client_id: "client-1".to_string(),
+        command: "uptime".to_string(),
+        output: format!("output of {}", command_id),
+        error_output: String::new(),
+        exit_code: 0,
+        executed_at: std::time::SystemTime::now(),
+        cancelled: false,
+        timed_out: false,
+    }
+}
+
+#[test]
+fn test_outbox_persists_in_order_and_is_bounded() {
+    use crate::tcp_services::outbox::Outbox;
+
+    let temp_dir = tempdir().unwrap();
+    let mut outbox = Outbox::open(temp_dir.path(), 3).unwrap();
+    for id in ["cmd-1", "cmd-2", "cmd-3"] {
+        outbox.push(&sample_response(id)).unwrap();
+    }
+    assert!(outbox.remove("cmd-2").unwrap());
+    assert!(!outbox.remove("cmd-2").unwrap());
+    drop(outbox);
+
+    // 重新打开后保持写入顺序，超过上限时丢弃最旧的结果
+    let mut outbox = Outbox::open(temp_dir.path(), 3).unwrap();
+    outbox.push(&sample_response("cmd-4")).unwrap();
+    outbox.push(&sample_response("cmd-5")).unwrap();
+    let ids: Vec<String> = outbox.pending().into_iter().map(|r| r.command_id).collect();
+    assert_eq!(ids, ["cmd-3", "cmd-4", "cmd-5"]);
+}
+
+#[tokio::test]
+async fn test_outbox_replayed_after_handshake() {
+    use crate::tcp_services::outbox::Outbox;
+    use ops_common::protocol::Envelope;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let outbox_dir = temp_dir.path().join("state").join("outbox");
+
+    // 上次运行遗留的未确认结果
+    let mut outbox = Outbox::open(&outbox_dir, 10).unwrap();
+    outbox.push(&sample_response("cmd-1")).unwrap();
+    outbox.push(&sample_response("cmd-2")).unwrap();
+    drop(outbox);
+
+    let server = async {
+        let (mut framed, _) = accept_hello(&listener).await;
+        let mut replayed = Vec::new();
+        for _ in 0..2 {
+            match framed.read_envelope().await.unwrap() {
+                Envelope::CommandResponse(response) => replayed.push(response.command_id),
+                other => panic!("Unexpected envelope: {:?}", other),
+            }
+        }
+        assert_eq!(replayed, ["cmd-1", "cmd-2"]);
+
+        // 确认后客户端从发件箱删除
+        framed.write_envelope(&Envelope::ResultAck { command_id: "cmd-1".to_string() }).await.unwrap();
+        framed
+    };
+    let (session, _framed) = tokio::join!(crate::tcp_services::client::TcpSession::new(config), server);
+    let _session = session.unwrap();
+
+    for _ in 0..50 {
+        if Outbox::open(&outbox_dir, 10).unwrap().len() == 1 {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("acknowledged result was not removed from the outbox");
+}
+
+#[tokio::test]
+async fn test_blocked_command_rejected_with_reason() {
+    use ops_common::protocol::Envelope;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        command_log_file: temp_dir.path().join("commands.log").to_str().unwrap().to_string(),
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    framed.write_envelope(&Envelope::CommandRequest {
+        command_id: "cmd-1".to_string(),
+        command: "rm -rf /".to_string(),
+        timeout_secs: None,
+        signature: None,
+    }).await.unwrap();
+
+    // 被策略拦截的命令只回复 command_rejected，不执行也不返回结果
+    match framed.read_envelope().await.unwrap() {
+        Envelope::CommandRejected { command_id, reason } => {
+            assert_eq!(command_id, "cmd-1");
+            assert!(!reason.is_empty());
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_server_policy_enforced_and_local_policy_only_tightens() {
+    use ops_common::command_policy::CommandPolicy;
+    use ops_common::protocol::Envelope;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let policy_file = temp_dir.path().join("policy.toml");
+    std::fs::write(&policy_file, "[[rules]]\naction = \"allow\"\nprograms = [\"uptime\", \"date\"]\n").unwrap();
+    let config = ClientConfig {
+        command_log_file: temp_dir.path().join("commands.log").to_str().unwrap().to_string(),
+        command_policy_file: Some(policy_file.to_str().unwrap().to_string()),
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    let server_policy = CommandPolicy::from_toml("[[rules]]\naction = \"allow\"\nprograms = [\"date\", \"whoami\"]\n").unwrap();
+    framed.write_envelope(&Envelope::CommandPolicy { policy_id: "policy-1".to_string(), policy: server_policy, signature: None }).await.unwrap();
+
+    // 本地允许但服务端不允许、服务端允许但本地不允许的命令都被拒绝
+    for (command_id, command) in [("cmd-1", "uptime"), ("cmd-2", "whoami")] {
+        framed.write_envelope(&Envelope::CommandRequest {
+            command_id: command_id.to_string(),
+            command: command.to_string(),
+            timeout_secs: None,
+            signature: None,
+        }).await.unwrap();
+        match framed.read_envelope().await.unwrap() {
+            Envelope::CommandRejected { command_id: rejected, .. } => assert_eq!(rejected, command_id),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    framed.write_envelope(&Envelope::CommandRequest {
+        command_id: "cmd-3".to_string(),
+        command: "date".to_string(),
+        timeout_secs: None,
+        signature: None,
+    }).await.unwrap();
+    match framed.read_envelope().await.unwrap() {
+        Envelope::CommandAccepted { command_id } => assert_eq!(command_id, "cmd-3"),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+}
+
+#[test]
+fn test_policy_status_reports_server_and_local_versions() {
+    use ops_common::command_policy::CommandPolicy;
+
+    let mut policy = crate::policy::EnforcedPolicy::load(&ClientConfig::default()).unwrap();
+    assert_eq!(policy.status(), ops_common::PolicyStatus::default());
+    // 内置策略不再允许 /tmp 下的脚本
+    assert!(matches!(policy.validator().validate("bash /tmp/apps/x.sh"), ValidationResult::Blocked { .. }));
+
+    let server_policy = CommandPolicy::builtin();
+    let version = policy.apply_server_policy(server_policy.clone()).unwrap();
+    assert_eq!(version, server_policy.version());
+    assert_eq!(policy.status().version, Some(version));
+    assert_eq!(policy.status().local_version, None);
+
+    // 无效的服务端策略不会替换当前策略
+    let mut invalid = server_policy;
+    invalid.rules[0].args = vec!["(".to_string()];
+    assert!(policy.apply_server_policy(invalid).is_err());
+    assert_eq!(policy.status().version, Some(CommandPolicy::builtin().version()));
+}
+
+#[tokio::test]
+async fn test_unsigned_forged_and_replayed_commands_rejected() {
+    use std::time::Duration;
+    use ops_common::command_signing::CommandSigner;
+    use ops_common::protocol::Envelope;
+
+    let signer = CommandSigner::from_pem(&CommandSigner::generate_pem().unwrap()).unwrap();
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        command_log_file: temp_dir.path().join("commands.log").to_str().unwrap().to_string(),
+        command_signing_public_key: Some(signer.public_key()),
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    // 未签名、签给其他客户端、执行超时被改动的命令都被拒绝
+    let signed_for_other = signer.sign("cmd-2", "client-2", "uptime", None, Duration::from_secs(60));
+    let other_timeout = signer.sign("cmd-4", "client-1", "uptime", Some(30), Duration::from_secs(60));
+    let requests = [("cmd-1", None), ("cmd-2", Some(signed_for_other)), ("cmd-4", Some(other_timeout))];
+    for (command_id, signature) in requests {
+        framed.write_envelope(&Envelope::CommandRequest {
+            command_id: command_id.to_string(),
+            command: "uptime".to_string(),
+            timeout_secs: None,
+            signature,
+        }).await.unwrap();
+        match framed.read_envelope().await.unwrap() {
+            Envelope::CommandRejected { command_id: rejected, .. } => assert_eq!(rejected, command_id),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    // 签名有效的命令执行一次，重放同一条命令被拒绝
+    let request = Envelope::CommandRequest {
+        command_id: "cmd-3".to_string(),
+        command: "uptime".to_string(),
+        timeout_secs: None,
+        signature: Some(signer.sign("cmd-3", "client-1", "uptime", None, Duration::from_secs(60))),
+    };
+    framed.write_envelope(&request).await.unwrap();
+    assert!(matches!(framed.read_envelope().await.unwrap(), Envelope::CommandAccepted { .. }));
+    framed.write_envelope(&request).await.unwrap();
+    loop {
+        match framed.read_envelope().await.unwrap() {
+            Envelope::CommandRejected { command_id, reason } => {
+                assert_eq!(command_id, "cmd-3");
+                assert!(reason.contains("重复"));
+                break;
+            }
+            Envelope::CommandOutput(_) | Envelope::CommandResponse(_) => {}
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_unsigned_terminal_and_file_requests_rejected() {
+    use std::time::Duration;
+    use ops_common::command_signing::{CommandSigner, SignedRequest};
+    use ops_common::protocol::{Envelope, FilePushStart};
+
+    let signer = CommandSigner::from_pem(&CommandSigner::generate_pem().unwrap()).unwrap();
+    let temp_dir = tempdir().unwrap();
+    let push_dir = temp_dir.path().join("push");
+    fs::create_dir(&push_dir).unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        command_signing_public_key: Some(signer.public_key()),
+        terminal_enabled: true,
+        terminal_shell: "/bin/sh".to_string(),
+        file_push_dirs: vec![push_dir.to_str().unwrap().to_string()],
+        file_fetch_dirs: vec![temp_dir.path().to_str().unwrap().to_string()],
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    // 未签名的终端请求不会打开 shell
+    framed.write_envelope(&Envelope::TerminalOpen { session_id: "term-1".to_string(), cols: 80, rows: 24, signature: None }).await.unwrap();
+    match framed.read_envelope().await.unwrap() {
+        Envelope::TerminalClosed { session_id, reason } => {
+            assert_eq!(session_id, "term-1");
+            assert!(reason.unwrap().contains("签名"));
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    // 未签名或篡改了目标路径的推送不会写入文件
+    let mut start = FilePushStart {
+        transfer_id: "xfer-1".to_string(),
+        path: push_dir.join("a.txt").to_str().unwrap().to_string(),
+        size: 0,
+        sha256: ops_common::file_transfer::sha256_hex(b""),
+        mode: None,
+        signature: None,
+    };
+    let signature = signer.sign_request(SignedRequest::FilePush, "xfer-1", "client-1", &start.signed_content(), Duration::from_secs(60));
+    for signature in [None, Some(signature.clone())] {
+        let mut forged = start.clone();
+        if signature.is_some() {
+            forged.path = push_dir.join("b.txt").to_str().unwrap().to_string();
+        }
+        forged.signature = signature;
+        framed.write_envelope(&Envelope::FilePushStart(forged)).await.unwrap();
+        match framed.read_envelope().await.unwrap() {
+            Envelope::FilePushResult { success, message, .. } => assert!(!success && message.contains("签名"), "{}", message),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+    assert!(fs::read_dir(&push_dir).unwrap().next().is_none());
+
+    // 未签名的文件读取请求被拒绝
+    framed.write_envelope(&Envelope::FileFetchRequest {
+        fetch_id: "fetch-1".to_string(),
+        path: temp_dir.path().join("client_id.txt").to_str().unwrap().to_string(),
+        range: ops_common::protocol::FetchRange::Whole,
+        signature: None,
+    }).await.unwrap();
+    assert!(matches!(framed.read_envelope().await.unwrap(), Envelope::FileFetchFailed { .. }));
+
+    // 签名有效的推送正常写入
+    start.signature = Some(signature);
+    framed.write_envelope(&Envelope::FilePushStart(start)).await.unwrap();
+    match framed.read_envelope().await.unwrap() {
+        Envelope::FilePushResult { success, message, .. } => assert!(success, "{}", message),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+    assert!(push_dir.join("a.txt").exists());
+}
+
+#[tokio::test]
+async fn test_unsigned_command_policy_ignored() {
+    use std::time::Duration;
+    use ops_common::command_policy::CommandPolicy;
+    use ops_common::command_signing::{CommandSigner, SignedRequest};
+    use ops_common::protocol::{Envelope, command_policy_signed_content};
+
+    let signer = CommandSigner::from_pem(&CommandSigner::generate_pem().unwrap()).unwrap();
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        command_log_file: temp_dir.path().join("commands.log").to_str().unwrap().to_string(),
+        command_signing_public_key: Some(signer.public_key()),
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    let policy = CommandPolicy::from_toml("[[rules]]\naction = \"allow\"\nprograms = [\"date\"]\n").unwrap();
+    let uptime = |command_id: &str| Envelope::CommandRequest {
+        command_id: command_id.to_string(),
+        command: "uptime".to_string(),
+        timeout_secs: None,
+        signature: Some(signer.sign(command_id, "client-1", "uptime", None, Duration::from_secs(60))),
+    };
+
+    // 未签名的策略不生效，仍按内置策略放行 uptime
+    framed.write_envelope(&Envelope::CommandPolicy { policy_id: "policy-1".to_string(), policy: policy.clone(), signature: None }).await.unwrap();
+    framed.write_envelope(&uptime("cmd-1")).await.unwrap();
+    match framed.read_envelope().await.unwrap() {
+        Envelope::CommandAccepted { command_id } => assert_eq!(command_id, "cmd-1"),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    // 签名有效的策略生效
+    let signature = signer.sign_request(SignedRequest::CommandPolicy, "policy-2", "client-1", &command_policy_signed_content(&policy), Duration::from_secs(60));
+    framed.write_envelope(&Envelope::CommandPolicy { policy_id: "policy-2".to_string(), policy, signature: Some(signature) }).await.unwrap();
+    framed.write_envelope(&uptime("cmd-2")).await.unwrap();
+    loop {
+        match framed.read_envelope().await.unwrap() {
+            Envelope::CommandRejected { command_id, .. } => {
+                assert_eq!(command_id, "cmd-2");
+                break;
+            }
+            Envelope::CommandOutput(_) | Envelope::CommandResponse(_) => {}
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_replay_rejected_after_client_restart() {
+    use std::time::Duration;
+    use ops_common::command_signing::{CommandSigner, CommandVerifier};
+
+    let signer = CommandSigner::from_pem(&CommandSigner::generate_pem().unwrap()).unwrap();
+    let temp_dir = tempdir().unwrap();
+    let seen_file = temp_dir.path().join("state").join("seen_requests.json");
+    let signature = signer.sign("cmd-1", "client-1", "uptime", None, Duration::from_secs(60));
+
+    let mut verifier = CommandVerifier::new(&signer.public_key()).unwrap().persist_to(&seen_file).unwrap();
+    assert!(verifier.verify("cmd-1", "client-1", "uptime", None, Some(&signature)).is_ok());
+    drop(verifier);
+
+    // 重启后从状态目录恢复已执行的请求ID，有效期内的重放仍被拒绝
+    let mut verifier = CommandVerifier::new(&signer.public_key()).unwrap().persist_to(&seen_file).unwrap();
+    assert!(verifier.verify("cmd-1", "client-1", "uptime", None, Some(&signature)).is_err());
+    let other = signer.sign("cmd-2", "client-1", "uptime", None, Duration::from_secs(60));
+    assert!(verifier.verify("cmd-2", "client-1", "uptime", None, Some(&other)).is_ok());
+
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(fs::metadata(&seen_file).unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[test]
+fn test_complete_utf8_len_keeps_partial_character() {
+    use crate::tcp_services::client::complete_utf8_len;
+
+    let text = "输出".as_bytes();
+    assert_eq!(complete_utf8_len(text), text.len());
+    // 第二个字符只到了两个字节，留到下一块
+    assert_eq!(complete_utf8_len(&text[..5]), 3);
+    assert_eq!(complete_utf8_len(b"plain"), 5);
+    // 非法字节不会让读取停滞
+    assert_eq!(complete_utf8_len(&[b'a', 0xFF]), 2);
+}
+
+#[tokio::test]
+async fn test_command_output_streamed_before_result() {
+    use ops_common::protocol::{Envelope, OutputStream};
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        command_log_file: temp_dir.path().join("commands.log").to_str().unwrap().to_string(),
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    let listed = temp_dir.path().join("listed");
+    fs::create_dir(&listed).unwrap();
+    fs::write(listed.join("streamed"), "").unwrap();
+
+    framed.write_envelope(&Envelope::CommandRequest {
+        command_id: "cmd-1".to_string(),
+        command: format!("ls {}", listed.display()),
+        timeout_secs: None,
+        signature: None,
+    }).await.unwrap();
+
+    assert!(matches!(framed.read_envelope().await.unwrap(), Envelope::CommandAccepted { .. }));
+
+    // 协商了 streaming_output 时，输出分块先于最终结果到达
+    let mut streamed = String::new();
+    let response = loop {
+        match framed.read_envelope().await.unwrap() {
+            Envelope::CommandOutput(chunk) => {
+                assert_eq!(chunk.command_id, "cmd-1");
+                assert_eq!(chunk.stream, OutputStream::Stdout);
+                streamed.push_str(&chunk.data);
+            }
+            Envelope::CommandResponse(response) => break response,
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    };
+
+    assert_eq!(streamed, "streamed\n");
+    assert_eq!(response.output, "streamed\n");
+    assert_eq!(response.exit_code, 0);
+}
+
+#[tokio::test]
+async fn test_command_output_returned_verbatim() {
+    use ops_common::protocol::Envelope;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        command_log_file: temp_dir.path().join("commands.log").to_str().unwrap().to_string(),
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    // 输出中出现类似结果格式的文本时照原样返回
+    let listed = temp_dir.path().join("listed");
+    fs::create_dir(&listed).unwrap();
+    fs::write(listed.join("a"), "").unwrap();
+    fs::write(listed.join("错误输出:"), "").unwrap();
+
+    framed.write_envelope(&Envelope::CommandRequest {
+        command_id: "cmd-1".to_string(),
+        command: format!("ls {}", listed.display()),
+        timeout_secs: None,
+        signature: None,
+    }).await.unwrap();
+
+    let response = loop {
+        match framed.read_envelope().await.unwrap() {
+            Envelope::CommandAccepted { .. } | Envelope::CommandOutput(_) => {}
+            Envelope::CommandResponse(response) => break response,
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    };
+    assert_eq!(response.output, "a\n错误输出:\n");
+    assert_eq!(response.error_output, "");
+    assert_eq!(response.exit_code, 0);
+    assert!(!response.cancelled && !response.timed_out);
+}
+
+#[tokio::test]
+async fn test_cancel_command_terminates_process_group() {
+    use ops_common::protocol::Envelope;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        command_log_file: temp_dir.path().join("commands.log").to_str().unwrap().to_string(),
+        cancel_grace_secs: 1,
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    let followed = temp_dir.path().join("followed.log");
+    fs::write(&followed, "before cancel\n").unwrap();
+
+    // tail -f 不会自行退出，只能被取消
+    framed.write_envelope(&Envelope::CommandRequest {
+        command_id: "cmd-1".to_string(),
+        command: format!("tail -f {}", followed.display()),
+        timeout_secs: None,
+        signature: None,
+    }).await.unwrap();
+    assert!(matches!(framed.read_envelope().await.unwrap(), Envelope::CommandAccepted { .. }));
+    assert!(matches!(framed.read_envelope().await.unwrap(), Envelope::CommandOutput(_)));
+
+    framed.write_envelope(&Envelope::CancelCommand { command_id: "cmd-1".to_string() }).await.unwrap();
+
+    let response = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match framed.read_envelope().await.unwrap() {
+                Envelope::CommandOutput(_) => continue,
+                Envelope::CommandResponse(response) => break response,
+                other => panic!("Unexpected envelope: {:?}", other),
+            }
+        }
+    }).await.expect("cancelled command should report a result");
+
+    assert!(response.cancelled);
+    assert_eq!(response.command_id, "cmd-1");
+    assert!(response.output.contains("before cancel"));
+    assert_ne!(response.exit_code, 0);
+}
+
+#[tokio::test]
+async fn test_command_timeout_kills_process_and_reports_partial_output() {
+    use ops_common::protocol::Envelope;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        command_log_file: temp_dir.path().join("commands.log").to_str().unwrap().to_string(),
+        cancel_grace_secs: 1,
+        max_command_timeout_secs: 1,
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    let followed = temp_dir.path().join("followed.log");
+    fs::write(&followed, "before timeout\n").unwrap();
+
+    // 服务端要求的时限超过客户端上限，按上限 1 秒执行
+    framed.write_envelope(&Envelope::CommandRequest {
+        command_id: "cmd-1".to_string(),
+        command: format!("tail -f {}", followed.display()),
+        timeout_secs: Some(600),
+        signature: None,
+    }).await.unwrap();
+
+    let response = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match framed.read_envelope().await.unwrap() {
+                Envelope::CommandAccepted { .. } | Envelope::CommandOutput(_) => continue,
+                Envelope::CommandResponse(response) => break response,
+                other => panic!("Unexpected envelope: {:?}", other),
+            }
+        }
+    }).await.expect("timed out command should report a result");
+
+    assert!(response.timed_out);
+    assert!(!response.cancelled);
+    assert_eq!(response.exit_code, 124);
+    assert!(response.output.contains("before timeout"));
+}
+
+#[tokio::test]
+async fn test_worker_pool_limits_concurrency_and_reports_queue() {
+    use crate::tcp_services::worker_pool::WorkerPool;
+
+    let pool = std::sync::Arc::new(WorkerPool::new(1));
+    let first = pool.acquire().await;
+
+    let waiting = tokio::spawn({
+        let pool = std::sync::Arc::clone(&pool);
+        async move { pool.acquire().await }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let status = pool.status();
+    assert_eq!((status.running, status.queued, status.max_concurrent), (1, 1, 1));
+
+    // 释放执行槽后排队的命令开始执行
+    drop(first);
+    let second = tokio::time::timeout(std::time::Duration::from_secs(1), waiting).await.unwrap().unwrap();
+    assert_eq!((pool.status().running, pool.status().queued), (1, 0));
+    drop(second);
+    assert_eq!(pool.status().running, 0);
+}
+
+#[tokio::test]
+async fn test_quick_command_not_blocked_by_long_running_one() {
+    use ops_common::protocol::Envelope;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        command_log_file: temp_dir.path().join("commands.log").to_str().unwrap().to_string(),
+        max_concurrent_commands: 2,
+        cancel_grace_secs: 1,
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    let followed = temp_dir.path().join("followed.log");
+    fs::write(&followed, "").unwrap();
+
+    framed.write_envelope(&Envelope::CommandRequest {
+        command_id: "slow".to_string(),
+        command: format!("tail -f {}", followed.display()),
+        timeout_secs: None,
+        signature: None,
+    }).await.unwrap();
+    framed.write_envelope(&Envelope::CommandRequest {
+        command_id: "quick".to_string(),
+        command: format!("ls {}", temp_dir.path().display()),
+        timeout_secs: None,
+        signature: None,
+    }).await.unwrap();
+
+    // 长时间运行的命令还在执行，快速命令照样返回结果
+    let response = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match framed.read_envelope().await.unwrap() {
+                Envelope::CommandResponse(response) => break response,
+                _ => continue,
+            }
+        }
+    }).await.expect("quick command should finish while the slow one runs");
+    assert_eq!(response.command_id, "quick");
+    assert!(response.output.contains("followed.log"));
+
+    framed.write_envelope(&Envelope::CancelCommand { command_id: "slow".to_string() }).await.unwrap();
+}
+
+// 读取终端输出直到出现期望的文本
+async fn read_terminal_until(
+    framed: &mut ops_common::codec::FramedStream<tokio::net::TcpStream>,
+    expected: &str,
+) -> String {
+    use ops_common::protocol::Envelope;
+
+    let mut output = String::new();
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while !output.contains(expected) {
+            match framed.read_envelope().await.unwrap() {
+                Envelope::TerminalOutput { data, .. } => output.push_str(&data),
+                Envelope::TerminalClosed { reason, .. } => panic!("terminal closed early: {:?}", reason),
+                _ => continue,
+            }
+        }
+    }).await.unwrap_or_else(|_| panic!("expected {:?} in terminal output: {:?}", expected, output));
+    output
+}
+
+#[tokio::test]
+async fn test_terminal_disabled_by_default() {
+    use ops_common::protocol::{Capability, Envelope};
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+
+    let (session, (mut framed, hello)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+    assert!(!hello.capabilities.contains(&Capability::Terminal));
+
+    framed.write_envelope(&Envelope::TerminalOpen { session_id: "term-1".to_string(), cols: 80, rows: 24, signature: None }).await.unwrap();
+    match framed.read_envelope().await.unwrap() {
+        Envelope::TerminalClosed { session_id, reason } => {
+            assert_eq!(session_id, "term-1");
+            assert!(reason.is_some());
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+}
 
-    #[test]
-    fn test_client_id_creation_and_retrieval() {
-        let temp_dir = tempdir().unwrap();
-        let client_id_file = temp_dir.path().join("test_client_id.txt");
-        
-        let mut config = ClientConfig::default();
-        config.client_id_file = client_id_file.to_str().unwrap().to_string();
+#[tokio::test]
+async fn test_terminal_runs_shell_on_pty() {
+    use ops_common::protocol::{Capability, Envelope};
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        terminal_enabled: true,
+        terminal_shell: "/bin/sh".to_string(),
+        ..config
+    };
 
-        // 测试创建新的客户端ID
-        let id1 = crate::tcp_services::client::get_or_create_client_id(&config).unwrap();
-        assert!(!id1.is_empty());
-        assert!(client_id_file.exists());
+    let (session, (mut framed, hello)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+    assert!(hello.capabilities.contains(&Capability::Terminal));
 
-        // 测试读取现有的客户端ID
-        let id2 = crate::tcp_services::client::get_or_create_client_id(&config).unwrap();
-        assert_eq!(id1, id2);
+    let session_id = "term-1".to_string();
+    framed.write_envelope(&Envelope::TerminalOpen { session_id: session_id.clone(), cols: 80, rows: 24, signature: None }).await.unwrap();
+
+    // 回显的是输入原文，只有 shell 真正执行后才会出现计算结果
+    framed.write_envelope(&Envelope::TerminalInput {
+        session_id: session_id.clone(),
+        data: "echo pty-$((40+2))\n".to_string(),
+    }).await.unwrap();
+    read_terminal_until(&mut framed, "pty-42").await;
+
+    framed.write_envelope(&Envelope::TerminalResize { session_id: session_id.clone(), cols: 100, rows: 30 }).await.unwrap();
+    framed.write_envelope(&Envelope::TerminalInput {
+        session_id: session_id.clone(),
+        data: "stty size\n".to_string(),
+    }).await.unwrap();
+    read_terminal_until(&mut framed, "30 100").await;
+
+    framed.write_envelope(&Envelope::TerminalInput { session_id: session_id.clone(), data: "exit\n".to_string() }).await.unwrap();
+    let reason = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match framed.read_envelope().await.unwrap() {
+                Envelope::TerminalClosed { session_id: closed, reason } => {
+                    assert_eq!(closed, session_id);
+                    break reason;
+                }
+                _ => continue,
+            }
+        }
+    }).await.expect("terminal should close after the shell exits");
+    assert_eq!(reason, None);
+}
+
+// 按协议把文件分块推送给客户端
+async fn push_file(
+    framed: &mut ops_common::codec::FramedStream<tokio::net::TcpStream>,
+    transfer_id: &str,
+    path: &std::path::Path,
+    content: &[u8],
+    sha256: String,
+) -> (bool, String) {
+    use ops_common::file_transfer::FILE_CHUNK_SIZE;
+    use ops_common::protocol::{Envelope, FilePushStart};
+
+    framed.write_envelope(&Envelope::FilePushStart(FilePushStart {
+        transfer_id: transfer_id.to_string(),
+        path: path.to_str().unwrap().to_string(),
+        size: content.len() as u64,
+        sha256,
+        mode: Some(0o750),
+        signature: None,
+    })).await.unwrap();
+    for (index, chunk) in content.chunks(FILE_CHUNK_SIZE).enumerate() {
+        framed.write_envelope(&Envelope::FilePushChunk {
+            transfer_id: transfer_id.to_string(),
+            offset: (index * FILE_CHUNK_SIZE) as u64,
+            data: chunk.to_vec(),
+        }).await.unwrap();
+    }
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            if let Envelope::FilePushResult { transfer_id: id, success, message } = framed.read_envelope().await.unwrap() {
+                assert_eq!(id, transfer_id);
+                break (success, message);
+            }
+        }
+    }).await.expect("client should report the transfer result")
+}
+
+#[tokio::test]
+async fn test_file_push_verified_and_written_atomically() {
+    use ops_common::file_transfer::sha256_hex;
+    use ops_common::protocol::Capability;
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = tempdir().unwrap();
+    let push_dir = temp_dir.path().join("push");
+    fs::create_dir(&push_dir).unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        file_push_dirs: vec![push_dir.to_str().unwrap().to_string()],
+        ..config
+    };
+
+    let (session, (mut framed, hello)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+    assert!(hello.capabilities.contains(&Capability::FileTransfer));
+
+    // 超过一个分块，覆盖已有文件
+    let target = push_dir.join("deploy.sh");
+    fs::write(&target, "old").unwrap();
+    let content: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+    let (success, message) = push_file(&mut framed, "xfer-1", &target, &content, sha256_hex(&content)).await;
+    assert!(success, "{}", message);
+    assert_eq!(fs::read(&target).unwrap(), content);
+    assert_eq!(fs::metadata(&target).unwrap().permissions().mode() & 0o7777, 0o750);
+
+    // 校验失败时保留原文件，不留下临时文件
+    let (success, message) = push_file(&mut framed, "xfer-2", &target, b"tampered", sha256_hex(b"expected")).await;
+    assert!(!success);
+    assert!(message.contains("SHA-256"));
+    assert_eq!(fs::read(&target).unwrap(), content);
+    assert_eq!(fs::read_dir(&push_dir).unwrap().count(), 1);
+
+    // 允许目录之外的路径被拒绝，包括经由 .. 跳出的路径
+    let outside = temp_dir.path().join("outside.txt");
+    let (success, _) = push_file(&mut framed, "xfer-3", &outside, b"x", sha256_hex(b"x")).await;
+    assert!(!success);
+    let escaped = push_dir.join("../escaped.txt");
+    let (success, _) = push_file(&mut framed, "xfer-4", &escaped, b"x", sha256_hex(b"x")).await;
+    assert!(!success);
+    assert!(!outside.exists());
+    assert!(!temp_dir.path().join("escaped.txt").exists());
+}
+
+#[tokio::test]
+async fn test_file_push_disabled_without_allowed_dirs() {
+    use ops_common::file_transfer::sha256_hex;
+    use ops_common::protocol::Capability;
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+
+    let (session, (mut framed, hello)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+    assert!(!hello.capabilities.contains(&Capability::FileTransfer));
+
+    let target = temp_dir.path().join("file.txt");
+    let (success, _) = push_file(&mut framed, "xfer-1", &target, b"data", sha256_hex(b"data")).await;
+    assert!(!success);
+    assert!(!target.exists());
+}
+
+// 请求客户端读取文件，返回 (起始偏移, 内容) 或失败原因
+async fn fetch_file(
+    framed: &mut ops_common::codec::FramedStream<tokio::net::TcpStream>,
+    fetch_id: &str,
+    path: &std::path::Path,
+    range: ops_common::protocol::FetchRange,
+) -> Result<(u64, Vec<u8>), String> {
+    use ops_common::file_transfer::sha256_hex;
+    use ops_common::protocol::Envelope;
+
+    framed.write_envelope(&Envelope::FileFetchRequest {
+        fetch_id: fetch_id.to_string(),
+        path: path.to_str().unwrap().to_string(),
+        range,
+        signature: None,
+    }).await.unwrap();
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        let mut start = None;
+        let mut content = Vec::new();
+        loop {
+            match framed.read_envelope().await.unwrap() {
+                Envelope::FileFetchStart { fetch_id: id, offset, length, .. } => {
+                    assert_eq!(id, fetch_id);
+                    start = Some((offset, length));
+                }
+                Envelope::FileFetchChunk { offset, data, .. } => {
+                    let (start, _) = start.expect("chunk before start");
+                    assert_eq!(offset, start + content.len() as u64);
+                    content.extend_from_slice(&data);
+                }
+                Envelope::FileFetchEnd { sha256, .. } => {
+                    let (offset, length) = start.expect("end before start");
+                    assert_eq!(content.len() as u64, length);
+                    assert_eq!(sha256, sha256_hex(&content));
+                    break Ok((offset, content));
+                }
+                Envelope::FileFetchFailed { fetch_id: id, reason } => {
+                    assert_eq!(id, fetch_id);
+                    break Err(reason);
+                }
+                _ => {}
+            }
+        }
+    }).await.expect("client should answer the fetch")
+}
+
+#[tokio::test]
+async fn test_file_fetch_whole_range_and_tail() {
+    use ops_common::protocol::{Capability, FetchRange};
+
+    let temp_dir = tempdir().unwrap();
+    let log_dir = temp_dir.path().join("logs");
+    fs::create_dir(&log_dir).unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        file_fetch_dirs: vec![log_dir.to_str().unwrap().to_string()],
+        max_fetch_file_size: 100_000,
+        ..config
+    };
+
+    let (session, (mut framed, hello)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+    assert!(hello.capabilities.contains(&Capability::FileFetch));
+
+    let log: String = (0..5000).map(|i| format!("line {}\n", i)).collect();
+    let log_file = log_dir.join("app.log");
+    fs::write(&log_file, &log).unwrap();
+
+    let (offset, content) = fetch_file(&mut framed, "f-1", &log_file, FetchRange::Whole).await.unwrap();
+    assert_eq!(offset, 0);
+    assert_eq!(content, log.as_bytes());
+
+    let (offset, content) = fetch_file(&mut framed, "f-2", &log_file, FetchRange::Bytes { offset: 7, length: Some(6) }).await.unwrap();
+    assert_eq!(offset, 7);
+    assert_eq!(content, b"line 1");
+
+    // 末尾的换行不算新的一行
+    let (_, content) = fetch_file(&mut framed, "f-3", &log_file, FetchRange::Tail { lines: 2 }).await.unwrap();
+    assert_eq!(content, b"line 4998\nline 4999\n");
+    let (offset, content) = fetch_file(&mut framed, "f-4", &log_file, FetchRange::Tail { lines: 10_000 }).await.unwrap();
+    assert_eq!((offset, content.len()), (0, log.len()));
+
+    // 超过大小上限：整文件读取被拒绝，按行读取只返回最后部分
+    let big: String = (0..20_000).map(|i| format!("line {}\n", i)).collect();
+    fs::write(&log_file, &big).unwrap();
+    let reason = fetch_file(&mut framed, "f-5", &log_file, FetchRange::Whole).await.unwrap_err();
+    assert!(reason.contains("超过上限"), "{}", reason);
+    let (_, content) = fetch_file(&mut framed, "f-6", &log_file, FetchRange::Tail { lines: 20_000 }).await.unwrap();
+    assert_eq!(content.len(), 100_000);
+    assert!(big.as_bytes().ends_with(&content));
+}
+
+#[tokio::test]
+async fn test_file_fetch_restricted_to_allowed_dirs() {
+    use ops_common::protocol::{Capability, FetchRange};
+
+    let temp_dir = tempdir().unwrap();
+    let log_dir = temp_dir.path().join("logs");
+    fs::create_dir(&log_dir).unwrap();
+    let secret = temp_dir.path().join("secret.txt");
+    fs::write(&secret, "secret").unwrap();
+    std::os::unix::fs::symlink(&secret, log_dir.join("link.log")).unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let config = ClientConfig {
+        file_fetch_dirs: vec![log_dir.to_str().unwrap().to_string()],
+        ..config
+    };
+
+    let (session, (mut framed, _)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+
+    // 直接访问、经由 .. 跳出、经由符号链接跳出都被拒绝
+    for (id, path) in [("f-1", secret.clone()), ("f-2", log_dir.join("../secret.txt")), ("f-3", log_dir.join("link.log"))] {
+        let reason = fetch_file(&mut framed, id, &path, FetchRange::Whole).await.unwrap_err();
+        assert!(!reason.is_empty());
+    }
+    let reason = fetch_file(&mut framed, "f-4", &log_dir, FetchRange::Whole).await.unwrap_err();
+    assert!(reason.contains("不是普通文件"), "{}", reason);
+    drop(framed);
+
+    // 未配置允许目录时不声明该功能
+    let (listener, config) = stub_server(temp_dir.path()).await;
+    let (session, (_framed, hello)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+    assert!(!hello.capabilities.contains(&Capability::FileFetch));
+}
+
+#[tokio::test]
+async fn test_log_follower_survives_rotation_and_truncation() {
+    use crate::tcp_services::log_tail::LogFollower;
+    use std::io::Write;
+
+    let temp_dir = tempdir().unwrap();
+    let log = temp_dir.path().join("app.log");
+    let append = |path: &std::path::Path, data: &str| {
+        fs::OpenOptions::new().append(true).open(path).unwrap().write_all(data.as_bytes()).unwrap();
+    };
+    fs::write(&log, "a\nb\nc\n").unwrap();
+
+    let mut follower = LogFollower::open(log.clone(), 2).await.unwrap();
+    assert_eq!(follower.poll().await.unwrap(), "b\nc\n");
+    assert_eq!(follower.poll().await.unwrap(), "");
+
+    // 不完整的行等写完再回传
+    append(&log, "d\npart");
+    assert_eq!(follower.poll().await.unwrap(), "d\n");
+    append(&log, "ial\n");
+    assert_eq!(follower.poll().await.unwrap(), "partial\n");
+
+    // 改名轮转：先读完旧文件中迟到的内容，再从新文件开头读取
+    let rotated = temp_dir.path().join("app.log.1");
+    fs::rename(&log, &rotated).unwrap();
+    append(&rotated, "late\n");
+    fs::write(&log, "new 1\n").unwrap();
+    assert_eq!(follower.poll().await.unwrap(), "late\n");
+    assert_eq!(follower.poll().await.unwrap(), "new 1\n");
+
+    // 原地截断后从头读取
+    fs::write(&log, "t\n").unwrap();
+    assert_eq!(follower.poll().await.unwrap(), "t\n");
+}
+
+#[tokio::test]
+async fn test_log_tail_streams_app_log_until_stopped() {
+    use ops_common::protocol::{Capability, Envelope};
+    use std::io::Write;
+
+    let temp_dir = tempdir().unwrap();
+    let app_dir = temp_dir.path().join("web");
+    fs::create_dir_all(app_dir.join("logs")).unwrap();
+    fs::write(app_dir.join("version.txt"), "version: 1.0.0").unwrap();
+    let log = app_dir.join("logs/app.log");
+    fs::write(&log, "first\nlast\n").unwrap();
+    fs::write(temp_dir.path().join("secret.txt"), "secret\n").unwrap();
+    let (listener, config) = stub_server(temp_dir.path()).await;
+
+    let (session, (mut framed, hello)) = tokio::join!(
+        crate::tcp_services::client::TcpSession::new(config),
+        accept_hello(&listener),
+    );
+    let _session = session.unwrap();
+    assert!(hello.capabilities.contains(&Capability::LogTail));
+
+    async fn next_log_event(framed: &mut ops_common::codec::FramedStream<tokio::net::TcpStream>) -> Envelope {
+        loop {
+            let event = framed.read_envelope().await.unwrap();
+            if matches!(event, Envelope::LogTailData { .. } | Envelope::LogTailEnded { .. }) {
+                break event;
+            }
+        }
+    }
+    let wait = std::time::Duration::from_secs(5);
+
+    framed.write_envelope(&Envelope::LogTailStart {
+        tail_id: "tail-1".to_string(),
+        app: "web".to_string(),
+        file: "logs/app.log".to_string(),
+        lines: 1,
+        signature: None,
+    }).await.unwrap();
+    match tokio::time::timeout(wait, next_log_event(&mut framed)).await.unwrap() {
+        Envelope::LogTailData { tail_id, data } => assert_eq!((tail_id.as_str(), data.as_str()), ("tail-1", "last\n")),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    fs::OpenOptions::new().append(true).open(&log).unwrap().write_all(b"new line\n").unwrap();
+    match tokio::time::timeout(wait, next_log_event(&mut framed)).await.unwrap() {
+        Envelope::LogTailData { data, .. } => assert_eq!(data, "new line\n"),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    // 停止后不再回传
+    framed.write_envelope(&Envelope::LogTailStop { tail_id: "tail-1".to_string() }).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    fs::OpenOptions::new().append(true).open(&log).unwrap().write_all(b"ignored\n").unwrap();
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(1200), next_log_event(&mut framed)).await.is_err());
+
+    // 不能跳出应用目录
+    framed.write_envelope(&Envelope::LogTailStart {
+        tail_id: "tail-2".to_string(),
+        app: "web".to_string(),
+        file: "../secret.txt".to_string(),
+        lines: 10,
+        signature: None,
+    }).await.unwrap();
+    match tokio::time::timeout(wait, next_log_event(&mut framed)).await.unwrap() {
+        Envelope::LogTailEnded { tail_id, reason } => {
+            assert_eq!(tail_id, "tail-2");
+            assert!(reason.contains("相对路径"), "{}", reason);
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
     }
+}
 
-    #[test]
-    fn test_command_validator_allowed_commands() {
-        let validator = CommandValidator::new();
-        
-        match validator.validate("ps aux") {
-            ValidationResult::Allowed => {},
-            ValidationResult::Blocked { reason } => panic!("Should be allowed: {}", reason),
-        }
-
-        match validator.validate("ls -la") {
-            ValidationResult::Allowed => {},
-            ValidationResult::Blocked { reason } => panic!("Should be allowed: {}", reason),
-        }
-    }
+#[tokio::test]
+async fn test_enrollment_saves_credential_and_rotation_is_acked() {
+    use ops_common::codec::FramedStream;
+    use ops_common::protocol::Envelope;
+    use ops_common::tcp_auth::{TcpAuthMessage, TcpAuthenticator};
 
-    #[test]
-    fn test_command_validator_dangerous_commands() {
-        let validator = CommandValidator::new();
-        
-        match validator.validate("rm -rf /") {
-            ValidationResult::Blocked { reason } => {
-                assert!(reason.contains("危险模式") || reason.contains("dangerous"));
-            },
-            ValidationResult::Allowed => panic!("Should be blocked"),
-        }
-
-        match validator.validate("shutdown now") {
-            ValidationResult::Blocked { reason } => {
-                assert!(reason.contains("危险模式") || reason.contains("dangerous"));
-            },
-            ValidationResult::Allowed => panic!("Should be blocked"),
-        }
-    }
-
-    #[test]
-    fn test_command_validator_unknown_commands() {
-        let validator = CommandValidator::new();
-        
-        match validator.validate("malicious_unknown_command") {
-            ValidationResult::Blocked { reason } => {
-                assert!(reason.contains("不在允许列表中") || reason.contains("not allowed"));
-            },
-            ValidationResult::Allowed => panic!("Should be blocked"),
-        }
-    }
-
-    #[test]
-    fn test_command_validator_empty_command() {
-        let validator = CommandValidator::new();
-        
-        match validator.validate("") {
-            ValidationResult::Blocked { .. } => {},
-            ValidationResult::Allowed => panic!("Empty command should be blocked"),
-        }
-
-        match validator.validate("   ") {
-            ValidationResult::Blocked { .. } => {},
-            ValidationResult::Allowed => panic!("Whitespace-only command should be blocked"),
-        }
-    }
-
-    #[test]
-    fn test_command_validator_long_command() {
-        let validator = CommandValidator::new();
-        let long_command = "a".repeat(2000); // 超过默认的1000字符限制
-        
-        match validator.validate(&long_command) {
-            ValidationResult::Blocked { reason } => {
-                assert!(reason.contains("长度超过限制") || reason.contains("too long"));
-            },
-            ValidationResult::Allowed => panic!("Long command should be blocked"),
-        }
-    }
-
-    #[test]
-    fn test_command_sanitization() {
-        let validator = CommandValidator::new();
-        
-        let dirty_command = "ps aux; rm -rf /; echo hello";
-        let clean_command = validator.sanitize_command(dirty_command);
-        
-        // 应该移除危险的分隔符
-        assert!(!clean_command.contains(";"));
-        assert!(!clean_command.contains("&&"));
-        assert!(!clean_command.contains("||"));
-    }
-
-    #[test]
-    fn test_version_collector_empty_directory() {
-        let temp_dir = tempdir().unwrap();
-        let versions = crate::collection::version_collector::read_app_versions(
-            temp_dir.path().to_str().unwrap()
-        );
-        assert!(versions.is_empty());
-    }
-
-    #[test]
-    fn test_version_collector_with_valid_version_file() {
-        let temp_dir = tempdir().unwrap();
-        let app_dir = temp_dir.path().join("test_app");
-        fs::create_dir(&app_dir).unwrap();
-        
-        let version_file = app_dir.join("version.txt");
-        let version_data = r#"{"app":"test_app","created_time":"2024-01-01T00:00:00Z"}"#;
-        fs::write(&version_file, version_data).unwrap();
-        
-        let versions = crate::collection::version_collector::read_app_versions(
-            temp_dir.path().to_str().unwrap()
-        );
-        
-        assert_eq!(versions.len(), 1);
-        assert_eq!(versions[0].app, "test_app");
-        assert_eq!(versions[0].created_time, "2024-01-01T00:00:00Z");
-    }
-
-    #[test]
-    fn test_version_collector_with_invalid_json() {
-        let temp_dir = tempdir().unwrap();
-        let app_dir = temp_dir.path().join("bad_app");
-        fs::create_dir(&app_dir).unwrap();
-        
-        let version_file = app_dir.join("version.txt");
-        fs::write(&version_file, "invalid json").unwrap();
-        
-        let versions = crate::collection::version_collector::read_app_versions(
-            temp_dir.path().to_str().unwrap()
-        );
-        
-        // 应该忽略无效的JSON文件
-        assert!(versions.is_empty());
-    }
-}
\ No newline at end of file
+    // 模拟服务端的认证：发送质询，按需处理注册，然后用 secret 校验响应
+    async fn authenticate(framed: &mut FramedStream<tokio::net::TcpStream>, secret: &str, expect_enroll: bool) {
+        let TcpAuthMessage::Challenge { nonce, timestamp } = TcpAuthenticator::generate_challenge() else {
+            unreachable!();
+        };
+        framed.write_envelope(&Envelope::Auth(TcpAuthMessage::Challenge { nonce: nonce.clone(), timestamp })).await.unwrap();
+        if expect_enroll {
+            match framed.read_envelope().await.unwrap() {
+                Envelope::Auth(TcpAuthMessage::Enroll { client_id, token }) => {
+                    assert_eq!((client_id.as_str(), token.as_str()), ("client-1", "enroll-token"));
+                }
+                other => panic!("Unexpected envelope: {:?}", other),
+            }
+            framed.write_envelope(&Envelope::Auth(TcpAuthMessage::Enrolled { secret: secret.to_string() })).await.unwrap();
+        }
+        let response = match framed.read_envelope().await.unwrap() {
+            Envelope::Auth(response) => response,
+            other => panic!("Unexpected envelope: {:?}", other),
+        };
+        let valid = TcpAuthenticator::verify_response(&response, &nonce, timestamp, |_| vec![secret.to_string()]).unwrap();
+        assert!(valid, "client should answer with its own secret");
+        framed.write_envelope(&Envelope::Auth(TcpAuthenticator::create_success_result())).await.unwrap();
+    }
+
+    let temp_dir = tempdir().unwrap();
+    let (listener, mut config) = stub_server(temp_dir.path()).await;
+    config.tcp_auth_enabled = true;
+    config.tcp_auth_secret = Some("legacy-secret".to_string());
+    config.enrollment_token = Some("enroll-token".to_string());
+    let credential_file = temp_dir.path().join("state/credential");
+
+    // 首次连接没有保存的凭据，先用注册令牌换取密钥
+    let server = async {
+        let (mut framed, _) = accept_hello(&listener).await;
+        authenticate(&mut framed, "enrolled-secret", true).await;
+        framed
+    };
+    let (session, mut framed) = tokio::join!(crate::tcp_services::client::TcpSession::new(config), server);
+    let _session = session.unwrap();
+    assert_eq!(fs::read_to_string(&credential_file).unwrap(), "enrolled-secret");
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(fs::metadata(&credential_file).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // 服务端轮换密钥：客户端保存后确认
+    framed.write_envelope(&Envelope::CredentialUpdate { secret: "rotated-secret".to_string() }).await.unwrap();
+    let wait = std::time::Duration::from_secs(5);
+    tokio::time::timeout(wait, async {
+        while !matches!(framed.read_envelope().await.unwrap(), Envelope::CredentialUpdateAck) {}
+    }).await.expect("client should acknowledge the new credential");
+    assert_eq!(fs::read_to_string(&credential_file).unwrap(), "rotated-secret");
+
+    // 重连时直接使用新密钥，不再注册
+    drop(framed);
+    let server = async {
+        let (mut framed, _) = accept_hello(&listener).await;
+        authenticate(&mut framed, "rotated-secret", false).await;
+    };
+    tokio::time::timeout(wait, server).await.expect("client should reconnect");
+}
@@ -0,0 +1,313 @@
+use std::fmt;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use crate::protocol::Envelope;
+
+/// 默认最大帧大小：16 MiB
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// 帧头长度：4 字节大端序负载长度
+const FRAME_HEADER_LEN: usize = 4;
+
+/// 帧长度超过上限时返回的错误，包装在 `io::ErrorKind::InvalidData` 中
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameTooLarge {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "帧长度 {} 字节超过上限 {} 字节", self.size, self.max)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+impl FrameTooLarge {
+    /// 判断一个 IO 错误是否由超长帧引起
+    pub fn is_frame_too_large(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|inner| inner.is::<FrameTooLarge>())
+    }
+
+    fn into_io_error(self) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, self)
+    }
+}
+
+/// 长度前缀帧编解码配置
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        Self {
+            max_frame_size: max_frame_size.min(u32::MAX as usize),
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// 为负载添加长度前缀
+    pub fn encode(&self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        if payload.len() > self.max_frame_size {
+            return Err(FrameTooLarge { size: payload.len(), max: self.max_frame_size }.into_io_error());
+        }
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        Ok(frame)
+    }
+
+    /// 从缓冲区中取出一个完整帧；数据不足时返回 `None`
+    ///
+    /// 帧头一到达就检查长度，超长帧不会被缓冲。
+    pub fn decode(&self, buffer: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        if buffer.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header.copy_from_slice(&buffer[..FRAME_HEADER_LEN]);
+        let len = u32::from_be_bytes(header) as usize;
+
+        if len > self.max_frame_size {
+            return Err(FrameTooLarge { size: len, max: self.max_frame_size }.into_io_error());
+        }
+
+        if buffer.len() < FRAME_HEADER_LEN + len {
+            buffer.reserve(FRAME_HEADER_LEN + len - buffer.len());
+            return Ok(None);
+        }
+
+        let frame = buffer[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+        buffer.drain(..FRAME_HEADER_LEN + len);
+        Ok(Some(frame))
+    }
+}
+
+/// 读取长度前缀帧
+///
+/// 使用内部缓冲区，`read_frame` 可以安全地被超时或 `select!` 取消而不会丢失半个帧。
+pub struct FrameReader<R> {
+    reader: R,
+    codec: FrameCodec,
+    read_buffer: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R, codec: FrameCodec) -> Self {
+        Self {
+            reader,
+            codec,
+            read_buffer: Vec::new(),
+        }
+    }
+
+    /// 读取一个完整帧的负载
+    pub async fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        let mut chunk = [0u8; 8192];
+        loop {
+            if let Some(frame) = self.codec.decode(&mut self.read_buffer)? {
+                return Ok(frame);
+            }
+
+            let n = self.reader.read(&mut chunk).await?;
+            if n == 0 {
+                let message = if self.read_buffer.is_empty() {
+                    "连接已关闭"
+                } else {
+                    "连接在帧传输中途关闭"
+                };
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message));
+            }
+            self.read_buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// 读取并解析一个协议消息
+    pub async fn read_envelope(&mut self) -> io::Result<Envelope> {
+        let frame = self.read_frame().await?;
+        Envelope::decode(&frame)
+    }
+}
+
+/// 写入长度前缀帧
+pub struct FrameWriter<W> {
+    writer: W,
+    codec: FrameCodec,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(writer: W, codec: FrameCodec) -> Self {
+        Self { writer, codec }
+    }
+
+    /// 写入一个完整帧并刷新
+    pub async fn write_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        let frame = self.codec.encode(payload)?;
+        self.writer.write_all(&frame).await?;
+        self.writer.flush().await
+    }
+
+    /// 编码并发送一个协议消息
+    pub async fn write_envelope(&mut self, envelope: &Envelope) -> io::Result<()> {
+        let payload = envelope.encode()?;
+        self.write_frame(&payload).await
+    }
+
+    /// 关闭写方向，对端读到 EOF
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.writer.shutdown().await
+    }
+}
+
+/// 带长度前缀帧的双向流，用于握手阶段；握手完成后用 `split` 拆分为独立的读写两端
+pub struct FramedStream<S> {
+    reader: FrameReader<S>,
+}
+
+impl<S> FramedStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S, codec: FrameCodec) -> Self {
+        Self {
+            reader: FrameReader::new(stream, codec),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.reader.reader
+    }
+
+    /// 读取一个完整帧的负载
+    pub async fn read_frame(&mut self) -> io::Result<Vec<u8>> {
+        self.reader.read_frame().await
+    }
+
+    /// 写入一个完整帧并刷新
+    pub async fn write_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        let frame = self.reader.codec.encode(payload)?;
+        self.reader.reader.write_all(&frame).await?;
+        self.reader.reader.flush().await
+    }
+
+    /// 读取并解析一个协议消息
+    pub async fn read_envelope(&mut self) -> io::Result<Envelope> {
+        self.reader.read_envelope().await
+    }
+
+    /// 编码并发送一个协议消息
+    pub async fn write_envelope(&mut self, envelope: &Envelope) -> io::Result<()> {
+        let payload = envelope.encode()?;
+        self.write_frame(&payload).await
+    }
+
+    /// 拆分为读写两端，已缓冲但未读取的数据保留在读端
+    pub fn split(self) -> (FrameReader<ReadHalf<S>>, FrameWriter<WriteHalf<S>>) {
+        let FrameReader { reader: stream, codec, read_buffer } = self.reader;
+        let (read_half, write_half) = tokio::io::split(stream);
+        (
+            FrameReader { reader: read_half, codec, read_buffer },
+            FrameWriter::new(write_half, codec),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_waits_for_complete_frame() {
+        let codec = FrameCodec::new(1024);
+        let frame = codec.encode(b"hello").unwrap();
+
+        let mut buffer = frame[..6].to_vec();
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+
+        buffer.extend_from_slice(&frame[6..]);
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(b"hello".to_vec()));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_decode_coalesced_frames() {
+        let codec = FrameCodec::new(1024);
+        let mut buffer = codec.encode(b"first").unwrap();
+        buffer.extend(codec.encode(b"second").unwrap());
+
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn test_oversized_frame_rejected() {
+        let codec = FrameCodec::new(8);
+
+        let err = codec.encode(&[0u8; 9]).unwrap_err();
+        assert!(FrameTooLarge::is_frame_too_large(&err));
+
+        // 只收到帧头就应当拒绝
+        let mut buffer = 1_000_000u32.to_be_bytes().to_vec();
+        let err = codec.decode(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(FrameTooLarge::is_frame_too_large(&err));
+    }
+
+    #[tokio::test]
+    async fn test_framed_stream_roundtrip() {
+        let (client, server) = tokio::io::duplex(64);
+        let codec = FrameCodec::default();
+        let mut client = FramedStream::new(client, codec);
+        let mut server = FramedStream::new(server, codec);
+
+        let output = "x".repeat(100_000);
+        let sender = tokio::spawn(async move {
+            client.write_envelope(&Envelope::Broadcast { message: output }).await.unwrap();
+            client.write_envelope(&Envelope::Ack).await.unwrap();
+        });
+
+        match server.read_envelope().await.unwrap() {
+            Envelope::Broadcast { message } => assert_eq!(message.len(), 100_000),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+        assert!(matches!(server.read_envelope().await.unwrap(), Envelope::Ack));
+        sender.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_split_keeps_buffered_frames() {
+        let (client, server) = tokio::io::duplex(1024);
+        let codec = FrameCodec::default();
+        let mut client = FramedStream::new(client, codec);
+        let mut server = FramedStream::new(server, codec);
+
+        // 两个帧一次到达，第一个在握手阶段读取，第二个在拆分后读取
+        client.write_envelope(&Envelope::Ack).await.unwrap();
+        client.write_envelope(&Envelope::Broadcast { message: "after split".to_string() }).await.unwrap();
+        assert!(matches!(server.read_envelope().await.unwrap(), Envelope::Ack));
+
+        let (mut reader, mut writer) = server.split();
+        match reader.read_envelope().await.unwrap() {
+            Envelope::Broadcast { message } => assert_eq!(message, "after split"),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+
+        writer.write_envelope(&Envelope::Ack).await.unwrap();
+        assert!(matches!(client.read_envelope().await.unwrap(), Envelope::Ack));
+    }
+}
@@ -0,0 +1,612 @@
+// 命令校验策略：从 TOML 加载的按命令的 allow/deny 规则（子命令、参数正则、路径前缀）和脚本目录，
+// 任一 deny 规则命中即拒绝，否则需要至少一条 allow 规则匹配；格式见 default_policy.toml
+
+use std::path::{Component, Path, PathBuf};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use crate::file_transfer::sha256_hex;
+use crate::shell::Word;
+
+/// 内置的默认策略
+pub const DEFAULT_POLICY: &str = include_str!("default_policy.toml");
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    Allow,
+    Deny,
+}
+
+/// 一条规则，未指定的条件不参与匹配
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PolicyRule {
+    pub action: RuleAction,
+    /// 命令名，"*" 匹配任意命令
+    pub programs: Vec<String>,
+    /// 第一个非选项参数
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subcommands: Vec<String>,
+    /// 子命令是第几个非选项参数，默认第 1 个；如 `service <服务> status` 为 2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subcommand_position: Option<usize>,
+    /// 参数正则，匹配整个参数
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// 路径参数的目录前缀
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path_prefixes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// 允许执行的脚本
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScriptPolicy {
+    pub allowed_dirs: Vec<String>,
+    pub allowed_extensions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandPolicy {
+    #[serde(default = "default_max_command_length")]
+    pub max_command_length: usize,
+    #[serde(default)]
+    pub scripts: ScriptPolicy,
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,
+}
+
+fn default_max_command_length() -> usize {
+    1000
+}
+
+impl Default for CommandPolicy {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl CommandPolicy {
+    /// 内置的默认策略
+    pub fn builtin() -> Self {
+        Self::from_toml(DEFAULT_POLICY).expect("built-in command policy is valid")
+    }
+
+    /// 解析并检查策略（正则能否编译、路径前缀是否为绝对路径）
+    pub fn from_toml(content: &str) -> Result<Self, String> {
+        let policy: Self = toml::from_str(content).map_err(|e| format!("策略格式错误: {}", e))?;
+        policy.compile()?;
+        Ok(policy)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("读取策略文件 {} 失败: {}", path.display(), e))?;
+        Self::from_toml(&content).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// 策略版本：内容的 SHA-256 前 12 位，内容相同的策略版本相同
+    pub fn version(&self) -> String {
+        let content = serde_json::to_vec(self).expect("command policy is always serializable");
+        sha256_hex(&content)[..12].to_string()
+    }
+
+    pub(crate) fn compile(&self) -> Result<CompiledRules, String> {
+        let rules = self.rules.iter().enumerate()
+            .map(|(index, rule)| CompiledRule::new(index + 1, rule))
+            .collect::<Result<_, _>>()?;
+        Ok(CompiledRules { rules })
+    }
+}
+
+/// 校验一条命令时的当前目录
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WorkDir<'a> {
+    /// 没有执行过 cd，即客户端进程的工作目录，不含 / 的参数不当作路径
+    Initial,
+    /// 由前面的 `cd <绝对路径> &&` 确定的目录
+    Known(&'a Path),
+    /// 前面的 cd 可能生效也可能没有生效，相对路径无法确定
+    Unknown,
+}
+
+/// 编译后的规则，编号从 1 开始，对应策略文件中的顺序
+#[derive(Debug, Clone)]
+pub(crate) struct CompiledRules {
+    rules: Vec<CompiledRule>,
+}
+
+#[derive(Debug, Clone)]
+struct CompiledRule {
+    number: usize,
+    rule: PolicyRule,
+    args: Vec<Regex>,
+    // 参数正则都只匹配选项时，`--` 之后的参数不可能命中
+    options_only: bool,
+}
+
+/// deny 规则的匹配结果
+enum DenyMatch {
+    No,
+    Yes(Option<String>), // 命中的参数
+    Unknown(String),     // 无法确定的参数
+}
+
+impl CompiledRule {
+    fn new(number: usize, rule: &PolicyRule) -> Result<Self, String> {
+        if rule.programs.is_empty() {
+            return Err(format!("规则 #{}: programs 不能为空", number));
+        }
+        if rule.subcommand_position == Some(0) {
+            return Err(format!("规则 #{}: subcommand_position 从 1 开始", number));
+        }
+        if let Some(prefix) = rule.path_prefixes.iter().find(|prefix| !Path::new(prefix).is_absolute()) {
+            return Err(format!("规则 #{}: 路径前缀必须是绝对路径: {}", number, prefix));
+        }
+        let args = rule.args.iter()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{})$", pattern))
+                    .map_err(|e| format!("规则 #{}: 参数正则 {} 无效: {}", number, pattern, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let options_only = !rule.args.is_empty()
+            && rule.args.iter().all(|pattern| pattern.starts_with('-') && !pattern.contains('|'));
+        Ok(Self { number, rule: rule.clone(), args, options_only })
+    }
+
+    fn applies_to(&self, program: &str) -> bool {
+        self.rule.programs.iter().any(|p| p == "*" || p == program)
+    }
+
+    /// 规则说明：有 reason 时用 reason，否则列出条件
+    fn summary(&self, program: &str) -> String {
+        self.rule.reason.clone().unwrap_or_else(|| self.describe(program))
+    }
+
+    fn describe(&self, program: &str) -> String {
+        let action = match self.rule.action {
+            RuleAction::Allow => "allow",
+            RuleAction::Deny => "deny",
+        };
+        let mut text = format!("{} {}", action, program);
+        if !self.rule.subcommands.is_empty() {
+            match self.rule.subcommand_position {
+                Some(position) => text.push_str(&format!(" 第 {} 个参数 {:?}", position, self.rule.subcommands)),
+                None => text.push_str(&format!(" 子命令 {:?}", self.rule.subcommands)),
+            }
+        }
+        if !self.rule.args.is_empty() {
+            text.push_str(&format!(" 参数 {:?}", self.rule.args));
+        }
+        if !self.rule.path_prefixes.is_empty() {
+            text.push_str(&format!(" 路径 {:?}", self.rule.path_prefixes));
+        }
+        text
+    }
+
+    fn subcommand_position(&self) -> usize {
+        self.rule.subcommand_position.unwrap_or(1)
+    }
+
+    fn matches_arg(&self, value: &str) -> bool {
+        self.args.iter().any(|regex| regex.is_match(value))
+    }
+
+    fn under_prefix(&self, path: &Path) -> bool {
+        self.rule.path_prefixes.iter().any(|prefix| path.starts_with(prefix))
+    }
+
+    /// deny 规则：路径位于前缀下，或是前缀的上级目录（递归读取时会读到前缀下的文件）
+    fn covers(&self, path: &Path) -> bool {
+        self.rule.path_prefixes.iter().any(|prefix| path.starts_with(prefix) || Path::new(prefix).starts_with(path))
+    }
+
+    fn deny_match(&self, args: &Arguments) -> DenyMatch {
+        let mut hit = None;
+        let mut unknown = None;
+
+        if !self.rule.subcommands.is_empty() {
+            match args.subcommand(self.subcommand_position()) {
+                None => return DenyMatch::No,
+                Some((raw, None)) => unknown = Some(raw.to_string()),
+                Some((_, Some(value))) if self.rule.subcommands.contains(value) => hit = Some(value.clone()),
+                Some(_) => return DenyMatch::No,
+            }
+        }
+
+        if !self.args.is_empty() {
+            if let Some(value) = args.values.iter().flatten().find(|value| self.matches_arg(value)) {
+                hit = Some(value.clone());
+            } else if let Some(raw) = args.unknown(self.options_only) {
+                unknown.get_or_insert(raw.to_string());
+            } else {
+                return DenyMatch::No;
+            }
+        }
+
+        if !self.rule.path_prefixes.is_empty() {
+            let paths = args.paths(None);
+            if let Some(path) = paths.iter().flat_map(|p| p.as_ref().ok()).find(|p| self.covers(p)) {
+                hit = Some(path.display().to_string());
+            } else if let Some(Err(raw)) = paths.iter().find(|p| p.is_err()) {
+                unknown.get_or_insert(raw.clone());
+            } else {
+                return DenyMatch::No;
+            }
+        }
+
+        match unknown {
+            Some(raw) => DenyMatch::Unknown(raw),
+            None => DenyMatch::Yes(hit),
+        }
+    }
+
+    /// allow 规则的全部条件是否满足，不满足时返回原因
+    fn allow_match(&self, args: &Arguments) -> Result<(), String> {
+        let subcommand = args.subcommand_index(self.subcommand_position());
+        if !self.rule.subcommands.is_empty() {
+            match args.subcommand(self.subcommand_position()) {
+                Some((_, Some(value))) if self.rule.subcommands.contains(value) => {}
+                Some((raw, _)) => return Err(format!("子命令 {} 不在 {:?} 中", raw, self.rule.subcommands)),
+                None => return Err(format!("缺少子命令 {:?}", self.rule.subcommands)),
+            }
+        }
+
+        if !self.args.is_empty() {
+            let skip = if self.rule.subcommands.is_empty() { None } else { subcommand };
+            for (index, value) in args.values.iter().enumerate() {
+                if Some(index) == skip {
+                    continue;
+                }
+                if !value.as_deref().is_some_and(|value| self.matches_arg(value)) {
+                    return Err(format!("参数 {} 不符合 {:?}", args.words[index].raw, self.rule.args));
+                }
+            }
+        }
+
+        if !self.rule.path_prefixes.is_empty() {
+            let skip = if self.rule.subcommands.is_empty() { None } else { subcommand };
+            for path in args.paths(skip) {
+                match path {
+                    Ok(path) if self.under_prefix(&path) => {}
+                    Ok(path) => {
+                        return Err(format!("路径 {} 不在 {:?} 下", path.display(), self.rule.path_prefixes));
+                    }
+                    Err(raw) => return Err(format!("无法确定路径 {}", raw)),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CompiledRules {
+    /// 依次检查 deny 规则，任一命中即拒绝；参数无法确定而可能命中时同样拒绝
+    pub(crate) fn check_deny(&self, program: &str, words: &[Word], cwd: WorkDir) -> Result<(), String> {
+        let args = Arguments::new(words, cwd);
+        for rule in self.rules.iter().filter(|r| r.rule.action == RuleAction::Deny && r.applies_to(program)) {
+            match rule.deny_match(&args) {
+                DenyMatch::No => {}
+                DenyMatch::Yes(hit) => {
+                    let hit = hit.map(|hit| format!("，参数 {}", hit)).unwrap_or_default();
+                    return Err(format!("包含危险模式: {}（规则 #{}{}）", rule.summary(program), rule.number, hit));
+                }
+                DenyMatch::Unknown(raw) => {
+                    return Err(format!(
+                        "无法确定参数 {} 是否命中禁止规则 #{}（{}）", raw, rule.number, rule.summary(program)
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 输入重定向读取的文件按 deny 规则的路径条件检查，路径的认定与参数相同；
+    /// program 为 None（复合命令的重定向，读取者不确定）时检查所有带路径条件的 deny 规则
+    pub(crate) fn check_input_redirect(&self, program: Option<&str>, target: &Word, cwd: WorkDir) -> Result<(), String> {
+        let name = program.unwrap_or("*");
+        let paths = Arguments::new(std::slice::from_ref(target), cwd).paths(None);
+        let rules = self.rules.iter().filter(|r| {
+            r.rule.action == RuleAction::Deny
+                && !r.rule.path_prefixes.is_empty()
+                && program.is_none_or(|program| r.applies_to(program))
+        });
+        for rule in rules {
+            for path in &paths {
+                match path {
+                    Ok(path) if rule.covers(path) => {
+                        return Err(format!(
+                            "包含危险模式: {}（规则 #{}，输入重定向 {}）", rule.summary(name), rule.number, path.display()
+                        ));
+                    }
+                    Ok(_) => {}
+                    Err(raw) => {
+                        return Err(format!(
+                            "无法确定输入重定向 {} 是否命中禁止规则 #{}（{}）", raw, rule.number, rule.summary(name)
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 查找匹配的 allow 规则，返回匹配说明
+    pub(crate) fn check_allow(&self, program: &str, words: &[Word], cwd: WorkDir) -> Result<String, String> {
+        let args = Arguments::new(words, cwd);
+        let mut mismatches = Vec::new();
+        for rule in self.rules.iter().filter(|r| r.rule.action == RuleAction::Allow && r.applies_to(program)) {
+            match rule.allow_match(&args) {
+                Ok(()) => return Ok(format!("匹配规则 #{}: {}", rule.number, rule.describe(program))),
+                Err(reason) => mismatches.push(format!("规则 #{}: {}", rule.number, reason)),
+            }
+        }
+        if mismatches.is_empty() {
+            Err(format!("命令不在允许列表中: {}", program))
+        } else {
+            Err(format!("命令不符合允许规则: {}（{}）", program, mismatches.join("；")))
+        }
+    }
+}
+
+/// 一条命令的参数（不含命令名）
+struct Arguments<'a> {
+    words: &'a [Word],
+    values: Vec<Option<String>>, // 字面值，含展开时为 None
+    cwd: WorkDir<'a>,
+}
+
+impl<'a> Arguments<'a> {
+    fn new(words: &'a [Word], cwd: WorkDir<'a>) -> Self {
+        Self { words, values: words.iter().map(Word::literal).collect(), cwd }
+    }
+
+    /// 第 position 个（从 1 开始）非选项参数的位置
+    fn subcommand_index(&self, position: usize) -> Option<usize> {
+        self.values.iter().enumerate()
+            .filter(|(_, value)| !value.as_deref().is_some_and(|v| v.starts_with('-')))
+            .nth(position - 1)
+            .map(|(index, _)| index)
+    }
+
+    fn subcommand(&self, position: usize) -> Option<(&str, Option<&String>)> {
+        self.subcommand_index(position).map(|index| (self.words[index].raw.as_str(), self.values[index].as_ref()))
+    }
+
+    /// 第一个无法确定的参数；options_only 时 `--` 之后的参数不会被当作选项，跳过
+    fn unknown(&self, options_only: bool) -> Option<&str> {
+        let options_end = self.values.iter().position(|v| v.as_deref() == Some("--")).unwrap_or(self.values.len());
+        self.values.iter().enumerate()
+            .find(|(index, value)| value.is_none() && (!options_only || *index < options_end))
+            .map(|(index, _)| self.words[index].raw.as_str())
+    }
+
+    /// 路径参数：含 / 的参数和 `--opt=/path` 的值；执行过 cd 后所有非选项参数都按相对路径处理。
+    /// 无法确定的参数和当前目录未知的相对路径返回 Err(原文)
+    fn paths(&self, skip: Option<usize>) -> Vec<Result<PathBuf, String>> {
+        let mut paths = Vec::new();
+        for (index, value) in self.values.iter().enumerate() {
+            if Some(index) == skip {
+                continue;
+            }
+            let raw = &self.words[index].raw;
+            let Some(value) = value else {
+                paths.push(Err(raw.clone()));
+                continue;
+            };
+            let candidate = if value.starts_with('-') {
+                match value.split_once('=') {
+                    Some((_, path)) if path.contains('/') => path,
+                    _ => continue,
+                }
+            } else if value.contains('/') || self.cwd != WorkDir::Initial {
+                value.as_str()
+            } else {
+                continue;
+            };
+            paths.push(resolve(candidate, self.cwd).ok_or_else(|| raw.clone()));
+        }
+        paths
+    }
+}
+
+/// 把路径解析为规范的绝对路径，当前目录未知的相对路径返回 None
+fn resolve(value: &str, cwd: WorkDir) -> Option<PathBuf> {
+    let path = Path::new(value);
+    if path.is_absolute() {
+        normalize(path)
+    } else if let WorkDir::Known(cwd) = cwd {
+        normalize(&cwd.join(path))
+    } else {
+        None
+    }
+}
+
+/// 按字面处理 `.` 和 `..`；`/proc/<pid>/root` 按根目录处理，`/proc/<pid>/cwd`、`/proc/<pid>/fd/<n>`
+/// 指向的位置无法确定，返回 None
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+        match proc_link(&normalized) {
+            Some(ProcLink::Root) => normalized = PathBuf::from("/"),
+            Some(ProcLink::Unresolved) => return None,
+            None => {}
+        }
+    }
+    Some(normalized)
+}
+
+/// /proc 下指向其他位置的进程链接
+enum ProcLink {
+    Root,
+    Unresolved,
+}
+
+fn proc_link(path: &Path) -> Option<ProcLink> {
+    let parts: Vec<&str> = path.iter().skip(1).map(|part| part.to_str().unwrap_or("")).collect();
+    let rest = match parts.as_slice() {
+        ["proc", _, "task", _, rest @ ..] => rest,
+        ["proc", _, rest @ ..] => rest,
+        _ => return None,
+    };
+    match rest {
+        ["root"] => Some(ProcLink::Root),
+        ["cwd"] | ["fd", _] => Some(ProcLink::Unresolved),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell;
+
+    fn words(command: &str) -> Vec<Word> {
+        let list = shell::parse(command).unwrap();
+        match &list[0].first.commands[0] {
+            shell::Command::Simple(simple) => simple.words[1..].to_vec(),
+            other => panic!("expected simple command, got {:?}", other),
+        }
+    }
+
+    fn check(rules: &CompiledRules, program: &str, command: &str) -> Result<String, String> {
+        let words = words(command);
+        rules.check_deny(program, &words, WorkDir::Initial)?;
+        rules.check_allow(program, &words, WorkDir::Initial)
+    }
+
+    #[test]
+    fn test_builtin_policy_loads() {
+        let policy = CommandPolicy::builtin();
+        assert_eq!(policy.max_command_length, 1000);
+        assert!(policy.scripts.allowed_extensions.contains(&"sh".to_string()));
+        assert!(policy.rules.iter().any(|rule| rule.action == RuleAction::Deny));
+        assert!(!policy.scripts.allowed_dirs.iter().any(|dir| dir.starts_with("/tmp")));
+    }
+
+    #[test]
+    fn test_version_follows_content() {
+        let policy = CommandPolicy::builtin();
+        assert_eq!(policy.version().len(), 12);
+        assert_eq!(policy.version(), CommandPolicy::builtin().version());
+
+        let mut changed = policy.clone();
+        changed.scripts.allowed_dirs.push("/srv/scripts".to_string());
+        assert_ne!(changed.version(), policy.version());
+    }
+
+    #[test]
+    fn test_deny_overrides_allow() {
+        let policy = CommandPolicy::from_toml(r#"
+            [[rules]]
+            action = "allow"
+            programs = ["systemctl"]
+
+            [[rules]]
+            action = "deny"
+            programs = ["systemctl"]
+            subcommands = ["stop"]
+            reason = "停止服务"
+        "#).unwrap();
+        let rules = policy.compile().unwrap();
+
+        assert!(check(&rules, "systemctl", "systemctl status nginx").is_ok());
+        let reason = check(&rules, "systemctl", "systemctl --no-pager stop nginx").unwrap_err();
+        assert!(reason.contains("停止服务") && reason.contains("#2"), "{}", reason);
+        assert!(check(&rules, "systemctl", "systemctl $(echo stop) nginx").unwrap_err().contains("无法确定参数"));
+    }
+
+    #[test]
+    fn test_subcommand_position() {
+        let policy = CommandPolicy::from_toml(r#"
+            [[rules]]
+            action = "allow"
+            programs = ["service"]
+            subcommands = ["status"]
+            subcommand_position = 2
+            args = ["[a-z]+"]
+        "#).unwrap();
+        let rules = policy.compile().unwrap();
+
+        assert!(check(&rules, "service", "service nginx status").is_ok());
+        assert!(check(&rules, "service", "service nginx force-reload").unwrap_err().contains("force-reload"));
+        assert!(check(&rules, "service", "service nginx").unwrap_err().contains("缺少子命令"));
+        assert!(check(&rules, "service", "service status nginx").is_err());
+        assert!(CommandPolicy::from_toml("[[rules]]\naction = \"allow\"\nprograms = [\"service\"]\nsubcommand_position = 0").is_err());
+    }
+
+    #[test]
+    fn test_subcommand_argument_and_path_rules() {
+        let policy = CommandPolicy::from_toml(r#"
+            [[rules]]
+            action = "allow"
+            programs = ["journalctl"]
+            args = ["-u", "[a-z-]+\\.service", "-n", "[0-9]+"]
+
+            [[rules]]
+            action = "allow"
+            programs = ["tail"]
+            path_prefixes = ["/var/log"]
+
+            [[rules]]
+            action = "deny"
+            programs = ["tail"]
+            path_prefixes = ["/var/log/secure"]
+        "#).unwrap();
+        let rules = policy.compile().unwrap();
+
+        assert!(check(&rules, "journalctl", "journalctl -u nginx.service -n 50").is_ok());
+        assert!(check(&rules, "journalctl", "journalctl -u nginx.service -f").unwrap_err().contains("-f"));
+
+        assert!(check(&rules, "tail", "tail -n 20 /var/log/syslog").is_ok());
+        assert!(check(&rules, "tail", "tail /etc/passwd").unwrap_err().contains("不在"));
+        assert!(check(&rules, "tail", "tail /var/log/../../etc/passwd").is_err());
+        assert!(check(&rules, "tail", "tail /var/log/secure").unwrap_err().contains("危险模式"));
+        assert!(check(&rules, "tail", "tail $LOG").unwrap_err().contains("无法确定"));
+        assert!(check(&rules, "ls", "ls").unwrap_err().contains("不在允许列表中"));
+    }
+
+    #[test]
+    fn test_path_deny_covers_parent_dirs_and_proc_links() {
+        let policy = CommandPolicy::from_toml(r#"
+            [[rules]]
+            action = "allow"
+            programs = ["cat", "grep"]
+
+            [[rules]]
+            action = "deny"
+            programs = ["cat", "grep"]
+            path_prefixes = ["/etc/shadow", "/root/.ssh"]
+        "#).unwrap();
+        let rules = policy.compile().unwrap();
+
+        assert!(check(&rules, "cat", "cat /etc/passwd").is_ok());
+        assert!(check(&rules, "grep", "grep -r PRIVATE /var/log").is_ok());
+        // 前缀的上级目录同样命中
+        assert!(check(&rules, "grep", "grep -r PRIVATE /root").unwrap_err().contains("危险模式"));
+        assert!(check(&rules, "grep", "grep -r PRIVATE /").unwrap_err().contains("危险模式"));
+        // /proc/<pid>/root 按根目录处理，cwd 和 fd 无法确定
+        assert!(check(&rules, "cat", "cat /proc/self/root/etc/shadow").unwrap_err().contains("危险模式"));
+        assert!(check(&rules, "cat", "cat /proc/1/task/1/root/../etc/shadow").unwrap_err().contains("危险模式"));
+        assert!(check(&rules, "cat", "cat /proc/self/cwd/.ssh/id_rsa").unwrap_err().contains("无法确定"));
+        assert!(check(&rules, "cat", "cat /proc/self/fd/3").unwrap_err().contains("无法确定"));
+        assert!(check(&rules, "cat", "cat /proc/self/status").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_policy_rejected() {
+        assert!(CommandPolicy::from_toml("[[rules]]\naction = \"allow\"\nprograms = []").is_err());
+        assert!(CommandPolicy::from_toml("[[rules]]\naction = \"deny\"\nprograms = [\"ls\"]\nargs = [\"(\"]").is_err());
+        assert!(CommandPolicy::from_toml("[[rules]]\naction = \"deny\"\nprograms = [\"ls\"]\npath_prefixes = [\"var\"]").is_err());
+        assert!(CommandPolicy::from_toml("[[rules]]\naction = \"maybe\"\nprograms = [\"ls\"]").is_err());
+    }
+}
@@ -1,9 +1,59 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
+use crate::codec::DEFAULT_MAX_FRAME_SIZE;
+use crate::command_policy::{CommandPolicy, ScriptPolicy};
+
+/// 客户端默认的应用目录；服务端默认允许执行其中的脚本，Web 界面的应用管理才能直接使用
+pub const DEFAULT_APPS_BASE_DIR: &str = "/opt/apps";
+
+/// 读取 OPS_MAX_FRAME_SIZE，客户端与服务端共用
+fn max_frame_size_from_env() -> usize {
+    env::var("OPS_MAX_FRAME_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_FRAME_SIZE)
+}
+
+/// 读取逗号分隔的列表型环境变量，未设置时为空
+fn list_from_env(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// 解析客户端分组，格式为 `web=id1,id2;db=id3`
+pub fn parse_client_groups(value: &str) -> HashMap<String, Vec<String>> {
+    value
+        .split(';')
+        .filter_map(|group| {
+            let (name, members) = group.split_once('=')?;
+            let members = members
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect();
+            Some((name.trim().to_string(), members))
+        })
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// 读取布尔型环境变量，接受 true / 1
+fn flag_from_env(name: &str) -> bool {
+    env::var(name)
+        .map(|v| v.to_lowercase() == "true" || v == "1")
+        .unwrap_or(false)
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ServerConfig {
     pub tcp_bind_addr: String,
     pub http_bind_addr: String,
@@ -13,8 +63,30 @@ pub struct ServerConfig {
     pub client_timeout_secs: u64,
     pub max_connections: usize,
     pub auth_token: Option<String>,
-    pub allowed_script_dirs: Vec<String>, // 允许执行脚本的目录
-    pub allowed_script_extensions: Vec<String>, // 允许的脚本扩展名
+    pub allowed_script_dirs: Vec<String>, // 允许执行脚本的目录，写入下发给客户端的策略
+    pub allowed_script_extensions: Vec<String>, // 允许的脚本扩展名，写入下发给客户端的策略
+    pub command_policy_file: Option<String>, // 下发给客户端的命令校验策略（TOML），未设置时使用内置策略
+    pub max_frame_size: usize, // TCP 单帧最大字节数
+    pub tls_enabled: bool, // TCP 通道是否启用 TLS
+    pub tls_cert_file: Option<String>, // 服务端证书（PEM）
+    pub tls_key_file: Option<String>, // 服务端私钥（PEM）
+    pub tls_client_ca_file: Option<String>, // 设置后启用 mTLS，客户端证书必须由该 CA 签发且身份与 client_id 一致
+    pub resume_token_ttl_secs: u64, // 连接断开后续连令牌的有效期
+    pub terminal_users: Vec<String>, // 允许打开 Web 终端的登录用户，为空时禁用终端
+    pub audit_log_file: String, // 审计日志（终端会话等），JSON Lines 格式
+    pub client_groups: HashMap<String, Vec<String>>, // 客户端分组：组名 -> client_id 列表
+    pub max_push_file_size: usize, // 推送文件的大小上限
+    pub max_fetch_file_size: u64, // 从客户端读取文件的大小上限
+    pub tcp_auth_enabled: bool, // TCP 连接是否需要质询-响应认证
+    pub tcp_auth_secret: Option<String>, // 旧版共享密钥，只用于尚未注册凭据的客户端
+    pub credentials_file: String, // 客户端凭据和注册令牌的存储文件
+    pub require_client_approval: bool, // 新客户端需要管理员批准后才能加入
+    pub approvals_file: String, // 客户端审批结果的存储文件
+    pub command_signing_key_file: Option<String>, // 命令签名私钥（Ed25519，PKCS#8 PEM），设置后对下发的命令签名
+    pub command_signature_ttl_secs: u64, // 命令签名的有效期
+    pub auth_max_failures: u32, // 同一 IP 在窗口内认证失败达到该次数后被临时封禁，0 表示不封禁
+    pub auth_failure_window_secs: u64, // 认证失败的计数窗口
+    pub auth_ban_secs: u64, // 封禁时长
 }
 
 impl Default for ServerConfig {
@@ -32,6 +104,7 @@ impl Default for ServerConfig {
                 "/opt/ops-scripts".to_string(),
                 "/usr/local/bin/scripts".to_string(),
                 "/home/ops/scripts".to_string(),
+                DEFAULT_APPS_BASE_DIR.to_string(),
             ],
             allowed_script_extensions: vec![
                 "sh".to_string(),
@@ -39,6 +112,28 @@ impl Default for ServerConfig {
                 "pl".to_string(),
                 "rb".to_string(),
             ],
+            command_policy_file: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            tls_enabled: false,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_client_ca_file: None,
+            resume_token_ttl_secs: 60,
+            terminal_users: Vec::new(),
+            audit_log_file: "audit.log".to_string(),
+            client_groups: HashMap::new(),
+            max_push_file_size: 64 * 1024 * 1024,
+            max_fetch_file_size: 64 * 1024 * 1024,
+            tcp_auth_enabled: false,
+            tcp_auth_secret: None,
+            credentials_file: "credentials.json".to_string(),
+            require_client_approval: false,
+            approvals_file: "approvals.json".to_string(),
+            command_signing_key_file: None,
+            command_signature_ttl_secs: 300,
+            auth_max_failures: 5,
+            auth_failure_window_secs: 300,
+            auth_ban_secs: 900,
         }
     }
 }
@@ -70,7 +165,7 @@ impl ServerConfig {
                 .unwrap_or(1000),
             auth_token: env::var("OPS_AUTH_TOKEN").ok(),
             allowed_script_dirs: env::var("OPS_ALLOWED_SCRIPT_DIRS")
-                .unwrap_or_else(|_| "/opt/ops-scripts,/usr/local/bin/scripts,/home/ops/scripts".to_string())
+                .unwrap_or_else(|_| format!("/opt/ops-scripts,/usr/local/bin/scripts,/home/ops/scripts,{}", DEFAULT_APPS_BASE_DIR))
                 .split(',')
                 .map(|s| s.trim().to_string())
                 .collect(),
@@ -79,6 +174,52 @@ impl ServerConfig {
                 .split(',')
                 .map(|s| s.trim().to_string())
                 .collect(),
+            command_policy_file: env::var("OPS_COMMAND_POLICY_FILE").ok(),
+            max_frame_size: max_frame_size_from_env(),
+            tls_enabled: flag_from_env("OPS_TLS_ENABLED"),
+            tls_cert_file: env::var("OPS_TLS_CERT_FILE").ok(),
+            tls_key_file: env::var("OPS_TLS_KEY_FILE").ok(),
+            tls_client_ca_file: env::var("OPS_TLS_CLIENT_CA_FILE").ok(),
+            resume_token_ttl_secs: env::var("OPS_RESUME_TOKEN_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(60),
+            terminal_users: list_from_env("OPS_TERMINAL_USERS"),
+            audit_log_file: env::var("OPS_AUDIT_LOG_FILE")
+                .unwrap_or_else(|_| "audit.log".to_string()),
+            client_groups: parse_client_groups(&env::var("OPS_CLIENT_GROUPS").unwrap_or_default()),
+            max_push_file_size: env::var("OPS_MAX_PUSH_FILE_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(64 * 1024 * 1024),
+            max_fetch_file_size: env::var("OPS_MAX_FETCH_FILE_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(64 * 1024 * 1024),
+            tcp_auth_enabled: flag_from_env("OPS_TCP_AUTH_ENABLED"),
+            tcp_auth_secret: env::var("OPS_TCP_AUTH_SECRET").ok(),
+            credentials_file: env::var("OPS_CREDENTIALS_FILE")
+                .unwrap_or_else(|_| "credentials.json".to_string()),
+            require_client_approval: flag_from_env("OPS_REQUIRE_CLIENT_APPROVAL"),
+            approvals_file: env::var("OPS_APPROVALS_FILE")
+                .unwrap_or_else(|_| "approvals.json".to_string()),
+            command_signing_key_file: env::var("OPS_COMMAND_SIGNING_KEY_FILE").ok(),
+            command_signature_ttl_secs: env::var("OPS_COMMAND_SIGNATURE_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(300),
+            auth_max_failures: env::var("OPS_AUTH_MAX_FAILURES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5),
+            auth_failure_window_secs: env::var("OPS_AUTH_FAILURE_WINDOW_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(300),
+            auth_ban_secs: env::var("OPS_AUTH_BAN_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(900),
         }
     }
 
@@ -88,6 +229,19 @@ impl ServerConfig {
         Ok(config)
     }
 
+    /// 下发给客户端的策略：策略文件或内置策略，脚本目录和扩展名以 allowed_script_dirs / allowed_script_extensions 为准
+    pub fn command_policy(&self) -> Result<CommandPolicy, String> {
+        let mut policy = match &self.command_policy_file {
+            Some(path) => CommandPolicy::load(path)?,
+            None => CommandPolicy::builtin(),
+        };
+        policy.scripts = ScriptPolicy {
+            allowed_dirs: self.allowed_script_dirs.clone(),
+            allowed_extensions: self.allowed_script_extensions.clone(),
+        };
+        Ok(policy)
+    }
+
     pub fn tcp_address(&self) -> String {
         format!("{}:{}", self.tcp_bind_addr, self.tcp_port)
     }
@@ -98,6 +252,7 @@ impl ServerConfig {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ClientConfig {
     pub server_host: String,
     pub server_port: u16,
@@ -109,6 +264,29 @@ pub struct ClientConfig {
     pub apps_base_dir: String,
     pub command_log_file: String,
     pub auth_token: Option<String>,
+    pub max_frame_size: usize, // TCP 单帧最大字节数
+    pub tls_enabled: bool, // 是否通过 TLS 连接服务端
+    pub tls_ca_file: Option<String>, // 用于校验服务端证书的 CA（PEM）
+    pub tls_server_name: Option<String>, // 校验证书时使用的服务端名称，默认取 server_host
+    pub tls_cert_file: Option<String>, // 客户端证书（PEM），服务端启用 mTLS 时需要
+    pub tls_key_file: Option<String>, // 客户端私钥（PEM）
+    pub state_dir: String, // 客户端状态目录（命令结果发件箱等）
+    pub outbox_max_entries: usize, // 发件箱最多保存的未确认结果数
+    pub cancel_grace_secs: u64, // 取消命令时 SIGTERM 之后等待多久再 SIGKILL
+    pub command_timeout_secs: u64, // 服务端未指定超时时命令的默认执行时限
+    pub max_command_timeout_secs: u64, // 服务端可以指定的最大执行时限
+    pub max_concurrent_commands: usize, // 同时执行的命令数上限，其余命令排队等待
+    pub terminal_enabled: bool, // 是否允许服务端打开交互式终端
+    pub terminal_shell: String, // 终端使用的 shell
+    pub file_push_dirs: Vec<String>, // 允许服务端推送文件的目录，为空时禁用文件推送
+    pub max_push_file_size: u64, // 接收推送文件的大小上限
+    pub file_fetch_dirs: Vec<String>, // 允许服务端读取文件的目录，为空时禁用文件读取
+    pub max_fetch_file_size: u64, // 单次回传文件内容的大小上限
+    pub tcp_auth_enabled: bool, // 服务端是否要求 TCP 认证
+    pub tcp_auth_secret: Option<String>, // 旧版共享密钥，state_dir 中没有凭据时使用
+    pub enrollment_token: Option<String>, // 一次性注册令牌，首次连接时换取本机专用密钥
+    pub command_signing_public_key: Option<String>, // 服务端命令签名公钥（base64），设置后只执行签名有效的命令
+    pub command_policy_file: Option<String>, // 命令校验策略（TOML），未设置时使用内置策略
 }
 
 impl Default for ClientConfig {
@@ -121,9 +299,32 @@ impl Default for ClientConfig {
             retry_base_delay_secs: 2,
             retry_max_delay_secs: 60,
             client_id_file: "/tmp/client_id.txt".to_string(),
-            apps_base_dir: "/tmp/apps".to_string(),
+            apps_base_dir: DEFAULT_APPS_BASE_DIR.to_string(),
             command_log_file: "/tmp/client_commands.log".to_string(),
             auth_token: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            tls_enabled: false,
+            tls_ca_file: None,
+            tls_server_name: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            state_dir: "/tmp/ops-client".to_string(),
+            outbox_max_entries: 1000,
+            cancel_grace_secs: 5,
+            command_timeout_secs: 300,
+            max_command_timeout_secs: 3600,
+            max_concurrent_commands: 4,
+            terminal_enabled: false,
+            terminal_shell: "/bin/sh".to_string(),
+            file_push_dirs: Vec::new(),
+            max_push_file_size: 64 * 1024 * 1024,
+            file_fetch_dirs: Vec::new(),
+            max_fetch_file_size: 16 * 1024 * 1024,
+            tcp_auth_enabled: false,
+            tcp_auth_secret: None,
+            enrollment_token: None,
+            command_signing_public_key: None,
+            command_policy_file: None,
         }
     }
 }
@@ -155,13 +356,68 @@ impl ClientConfig {
             client_id_file: env::var("OPS_CLIENT_ID_FILE")
                 .unwrap_or_else(|_| "/tmp/client_id.txt".to_string()),
             apps_base_dir: env::var("OPS_APPS_BASE_DIR")
-                .unwrap_or_else(|_| "/tmp/apps".to_string()),
+                .unwrap_or_else(|_| DEFAULT_APPS_BASE_DIR.to_string()),
             command_log_file: env::var("OPS_COMMAND_LOG_FILE")
                 .unwrap_or_else(|_| "/tmp/client_commands.log".to_string()),
             auth_token: env::var("OPS_AUTH_TOKEN").ok(),
+            max_frame_size: max_frame_size_from_env(),
+            tls_enabled: flag_from_env("OPS_TLS_ENABLED"),
+            tls_ca_file: env::var("OPS_TLS_CA_FILE").ok(),
+            tls_server_name: env::var("OPS_TLS_SERVER_NAME").ok(),
+            tls_cert_file: env::var("OPS_TLS_CERT_FILE").ok(),
+            tls_key_file: env::var("OPS_TLS_KEY_FILE").ok(),
+            state_dir: env::var("OPS_STATE_DIR")
+                .unwrap_or_else(|_| "/tmp/ops-client".to_string()),
+            outbox_max_entries: env::var("OPS_OUTBOX_MAX_ENTRIES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1000),
+            cancel_grace_secs: env::var("OPS_CANCEL_GRACE_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(5),
+            command_timeout_secs: env::var("OPS_COMMAND_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(300),
+            max_command_timeout_secs: env::var("OPS_MAX_COMMAND_TIMEOUT_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(3600),
+            max_concurrent_commands: env::var("OPS_MAX_CONCURRENT_COMMANDS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(4),
+            terminal_enabled: flag_from_env("OPS_TERMINAL_ENABLED"),
+            terminal_shell: env::var("OPS_TERMINAL_SHELL")
+                .unwrap_or_else(|_| "/bin/sh".to_string()),
+            file_push_dirs: list_from_env("OPS_FILE_PUSH_DIRS"),
+            max_push_file_size: env::var("OPS_MAX_PUSH_FILE_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(64 * 1024 * 1024),
+            file_fetch_dirs: list_from_env("OPS_FILE_FETCH_DIRS"),
+            max_fetch_file_size: env::var("OPS_MAX_FETCH_FILE_SIZE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(16 * 1024 * 1024),
+            tcp_auth_enabled: flag_from_env("OPS_TCP_AUTH_ENABLED"),
+            tcp_auth_secret: env::var("OPS_TCP_AUTH_SECRET").ok(),
+            enrollment_token: env::var("OPS_ENROLLMENT_TOKEN").ok(),
+            command_signing_public_key: env::var("OPS_COMMAND_SIGNING_PUBLIC_KEY").ok(),
+            command_policy_file: env::var("OPS_COMMAND_POLICY_FILE").ok(),
         }
     }
 
+    /// 命令实际使用的执行时限：服务端指定值或默认值，不超过上限，至少 1 秒
+    pub fn command_timeout(&self, requested_secs: Option<u64>) -> Duration {
+        let secs = requested_secs
+            .unwrap_or(self.command_timeout_secs)
+            .min(self.max_command_timeout_secs)
+            .max(1);
+        Duration::from_secs(secs)
+    }
+
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
         let content = fs::read_to_string(path)?;
         let config: Self = toml::from_str(&content)?;
@@ -183,20 +439,68 @@ mod tests {
         let config = ServerConfig::default();
         assert_eq!(config.tcp_port, 12345);
         assert_eq!(config.http_port, 3000);
+        assert_eq!(config.max_frame_size, DEFAULT_MAX_FRAME_SIZE);
+    }
+
+    #[test]
+    fn test_partial_config_file_uses_defaults() {
+        let config: ClientConfig = toml::from_str("server_host = \"10.0.0.1\"").unwrap();
+        assert_eq!(config.server_host, "10.0.0.1");
+        assert_eq!(config.max_frame_size, DEFAULT_MAX_FRAME_SIZE);
     }
 
     #[test]
     fn test_client_config_from_env() {
-        env::set_var("OPS_SERVER_HOST", "test-server");
-        env::set_var("OPS_SERVER_PORT", "9999");
+        // SAFETY: 测试中修改环境变量，仅影响本测试读取的配置项
+        unsafe {
+            env::set_var("OPS_SERVER_HOST", "test-server");
+            env::set_var("OPS_SERVER_PORT", "9999");
+        }
         
         let config = ClientConfig::from_env();
         assert_eq!(config.server_host, "test-server");
         assert_eq!(config.server_port, 9999);
         
         // 清理环境变量
-        env::remove_var("OPS_SERVER_HOST");
-        env::remove_var("OPS_SERVER_PORT");
+        unsafe {
+            env::remove_var("OPS_SERVER_HOST");
+            env::remove_var("OPS_SERVER_PORT");
+        }
+    }
+
+    #[test]
+    fn test_command_timeout_clamped_to_maximum() {
+        let config = ClientConfig {
+            command_timeout_secs: 60,
+            max_command_timeout_secs: 600,
+            ..Default::default()
+        };
+        assert_eq!(config.command_timeout(None), Duration::from_secs(60));
+        assert_eq!(config.command_timeout(Some(120)), Duration::from_secs(120));
+        assert_eq!(config.command_timeout(Some(86400)), Duration::from_secs(600));
+        assert_eq!(config.command_timeout(Some(0)), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_parse_client_groups() {
+        let groups = parse_client_groups("web = a, b; db=c;;bad");
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups["web"], vec!["a", "b"]);
+        assert_eq!(groups["db"], vec!["c"]);
+        assert!(parse_client_groups("").is_empty());
+    }
+
+    #[test]
+    fn test_server_command_policy_uses_script_settings() {
+        let config = ServerConfig {
+            allowed_script_dirs: vec!["/srv/scripts".to_string()],
+            allowed_script_extensions: vec!["sh".to_string()],
+            ..Default::default()
+        };
+        let policy = config.command_policy().unwrap();
+        assert_eq!(policy.scripts.allowed_dirs, vec!["/srv/scripts"]);
+        assert_eq!(policy.scripts.allowed_extensions, vec!["sh"]);
+        assert_eq!(policy.rules, CommandPolicy::builtin().rules);
     }
 
     #[test]
@@ -0,0 +1,44 @@
+// 文件传输的公共部分：分块大小和 SHA-256 校验
+
+use sha2::{Digest, Sha256};
+
+/// 每个文件分块的原始字节数，base64 编码后仍远小于帧上限
+pub const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// 增量计算 SHA-256，结果为小写十六进制
+#[derive(Default)]
+pub struct Checksum(Sha256);
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> String {
+        hex::encode(self.0.finalize())
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    let mut checksum = Checksum::new();
+    checksum.update(data);
+    checksum.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incremental_checksum_matches_one_shot() {
+        let mut checksum = Checksum::new();
+        checksum.update(b"hello ");
+        checksum.update(b"world");
+        assert_eq!(checksum.finish(), sha256_hex(b"hello world"));
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+}
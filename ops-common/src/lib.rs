@@ -1,8 +1,15 @@
 // ops-common/src/lib.rs
 
+pub mod codec;
+pub mod command_policy;
+pub mod command_signing;
 pub mod config;
+pub mod file_transfer;
+pub mod protocol;
 pub mod security;
+pub mod shell;
 pub mod tcp_auth;
+pub mod tls;
 
 use serde::{ Deserialize, Serialize };
 use std::time::SystemTime;
@@ -30,10 +37,7 @@ impl HostInfo {
             .unwrap_or_else(|_| "unknown".to_string());
 
         // let cpu = sys.cpus().first().cloned().unwrap_or_default();
-        let cpu = sys
-            .cpus()
-            .first()
-            .map(|cpu| cpu);
+        let cpu = sys.cpus().first();
         let cpu_model = cpu
             .as_ref()
             .map(|c| c.brand().to_string())
@@ -57,6 +61,12 @@ impl HostInfo {
     }
 }
 
+impl Default for HostInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VersionInfo {
     pub app: String,
@@ -80,6 +90,21 @@ pub enum ServiceStatus {
     Unknown,
 }
 
+/// 客户端命令执行队列的状态，随心跳上报
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CommandQueueStatus {
+    pub running: usize, // 正在执行的命令数
+    pub queued: usize, // 等待空闲执行槽的命令数
+    pub max_concurrent: usize, // 最大并发执行数
+}
+
+/// 客户端执行的命令校验策略，随心跳上报
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PolicyStatus {
+    pub version: Option<String>, // 服务端下发的策略版本，尚未收到时为空（使用本地策略或内置策略）
+    pub local_version: Option<String>, // 本地策略文件的版本，未配置时为空
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientInfo {
     pub client_id: String,
@@ -87,6 +112,13 @@ pub struct ClientInfo {
     pub version_info: Vec<VersionInfo>,
     pub app_info: Vec<AppInfo>,
     pub last_seen: SystemTime,
+    #[serde(default)]
+    pub command_queue: CommandQueueStatus,
+    #[serde(default)]
+    pub command_policy: PolicyStatus,
+    /// 客户端的应用目录（apps_base_dir），服务端据此生成应用管理命令
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub apps_base_dir: Option<String>,
 }
 
 
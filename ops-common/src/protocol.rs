@@ -0,0 +1,674 @@
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use crate::ClientInfo;
+use crate::command_policy::CommandPolicy;
+use crate::command_signing::CommandSignature;
+use crate::tcp_auth::TcpAuthMessage;
+
+/// 当前线路协议版本，写入每个帧的 `v` 字段
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// 仍然兼容的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// 连接双方可协商的可选功能
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// 命令输出分块实时回传
+    StreamingOutput,
+    /// 帧负载压缩
+    Compression,
+    /// 文件传输
+    FileTransfer,
+    /// 从客户端读取文件
+    FileFetch,
+    /// 跟踪应用日志
+    LogTail,
+    /// 基于 PTY 的交互式终端
+    Terminal,
+    /// 在线轮换客户端密钥
+    CredentialRotation,
+    /// 服务端下发命令校验策略
+    CommandPolicy,
+    /// 对端声明了本端不认识的功能，协商时忽略
+    #[serde(untagged)]
+    Unknown(String),
+}
+
+/// 本端实现的功能集合
+pub fn supported_capabilities() -> Vec<Capability> {
+    vec![Capability::StreamingOutput, Capability::FileTransfer, Capability::FileFetch, Capability::LogTail, Capability::Terminal, Capability::CredentialRotation, Capability::CommandPolicy]
+}
+
+/// 客户端重连时携带的续连令牌
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ResumeRequest {
+    pub client_id: String,
+    pub token: String,
+}
+
+/// 连接建立后双方首先交换的握手消息
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub capabilities: Vec<Capability>,
+    pub agent_version: String,
+    /// 请求恢复之前的会话，成功时跳过认证质询
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<ResumeRequest>,
+}
+
+impl Hello {
+    /// 按本端编译时的协议版本和功能构建握手消息
+    pub fn local() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            capabilities: supported_capabilities(),
+            agent_version: env!("CARGO_PKG_VERSION").to_string(),
+            resume: None,
+        }
+    }
+
+    /// 与对端协商：取双方都支持的最高版本和功能交集，版本区间不相交时返回拒绝原因
+    pub fn negotiate(&self, remote: &Hello) -> Result<HelloAck, String> {
+        let protocol_version = self.protocol_version.min(remote.protocol_version);
+        let required = self.min_protocol_version.max(remote.min_protocol_version);
+
+        if protocol_version < required {
+            return Err(format!(
+                "协议版本不兼容: 本端支持 {}-{}，对端支持 {}-{}",
+                self.min_protocol_version, self.protocol_version,
+                remote.min_protocol_version, remote.protocol_version
+            ));
+        }
+
+        let capabilities = self.capabilities
+            .iter()
+            .filter(|cap| !matches!(cap, Capability::Unknown(_)) && remote.capabilities.contains(cap))
+            .cloned()
+            .collect();
+
+        Ok(HelloAck { protocol_version, capabilities, resumed: false })
+    }
+}
+
+/// 服务端对握手的应答，携带协商结果
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HelloAck {
+    pub protocol_version: u32,
+    pub capabilities: Vec<Capability>,
+    /// 是否已凭续连令牌恢复会话
+    #[serde(default)]
+    pub resumed: bool,
+}
+
+impl HelloAck {
+    pub fn supports(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+/// 客户端返回的命令执行结果
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandResponse {
+    pub command_id: String,
+    pub client_id: String,
+    pub command: String,
+    pub output: String,
+    pub error_output: String,
+    pub exit_code: i32,
+    pub executed_at: SystemTime,
+    /// 命令被服务端取消，输出只包含取消前产生的部分
+    #[serde(default)]
+    pub cancelled: bool,
+    /// 命令执行超时被客户端终止，输出只包含超时前产生的部分
+    #[serde(default)]
+    pub timed_out: bool,
+}
+
+/// 命令输出所属的流
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// 命令执行过程中实时回传的一段输出
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandOutputChunk {
+    pub command_id: String,
+    /// 同一命令内从 0 开始递增，stdout 与 stderr 共用一个序列
+    pub seq: u64,
+    pub stream: OutputStream,
+    pub data: String,
+}
+
+/// 服务端推送文件的元信息，随后按顺序发送 file_push_chunk
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FilePushStart {
+    pub transfer_id: String,
+    /// 客户端上的目标绝对路径
+    pub path: String,
+    pub size: u64,
+    /// 文件内容的 SHA-256（小写十六进制）
+    pub sha256: String,
+    /// 文件权限，缺省为 0644
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// 服务端配置了签名私钥时携带，客户端配置了公钥时必须校验通过才写入
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<CommandSignature>,
+}
+
+impl FilePushStart {
+    /// 签名覆盖的内容：目标路径、大小、校验和与权限；分块内容由签名的校验和保护
+    pub fn signed_content(&self) -> String {
+        serde_json::json!([self.path, self.size, self.sha256, self.mode]).to_string()
+    }
+}
+
+/// 命令请求签名覆盖的内容：命令和执行超时
+pub fn command_signed_content(command: &str, timeout_secs: Option<u64>) -> String {
+    serde_json::json!([command, timeout_secs]).to_string()
+}
+
+/// 文件读取请求签名覆盖的内容
+pub fn file_fetch_signed_content(path: &str, range: &FetchRange) -> String {
+    serde_json::json!([path, range]).to_string()
+}
+
+/// 命令策略签名覆盖的内容：整份策略
+pub fn command_policy_signed_content(policy: &CommandPolicy) -> String {
+    serde_json::to_string(policy).expect("command policy is always serializable")
+}
+
+/// 日志跟踪请求签名覆盖的内容
+pub fn log_tail_signed_content(app: &str, file: &str, lines: u64) -> String {
+    serde_json::json!([app, file, lines]).to_string()
+}
+
+/// 读取文件的范围
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum FetchRange {
+    /// 整个文件
+    Whole,
+    /// 从 offset 开始的 length 个字节，length 缺省时读到文件末尾
+    Bytes { offset: u64, length: Option<u64> },
+    /// 最后 lines 行
+    Tail { lines: u64 },
+}
+
+/// 二进制数据在 JSON 帧中以 base64 编码
+mod base64_bytes {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// 客户端重连成功后上报的重连情况
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReconnectReport {
+    pub client_id: String,
+    /// 本次重连共尝试的次数（含成功的一次）
+    pub attempts: u32,
+    /// 从断开到重新完成认证的秒数
+    pub downtime_secs: u64,
+    /// 断开原因
+    pub reason: String,
+    /// 最后一次失败尝试的错误
+    pub last_error: Option<String>,
+    /// 是否凭续连令牌恢复了原会话
+    #[serde(default)]
+    pub resumed: bool,
+}
+
+/// 客户端与服务端之间双向传输的全部帧类型
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "data_type", rename_all = "snake_case")]
+pub enum Envelope {
+    /// 客户端连接后发送的第一个帧，声明协议版本与功能
+    Hello(Hello),
+    /// 服务端返回的协商结果
+    HelloAck(HelloAck),
+    /// 客户端心跳，携带主机与应用信息
+    ClientInfo(ClientInfo),
+    /// 客户端返回的命令执行结果
+    CommandResponse(CommandResponse),
+    /// 命令执行中的输出分块，仅在协商了 streaming_output 时发送
+    CommandOutput(CommandOutputChunk),
+    /// 客户端重连后的重连报告
+    ReconnectReport(ReconnectReport),
+    /// 认证质询、响应与结果
+    Auth(TcpAuthMessage),
+    /// 服务端下发给客户端的命令
+    CommandRequest {
+        command_id: String,
+        command: String,
+        /// 执行超时秒数，缺省时使用客户端配置的默认值，并受客户端上限约束
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout_secs: Option<u64>,
+        /// 服务端配置了签名私钥时携带，客户端配置了公钥时必须校验通过才执行
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<CommandSignature>,
+    },
+    /// 服务端要求取消正在执行的命令
+    CancelCommand {
+        command_id: String,
+    },
+    /// 服务端广播消息
+    Broadcast {
+        message: String,
+    },
+    /// 服务端对心跳的确认
+    Ack,
+    /// 客户端收到命令并通过校验，开始执行
+    CommandAccepted {
+        command_id: String,
+    },
+    /// 客户端拒绝执行命令，reason 为策略拒绝原因
+    CommandRejected {
+        command_id: String,
+        reason: String,
+    },
+    /// 服务端要求客户端打开一个 PTY 终端
+    TerminalOpen {
+        session_id: String,
+        cols: u16,
+        rows: u16,
+        /// 签名覆盖 session_id，客户端配置了公钥时必须校验通过才打开
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<CommandSignature>,
+    },
+    /// 操作员在终端中的输入
+    TerminalInput {
+        session_id: String,
+        data: String,
+    },
+    /// 调整终端窗口大小
+    TerminalResize {
+        session_id: String,
+        cols: u16,
+        rows: u16,
+    },
+    /// 服务端关闭终端，客户端结束 shell
+    TerminalClose {
+        session_id: String,
+    },
+    /// 终端输出
+    TerminalOutput {
+        session_id: String,
+        data: String,
+    },
+    /// 客户端的终端已结束（shell 退出或无法启动），reason 为异常原因
+    TerminalClosed {
+        session_id: String,
+        reason: Option<String>,
+    },
+    /// 服务端开始推送文件，仅发给协商了 file_transfer 的客户端
+    FilePushStart(FilePushStart),
+    /// 文件内容分块，offset 为该块在文件中的起始位置
+    FilePushChunk {
+        transfer_id: String,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// 客户端写入文件的结果
+    FilePushResult {
+        transfer_id: String,
+        success: bool,
+        message: String,
+    },
+    /// 服务端要求客户端读取文件，仅发给协商了 file_fetch 的客户端
+    FileFetchRequest {
+        fetch_id: String,
+        path: String,
+        range: FetchRange,
+        /// 签名覆盖路径和范围，客户端配置了公钥时必须校验通过才读取
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<CommandSignature>,
+    },
+    /// 客户端开始回传文件：文件总大小和实际读取的区间
+    FileFetchStart {
+        fetch_id: String,
+        file_size: u64,
+        offset: u64,
+        length: u64,
+    },
+    /// 文件内容分块，offset 为该块在文件中的起始位置
+    FileFetchChunk {
+        fetch_id: String,
+        offset: u64,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+    /// 全部分块已发出，sha256 为回传内容的校验和
+    FileFetchEnd {
+        fetch_id: String,
+        sha256: String,
+    },
+    /// 客户端无法读取文件
+    FileFetchFailed {
+        fetch_id: String,
+        reason: String,
+    },
+    /// 服务端要求跟踪应用日志：先回传最后 lines 行，之后持续回传新增的行
+    LogTailStart {
+        tail_id: String,
+        /// apps_base_dir 下的应用目录名
+        app: String,
+        /// 应用目录内的相对路径
+        file: String,
+        lines: u64,
+        /// 签名覆盖应用、文件和行数，客户端配置了公钥时必须校验通过才跟踪
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<CommandSignature>,
+    },
+    /// 服务端停止跟踪日志
+    LogTailStop {
+        tail_id: String,
+    },
+    /// 日志新增的完整行
+    LogTailData {
+        tail_id: String,
+        data: String,
+    },
+    /// 客户端结束跟踪（文件无法打开或读取失败）
+    LogTailEnded {
+        tail_id: String,
+        reason: String,
+    },
+    /// 服务端轮换客户端密钥，客户端保存后回复 credential_update_ack
+    CredentialUpdate {
+        secret: String,
+    },
+    /// 客户端已保存新密钥，服务端随后停用旧密钥
+    CredentialUpdateAck,
+    /// 服务端确认已保存命令结果，客户端据此清理发件箱
+    ResultAck {
+        command_id: String,
+    },
+    /// 认证通过后服务端下发的命令校验策略，客户端以此为准，本地策略只能进一步收紧
+    CommandPolicy {
+        /// 每次下发唯一，作为签名的请求ID
+        policy_id: String,
+        policy: CommandPolicy,
+        /// 签名覆盖整份策略，客户端配置了公钥时必须校验通过才应用
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<CommandSignature>,
+    },
+    /// 认证成功或恢复会话后服务端签发的续连令牌，断开后 expires_in_secs 秒内有效
+    SessionToken {
+        token: String,
+        expires_in_secs: u64,
+    },
+    /// 服务端拒绝连接（例如超过最大连接数）
+    ConnectionRejected {
+        reason: String,
+    },
+}
+
+/// 线路上的帧格式：协议版本 + 消息体
+#[derive(Serialize, Deserialize)]
+struct VersionedFrame<T> {
+    v: u32,
+    #[serde(flatten)]
+    envelope: T,
+}
+
+impl Envelope {
+    /// 序列化为带版本号的 JSON 帧
+    pub fn encode(&self) -> std::io::Result<Vec<u8>> {
+        let frame = VersionedFrame { v: PROTOCOL_VERSION, envelope: self };
+        serde_json::to_vec(&frame)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// 从 JSON 帧解析消息，拒绝不支持的协议版本
+    ///
+    /// 握手消息不检查帧版本，由 `Hello::negotiate` 决定是否兼容。
+    pub fn decode(data: &[u8]) -> std::io::Result<Self> {
+        let frame: VersionedFrame<Envelope> = serde_json::from_slice(data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let is_handshake = matches!(frame.envelope, Envelope::Hello(_) | Envelope::HelloAck(_));
+        if !is_handshake && !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&frame.v) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("不支持的协议版本: {} (当前版本: {})", frame.v, PROTOCOL_VERSION),
+            ));
+        }
+
+        Ok(frame.envelope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_request_roundtrip_with_separators() {
+        let command = "echo 'a::b'\nls -la".to_string();
+        let envelope = Envelope::CommandRequest {
+            command_id: "cmd-1".to_string(),
+            command: command.clone(),
+            timeout_secs: Some(30),
+            signature: None,
+        };
+
+        let encoded = envelope.encode().unwrap();
+        assert!(!encoded.contains(&b'\n'), "encoded frame must not contain raw newlines");
+
+        match Envelope::decode(&encoded).unwrap() {
+            Envelope::CommandRequest { command_id, command: decoded, timeout_secs, .. } => {
+                assert_eq!(command_id, "cmd-1");
+                assert_eq!(decoded, command);
+                assert_eq!(timeout_secs, Some(30));
+            }
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_auth_message_roundtrip() {
+        let envelope = Envelope::Auth(TcpAuthMessage::AuthResult {
+            success: true,
+            message: "ok".to_string(),
+        });
+
+        let encoded = envelope.encode().unwrap();
+        match Envelope::decode(&encoded).unwrap() {
+            Envelope::Auth(TcpAuthMessage::AuthResult { success, message }) => {
+                assert!(success);
+                assert_eq!(message, "ok");
+            }
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_command_output_roundtrip() {
+        let chunk = CommandOutputChunk {
+            command_id: "cmd-1".to_string(),
+            seq: 3,
+            stream: OutputStream::Stderr,
+            data: "warning\n".to_string(),
+        };
+
+        let encoded = Envelope::CommandOutput(chunk.clone()).encode().unwrap();
+        let text = String::from_utf8(encoded.clone()).unwrap();
+        assert!(text.contains(r#""data_type":"command_output""#));
+        assert!(text.contains(r#""stream":"stderr""#));
+
+        match Envelope::decode(&encoded).unwrap() {
+            Envelope::CommandOutput(decoded) => assert_eq!(decoded, chunk),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_terminal_frames_roundtrip() {
+        let open = Envelope::TerminalOpen { session_id: "term-1".to_string(), cols: 80, rows: 24, signature: None };
+        let text = String::from_utf8(open.encode().unwrap()).unwrap();
+        assert!(text.contains(r#""data_type":"terminal_open""#));
+
+        let closed = Envelope::TerminalClosed { session_id: "term-1".to_string(), reason: None };
+        match Envelope::decode(&closed.encode().unwrap()).unwrap() {
+            Envelope::TerminalClosed { session_id, reason } => {
+                assert_eq!(session_id, "term-1");
+                assert_eq!(reason, None);
+            }
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_push_chunk_base64_roundtrip() {
+        let chunk = Envelope::FilePushChunk {
+            transfer_id: "xfer-1".to_string(),
+            offset: 0,
+            data: vec![0, 159, 146, 150, 255],
+        };
+        let encoded = chunk.encode().unwrap();
+        let text = String::from_utf8(encoded.clone()).unwrap();
+        assert!(text.contains(r#""data":"AJ+Slv8=""#));
+
+        match Envelope::decode(&encoded).unwrap() {
+            Envelope::FilePushChunk { data, .. } => assert_eq!(data, vec![0, 159, 146, 150, 255]),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_file_fetch_request_roundtrip() {
+        let request = Envelope::FileFetchRequest {
+            fetch_id: "fetch-1".to_string(),
+            path: "/var/log/app.log".to_string(),
+            range: FetchRange::Tail { lines: 100 },
+            signature: None,
+        };
+        let encoded = request.encode().unwrap();
+        let text = String::from_utf8(encoded.clone()).unwrap();
+        assert!(text.contains(r#""range":{"mode":"tail","lines":100}"#));
+
+        match Envelope::decode(&encoded).unwrap() {
+            Envelope::FileFetchRequest { range, .. } => assert_eq!(range, FetchRange::Tail { lines: 100 }),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_client_info_roundtrip() {
+        let info = ClientInfo {
+            client_id: "client-1".to_string(),
+            system_info: crate::HostInfo {
+                hostname: "host".to_string(),
+                cpu_model: "cpu".to_string(),
+                cpu_usage: 1.5,
+                total_memory: 1024,
+                free_memory: 512,
+                used_memory: 512,
+                ip_addresses: vec!["10.0.0.1/24".to_string()],
+            },
+            version_info: Vec::new(),
+            app_info: Vec::new(),
+            last_seen: SystemTime::now(),
+            command_queue: crate::CommandQueueStatus { running: 1, queued: 2, max_concurrent: 4 },
+            command_policy: crate::PolicyStatus { version: Some("0123456789ab".to_string()), local_version: None },
+            apps_base_dir: Some("/opt/apps".to_string()),
+        };
+
+        let encoded = Envelope::ClientInfo(info).encode().unwrap();
+        match Envelope::decode(&encoded).unwrap() {
+            Envelope::ClientInfo(decoded) => {
+                assert_eq!(decoded.client_id, "client-1");
+                assert_eq!(decoded.system_info.total_memory, 1024);
+                assert_eq!(decoded.command_queue.queued, 2);
+                assert_eq!(decoded.command_policy.version.as_deref(), Some("0123456789ab"));
+            }
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_command_policy_roundtrip() {
+        let policy = CommandPolicy::builtin();
+        let encoded = Envelope::CommandPolicy { policy_id: "policy-1".to_string(), policy: policy.clone(), signature: None }.encode().unwrap();
+        let text = String::from_utf8(encoded.clone()).unwrap();
+        assert!(text.contains(r#""data_type":"command_policy""#));
+
+        match Envelope::decode(&encoded).unwrap() {
+            Envelope::CommandPolicy { policy: decoded, .. } => assert_eq!(decoded.version(), policy.version()),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version_rejected() {
+        let data = br#"{"v":99,"data_type":"ack"}"#;
+        assert!(Envelope::decode(data).is_err());
+    }
+
+    #[test]
+    fn test_hello_from_newer_peer_decodes() {
+        let data = br#"{"v":99,"data_type":"hello","protocol_version":99,"min_protocol_version":1,"capabilities":["streaming_output","quantum_sync"],"agent_version":"9.0.0"}"#;
+        match Envelope::decode(data).unwrap() {
+            Envelope::Hello(hello) => {
+                assert_eq!(hello.capabilities[0], Capability::StreamingOutput);
+                assert_eq!(hello.capabilities[1], Capability::Unknown("quantum_sync".to_string()));
+            }
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_picks_common_version_and_capabilities() {
+        let local = Hello {
+            protocol_version: 3,
+            min_protocol_version: 1,
+            capabilities: vec![Capability::StreamingOutput, Capability::FileTransfer],
+            agent_version: "0.1.0".to_string(),
+            resume: None,
+        };
+        let remote = Hello {
+            protocol_version: 2,
+            min_protocol_version: 2,
+            capabilities: vec![Capability::FileTransfer, Capability::Unknown("x".to_string())],
+            agent_version: "0.2.0".to_string(),
+            resume: None,
+        };
+
+        let ack = local.negotiate(&remote).unwrap();
+        assert_eq!(ack.protocol_version, 2);
+        assert_eq!(ack.capabilities, vec![Capability::FileTransfer]);
+    }
+
+    #[test]
+    fn test_negotiate_rejects_disjoint_versions() {
+        let local = Hello::local();
+        let remote = Hello {
+            protocol_version: PROTOCOL_VERSION + 2,
+            min_protocol_version: PROTOCOL_VERSION + 1,
+            capabilities: Vec::new(),
+            agent_version: "9.0.0".to_string(),
+            resume: None,
+        };
+
+        assert!(local.negotiate(&remote).is_err());
+    }
+}
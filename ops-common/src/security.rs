@@ -1,5 +1,10 @@
-use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
 use serde::{Deserialize, Serialize};
+use crate::command_policy::{CommandPolicy, CompiledRules, WorkDir};
+use crate::shell;
+
+/// 只能用来执行脚本文件的解释器
+const SHELL_INTERPRETERS: &[&str] = &["bash", "sh"];
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PredefinedCommand {
@@ -20,207 +25,17 @@ impl PredefinedCommand {
     }
 }
 
+/// 命令校验器：按 Shell 语法拆分命令，逐条按策略（见 command_policy）校验
 #[derive(Debug, Clone)]
 pub struct CommandValidator {
-    allowed_commands: HashSet<String>,
-    blocked_patterns: Vec<String>,
-    max_command_length: usize,
-    allowed_script_dirs: Vec<String>, // 允许执行脚本的目录白名单
-    allowed_script_extensions: HashSet<String>, // 允许的脚本文件扩展名
+    policy: CommandPolicy,
+    rules: CompiledRules,
+    local: Option<Box<CommandValidator>>, // 附加的本地策略，命令必须同时通过
 }
 
 impl Default for CommandValidator {
     fn default() -> Self {
-        let mut allowed_commands = HashSet::new();
-        
-        // 系统信息查看命令
-        allowed_commands.insert("ps".to_string());
-        allowed_commands.insert("ls".to_string());
-        allowed_commands.insert("pwd".to_string());
-        allowed_commands.insert("whoami".to_string());
-        allowed_commands.insert("id".to_string());
-        allowed_commands.insert("groups".to_string());
-        allowed_commands.insert("date".to_string());
-        allowed_commands.insert("uptime".to_string());
-        allowed_commands.insert("hostname".to_string());
-        allowed_commands.insert("uname".to_string());
-        
-        // 资源监控命令
-        allowed_commands.insert("df".to_string());
-        allowed_commands.insert("free".to_string());
-        allowed_commands.insert("top".to_string());
-        allowed_commands.insert("htop".to_string());
-        allowed_commands.insert("iostat".to_string());
-        allowed_commands.insert("vmstat".to_string());
-        allowed_commands.insert("sar".to_string());
-        allowed_commands.insert("mpstat".to_string());
-        
-        // 网络信息查看
-        allowed_commands.insert("netstat".to_string());
-        allowed_commands.insert("ss".to_string());
-        allowed_commands.insert("ip".to_string());
-        allowed_commands.insert("ifconfig".to_string());
-        allowed_commands.insert("ping".to_string());
-        
-        // 文件查看命令（只读）
-        allowed_commands.insert("cat".to_string());
-        allowed_commands.insert("head".to_string());
-        allowed_commands.insert("tail".to_string());
-        allowed_commands.insert("less".to_string());
-        allowed_commands.insert("more".to_string());
-        allowed_commands.insert("grep".to_string());
-        allowed_commands.insert("find".to_string());
-        allowed_commands.insert("wc".to_string());
-        allowed_commands.insert("sort".to_string());
-        allowed_commands.insert("uniq".to_string());
-        
-        // 服务管理（只读操作）
-        allowed_commands.insert("systemctl".to_string());
-        allowed_commands.insert("journalctl".to_string());
-        allowed_commands.insert("service".to_string());
-        
-        // 环境变量和历史
-        allowed_commands.insert("env".to_string());
-        allowed_commands.insert("history".to_string());
-        allowed_commands.insert("which".to_string());
-        allowed_commands.insert("whereis".to_string());
-        
-        // Shell命令（用于执行脚本）
-        allowed_commands.insert("bash".to_string());
-        allowed_commands.insert("sh".to_string());
-        
-        // 进程管理命令（用于服务管理）
-        allowed_commands.insert("kill".to_string());
-        allowed_commands.insert("cd".to_string());
-
-        let blocked_patterns = vec![
-            // 系统关机/重启命令
-            "shutdown".to_string(),
-            "reboot".to_string(),
-            "halt".to_string(),
-            "poweroff".to_string(),
-            "init 0".to_string(),
-            "init 6".to_string(),
-            "systemctl poweroff".to_string(),
-            "systemctl reboot".to_string(),
-            "systemctl halt".to_string(),
-            
-            // 危险的文件操作
-            "rm -rf".to_string(),
-            "rm -r".to_string(),
-            "rm /".to_string(),
-            "rmdir".to_string(),
-            "> /dev/".to_string(),
-            "dd if=".to_string(),
-            "dd of=".to_string(),
-            "mkfs".to_string(),
-            "fdisk".to_string(),
-            "parted".to_string(),
-            "format".to_string(),
-            
-            // 权限提升命令
-            "sudo su".to_string(),
-            "su -".to_string(),
-            "su root".to_string(),
-            "sudo -i".to_string(),
-            "sudo bash".to_string(),
-            "sudo sh".to_string(),
-            "passwd".to_string(),
-            "usermod".to_string(),
-            "useradd".to_string(),
-            "userdel".to_string(),
-            "chmod 777".to_string(),
-            "chmod 4755".to_string(),
-            "chown root".to_string(),
-            
-            // 网络下载和连接
-            "curl".to_string(),
-            "wget".to_string(),
-            "nc -".to_string(),
-            "netcat".to_string(),
-            "telnet".to_string(),
-            "ftp".to_string(),
-            "sftp".to_string(),
-            "scp".to_string(),
-            "rsync".to_string(),
-            
-            // 危险的Shell执行模式
-            "bash -i".to_string(),
-            "sh -i".to_string(),
-            "exec".to_string(),
-            "eval".to_string(),
-            "source".to_string(),
-            "python -c".to_string(),
-            "perl -e".to_string(),
-            "ruby -e".to_string(),
-            
-            // 进程和服务控制
-            "kill -9".to_string(),
-            "killall".to_string(),
-            "pkill".to_string(),
-            "systemctl start".to_string(),
-            "systemctl stop".to_string(),
-            "systemctl restart".to_string(),
-            "systemctl enable".to_string(),
-            "systemctl disable".to_string(),
-            "service start".to_string(),
-            "service stop".to_string(),
-            "service restart".to_string(),
-            
-            // 包管理器
-            "apt install".to_string(),
-            "apt remove".to_string(),
-            "apt purge".to_string(),
-            "yum install".to_string(),
-            "yum remove".to_string(),
-            "dnf install".to_string(),
-            "dnf remove".to_string(),
-            "rpm -i".to_string(),
-            "rpm -e".to_string(),
-            "dpkg -i".to_string(),
-            "dpkg -r".to_string(),
-            "pip install".to_string(),
-            "npm install".to_string(),
-            
-            // 计划任务和定时任务
-            "crontab".to_string(),
-            "at ".to_string(),
-            "batch".to_string(),
-            
-            // 挂载和存储操作
-            "mount".to_string(),
-            "umount".to_string(),
-            "fsck".to_string(),
-            "e2fsck".to_string(),
-            
-            // 命令注入防护（只阻止明显的注入模式）
-            "`".to_string(),
-            "$(".to_string(),
-        ];
-
-        // 默认允许的脚本目录（增加应用目录）
-        let allowed_script_dirs = vec![
-            "/opt/ops-scripts".to_string(),     // 默认运维脚本目录
-            "/usr/local/bin/scripts".to_string(), // 本地脚本目录
-            "/home/ops/scripts".to_string(),    // ops用户脚本目录
-            "/tmp/ops-scripts".to_string(),     // 测试脚本目录
-            "/tmp/apps".to_string(),            // 应用脚本目录
-        ];
-
-        // 允许的脚本文件扩展名
-        let mut allowed_script_extensions = HashSet::new();
-        allowed_script_extensions.insert("sh".to_string());
-        allowed_script_extensions.insert("py".to_string());
-        allowed_script_extensions.insert("pl".to_string());
-        allowed_script_extensions.insert("rb".to_string());
-
-        Self {
-            allowed_commands,
-            blocked_patterns,
-            max_command_length: 1000,
-            allowed_script_dirs,
-            allowed_script_extensions,
-        }
+        Self::from_policy(CommandPolicy::builtin()).expect("built-in command policy is valid")
     }
 }
 
@@ -230,6 +45,21 @@ pub enum ValidationResult {
     Blocked { reason: String },
 }
 
+/// 命令中一条简单命令的校验结论
+#[derive(Debug, Clone, Serialize)]
+pub struct CommandDecision {
+    pub command: String,
+    pub allowed: bool,
+    pub detail: String,
+}
+
+/// 校验结果及逐条命令的判断过程，用于 `ops-client policy test`
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub result: ValidationResult,
+    pub decisions: Vec<CommandDecision>,
+}
+
 impl CommandValidator {
     pub fn new() -> Self {
         Self::default()
@@ -273,105 +103,64 @@ impl CommandValidator {
             PredefinedCommand::new("which bash", "查找命令位置", "环境信息", "显示bash命令的完整路径"),
 
             // 脚本执行类
-            PredefinedCommand::new("/tmp/ops-scripts/health-check.sh", "系统健康检查", "脚本执行", "执行系统健康检查脚本"),
-            PredefinedCommand::new("/tmp/ops-scripts/disk-usage.py", "磁盘使用分析", "脚本执行", "分析磁盘使用情况"),
+            PredefinedCommand::new("/opt/ops-scripts/health-check.sh", "系统健康检查", "脚本执行", "执行系统健康检查脚本"),
+            PredefinedCommand::new("/opt/ops-scripts/disk-usage.py", "磁盘使用分析", "脚本执行", "分析磁盘使用情况"),
         ]
     }
 
-    pub fn with_allowed_commands(mut self, commands: Vec<String>) -> Self {
-        self.allowed_commands = commands.into_iter().collect();
-        self
-    }
-
-    pub fn add_allowed_command(&mut self, command: String) {
-        self.allowed_commands.insert(command);
+    pub fn from_policy(policy: CommandPolicy) -> Result<Self, String> {
+        let rules = policy.compile()?;
+        Ok(Self { policy, rules, local: None })
     }
 
-    pub fn add_blocked_pattern(&mut self, pattern: String) {
-        self.blocked_patterns.push(pattern);
-    }
-
-    pub fn add_allowed_script_dir(&mut self, dir: String) {
-        self.allowed_script_dirs.push(dir);
+    /// 在当前策略之外要求命令同时通过 local 的校验，只能收紧，不能放宽
+    pub fn restricted_by(mut self, local: CommandValidator) -> Self {
+        self.local = Some(Box::new(local));
+        self
     }
 
-    pub fn add_allowed_script_extension(&mut self, ext: String) {
-        self.allowed_script_extensions.insert(ext);
+    pub fn policy(&self) -> &CommandPolicy {
+        &self.policy
     }
 
-    pub fn with_allowed_script_dirs(mut self, dirs: Vec<String>) -> Self {
-        self.allowed_script_dirs = dirs;
-        self
+    pub fn local_policy(&self) -> Option<&CommandPolicy> {
+        self.local.as_ref().map(|local| &local.policy)
     }
 
+    /// 解析整条命令行，逐条校验其中的每个简单命令（包括管道、子 shell、if 分支和命令替换中的命令），
+    /// 无法解析或归类的语法直接拒绝
     pub fn validate(&self, command: &str) -> ValidationResult {
-        // 检查命令长度
-        if command.len() > self.max_command_length {
-            return ValidationResult::Blocked {
-                reason: format!("命令长度超过限制: {} > {}", command.len(), self.max_command_length),
-            };
-        }
-
-        // 检查空命令
-        if command.trim().is_empty() {
-            return ValidationResult::Blocked {
-                reason: "空命令".to_string(),
-            };
-        }
-
-        // 检查是否是应用管理命令（特殊处理）
-        if self.is_app_management_command(command) {
-            return self.validate_app_management_command(command);
-        }
+        self.explain(command).result
+    }
 
-        // 检查危险模式
-        for pattern in &self.blocked_patterns {
-            if command.to_lowercase().contains(&pattern.to_lowercase()) {
-                return ValidationResult::Blocked {
-                    reason: format!("包含危险模式: {}", pattern),
+    /// 校验命令并记录每条简单命令的判断依据
+    pub fn explain(&self, command: &str) -> Explanation {
+        let mut checker = Checker { validator: self, decisions: Vec::new(), changed_dir: false };
+        let result = match checker.check_command_line(command) {
+            Ok(()) => ValidationResult::Allowed,
+            Err(reason) => ValidationResult::Blocked { reason },
+        };
+        if let (ValidationResult::Allowed, Some(local)) = (&result, &self.local) {
+            let explanation = local.explain(command);
+            if let ValidationResult::Blocked { reason } = explanation.result {
+                return Explanation {
+                    result: ValidationResult::Blocked { reason: format!("本地策略: {}", reason) },
+                    decisions: explanation.decisions,
                 };
             }
         }
-
-        // 提取第一个命令词
-        let parts: Vec<&str> = command.trim().split_whitespace().collect();
-        if let Some(base_command) = parts.first() {
-            // 检查是否是脚本路径
-            if self.is_script_path(base_command) {
-                // 对脚本路径进行特殊验证
-                match self.validate_script_path(base_command) {
-                    ValidationResult::Allowed => {},
-                    blocked => return blocked,
-                }
-            } else {
-                // 检查是否在允许列表中
-                if !self.allowed_commands.contains(*base_command) {
-                    return ValidationResult::Blocked {
-                        reason: format!("命令不在允许列表中: {}", base_command),
-                    };
-                }
-            }
-        } else {
-            return ValidationResult::Blocked {
-                reason: "无法解析命令".to_string(),
-            };
-        }
-
-        ValidationResult::Allowed
+        Explanation { result, decisions: checker.decisions }
     }
 
-    pub fn sanitize_command(&self, command: &str) -> String {
-        // 移除潜在的注入字符
-        command
-            .replace(";", " ")
-            .replace("&&", " ")
-            .replace("||", " ")
-            .replace("|", " ")
-            .replace("`", "")
-            .replace("$", "")
-            .replace("&", "")
-            .trim()
-            .to_string()
+    /// 应用管理命令停止服务后删除 pid 文件：`rm -f <app>.pid`，当前目录必须是允许的脚本目录
+    fn is_pid_file_cleanup(&self, program: &str, args: &[Option<String>], cwd: &Option<PathBuf>) -> bool {
+        let in_script_dir = cwd.as_ref().is_some_and(|cwd| {
+            self.policy.scripts.allowed_dirs.iter().any(|dir| cwd.starts_with(dir))
+        });
+        let pid_file = |arg: &Option<String>| {
+            arg.as_deref().is_some_and(|name| name.ends_with(".pid") && !name.starts_with('-') && !name.contains('/'))
+        };
+        program == "rm" && in_script_dir && matches!(args, [Some(flag), file] if flag == "-f" && pid_file(file))
     }
 
     /// 检查是否为脚本路径（包含路径分隔符且不是纯命令名）
@@ -381,17 +170,14 @@ impl CommandValidator {
 
     /// 检查文件是否有脚本扩展名
     fn has_script_extension(&self, path: &str) -> bool {
-        if let Some(ext) = path.split('.').last() {
-            self.allowed_script_extensions.contains(ext)
-        } else {
-            false
-        }
+        Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| self.policy.scripts.allowed_extensions.iter().any(|allowed| allowed == ext))
     }
 
     /// 验证脚本路径是否安全
     fn validate_script_path(&self, script_path: &str) -> ValidationResult {
-        use std::path::Path;
-        
         // 检查路径是否为绝对路径
         let path = Path::new(script_path);
         if !path.is_absolute() {
@@ -409,29 +195,22 @@ impl CommandValidator {
         }
 
         // 检查是否在允许的目录中
-        let mut allowed = false;
-        for allowed_dir in &self.allowed_script_dirs {
-            if script_path.starts_with(allowed_dir) {
-                allowed = true;
-                break;
-            }
-        }
-
+        let scripts = &self.policy.scripts;
+        let allowed = scripts.allowed_dirs.iter().any(|allowed_dir| path.starts_with(allowed_dir));
         if !allowed {
             return ValidationResult::Blocked {
-                reason: format!("脚本不在允许的目录中。允许的目录: {:?}", self.allowed_script_dirs),
+                reason: format!("脚本不在允许的目录中。允许的目录: {:?}", scripts.allowed_dirs),
             };
         }
 
         // 检查文件扩展名
         if let Some(ext) = path.extension() {
-            if let Some(ext_str) = ext.to_str() {
-                if !self.allowed_script_extensions.contains(ext_str) {
-                    return ValidationResult::Blocked {
-                        reason: format!("不允许的脚本类型: .{}，允许的类型: {:?}", 
-                                       ext_str, self.allowed_script_extensions),
-                    };
-                }
+            if let Some(ext_str) = ext.to_str()
+                && !scripts.allowed_extensions.iter().any(|allowed| allowed == ext_str) {
+                return ValidationResult::Blocked {
+                    reason: format!("不允许的脚本类型: .{}，允许的类型: {:?}", 
+                                   ext_str, scripts.allowed_extensions),
+                };
             }
         } else {
             return ValidationResult::Blocked {
@@ -444,64 +223,262 @@ impl CommandValidator {
 
     /// 获取允许的脚本目录列表
     pub fn get_allowed_script_dirs(&self) -> &Vec<String> {
-        &self.allowed_script_dirs
+        &self.policy.scripts.allowed_dirs
     }
 
     /// 获取允许的脚本扩展名列表
     pub fn get_allowed_script_extensions(&self) -> Vec<String> {
-        self.allowed_script_extensions.iter().cloned().collect()
+        self.policy.scripts.allowed_extensions.clone()
+    }
+}
+
+/// 按执行顺序遍历命令。cwd 为确定的当前目录（由前面的 `cd <绝对路径> &&` 得出），用于校验相对路径的
+/// 脚本和路径参数；各 check_* 返回执行后确定的当前目录
+struct Checker<'a> {
+    validator: &'a CommandValidator,
+    decisions: Vec<CommandDecision>,
+    // 已经检查过 cd：之后当前目录不确定时，相对路径不能再按客户端进程的工作目录对待
+    changed_dir: bool,
+}
+
+impl Checker<'_> {
+    fn check_command_line(&mut self, command: &str) -> Result<(), String> {
+        // 检查命令长度
+        let max_length = self.validator.policy.max_command_length;
+        if command.len() > max_length {
+            return Err(format!("命令长度超过限制: {} > {}", command.len(), max_length));
+        }
+
+        // 检查空命令
+        if command.trim().is_empty() {
+            return Err("空命令".to_string());
+        }
+
+        let list = shell::parse(command).map_err(|e| format!("无法解析命令: {}", e))?;
+        if list.is_empty() {
+            return Err("空命令".to_string());
+        }
+        self.check_list(&list, None).map(|_| ())
     }
 
-    /// 检查是否是应用管理命令
-    fn is_app_management_command(&self, command: &str) -> bool {
-        // 检查是否是cd /tmp/apps/xxx && bash xxx.sh命令模式
-        (command.contains("cd /tmp/apps/") && command.contains("bash") && command.contains(".sh")) ||
-        // 检查是否包含应用管理的特定模式
-        (command.contains("/tmp/apps/") && (command.contains("kill") || command.contains("if") || command.contains("pid")))
+    fn check_list(&mut self, list: &shell::List, cwd: Option<PathBuf>) -> Result<Option<PathBuf>, String> {
+        let mut cwd = cwd;
+        for and_or in list {
+            cwd = self.check_and_or(and_or, cwd)?;
+        }
+        Ok(cwd)
     }
 
-    /// 验证应用管理命令
-    fn validate_app_management_command(&self, command: &str) -> ValidationResult {
-        // 应用管理命令的特殊验证逻辑
-        
-        // 检查是否包含 /tmp/apps/ 路径
-        if !command.contains("/tmp/apps/") {
-            return ValidationResult::Blocked {
-                reason: "应用管理命令必须在 /tmp/apps/ 目录下执行".to_string(),
-            };
+    fn check_and_or(&mut self, and_or: &shell::AndOr, cwd: Option<PathBuf>) -> Result<Option<PathBuf>, String> {
+        let start = cwd.clone();
+        let mut current = self.check_pipeline(&and_or.first, cwd)?;
+        for (connector, pipeline) in &and_or.rest {
+            // || 之后的命令在前面失败时才执行，此时 cd 是否生效不确定
+            if *connector == shell::Connector::Or && current != start {
+                current = None;
+            }
+            current = self.check_pipeline(pipeline, current)?;
         }
+        // 链中的命令可能失败，之后的命令所在目录不确定
+        Ok(if current == start { current } else { None })
+    }
 
-        // 检查是否包含危险的命令注入
-        if command.contains("rm -rf") || command.contains("format") || command.contains("dd") ||
-           command.contains("curl") || command.contains("wget") || command.contains("nc ") ||
-           command.contains("netcat") || command.contains("telnet") {
-            return ValidationResult::Blocked {
-                reason: "应用管理命令包含危险操作".to_string(),
-            };
+    fn check_pipeline(&mut self, pipeline: &shell::Pipeline, cwd: Option<PathBuf>) -> Result<Option<PathBuf>, String> {
+        if let [command] = pipeline.commands.as_slice() {
+            return self.check_command(command, cwd);
         }
+        // 管道中的每个命令都在子 shell 中执行，不影响当前目录
+        for command in &pipeline.commands {
+            self.check_command(command, cwd.clone())?;
+        }
+        Ok(cwd)
+    }
 
-        // 允许的应用管理操作模式
-        let allowed_patterns = vec![
-            r"cd /tmp/apps/[\w\-]+ && bash [\w\-]+\.sh (start|stop|status|update)",
-            r"cd /tmp/apps/[\w\-]+ && if \[ -f [\w\-]+\.pid \]",
-            r"kill \$\(cat [\w\-]+\.pid\)",
-            r"rm -f [\w\-]+\.pid",
-            r"ps -p \$pid",
-        ];
-
-        // 简化验证：检查关键词
-        let has_valid_pattern = 
-            (command.contains("bash") && command.contains(".sh")) ||
-            (command.contains("kill") && command.contains("cat") && command.contains(".pid")) ||
-            (command.contains("ps -p"));
-
-        if has_valid_pattern {
-            ValidationResult::Allowed
+    fn check_command(&mut self, command: &shell::Command, cwd: Option<PathBuf>) -> Result<Option<PathBuf>, String> {
+        match command {
+            shell::Command::Simple(simple) => self.check_simple(simple, cwd),
+            shell::Command::Subshell { body, redirects } => {
+                self.check_redirects(redirects, None, &cwd)?;
+                self.check_list(body, cwd.clone())?;
+                Ok(cwd)
+            }
+            shell::Command::Group { body, redirects } => {
+                self.check_redirects(redirects, None, &cwd)?;
+                let after = self.check_list(body, cwd.clone())?;
+                Ok(if after == cwd { cwd } else { None })
+            }
+            shell::Command::If { branches, else_body, redirects } => {
+                self.check_redirects(redirects, None, &cwd)?;
+                let mut changed = false;
+                for (condition, body) in branches {
+                    let after_condition = self.check_list(condition, cwd.clone())?;
+                    let after_body = self.check_list(body, after_condition.clone())?;
+                    changed |= after_condition != cwd || after_body != cwd;
+                }
+                if let Some(body) = else_body {
+                    changed |= self.check_list(body, cwd.clone())? != cwd;
+                }
+                Ok(if changed { None } else { cwd })
+            }
+        }
+    }
+
+    fn check_simple(&mut self, command: &shell::SimpleCommand, cwd: Option<PathBuf>) -> Result<Option<PathBuf>, String> {
+        for word in command.assignments.iter().chain(&command.words) {
+            self.check_substitutions(word, &cwd)?;
+        }
+        // 命令名无法确定时 check_program 会拒绝，这里按任意命令检查
+        let program = command.words.first().and_then(shell::Word::literal);
+        let name = program.as_deref().map(|program| program.rsplit('/').next().unwrap_or(program));
+        self.check_redirects(&command.redirects, name, &cwd)?;
+
+        let Some((program_word, arg_words)) = command.words.split_first() else {
+            // 只有变量赋值或重定向
+            return Ok(cwd);
+        };
+        let text = command.words.iter().map(|word| word.raw.as_str()).collect::<Vec<_>>().join(" ");
+        match self.check_program(command, program_word, arg_words, &cwd) {
+            Ok((detail, cwd)) => {
+                self.decisions.push(CommandDecision { command: text, allowed: true, detail });
+                Ok(cwd)
+            }
+            Err(reason) => {
+                self.decisions.push(CommandDecision { command: text, allowed: false, detail: reason.clone() });
+                Err(reason)
+            }
+        }
+    }
+
+    /// 校验一条简单命令，返回判断依据和执行后的当前目录
+    fn check_program(
+        &mut self,
+        command: &shell::SimpleCommand,
+        program_word: &shell::Word,
+        arg_words: &[shell::Word],
+        cwd: &Option<PathBuf>,
+    ) -> Result<(String, Option<PathBuf>), String> {
+        let validator = self.validator;
+        if let Some(assignment) = command.assignments.first() {
+            return Err(format!("不允许在命令前设置环境变量: {}", assignment.raw));
+        }
+        let program = program_word
+            .literal()
+            .ok_or_else(|| format!("无法确定要执行的命令: {}", program_word.raw))?;
+        let name = program.rsplit('/').next().unwrap_or(&program);
+        let args: Vec<Option<String>> = arg_words.iter().map(shell::Word::literal).collect();
+
+        let work_dir = self.work_dir(cwd);
+
+        // 禁止规则优先
+        validator.rules.check_deny(name, arg_words, work_dir)?;
+
+        let mut detail = if validator.is_script_path(&program) {
+            // 对脚本路径进行特殊验证
+            if let ValidationResult::Blocked { reason } = validator.validate_script_path(&program) {
+                return Err(reason);
+            }
+            format!("脚本 {} 位于允许的目录", program)
         } else {
-            ValidationResult::Blocked {
-                reason: "不支持的应用管理命令格式".to_string(),
+            match validator.rules.check_allow(name, arg_words, work_dir) {
+                Ok(detail) => detail,
+                Err(_) if validator.is_pid_file_cleanup(&program, &args, cwd) => {
+                    "在应用目录中删除 pid 文件".to_string()
+                }
+                Err(reason) => return Err(reason),
+            }
+        };
+
+        if program == "cd" {
+            self.changed_dir = true;
+            return Ok((detail, change_dir(&args, cwd)));
+        }
+        if SHELL_INTERPRETERS.contains(&program.as_str()) {
+            let script = self.check_interpreter_script(&args, arg_words, cwd)?;
+            detail.push_str(&format!("，脚本 {} 位于允许的目录", script.display()));
+        }
+        Ok((detail, cwd.clone()))
+    }
+
+    /// 规则匹配使用的当前目录
+    fn work_dir<'p>(&self, cwd: &'p Option<PathBuf>) -> WorkDir<'p> {
+        match cwd {
+            Some(cwd) => WorkDir::Known(cwd),
+            None if self.changed_dir => WorkDir::Unknown,
+            None => WorkDir::Initial,
+        }
+    }
+
+    fn check_substitutions(&mut self, word: &shell::Word, cwd: &Option<PathBuf>) -> Result<(), String> {
+        for list in word.substitutions() {
+            self.check_list(list, cwd.clone())?;
+        }
+        Ok(())
+    }
+
+    /// 只允许读取文件、复制/关闭文件描述符和把输出丢到 /dev/null；读取的文件与命令参数一样受 deny 规则约束。
+    /// program 为读取输入的命令，复合命令的重定向为 None
+    fn check_redirects(&mut self, redirects: &[shell::Redirect], program: Option<&str>, cwd: &Option<PathBuf>) -> Result<(), String> {
+        use shell::RedirectOp;
+        for redirect in redirects {
+            self.check_substitutions(&redirect.target, cwd)?;
+            let target = redirect.target.literal();
+            let allowed = match redirect.op {
+                RedirectOp::Input => {
+                    self.validator.rules.check_input_redirect(program, &redirect.target, self.work_dir(cwd))?;
+                    true
+                }
+                RedirectOp::DupInput | RedirectOp::DupOutput
+                    if target.as_deref().is_some_and(|t| t == "-" || t.chars().all(|c| c.is_ascii_digit())) => true,
+                RedirectOp::DupInput => false,
+                RedirectOp::Output | RedirectOp::Append | RedirectOp::OutputAll | RedirectOp::DupOutput => {
+                    target.as_deref() == Some("/dev/null")
+                }
+            };
+            if !allowed {
+                return Err(format!("只允许将输出重定向到 /dev/null: {}", redirect.target.raw));
             }
         }
+        Ok(())
+    }
+
+    /// bash/sh 只能直接执行允许目录中的脚本文件，不接受 -c、选项或从标准输入读取命令
+    fn check_interpreter_script(&self, args: &[Option<String>], arg_words: &[shell::Word], cwd: &Option<PathBuf>) -> Result<PathBuf, String> {
+        let script = match args.first() {
+            None => return Err("Shell 只能用于执行脚本文件".to_string()),
+            Some(None) => return Err(format!("无法确定要执行的脚本: {}", arg_words[0].raw)),
+            Some(Some(script)) if script.starts_with('-') => {
+                return Err(format!("Shell 只能直接执行脚本文件，不支持选项: {}", script));
+            }
+            Some(Some(script)) => script,
+        };
+        let path = Path::new(script);
+        let resolved = if path.is_absolute() {
+            path.to_path_buf()
+        } else if path.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err("脚本路径包含危险的路径遍历字符".to_string());
+        } else if let Some(cwd) = cwd {
+            cwd.join(path)
+        } else {
+            return Err(format!("相对路径的脚本需要先用 `cd <允许的目录> &&` 进入脚本目录: {}", script));
+        };
+        match self.validator.validate_script_path(&resolved.to_string_lossy()) {
+            ValidationResult::Allowed => Ok(resolved),
+            ValidationResult::Blocked { reason } => Err(reason),
+        }
+    }
+}
+
+/// `cd` 之后确定的当前目录：只认绝对路径或在已知目录下的相对路径，且不含 `..`
+fn change_dir(args: &[Option<String>], cwd: &Option<PathBuf>) -> Option<PathBuf> {
+    let [Some(dir)] = args else { return None };
+    let path = Path::new(dir);
+    if path.components().any(|c| matches!(c, Component::ParentDir | Component::CurDir)) {
+        return None;
+    }
+    if path.is_absolute() {
+        Some(path.to_path_buf())
+    } else {
+        cwd.as_ref().map(|cwd| cwd.join(path))
     }
 }
 
@@ -574,11 +551,212 @@ mod tests {
         }
     }
 
+    fn blocked_reason(validator: &CommandValidator, command: &str) -> String {
+        match validator.validate(command) {
+            ValidationResult::Blocked { reason } => reason,
+            ValidationResult::Allowed => panic!("Should be blocked: {}", command),
+        }
+    }
+
+    fn assert_allowed(validator: &CommandValidator, command: &str) {
+        if let ValidationResult::Blocked { reason } = validator.validate(command) {
+            panic!("Should be allowed: {} ({})", command, reason);
+        }
+    }
+
+    #[test]
+    fn test_every_command_in_line_validated() {
+        let validator = CommandValidator::new();
+
+        assert_allowed(&validator, "ps aux | grep nginx | wc -l");
+        assert_allowed(&validator, "df -h && free -m; uptime");
+        // 危险模式只匹配命令和参数，不再误伤普通文本
+        assert_allowed(&validator, "echo format");
+        assert_allowed(&validator, "grep 'shutdown' /var/log/syslog");
+
+        assert!(blocked_reason(&validator, "ps aux; rm -rf /").contains("危险模式"));
+        assert!(blocked_reason(&validator, "cat x | sh").contains("脚本"));
+        assert!(blocked_reason(&validator, "ls $(python3 x.py)").contains("不在允许列表中"));
+        assert!(blocked_reason(&validator, "ls && (true || mkfs /dev/sda)").contains("危险模式"));
+        assert!(blocked_reason(&validator, "r''m -rf /").contains("危险模式"));
+        assert!(blocked_reason(&validator, "systemctl $(echo stop) nginx").contains("无法确定参数"));
+        assert!(blocked_reason(&validator, "LD_PRELOAD=/tmp/x.so ls").contains("环境变量"));
+        assert!(blocked_reason(&validator, "$CMD -la").contains("无法确定"));
+        assert!(blocked_reason(&validator, "env rm x").contains("env"));
+        assert!(blocked_reason(&validator, "find / -name x -delete").contains("-delete"));
+        assert!(blocked_reason(&validator, "echo `id`").contains("无法解析"));
+        assert!(blocked_reason(&validator, "sleep 60 &").contains("无法解析"));
+    }
+
+    #[test]
+    fn test_builtin_policy_blocks_writes_and_sensitive_reads() {
+        let validator = CommandValidator::new();
+
+        assert_allowed(&validator, "sort -n /var/log/app.log");
+        assert_allowed(&validator, "kill -TERM 1234");
+        assert_allowed(&validator, "journalctl -n 20");
+        assert_allowed(&validator, "grep -r ERROR /var/log");
+
+        assert!(blocked_reason(&validator, "sort -o /etc/cron.d/x /tmp/a").contains("危险模式"));
+        assert!(blocked_reason(&validator, "sort --output=/etc/cron.d/x /tmp/a").contains("危险模式"));
+        assert!(blocked_reason(&validator, "kill -n 9 1").contains("危险模式"));
+        assert!(blocked_reason(&validator, "kill -s KILL 1").contains("危险模式"));
+        assert!(blocked_reason(&validator, "kill --signal=9 1").contains("危险模式"));
+        assert!(blocked_reason(&validator, "journalctl --vacuum-size=1").contains("危险模式"));
+        assert!(blocked_reason(&validator, "journalctl --rotate").contains("危险模式"));
+        assert!(blocked_reason(&validator, "journalctl --flush").contains("危险模式"));
+        assert!(blocked_reason(&validator, "history -c").contains("不在允许列表中"));
+        assert!(blocked_reason(&validator, "grep -r PRIVATE /root").contains("危险模式"));
+        assert!(blocked_reason(&validator, "cat /proc/self/root/etc/shadow").contains("危险模式"));
+        assert!(blocked_reason(&validator, "cd /proc/self/root && cat etc/shadow").contains("危险模式"));
+        assert!(blocked_reason(&validator, "cat /proc/self/cwd/.ssh/id_rsa").contains("无法确定"));
+    }
+
+    #[test]
+    fn test_builtin_policy_blocks_host_changes() {
+        let validator = CommandValidator::new();
+
+        assert_allowed(&validator, "sort /var/log/app.log | uniq -c");
+        assert_allowed(&validator, "uniq -d < /var/log/app.log");
+        assert_allowed(&validator, "sar -u 1 1");
+        assert_allowed(&validator, "hostname");
+        assert_allowed(&validator, "hostname -I");
+        assert_allowed(&validator, "date +%s");
+        assert_allowed(&validator, "date --date=yesterday");
+        assert_allowed(&validator, "ip addr show");
+        assert_allowed(&validator, "ip -br link");
+        assert_allowed(&validator, "ip route get 8.8.8.8");
+        assert_allowed(&validator, "ifconfig");
+        assert_allowed(&validator, "ifconfig eth0");
+
+        // 写文件
+        assert!(blocked_reason(&validator, "uniq /var/log/a /etc/cron.d/x").contains("不符合允许规则"));
+        assert!(blocked_reason(&validator, "sar -o /tmp/x 1 1").contains("危险模式"));
+        assert!(blocked_reason(&validator, "less -o /tmp/x /var/log/syslog").contains("危险模式"));
+        // 向所有进程或 init 发送信号
+        assert!(blocked_reason(&validator, "kill -TERM -1").contains("危险模式"));
+        assert!(blocked_reason(&validator, "kill -- -1").contains("危险模式"));
+        assert!(blocked_reason(&validator, "kill 1").contains("危险模式"));
+        assert!(blocked_reason(&validator, "ss -K dst 10.0.0.1").contains("危险模式"));
+        // 改变主机状态
+        assert!(blocked_reason(&validator, "hostname evil").contains("不符合允许规则"));
+        assert!(blocked_reason(&validator, "date -s 2020-01-01").contains("不符合允许规则"));
+        assert!(blocked_reason(&validator, "date 010100002020").contains("不符合允许规则"));
+        assert!(blocked_reason(&validator, "ip link set eth0 down").contains("不符合允许规则"));
+        assert!(blocked_reason(&validator, "ip addr add 10.0.0.2/24 dev eth0").contains("不符合允许规则"));
+        assert!(blocked_reason(&validator, "ip route add default via 10.0.0.1").contains("不符合允许规则"));
+        assert!(blocked_reason(&validator, "ip netns exec x sh").contains("不符合允许规则"));
+        assert!(blocked_reason(&validator, "ifconfig eth0 down").contains("不符合允许规则"));
+    }
+
     #[test]
-    fn test_command_sanitization() {
+    fn test_builtin_policy_allows_only_service_status() {
         let validator = CommandValidator::new();
-        let sanitized = validator.sanitize_command("ps aux; rm -rf /");
-        assert_eq!(sanitized, "ps aux  rm -rf /");
+
+        assert_allowed(&validator, "service nginx status");
+        assert_allowed(&validator, "service --status-all");
+
+        for command in [
+            "service nginx restart",
+            "service nginx force-reload",
+            "service nginx try-restart",
+            "service nginx condrestart",
+            "service nginx $(echo status)",
+            "service nginx",
+            "service --status-all nginx",
+        ] {
+            assert!(blocked_reason(&validator, command).contains("不符合允许规则"), "{}", command);
+        }
+    }
+
+    #[test]
+    fn test_redirects_restricted() {
+        let validator = CommandValidator::new();
+
+        assert_allowed(&validator, "ps -p 1 > /dev/null 2>&1");
+        assert_allowed(&validator, "grep error < /var/log/syslog");
+        // 输入重定向读取的文件同样受路径禁止规则约束
+        assert!(blocked_reason(&validator, "cat < /etc/shadow").contains("危险模式"));
+        assert!(blocked_reason(&validator, "cat 0</etc/shadow").contains("危险模式"));
+        assert!(blocked_reason(&validator, "{ cat; } < /root/.ssh/id_rsa").contains("危险模式"));
+        assert!(blocked_reason(&validator, "cat < $FILE").contains("无法确定"));
+        assert!(blocked_reason(&validator, "cd /etc; cat < shadow").contains("无法确定"));
+        assert!(blocked_reason(&validator, "echo x > /etc/passwd").contains("/dev/null"));
+        assert!(blocked_reason(&validator, "cat a >> b").contains("/dev/null"));
+    }
+
+    #[test]
+    fn test_scripts_resolved_against_cd() {
+        let validator = CommandValidator::new();
+
+        assert_allowed(&validator, "cd /opt/ops-scripts/demo && bash demo.sh start");
+        assert_allowed(&validator, "cd /opt/ops-scripts/demo && (bash demo.sh stop || true) && sleep 1 && bash demo.sh start");
+        assert_allowed(&validator, "cd /opt/ops-scripts/demo && if [ -f demo.pid ]; then pid=$(cat demo.pid); if ps -p $pid > /dev/null 2>&1; then echo 'running: '$pid; fi; fi");
+        assert_allowed(&validator, "bash /opt/ops-scripts/check.sh");
+
+        // cd 可能失败时不能确定脚本位置
+        assert!(blocked_reason(&validator, "cd /opt/ops-scripts/demo; bash demo.sh").contains("cd"));
+        assert!(blocked_reason(&validator, "bash demo.sh").contains("cd"));
+        assert!(blocked_reason(&validator, "cd /etc && bash x.sh").contains("允许的目录"));
+        // /tmp 任何用户都可写，默认不再允许执行其中的脚本
+        assert!(blocked_reason(&validator, "cd /tmp/apps/demo && bash demo.sh start").contains("允许的目录"));
+        assert!(blocked_reason(&validator, "cd /opt/ops-scripts/demo && bash ../../../etc/x.sh").contains("路径遍历"));
+        assert!(blocked_reason(&validator, "bash -c 'ls'").contains("选项"));
+        // 只能在脚本目录中删除 pid 文件
+        assert!(blocked_reason(&validator, "cd /etc && rm -f demo.pid").contains("不在允许列表中"));
+        assert!(blocked_reason(&validator, "cd /opt/ops-scripts/demo && rm -f demo.conf").contains("不在允许列表中"));
+        assert!(blocked_reason(&validator, "cd /opt/ops-scripts/demo && kill $(cat demo.pid)").contains("无法确定参数"));
+        assert!(blocked_reason(&validator, "cd /opt/ops-scripts/demo && kill -- $(cat demo.pid)").contains("无法确定参数"));
+    }
+
+    #[test]
+    fn test_relative_paths_unknown_after_uncertain_cd() {
+        let validator = CommandValidator::new();
+
+        assert_allowed(&validator, "cat hosts");
+        assert_allowed(&validator, "cd /var/log && cat syslog");
+        assert!(blocked_reason(&validator, "cd /etc && cat shadow").contains("危险模式"));
+        // cd 可能没有生效时，当前目录不确定，相对路径可能指向敏感文件
+        assert!(blocked_reason(&validator, "cd /etc; cat shadow").contains("无法确定"));
+        assert!(blocked_reason(&validator, "cd /root/.ssh || cat id_rsa").contains("无法确定"));
+        assert!(blocked_reason(&validator, "if cd /etc; then cat shadow; fi").contains("无法确定"));
+    }
+
+    #[test]
+    fn test_local_policy_only_tightens() {
+        let server = CommandPolicy::from_toml(r#"
+            [scripts]
+            allowed_dirs = ["/opt/ops-scripts"]
+            allowed_extensions = ["sh"]
+
+            [[rules]]
+            action = "allow"
+            programs = ["ls", "uptime", "bash"]
+        "#).unwrap();
+        let local = CommandPolicy::from_toml(r#"
+            [scripts]
+            allowed_dirs = ["/opt/ops-scripts", "/home/ops/scripts"]
+            allowed_extensions = ["sh"]
+
+            [[rules]]
+            action = "allow"
+            programs = ["ls", "whoami", "bash"]
+
+            [[rules]]
+            action = "deny"
+            programs = ["ls"]
+            args = ["-R"]
+        "#).unwrap();
+        let validator = CommandValidator::from_policy(server).unwrap()
+            .restricted_by(CommandValidator::from_policy(local).unwrap());
+
+        assert_allowed(&validator, "ls -la");
+        assert_allowed(&validator, "bash /opt/ops-scripts/check.sh");
+        // 本地策略可以禁止服务端允许的命令，但不能放开服务端不允许的命令和目录
+        assert!(blocked_reason(&validator, "ls -R").starts_with("本地策略"));
+        assert!(blocked_reason(&validator, "uptime").starts_with("本地策略"));
+        assert!(!blocked_reason(&validator, "whoami").starts_with("本地策略"));
+        assert!(!blocked_reason(&validator, "bash /home/ops/scripts/x.sh").starts_with("本地策略"));
     }
 
     #[test]
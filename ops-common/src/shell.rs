@@ -0,0 +1,748 @@
+// POSIX Shell 命令行解析：把命令拆成命令序列、管道、重定向和命令替换，供命令校验逐条检查；
+// 只支持运维命令常用的语法，for/while/case、heredoc、反引号等无法归类的结构直接报错
+
+/// 嵌套（子 shell、命令替换、if）的最大层数
+const MAX_DEPTH: usize = 16;
+
+/// 以 `;` 或换行分隔的命令序列
+pub type List = Vec<AndOr>;
+
+/// 以 `&&` / `||` 连接的管道
+#[derive(Debug, Clone, PartialEq)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    pub negated: bool,
+    pub commands: Vec<Command>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Simple(SimpleCommand),
+    /// `( ... )`，在子 shell 中执行
+    Subshell { body: List, redirects: Vec<Redirect> },
+    /// `{ ...; }`，在当前 shell 中执行
+    Group { body: List, redirects: Vec<Redirect> },
+    /// `if ...; then ...; [elif ...; then ...;] [else ...;] fi`
+    If { branches: Vec<(List, List)>, else_body: Option<List>, redirects: Vec<Redirect> },
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SimpleCommand {
+    /// 命令前的 `NAME=value` 赋值
+    pub assignments: Vec<Word>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub fd: Option<u32>,
+    pub op: RedirectOp,
+    pub target: Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectOp {
+    Input,       // <
+    Output,      // > 或 >|
+    Append,      // >>
+    OutputAll,   // &> 或 &>>
+    DupInput,    // <&
+    DupOutput,   // >&
+}
+
+/// 一个 shell 单词，raw 为原始文本，用于提示信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    pub raw: String,
+    pub parts: Vec<WordPart>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WordPart {
+    /// 文本，quoted 表示来自引号或转义，不参与通配和波浪号展开
+    Text { text: String, quoted: bool },
+    /// `$NAME` / `${NAME}`
+    Param(String),
+    /// `$( ... )`
+    CommandSubst(List),
+}
+
+impl Word {
+    /// 不含任何展开时返回去掉引号后的值
+    pub fn literal(&self) -> Option<String> {
+        let mut value = String::new();
+        for (index, part) in self.parts.iter().enumerate() {
+            match part {
+                WordPart::Text { text, quoted: true } => value.push_str(text),
+                WordPart::Text { text, quoted: false } => {
+                    if has_unquoted_expansion(text, index == 0) {
+                        return None;
+                    }
+                    value.push_str(text);
+                }
+                _ => return None,
+            }
+        }
+        Some(value)
+    }
+
+    /// 单词中（含嵌套的）命令替换
+    pub fn substitutions(&self) -> impl Iterator<Item = &List> {
+        self.parts.iter().filter_map(|part| match part {
+            WordPart::CommandSubst(list) => Some(list),
+            _ => None,
+        })
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.parts.as_slice(), [WordPart::Text { text, quoted: false }] if text == keyword)
+    }
+}
+
+/// 未加引号的文本是否会被通配、波浪号或花括号展开
+fn has_unquoted_expansion(text: &str, leading: bool) -> bool {
+    if text.contains(['*', '?']) || (leading && text.starts_with('~')) {
+        return true;
+    }
+    if let Some(open) = text.find('[')
+        && text[open + 1..].contains(']')
+    {
+        return true;
+    }
+    if let Some(open) = text.find('{')
+        && let Some(close) = text[open..].find('}')
+    {
+        let inner = &text[open + 1..open + close];
+        return inner.contains(',') || inner.contains("..");
+    }
+    false
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(Word),
+    Redirect(Option<u32>, RedirectOp),
+    Pipe,
+    And,
+    Or,
+    Semi,
+    Newline,
+    Background,
+    OpenParen,
+    CloseParen,
+}
+
+/// 解析命令行
+pub fn parse(input: &str) -> Result<List, String> {
+    let mut parser = Parser { chars: input.chars().collect(), pos: 0, peeked: None, depth: 0 };
+    let list = parser.parse_list()?;
+    match parser.next_token()? {
+        None => Ok(list),
+        Some(token) => Err(format!("语法错误: 意外的 {}", describe(&token))),
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => format!("'{}'", word.raw),
+        Token::Redirect(..) => "重定向".to_string(),
+        Token::Pipe => "'|'".to_string(),
+        Token::And => "'&&'".to_string(),
+        Token::Or => "'||'".to_string(),
+        Token::Semi => "';'".to_string(),
+        Token::Newline => "换行".to_string(),
+        Token::Background => "'&'".to_string(),
+        Token::OpenParen => "'('".to_string(),
+        Token::CloseParen => "')'".to_string(),
+    }
+}
+
+const UNSUPPORTED_KEYWORDS: &[&str] = &["for", "while", "until", "case", "select", "function", "[[", "coproc"];
+const LIST_TERMINATORS: &[&str] = &["then", "elif", "else", "fi", "}", "do", "done", "esac"];
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    peeked: Option<Token>,
+    depth: usize,
+}
+
+impl Parser {
+    // ---- 语法 ----
+
+    fn parse_list(&mut self) -> Result<List, String> {
+        let mut list = Vec::new();
+        loop {
+            self.skip_separators()?;
+            match self.peek()? {
+                None | Some(Token::CloseParen) => break,
+                Some(Token::Word(word)) if LIST_TERMINATORS.iter().any(|k| word.is_keyword(k)) => break,
+                _ => {}
+            }
+            list.push(self.parse_and_or()?);
+            match self.peek()? {
+                Some(Token::Semi | Token::Newline) => {}
+                Some(Token::Background) => return Err("不支持后台执行 '&'".to_string()),
+                _ => break,
+            }
+        }
+        Ok(list)
+    }
+
+    fn skip_separators(&mut self) -> Result<(), String> {
+        while matches!(self.peek()?, Some(Token::Semi | Token::Newline)) {
+            self.next_token()?;
+        }
+        Ok(())
+    }
+
+    fn skip_newlines(&mut self) -> Result<(), String> {
+        while matches!(self.peek()?, Some(Token::Newline)) {
+            self.next_token()?;
+        }
+        Ok(())
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr, String> {
+        let first = self.parse_pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = match self.peek()? {
+                Some(Token::And) => Connector::And,
+                Some(Token::Or) => Connector::Or,
+                _ => break,
+            };
+            self.next_token()?;
+            self.skip_newlines()?;
+            rest.push((connector, self.parse_pipeline()?));
+        }
+        Ok(AndOr { first, rest })
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline, String> {
+        let negated = matches!(self.peek()?, Some(Token::Word(word)) if word.is_keyword("!"));
+        if negated {
+            self.next_token()?;
+        }
+        let mut commands = vec![self.parse_command()?];
+        while matches!(self.peek()?, Some(Token::Pipe)) {
+            self.next_token()?;
+            self.skip_newlines()?;
+            commands.push(self.parse_command()?);
+        }
+        Ok(Pipeline { negated, commands })
+    }
+
+    fn parse_command(&mut self) -> Result<Command, String> {
+        match self.peek()? {
+            Some(Token::OpenParen) => {
+                self.next_token()?;
+                if matches!(self.peek()?, Some(Token::OpenParen)) {
+                    return Err("不支持算术命令 '(( ))'".to_string());
+                }
+                let body = self.parse_nested(|parser| parser.parse_list())?;
+                self.expect(Token::CloseParen, "')'")?;
+                if body.is_empty() {
+                    return Err("语法错误: 空的子 shell".to_string());
+                }
+                Ok(Command::Subshell { body, redirects: self.parse_redirects()? })
+            }
+            Some(Token::Word(word)) if word.is_keyword("{") => {
+                self.next_token()?;
+                let body = self.parse_nested(|parser| parser.parse_list())?;
+                self.expect_keyword("}")?;
+                if body.is_empty() {
+                    return Err("语法错误: 空的命令组".to_string());
+                }
+                Ok(Command::Group { body, redirects: self.parse_redirects()? })
+            }
+            Some(Token::Word(word)) if word.is_keyword("if") => {
+                self.next_token()?;
+                self.parse_nested(|parser| parser.parse_if())
+            }
+            Some(Token::Word(word)) => {
+                if let Some(keyword) = UNSUPPORTED_KEYWORDS.iter().find(|k| word.is_keyword(k)) {
+                    return Err(format!("不支持的 Shell 结构: {}", keyword));
+                }
+                if LIST_TERMINATORS.iter().any(|k| word.is_keyword(k)) {
+                    return Err(format!("语法错误: 意外的 '{}'", word.raw));
+                }
+                self.parse_simple()
+            }
+            Some(Token::Redirect(..)) => self.parse_simple(),
+            Some(token) => Err(format!("语法错误: 意外的 {}", describe(&token))),
+            None => Err("语法错误: 命令不完整".to_string()),
+        }
+    }
+
+    fn parse_if(&mut self) -> Result<Command, String> {
+        let mut branches = Vec::new();
+        let mut else_body = None;
+        loop {
+            let condition = self.parse_list()?;
+            self.expect_keyword("then")?;
+            let body = self.parse_list()?;
+            if condition.is_empty() || body.is_empty() {
+                return Err("语法错误: if 的条件或分支为空".to_string());
+            }
+            branches.push((condition, body));
+            match self.next_token()? {
+                Some(Token::Word(word)) if word.is_keyword("elif") => continue,
+                Some(Token::Word(word)) if word.is_keyword("else") => {
+                    let body = self.parse_list()?;
+                    if body.is_empty() {
+                        return Err("语法错误: else 分支为空".to_string());
+                    }
+                    else_body = Some(body);
+                    self.expect_keyword("fi")?;
+                    break;
+                }
+                Some(Token::Word(word)) if word.is_keyword("fi") => break,
+                _ => return Err("语法错误: if 缺少 fi".to_string()),
+            }
+        }
+        Ok(Command::If { branches, else_body, redirects: self.parse_redirects()? })
+    }
+
+    fn parse_simple(&mut self) -> Result<Command, String> {
+        let mut command = SimpleCommand::default();
+        loop {
+            match self.peek()? {
+                Some(Token::Word(_)) => {
+                    let Some(Token::Word(word)) = self.next_token()? else { unreachable!() };
+                    if command.words.is_empty() && is_assignment(&word) {
+                        command.assignments.push(word);
+                    } else {
+                        command.words.push(word);
+                    }
+                }
+                Some(Token::Redirect(..)) => command.redirects.push(self.parse_redirect()?),
+                Some(Token::OpenParen) if !command.words.is_empty() => {
+                    return Err("不支持定义函数".to_string());
+                }
+                _ => break,
+            }
+        }
+        Ok(Command::Simple(command))
+    }
+
+    fn parse_redirects(&mut self) -> Result<Vec<Redirect>, String> {
+        let mut redirects = Vec::new();
+        while matches!(self.peek()?, Some(Token::Redirect(..))) {
+            redirects.push(self.parse_redirect()?);
+        }
+        Ok(redirects)
+    }
+
+    fn parse_redirect(&mut self) -> Result<Redirect, String> {
+        let Some(Token::Redirect(fd, op)) = self.next_token()? else { unreachable!() };
+        match self.next_token()? {
+            Some(Token::Word(target)) => Ok(Redirect { fd, op, target }),
+            _ => Err("语法错误: 重定向缺少目标".to_string()),
+        }
+    }
+
+    fn parse_nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T, String>) -> Result<T, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err("命令嵌套层数过多".to_string());
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn expect(&mut self, expected: Token, name: &str) -> Result<(), String> {
+        match self.next_token()? {
+            Some(token) if token == expected => Ok(()),
+            _ => Err(format!("语法错误: 缺少 {}", name)),
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        match self.next_token()? {
+            Some(Token::Word(word)) if word.is_keyword(keyword) => Ok(()),
+            _ => Err(format!("语法错误: 缺少 '{}'", keyword)),
+        }
+    }
+
+    // ---- 词法 ----
+
+    fn peek(&mut self) -> Result<Option<Token>, String> {
+        if self.peeked.is_none() {
+            self.peeked = self.lex()?;
+        }
+        Ok(self.peeked.clone())
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, String> {
+        match self.peeked.take() {
+            Some(token) => Ok(Some(token)),
+            None => self.lex(),
+        }
+    }
+
+    fn current(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        text.chars().enumerate().all(|(i, c)| self.at(i) == Some(c))
+    }
+
+    fn lex(&mut self) -> Result<Option<Token>, String> {
+        loop {
+            match self.current() {
+                Some(' ' | '\t') => self.pos += 1,
+                Some('\\') if self.at(1) == Some('\n') => self.pos += 2,
+                Some('#') => {
+                    while self.current().is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+        let Some(c) = self.current() else { return Ok(None) };
+
+        let operators: &[(&str, Option<Token>)] = &[
+            ("<<", None),
+            ("<>", None),
+            ("<(", None),
+            (">(", None),
+            (";;", None),
+            ("&&", Some(Token::And)),
+            ("||", Some(Token::Or)),
+            ("|&", Some(Token::Pipe)),
+            ("&>>", Some(Token::Redirect(None, RedirectOp::OutputAll))),
+            ("&>", Some(Token::Redirect(None, RedirectOp::OutputAll))),
+            ("|", Some(Token::Pipe)),
+            (";", Some(Token::Semi)),
+            ("&", Some(Token::Background)),
+            ("(", Some(Token::OpenParen)),
+            (")", Some(Token::CloseParen)),
+            ("\n", Some(Token::Newline)),
+        ];
+        for (text, token) in operators {
+            if self.starts_with(text) {
+                return match token {
+                    Some(token) => {
+                        self.pos += text.chars().count();
+                        Ok(Some(token.clone()))
+                    }
+                    None => Err(format!("不支持的 Shell 语法: '{}'", text)),
+                };
+            }
+        }
+        if c == '<' || c == '>' {
+            return self.lex_redirect(None).map(Some);
+        }
+        self.lex_word().map(Some)
+    }
+
+    fn lex_redirect(&mut self, fd: Option<u32>) -> Result<Token, String> {
+        if self.starts_with("<<") || self.starts_with("<>") || self.starts_with("<(") || self.starts_with(">(") {
+            let text: String = self.chars[self.pos..self.pos + 2].iter().collect();
+            return Err(format!("不支持的 Shell 语法: '{}'", text));
+        }
+        let (op, len) = if self.starts_with(">>") {
+            (RedirectOp::Append, 2)
+        } else if self.starts_with(">|") {
+            (RedirectOp::Output, 2)
+        } else if self.starts_with(">&") {
+            (RedirectOp::DupOutput, 2)
+        } else if self.starts_with("<&") {
+            (RedirectOp::DupInput, 2)
+        } else if self.starts_with(">") {
+            (RedirectOp::Output, 1)
+        } else {
+            (RedirectOp::Input, 1)
+        };
+        self.pos += len;
+        Ok(Token::Redirect(fd, op))
+    }
+
+    fn lex_word(&mut self) -> Result<Token, String> {
+        let start = self.pos;
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        while let Some(c) = self.current() {
+            match c {
+                ' ' | '\t' | '\n' | '|' | '&' | ';' | '(' | ')' => break,
+                '<' | '>' => {
+                    // 紧跟在数字后的重定向指定文件描述符，如 2>&1
+                    if parts.is_empty() && !text.is_empty() && text.chars().all(|c| c.is_ascii_digit()) {
+                        let fd = text.parse().map_err(|_| format!("无效的文件描述符: {}", text))?;
+                        return self.lex_redirect(Some(fd));
+                    }
+                    break;
+                }
+                '\\' => {
+                    self.pos += 1;
+                    match self.current() {
+                        Some('\n') => self.pos += 1,
+                        Some(escaped) => {
+                            flush(&mut parts, &mut text);
+                            parts.push(WordPart::Text { text: escaped.to_string(), quoted: true });
+                            self.pos += 1;
+                        }
+                        None => return Err("语法错误: 命令以 '\\' 结尾".to_string()),
+                    }
+                }
+                '\'' => {
+                    flush(&mut parts, &mut text);
+                    self.pos += 1;
+                    let mut quoted = String::new();
+                    loop {
+                        match self.current() {
+                            Some('\'') => break,
+                            Some(c) => quoted.push(c),
+                            None => return Err("语法错误: 单引号未闭合".to_string()),
+                        }
+                        self.pos += 1;
+                    }
+                    self.pos += 1;
+                    parts.push(WordPart::Text { text: quoted, quoted: true });
+                }
+                '"' => {
+                    flush(&mut parts, &mut text);
+                    self.pos += 1;
+                    self.lex_double_quoted(&mut parts)?;
+                }
+                '`' => return Err("不支持反引号命令替换，请使用 $(...)".to_string()),
+                '$' => {
+                    if let Some(part) = self.lex_dollar()? {
+                        flush(&mut parts, &mut text);
+                        parts.push(part);
+                    } else {
+                        text.push('$');
+                    }
+                }
+                _ => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        flush(&mut parts, &mut text);
+        let raw = self.chars[start..self.pos].iter().collect();
+        Ok(Token::Word(Word { raw, parts }))
+    }
+
+    fn lex_double_quoted(&mut self, parts: &mut Vec<WordPart>) -> Result<(), String> {
+        let mut text = String::new();
+        loop {
+            match self.current() {
+                Some('"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some('\\') => {
+                    match self.at(1) {
+                        Some(c @ ('$' | '`' | '"' | '\\')) => text.push(c),
+                        Some('\n') => {}
+                        Some(c) => {
+                            text.push('\\');
+                            text.push(c);
+                        }
+                        None => return Err("语法错误: 双引号未闭合".to_string()),
+                    }
+                    self.pos += 2;
+                }
+                Some('`') => return Err("不支持反引号命令替换，请使用 $(...)".to_string()),
+                Some('$') => {
+                    if let Some(part) = self.lex_dollar()? {
+                        if !text.is_empty() {
+                            parts.push(WordPart::Text { text: std::mem::take(&mut text), quoted: true });
+                        }
+                        parts.push(part);
+                    } else {
+                        text.push('$');
+                    }
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+                None => return Err("语法错误: 双引号未闭合".to_string()),
+            }
+        }
+        // 空字符串 "" 也是一个（空）参数
+        parts.push(WordPart::Text { text, quoted: true });
+        Ok(())
+    }
+
+    /// 解析 `$` 开头的展开，不构成展开时返回 None，`$` 按普通字符处理
+    fn lex_dollar(&mut self) -> Result<Option<WordPart>, String> {
+        match self.at(1) {
+            Some('(') if self.at(2) == Some('(') => Err("不支持算术展开 $((...))".to_string()),
+            Some('(') => {
+                self.pos += 2;
+                debug_assert!(self.peeked.is_none());
+                let list = self.parse_nested(|parser| parser.parse_list())?;
+                if !matches!(self.next_token()?, Some(Token::CloseParen)) {
+                    return Err("语法错误: $( 缺少 ')'".to_string());
+                }
+                if list.is_empty() {
+                    return Err("语法错误: 空的命令替换".to_string());
+                }
+                Ok(Some(WordPart::CommandSubst(list)))
+            }
+            Some('{') => {
+                let Some(end) = self.chars[self.pos..].iter().position(|&c| c == '}') else {
+                    return Err("语法错误: ${ 缺少 '}'".to_string());
+                };
+                let name: String = self.chars[self.pos + 2..self.pos + end].iter().collect();
+                if !is_parameter_name(&name) {
+                    return Err(format!("不支持的参数展开: ${{{}}}", name));
+                }
+                self.pos += end + 1;
+                Ok(Some(WordPart::Param(name)))
+            }
+            Some('\'') => Err("不支持 $'...' 引号".to_string()),
+            Some(c @ ('?' | '$' | '#' | '@' | '*' | '!' | '-' | '0'..='9')) => {
+                self.pos += 2;
+                Ok(Some(WordPart::Param(c.to_string())))
+            }
+            Some(c) if c == '_' || c.is_ascii_alphabetic() => {
+                self.pos += 1;
+                let mut name = String::new();
+                while let Some(c) = self.current().filter(|c| *c == '_' || c.is_ascii_alphanumeric()) {
+                    name.push(c);
+                    self.pos += 1;
+                }
+                Ok(Some(WordPart::Param(name)))
+            }
+            _ => {
+                self.pos += 1;
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn flush(parts: &mut Vec<WordPart>, text: &mut String) {
+    if !text.is_empty() {
+        parts.push(WordPart::Text { text: std::mem::take(text), quoted: false });
+    }
+}
+
+fn is_parameter_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => chars.all(|c| c == '_' || c.is_ascii_alphanumeric()),
+        Some(c) if c.is_ascii_digit() => chars.all(|c| c.is_ascii_digit()),
+        Some('?' | '$' | '#' | '@' | '*' | '!' | '-') => chars.next().is_none(),
+        _ => false,
+    }
+}
+
+/// `NAME=value` 形式的赋值
+fn is_assignment(word: &Word) -> bool {
+    let Some(WordPart::Text { text, quoted: false }) = word.parts.first() else { return false };
+    text.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simple(command: &Command) -> &SimpleCommand {
+        match command {
+            Command::Simple(simple) => simple,
+            other => panic!("expected simple command, got {:?}", other),
+        }
+    }
+
+    fn literals(command: &Command) -> Vec<String> {
+        simple(command).words.iter().map(|word| word.literal().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse_sequences_and_pipelines() {
+        let list = parse("ps aux | grep 'ops server' && echo ok; df -h").unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].first.commands.len(), 2);
+        assert_eq!(literals(&list[0].first.commands[1]), vec!["grep", "ops server"]);
+        assert_eq!(list[0].rest[0].0, Connector::And);
+        assert_eq!(literals(&list[1].first.commands[0]), vec!["df", "-h"]);
+
+        // 引号和转义不改变单词边界之外的含义
+        let list = parse(r#"echo "a;b" 'c|d' e\&f"#).unwrap();
+        assert_eq!(literals(&list[0].first.commands[0]), vec!["echo", "a;b", "c|d", "e&f"]);
+    }
+
+    #[test]
+    fn test_parse_redirects_and_substitutions() {
+        let list = parse("pid=$(cat app.pid); ps -p $pid > /dev/null 2>&1").unwrap();
+        let assignment = simple(&list[0].first.commands[0]);
+        assert!(assignment.words.is_empty());
+        let inner = assignment.assignments[0].substitutions().next().unwrap();
+        assert_eq!(literals(&inner[0].first.commands[0]), vec!["cat", "app.pid"]);
+
+        let ps = simple(&list[1].first.commands[0]);
+        assert_eq!(ps.words[2].literal(), None);
+        assert_eq!(ps.redirects.len(), 2);
+        assert_eq!(ps.redirects[0].op, RedirectOp::Output);
+        assert_eq!(ps.redirects[0].target.literal().as_deref(), Some("/dev/null"));
+        assert_eq!(ps.redirects[1].fd, Some(2));
+        assert_eq!(ps.redirects[1].op, RedirectOp::DupOutput);
+    }
+
+    #[test]
+    fn test_parse_compound_commands() {
+        let list = parse("cd /tmp/apps/demo && (if [ -f demo.pid ]; then kill -- $(cat demo.pid); else echo 'not running'; fi)").unwrap();
+        let Command::Subshell { body, .. } = &list[0].rest[0].1.commands[0] else { panic!("expected subshell") };
+        let Command::If { branches, else_body, .. } = &body[0].first.commands[0] else { panic!("expected if") };
+        assert_eq!(literals(&branches[0].0[0].first.commands[0]), vec!["[", "-f", "demo.pid", "]"]);
+        assert!(else_body.is_some());
+    }
+
+    #[test]
+    fn test_unsupported_constructs_rejected() {
+        for command in [
+            "echo `id`",
+            "cat <<EOF",
+            "sleep 10 &",
+            "for i in 1 2; do echo $i; done",
+            "echo $((1 + 2))",
+            "diff <(ls a) <(ls b)",
+            "echo ${PATH:-x}",
+            "f() { ls; }",
+            "echo 'unterminated",
+            "ls )",
+            "if true; then ls",
+        ] {
+            assert!(parse(command).is_err(), "should be rejected: {}", command);
+        }
+    }
+
+    #[test]
+    fn test_unquoted_expansions_are_not_literal() {
+        let list = parse("ls *.log ~/x {a,b} '*.log' [ a").unwrap();
+        let words = &simple(&list[0].first.commands[0]).words;
+        assert_eq!(words[1].literal(), None);
+        assert_eq!(words[2].literal(), None);
+        assert_eq!(words[3].literal(), None);
+        assert_eq!(words[4].literal().as_deref(), Some("*.log"));
+        assert_eq!(words[5].literal().as_deref(), Some("["));
+    }
+}
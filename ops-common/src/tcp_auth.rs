@@ -1,7 +1,9 @@
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
-use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use sha2::Sha256;
 use hmac::{Hmac, Mac};
+use ring::rand::{SecureRandom, SystemRandom};
 
 type HmacSha256 = Hmac<Sha256>;
 
@@ -31,18 +33,38 @@ pub enum TcpAuthMessage {
         success: bool,
         message: String,
     },
+
+    /// 还没有凭据的客户端用一次性注册令牌申请密钥
+    #[serde(rename = "enroll")]
+    Enroll {
+        client_id: String,
+        token: String,
+    },
+
+    /// 注册成功，服务器签发该客户端专用的密钥，客户端随后用它回应质询
+    #[serde(rename = "enrolled")]
+    Enrolled {
+        secret: String,
+    },
+}
+
+/// 生成随机密钥或令牌（系统随机源的 32 字节，即 256 位随机数的十六进制）
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).expect("system random source is available");
+    hex::encode(bytes)
 }
 
-/// TCP认证器
+/// TCP认证器，持有本客户端的密钥
 #[derive(Clone)]
 pub struct TcpAuthenticator {
-    shared_secret: String,
+    secret: String,
 }
 
 impl TcpAuthenticator {
     /// 创建新的TCP认证器
-    pub fn new(shared_secret: String) -> Self {
-        Self { shared_secret }
+    pub fn new(secret: String) -> Self {
+        Self { secret }
     }
     
     /// 生成认证质询
@@ -70,7 +92,7 @@ impl TcpAuthenticator {
         
         // 计算响应哈希: HMAC-SHA256(shared_secret, client_id + nonce + timestamp)
         let data = format!("{}{}{}", client_id, challenge_nonce, challenge_timestamp);
-        let response_hash = self.compute_hmac(&data)?;
+        let response_hash = compute_hmac(&self.secret, &data)?;
         
         Ok(TcpAuthMessage::Response {
             client_id,
@@ -80,8 +102,13 @@ impl TcpAuthenticator {
         })
     }
     
-    /// 验证客户端响应
-    pub fn verify_response(&self, response: &TcpAuthMessage, original_nonce: &str, original_timestamp: u64) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    /// 验证客户端响应，secrets_for 按 client_id 返回该客户端当前有效的密钥（轮换期间可能有两个）
+    pub fn verify_response(
+        response: &TcpAuthMessage,
+        original_nonce: &str,
+        original_timestamp: u64,
+        secrets_for: impl Fn(&str) -> Vec<String>
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
         if let TcpAuthMessage::Response { client_id, nonce, response_hash, timestamp } = response {
             // 验证nonce匹配
             if nonce != original_nonce {
@@ -98,25 +125,21 @@ impl TcpAuthenticator {
                 return Ok(false);
             }
             
-            // 重新计算期望的响应哈希
+            // 用该客户端的每个有效密钥重新计算期望的响应哈希
             let data = format!("{}{}{}", client_id, original_nonce, original_timestamp);
-            let expected_hash = self.compute_hmac(&data)?;
-            
-            // 使用恒定时间比较防止时序攻击
-            Ok(constant_time_compare(&expected_hash, response_hash))
+            for secret in secrets_for(client_id) {
+                let expected_hash = compute_hmac(&secret, &data)?;
+                // 使用恒定时间比较防止时序攻击
+                if constant_time_compare(&expected_hash, response_hash) {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
         } else {
             Ok(false)
         }
     }
     
-    /// 计算HMAC-SHA256
-    fn compute_hmac(&self, data: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        let mut mac = HmacSha256::new_from_slice(self.shared_secret.as_bytes())?;
-        mac.update(data.as_bytes());
-        let result = mac.finalize();
-        Ok(hex::encode(result.into_bytes()))
-    }
-    
     /// 创建认证成功消息
     pub fn create_success_result() -> TcpAuthMessage {
         TcpAuthMessage::AuthResult {
@@ -134,8 +157,92 @@ impl TcpAuthenticator {
     }
 }
 
+/// 质询的有效期，超过后客户端需要重新连接
+pub const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+
+/// 服务端已签发的质询，绑定到发出它的连接
+struct IssuedChallenge {
+    connection_id: String,
+    timestamp: u64,
+    issued_at: Instant,
+}
+
+/// 服务端的质询登记表：nonce 只能在签发它的连接上、有效期内使用一次，
+/// 截获的响应无法在其他连接上或再次使用
+pub struct NonceCache {
+    ttl: Duration,
+    issued: HashMap<String, IssuedChallenge>,
+}
+
+impl Default for NonceCache {
+    fn default() -> Self {
+        Self::new(CHALLENGE_TTL)
+    }
+}
+
+impl NonceCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl, issued: HashMap::new() }
+    }
+
+    /// 为连接生成质询并登记其 nonce
+    pub fn issue(&mut self, connection_id: &str) -> TcpAuthMessage {
+        self.prune();
+        let challenge = TcpAuthenticator::generate_challenge();
+        if let TcpAuthMessage::Challenge { nonce, timestamp } = &challenge {
+            self.issued.insert(nonce.clone(), IssuedChallenge {
+                connection_id: connection_id.to_string(),
+                timestamp: *timestamp,
+                issued_at: Instant::now(),
+            });
+        }
+        challenge
+    }
+
+    /// 使用 nonce，成功时返回质询的时间戳；nonce 随即作废。
+    /// 其他连接出示的 nonce 被拒绝但不作废，避免截获者借此让原连接认证失败
+    pub fn consume(&mut self, nonce: &str, connection_id: &str) -> Result<u64, &'static str> {
+        let challenge = self.issued.get(nonce).ok_or("质询不存在或已使用")?;
+        if challenge.connection_id != connection_id {
+            return Err("质询不属于当前连接");
+        }
+        let challenge = self.issued.remove(nonce).expect("challenge checked above");
+        if challenge.issued_at.elapsed() > self.ttl {
+            return Err("质询已过期");
+        }
+        Ok(challenge.timestamp)
+    }
+
+    /// 连接断开后作废其未使用的质询
+    pub fn forget_connection(&mut self, connection_id: &str) {
+        self.issued.retain(|_, challenge| challenge.connection_id != connection_id);
+    }
+
+    /// 清理过期的质询
+    pub fn prune(&mut self) {
+        let ttl = self.ttl;
+        self.issued.retain(|_, challenge| challenge.issued_at.elapsed() <= ttl);
+    }
+
+    pub fn len(&self) -> usize {
+        self.issued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.issued.is_empty()
+    }
+}
+
+/// 计算HMAC-SHA256
+fn compute_hmac(secret: &str, data: &str) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())?;
+    mac.update(data.as_bytes());
+    let result = mac.finalize();
+    Ok(hex::encode(result.into_bytes()))
+}
+
 /// 恒定时间字符串比较，防止时序攻击
-fn constant_time_compare(a: &str, b: &str) -> bool {
+pub fn constant_time_compare(a: &str, b: &str) -> bool {
     if a.len() != b.len() {
         return false;
     }
@@ -155,7 +262,6 @@ mod tests {
     #[test]
     fn test_tcp_authentication_flow() {
         let shared_secret = "test-secret-key-123";
-        let server_auth = TcpAuthenticator::new(shared_secret.to_string());
         let client_auth = TcpAuthenticator::new(shared_secret.to_string());
         
         // 1. 服务器生成质询
@@ -169,7 +275,7 @@ mod tests {
             ).unwrap();
             
             // 3. 服务器验证响应
-            let is_valid = server_auth.verify_response(&response, nonce, *timestamp).unwrap();
+            let is_valid = TcpAuthenticator::verify_response(&response, nonce, *timestamp, |_| vec![shared_secret.to_string()]).unwrap();
             assert!(is_valid, "Authentication should succeed with correct credentials");
         } else {
             panic!("Challenge message should be of Challenge type");
@@ -178,7 +284,6 @@ mod tests {
     
     #[test]
     fn test_authentication_with_wrong_secret() {
-        let server_auth = TcpAuthenticator::new("server-secret".to_string());
         let client_auth = TcpAuthenticator::new("wrong-secret".to_string());
         
         let challenge = TcpAuthenticator::generate_challenge();
@@ -189,11 +294,77 @@ mod tests {
                 *timestamp
             ).unwrap();
             
-            let is_valid = server_auth.verify_response(&response, nonce, *timestamp).unwrap();
+            let is_valid = TcpAuthenticator::verify_response(&response, nonce, *timestamp, |_| vec!["server-secret".to_string()]).unwrap();
             assert!(!is_valid, "Authentication should fail with wrong secret");
         }
     }
+
+    #[test]
+    fn test_verify_response_uses_per_client_secret() {
+        let secrets = |client_id: &str| match client_id {
+            "client-a" => vec!["secret-a".to_string()],
+            "client-b" => vec!["old-b".to_string(), "new-b".to_string()],
+            _ => Vec::new(),
+        };
+        let TcpAuthMessage::Challenge { nonce, timestamp } = TcpAuthenticator::generate_challenge() else {
+            panic!("Challenge message should be of Challenge type");
+        };
+        let respond = |client_id: &str, secret: &str| {
+            TcpAuthenticator::new(secret.to_string())
+                .generate_response(client_id.to_string(), nonce.clone(), timestamp)
+                .unwrap()
+        };
+
+        assert!(TcpAuthenticator::verify_response(&respond("client-a", "secret-a"), &nonce, timestamp, secrets).unwrap());
+        // 其他客户端的密钥不能冒用
+        assert!(!TcpAuthenticator::verify_response(&respond("client-a", "old-b"), &nonce, timestamp, secrets).unwrap());
+        // 轮换期间新旧密钥都有效
+        assert!(TcpAuthenticator::verify_response(&respond("client-b", "new-b"), &nonce, timestamp, secrets).unwrap());
+        assert!(!TcpAuthenticator::verify_response(&respond("unknown", "secret-a"), &nonce, timestamp, secrets).unwrap());
+    }
     
+    #[test]
+    fn test_nonce_cache_single_use_and_bound_to_connection() {
+        let mut cache = NonceCache::default();
+        let TcpAuthMessage::Challenge { nonce, timestamp } = cache.issue("conn-1") else {
+            panic!("Challenge message should be of Challenge type");
+        };
+
+        // 其他连接不能使用，也不会让 nonce 作废
+        assert!(cache.consume(&nonce, "conn-2").is_err());
+        assert_eq!(cache.consume(&nonce, "conn-1"), Ok(timestamp));
+        // 同一个 nonce 不能再次使用
+        assert!(cache.consume(&nonce, "conn-1").is_err());
+        assert!(cache.consume("unknown", "conn-1").is_err());
+
+        cache.issue("conn-3");
+        cache.forget_connection("conn-3");
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_nonce_cache_rejects_expired_challenge() {
+        let mut cache = NonceCache::new(Duration::ZERO);
+        let TcpAuthMessage::Challenge { nonce, .. } = cache.issue("conn-1") else {
+            panic!("Challenge message should be of Challenge type");
+        };
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(cache.consume(&nonce, "conn-1"), Err("质询已过期"));
+
+        cache.issue("conn-1");
+        std::thread::sleep(Duration::from_millis(5));
+        cache.prune();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_generate_secret_is_256_bit_hex() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 64);
+        assert!(hex::decode(&secret).is_ok());
+        assert_ne!(secret, generate_secret());
+    }
+
     #[test]
     fn test_constant_time_compare() {
         assert!(constant_time_compare("hello", "hello"));
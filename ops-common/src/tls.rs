@@ -0,0 +1,141 @@
+use std::io;
+use std::sync::Arc;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio::io::{AsyncRead, AsyncWrite};
+pub use tokio_rustls::{TlsAcceptor, TlsConnector};
+use rustls::server::WebPkiClientVerifier;
+use x509_parser::extensions::GeneralName;
+
+/// 客户端与服务端之间的传输流，明文 TCP 或 TLS 统一按此类型处理
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// 读取 PEM 文件中的全部证书
+pub fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| invalid_input(format!("读取证书文件 {} 失败: {}", path, e)))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid_input(format!("解析证书文件 {} 失败: {}", path, e)))?;
+
+    if certs.is_empty() {
+        return Err(invalid_input(format!("证书文件 {} 中没有证书", path)));
+    }
+    Ok(certs)
+}
+
+/// 读取 PEM 文件中的私钥（PKCS#8、PKCS#1 或 SEC1）
+pub fn load_private_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| invalid_input(format!("读取私钥文件 {} 失败: {}", path, e)))
+}
+
+/// 使用 ring 作为加密后端，避免依赖进程级默认 provider
+fn crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_roots(ca_file: &str) -> io::Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(ca_file)? {
+        roots.add(cert)
+            .map_err(|e| invalid_input(format!("CA 证书 {} 无效: {}", ca_file, e)))?;
+    }
+    Ok(roots)
+}
+
+/// 构建服务端 TLS 接收器；指定 client_ca_file 时要求客户端出示该 CA 签发的证书（mTLS）
+pub fn build_acceptor(cert_file: &str, key_file: &str, client_ca_file: Option<&str>) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+
+    let builder = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid_input(e.to_string()))?;
+
+    let builder = match client_ca_file {
+        Some(ca_file) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_roots(ca_file)?),
+                crypto_provider(),
+            )
+            .build()
+            .map_err(|e| invalid_input(format!("客户端 CA {} 无效: {}", ca_file, e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| invalid_input(format!("服务端证书与私钥不匹配: {}", e)))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// 构建客户端 TLS 连接器，只信任指定 CA 签发的服务端证书；
+/// 提供 client_identity（证书, 私钥）时在握手中出示客户端证书
+pub fn build_connector(ca_file: &str, client_identity: Option<(&str, &str)>) -> io::Result<TlsConnector> {
+    let builder = rustls::ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid_input(e.to_string()))?
+        .with_root_certificates(load_roots(ca_file)?);
+
+    let config = match client_identity {
+        Some((cert_file, key_file)) => builder
+            .with_client_auth_cert(load_certs(cert_file)?, load_private_key(key_file)?)
+            .map_err(|e| invalid_input(format!("客户端证书与私钥不匹配: {}", e)))?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// 对端证书中可用于标识身份的名称（CN 与 SAN）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub names: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// 从 DER 证书中提取 subject CN 以及 SAN 中的 DNS / URI 名称
+    pub fn from_certificate(cert: &CertificateDer<'_>) -> io::Result<Self> {
+        let (_, parsed) = x509_parser::parse_x509_certificate(cert.as_ref())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("解析对端证书失败: {}", e)))?;
+
+        let mut names: Vec<String> = parsed.subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(str::to_string)
+            .collect();
+
+        if let Ok(Some(san)) = parsed.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(value) | GeneralName::URI(value) => names.push(value.to_string()),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(Self { names })
+    }
+
+    /// 客户端上报的 client_id 是否与证书身份一致
+    pub fn matches(&self, client_id: &str) -> bool {
+        self.names.iter().any(|name| name == client_id)
+    }
+}
+
+/// 解析用于证书校验的服务端名称（域名或 IP）
+pub fn server_name(host: &str) -> io::Result<ServerName<'static>> {
+    ServerName::try_from(host.to_string())
+        .map_err(|e| invalid_input(format!("无效的 TLS 服务端名称 {}: {}", host, e)))
+}
@@ -0,0 +1,151 @@
+// 新客户端审批：启用后未批准的 client_id 只进入待审批列表，不登记连接、不接收命令；
+// 审批结果保存在 JSON 文件中，被拒绝的客户端永久不能加入
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+use crate::credentials::save_json;
+use crate::tcp_services::handle_socket::OutboundSender;
+
+/// 客户端的审批状态
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admission {
+    Approved,
+    Pending,
+    Rejected,
+}
+
+/// 等待审批的客户端，按最近一次心跳更新
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingClient {
+    pub client_id: String,
+    pub hostname: String,
+    pub ip_addresses: Vec<String>,
+    pub peer_addr: String,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    // 当前连接，拒绝时通过它通知客户端
+    #[serde(skip)]
+    sender: Option<OutboundSender>,
+}
+
+impl PendingClient {
+    pub fn sender(&self) -> Option<&OutboundSender> {
+        self.sender.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct ApprovalStore {
+    approved: BTreeMap<String, SystemTime>, // client_id -> 批准时间
+    rejected: BTreeMap<String, SystemTime>, // client_id -> 拒绝时间
+}
+
+/// 审批登记表；未指定文件时只保存在内存中
+#[derive(Default)]
+pub struct ClientApprovals {
+    path: Option<PathBuf>,
+    store: ApprovalStore,
+    pending: HashMap<String, PendingClient>,
+}
+
+impl ClientApprovals {
+    /// 从文件加载，文件不存在时为空
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let store = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("审批文件格式错误: {}", e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => ApprovalStore::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path: Some(path), store, pending: HashMap::new() })
+    }
+
+    pub fn status(&self, client_id: &str) -> Admission {
+        if self.store.rejected.contains_key(client_id) {
+            Admission::Rejected
+        } else if self.store.approved.contains_key(client_id) {
+            Admission::Approved
+        } else {
+            Admission::Pending
+        }
+    }
+
+    /// 客户端上报信息时按连接的 client_id 登记审批状态：未批准的客户端进入（或刷新）待审批列表
+    pub fn admit(&mut self, client_id: &str, info: &ops_common::ClientInfo, peer_addr: &str, sender: &OutboundSender) -> Admission {
+        let status = self.status(client_id);
+        if status != Admission::Pending {
+            return status;
+        }
+        let now = SystemTime::now();
+        let pending = self.pending.entry(client_id.to_string()).or_insert_with(|| PendingClient {
+            client_id: client_id.to_string(),
+            hostname: String::new(),
+            ip_addresses: Vec::new(),
+            peer_addr: String::new(),
+            first_seen: now,
+            last_seen: now,
+            sender: None,
+        });
+        pending.hostname = info.system_info.hostname.clone();
+        pending.ip_addresses = info.system_info.ip_addresses.clone();
+        pending.peer_addr = peer_addr.to_string();
+        pending.last_seen = now;
+        pending.sender = Some(sender.clone());
+        Admission::Pending
+    }
+
+    /// 待审批列表，按首次出现时间排序
+    pub fn pending(&self) -> Vec<PendingClient> {
+        let mut pending: Vec<_> = self.pending.values().cloned().collect();
+        pending.sort_by_key(|client| client.first_seen);
+        pending
+    }
+
+    /// 批准待审批的客户端，客户端下次上报信息时加入；不在待审批列表中时返回 false
+    pub fn approve(&mut self, client_id: &str) -> Result<bool, String> {
+        if !self.pending.contains_key(client_id) {
+            return Ok(false);
+        }
+        self.update(|store| {
+            store.approved.insert(client_id.to_string(), SystemTime::now());
+        })?;
+        self.pending.remove(client_id);
+        Ok(true)
+    }
+
+    /// 拒绝待审批的客户端，返回被移出列表的记录；不在待审批列表中时返回 None
+    pub fn reject(&mut self, client_id: &str) -> Result<Option<PendingClient>, String> {
+        if !self.pending.contains_key(client_id) {
+            return Ok(None);
+        }
+        self.update(|store| {
+            store.rejected.insert(client_id.to_string(), SystemTime::now());
+        })?;
+        Ok(self.pending.remove(client_id))
+    }
+
+    /// 移除超过 max_age 没有上报的待审批客户端
+    pub fn prune_pending(&mut self, max_age: Duration) {
+        let now = SystemTime::now();
+        self.pending.retain(|_, client| {
+            now.duration_since(client.last_seen).is_ok_and(|age| age < max_age)
+        });
+    }
+
+    /// 在副本上修改并落盘，成功后才替换内存中的状态
+    fn update(&mut self, change: impl FnOnce(&mut ApprovalStore)) -> Result<(), String> {
+        let mut store = self.store.clone();
+        change(&mut store);
+        if let Some(path) = &self.path {
+            save_json(path, &store).map_err(|e| format!("保存审批文件 {} 失败: {}", path.display(), e))?;
+        }
+        self.store = store;
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+// 审计日志：以 JSON Lines 格式记录终端会话等敏感操作，每条记录一行
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use serde::Serialize;
+
+/// 一条审计记录
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub user: String,
+    pub client_id: String,
+    pub session_id: String,
+    /// 事件类型：terminal_open / terminal_input / terminal_close / terminal_denied
+    pub event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(user: &str, client_id: &str, session_id: &str, event: &str, detail: Option<String>) -> Self {
+        Self {
+            time: chrono::Utc::now(),
+            user: user.to_string(),
+            client_id: client_id.to_string(),
+            session_id: session_id.to_string(),
+            event: event.to_string(),
+            detail,
+        }
+    }
+}
+
+/// 追加写入的审计日志；未配置文件时只写 tracing 日志
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Some(Arc::new(Mutex::new(file))) })
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        tracing::info!(
+            target: "audit",
+            "{} user={} client={} session={}",
+            record.event, record.user, record.client_id, record.session_id
+        );
+
+        let Some(file) = &self.file else { return };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(e) = file.write_all(&line).and_then(|_| file.flush()) {
+            tracing::error!("Failed to write audit log: {}", e);
+        }
+    }
+}
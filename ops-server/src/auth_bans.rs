@@ -0,0 +1,119 @@
+// TCP 认证失败统计：按来源 IP 计数，时间窗口内失败次数达到上限后临时封禁该 IP
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+use serde::Serialize;
+
+/// 来源 IP 的认证失败记录
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthFailureRecord {
+    pub ip: IpAddr,
+    /// 当前窗口内的失败次数
+    pub failures: u32,
+    pub total_failures: u64,
+    pub window_started_at: SystemTime,
+    pub last_failure_at: SystemTime,
+    /// 最近一次失败时声明的 client_id
+    pub last_client_id: Option<String>,
+    pub last_reason: String,
+    /// 封禁截止时间，未封禁或已解封时为空
+    pub banned_until: Option<SystemTime>,
+    pub bans: u32,
+}
+
+impl AuthFailureRecord {
+    fn is_banned(&self, now: SystemTime) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+pub struct AuthBans {
+    max_failures: u32, // 为 0 时只计数不封禁
+    window: Duration,
+    ban_duration: Duration,
+    records: HashMap<IpAddr, AuthFailureRecord>,
+}
+
+impl Default for AuthBans {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(300), Duration::from_secs(900))
+    }
+}
+
+impl AuthBans {
+    pub fn new(max_failures: u32, window: Duration, ban_duration: Duration) -> Self {
+        Self { max_failures, window, ban_duration, records: HashMap::new() }
+    }
+
+    /// IP 被封禁时返回封禁截止时间
+    pub fn banned_until(&self, ip: IpAddr) -> Option<SystemTime> {
+        self.records.get(&ip)?.banned_until.filter(|until| *until > SystemTime::now())
+    }
+
+    /// 记录一次认证失败；本次失败触发封禁时返回封禁时长
+    pub fn record_failure(&mut self, ip: IpAddr, client_id: Option<&str>, reason: &str) -> Option<Duration> {
+        let now = SystemTime::now();
+        let record = self.records.entry(ip).or_insert_with(|| AuthFailureRecord {
+            ip,
+            failures: 0,
+            total_failures: 0,
+            window_started_at: now,
+            last_failure_at: now,
+            last_client_id: None,
+            last_reason: String::new(),
+            banned_until: None,
+            bans: 0,
+        });
+        // 窗口结束后重新计数
+        if now.duration_since(record.window_started_at).unwrap_or_default() > self.window {
+            record.failures = 0;
+            record.window_started_at = now;
+        }
+        record.failures += 1;
+        record.total_failures += 1;
+        record.last_failure_at = now;
+        record.last_client_id = client_id.map(str::to_string);
+        record.last_reason = reason.to_string();
+
+        if self.max_failures == 0 || record.failures < self.max_failures || record.is_banned(now) {
+            return None;
+        }
+        record.banned_until = Some(now + self.ban_duration);
+        record.bans += 1;
+        record.failures = 0;
+        record.window_started_at = now;
+        Some(self.ban_duration)
+    }
+
+    /// 认证成功后清零该 IP 窗口内的失败次数
+    pub fn record_success(&mut self, ip: IpAddr) {
+        if let Some(record) = self.records.get_mut(&ip) {
+            record.failures = 0;
+        }
+    }
+
+    /// 全部记录，封禁中的在前，其余按最近失败时间倒序
+    pub fn list(&self) -> Vec<AuthFailureRecord> {
+        let now = SystemTime::now();
+        let mut list: Vec<_> = self.records.values().cloned().collect();
+        list.sort_by(|a, b| {
+            b.is_banned(now).cmp(&a.is_banned(now)).then(b.last_failure_at.cmp(&a.last_failure_at))
+        });
+        list
+    }
+
+    /// 解除封禁并清除记录；没有记录时返回 false
+    pub fn unban(&mut self, ip: IpAddr) -> bool {
+        self.records.remove(&ip).is_some()
+    }
+
+    /// 清理没有封禁且计数窗口已结束的记录
+    pub fn prune(&mut self) {
+        let now = SystemTime::now();
+        let window = self.window;
+        self.records.retain(|_, record| {
+            record.is_banned(now) || now.duration_since(record.last_failure_at).unwrap_or_default() <= window
+        });
+    }
+}
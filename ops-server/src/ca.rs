@@ -0,0 +1,189 @@
+// ops-server ca 子命令：生成本地 CA 并签发服务端 / 客户端证书
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use clap::Subcommand;
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use time::{Duration, OffsetDateTime};
+
+type CaResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca-key.pem";
+
+#[derive(Subcommand, Debug)]
+pub enum CaCommand {
+    /// 生成本地根 CA（ca.pem / ca-key.pem）
+    Init {
+        /// 证书输出目录
+        #[arg(long, default_value = "pki")]
+        dir: PathBuf,
+        /// CA 名称
+        #[arg(long, default_value = "Ops System CA")]
+        common_name: String,
+        /// 有效期（天）
+        #[arg(long, default_value_t = 3650)]
+        days: i64,
+        /// 覆盖已存在的文件
+        #[arg(long)]
+        force: bool,
+    },
+    /// 签发服务端证书，客户端通过 --san 中的名称或 IP 校验服务端
+    IssueServer {
+        /// CA 与证书所在目录
+        #[arg(long, default_value = "pki")]
+        dir: PathBuf,
+        /// 服务端域名或 IP，可重复指定
+        #[arg(long = "san", required = true)]
+        sans: Vec<String>,
+        /// 输出文件名前缀
+        #[arg(long, default_value = "server")]
+        name: String,
+        /// 有效期（天）
+        #[arg(long, default_value_t = 825)]
+        days: i64,
+        /// 覆盖已存在的文件
+        #[arg(long)]
+        force: bool,
+    },
+    /// 签发客户端证书，CN 与 SAN 均为 client_id
+    IssueClient {
+        /// CA 与证书所在目录
+        #[arg(long, default_value = "pki")]
+        dir: PathBuf,
+        /// 客户端 ID（与客户端 client_id 文件中的内容一致）
+        #[arg(long)]
+        client_id: String,
+        /// 有效期（天）
+        #[arg(long, default_value_t = 825)]
+        days: i64,
+        /// 覆盖已存在的文件
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// 证书与私钥的 PEM 文本
+pub struct IssuedCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+fn validity(params: &mut CertificateParams, days: i64) {
+    let now = OffsetDateTime::now_utc();
+    // 预留一小时时钟偏差
+    params.not_before = now - Duration::hours(1);
+    params.not_after = now + Duration::days(days);
+}
+
+/// 生成自签名根 CA
+pub fn generate_ca(common_name: &str, days: i64) -> CaResult<IssuedCert> {
+    let mut params = CertificateParams::new(Vec::<String>::new())?;
+    params.distinguished_name.push(DnType::CommonName, common_name);
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    validity(&mut params, days);
+
+    let key = KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+    Ok(IssuedCert { cert_pem: cert.pem(), key_pem: key.serialize_pem() })
+}
+
+/// 用 CA 签发服务端证书
+pub fn issue_server_cert(ca: &IssuedCert, sans: &[String], days: i64) -> CaResult<IssuedCert> {
+    let mut params = CertificateParams::new(sans.to_vec())?;
+    params.distinguished_name.push(DnType::CommonName, sans[0].as_str());
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    sign_leaf(ca, params, days)
+}
+
+/// 用 CA 签发客户端证书，身份写入 CN 和 SAN
+pub fn issue_client_cert(ca: &IssuedCert, client_id: &str, days: i64) -> CaResult<IssuedCert> {
+    let mut params = CertificateParams::new(vec![client_id.to_string()])?;
+    params.distinguished_name.push(DnType::CommonName, client_id);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    sign_leaf(ca, params, days)
+}
+
+fn sign_leaf(ca: &IssuedCert, mut params: CertificateParams, days: i64) -> CaResult<IssuedCert> {
+    params.is_ca = IsCa::ExplicitNoCa;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyEncipherment];
+    params.use_authority_key_identifier_extension = true;
+    validity(&mut params, days);
+
+    let issuer = Issuer::from_ca_cert_pem(&ca.cert_pem, KeyPair::from_pem(&ca.key_pem)?)?;
+    let key = KeyPair::generate()?;
+    let cert = params.signed_by(&key, &issuer)?;
+    Ok(IssuedCert { cert_pem: cert.pem(), key_pem: key.serialize_pem() })
+}
+
+fn load_ca(dir: &Path) -> CaResult<IssuedCert> {
+    let cert_path = dir.join(CA_CERT_FILE);
+    let key_path = dir.join(CA_KEY_FILE);
+    let cert_pem = fs::read_to_string(&cert_path)
+        .map_err(|e| format!("读取 {} 失败: {}（请先运行 ops-server ca init）", cert_path.display(), e))?;
+    let key_pem = fs::read_to_string(&key_path)
+        .map_err(|e| format!("读取 {} 失败: {}", key_path.display(), e))?;
+    Ok(IssuedCert { cert_pem, key_pem })
+}
+
+/// 写出证书和私钥，私钥权限为 0600
+fn write_pair(dir: &Path, name: &str, issued: &IssuedCert, force: bool) -> CaResult<(PathBuf, PathBuf)> {
+    // 名称用作文件名，不允许跳出输出目录
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(format!("无效的名称: {}（只允许字母、数字、-、_、.）", name).into());
+    }
+
+    fs::create_dir_all(dir)?;
+    let cert_path = dir.join(format!("{}.pem", name));
+    let key_path = dir.join(format!("{}-key.pem", name));
+
+    for path in [&cert_path, &key_path] {
+        if path.exists() && !force {
+            return Err(format!("{} 已存在，使用 --force 覆盖", path.display()).into());
+        }
+    }
+
+    fs::write(&cert_path, &issued.cert_pem)?;
+    let mut key_file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&key_path)?;
+    key_file.write_all(issued.key_pem.as_bytes())?;
+
+    Ok((cert_path, key_path))
+}
+
+pub fn run(command: CaCommand) -> CaResult<()> {
+    match command {
+        CaCommand::Init { dir, common_name, days, force } => {
+            let ca = generate_ca(&common_name, days)?;
+            let (cert, key) = write_pair(&dir, "ca", &ca, force)?;
+            println!("CA 证书: {}", cert.display());
+            println!("CA 私钥: {}（请妥善保管，不要分发到客户端）", key.display());
+        }
+        CaCommand::IssueServer { dir, sans, name, days, force } => {
+            let ca = load_ca(&dir)?;
+            let issued = issue_server_cert(&ca, &sans, days)?;
+            let (cert, key) = write_pair(&dir, &name, &issued, force)?;
+            println!("服务端证书: {}", cert.display());
+            println!("服务端私钥: {}", key.display());
+            println!("配置: OPS_TLS_ENABLED=true OPS_TLS_CERT_FILE={} OPS_TLS_KEY_FILE={}", cert.display(), key.display());
+        }
+        CaCommand::IssueClient { dir, client_id, days, force } => {
+            let ca = load_ca(&dir)?;
+            let issued = issue_client_cert(&ca, &client_id, days)?;
+            let (cert, key) = write_pair(&dir, &client_id, &issued, force)?;
+            println!("客户端证书: {}", cert.display());
+            println!("客户端私钥: {}", key.display());
+        }
+    }
+    Ok(())
+}
@@ -1,10 +1,17 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{SystemTime, Duration};
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, watch};
 use serde::{Serialize, Deserialize};
+use ops_common::protocol::CommandOutputChunk;
 use uuid::Uuid;
 
+/// 单条命令保留的实时输出上限，超过后丢弃后续分块
+const MAX_OUTPUT_BYTES: usize = 4 * 1024 * 1024;
+
+/// 已结束命令最多保留多少份实时输出
+const MAX_FINISHED_OUTPUTS: usize = 100;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CommandResult {
     pub command_id: String,
@@ -15,15 +22,31 @@ pub struct CommandResult {
     pub exit_code: i32,
     pub executed_at: SystemTime,
     pub received_at: SystemTime,
+    /// 命令被取消，输出只包含取消前产生的部分
+    #[serde(default)]
+    pub cancelled: bool,
+    /// 命令在客户端执行超时被终止，输出只包含超时前产生的部分
+    #[serde(default)]
+    pub timed_out: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CommandStatus {
-    Pending,
-    Executing,
+    Pending, // 已下发，客户端尚未确认收到
+    Delivered, // 客户端已接收并开始执行
+    Rejected(String), // 客户端按策略拒绝执行
     Completed(CommandResult),
+    Cancelled(CommandResult), // 已按请求取消，附带取消前的输出
+    TimedOut(CommandResult), // 客户端执行超时并终止了命令，附带超时前的输出
     Failed(String),
-    Timeout,
+    Timeout, // 服务端等待结果超时
+}
+
+impl CommandStatus {
+    /// 命令是否已经结束，不会再有新的输出
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, CommandStatus::Pending | CommandStatus::Delivered)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,26 +56,58 @@ pub struct PendingCommand {
     pub command: String,
     pub created_at: SystemTime,
     pub status: CommandStatus,
+    /// 下发命令时客户端所在的会话，会话过期时命令标记为失败
+    #[serde(default)]
+    pub session_id: Option<String>,
 }
 
+/// 一条命令已收到的实时输出
 #[derive(Default)]
+struct CommandOutput {
+    chunks: Vec<CommandOutputChunk>,
+    bytes: usize,
+    truncated: bool,
+    finished_at: Option<SystemTime>,
+}
+
+/// 保存命令结果的结果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreOutcome {
+    Stored,
+    Duplicate, // 同一客户端重发的结果，已保存过
+    Unknown, // 服务端不知道该命令（重启、已清理或结果已被淘汰）
+    OtherClient, // 命令属于其他客户端
+}
+
+#[derive(Clone)]
 pub struct CommandResultsManager {
     pending_commands: Arc<RwLock<HashMap<String, PendingCommand>>>,
     completed_results: Arc<RwLock<HashMap<String, CommandResult>>>,
+    outputs: Arc<RwLock<HashMap<String, CommandOutput>>>,
+    // 输出或命令状态变化时通知跟随输出的订阅者
+    changes: Arc<watch::Sender<()>>,
     max_results: usize,
 }
 
+impl Default for CommandResultsManager {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
 impl CommandResultsManager {
     pub fn new(max_results: usize) -> Self {
         Self {
             pending_commands: Arc::new(RwLock::new(HashMap::new())),
             completed_results: Arc::new(RwLock::new(HashMap::new())),
+            outputs: Arc::new(RwLock::new(HashMap::new())),
+            changes: Arc::new(watch::channel(()).0),
             max_results,
         }
     }
 
     // 创建一个新的命令请求
-    pub async fn create_command(&self, client_id: String, command: String) -> String {
+    pub async fn create_command(&self, client_id: String, command: String, session_id: Option<String>) -> String {
         let command_id = Uuid::new_v4().to_string();
         let pending_command = PendingCommand {
             command_id: command_id.clone(),
@@ -60,6 +115,7 @@ impl CommandResultsManager {
             command,
             created_at: SystemTime::now(),
             status: CommandStatus::Pending,
+            session_id,
         };
 
         let mut pending = self.pending_commands.write().await;
@@ -69,26 +125,141 @@ impl CommandResultsManager {
         command_id
     }
 
-    // 标记命令为执行中
-    pub async fn mark_executing(&self, command_id: &str) -> bool {
+    // 更新等待确认的命令状态；只接受命令目标客户端的确认
+    async fn acknowledge(&self, command_id: &str, client_id: &str, status: CommandStatus) -> bool {
         let mut pending = self.pending_commands.write().await;
-        if let Some(cmd) = pending.get_mut(command_id) {
-            cmd.status = CommandStatus::Executing;
-            tracing::info!("Command {} marked as executing", command_id);
-            true
-        } else {
-            false
+        match pending.get_mut(command_id) {
+            Some(cmd) if cmd.client_id == client_id && matches!(cmd.status, CommandStatus::Pending) => {
+                tracing::info!("Command {} marked as {:?}", command_id, status);
+                cmd.status = status;
+                self.changes.send_replace(());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // 客户端确认收到命令
+    pub async fn mark_delivered(&self, command_id: &str, client_id: &str) -> bool {
+        self.acknowledge(command_id, client_id, CommandStatus::Delivered).await
+    }
+
+    // 客户端拒绝执行命令
+    pub async fn mark_rejected(&self, command_id: &str, client_id: &str, reason: String) -> bool {
+        self.acknowledge(command_id, client_id, CommandStatus::Rejected(reason)).await
+    }
+
+    // 获取尚未返回结果的命令
+    pub async fn get_pending_command(&self, command_id: &str) -> Option<PendingCommand> {
+        let pending = self.pending_commands.read().await;
+        pending.get(command_id).cloned()
+    }
+
+    // 会话过期后，该会话中尚未返回结果的命令标记为失败
+    pub async fn fail_session_commands(&self, session_id: &str, reason: &str) -> usize {
+        let mut failed_ids = Vec::new();
+        {
+            let mut pending = self.pending_commands.write().await;
+            for cmd in pending.values_mut() {
+                if cmd.session_id.as_deref() == Some(session_id) && !cmd.status.is_finished() {
+                    cmd.status = CommandStatus::Failed(reason.to_string());
+                    failed_ids.push(cmd.command_id.clone());
+                }
+            }
+        }
+
+        self.finish_outputs(&failed_ids).await;
+        failed_ids.len()
+    }
+
+    // 追加客户端回传的输出分块；只接受目标客户端、尚未结束的命令，重复的分块直接丢弃
+    pub async fn append_output(&self, client_id: &str, chunk: CommandOutputChunk) -> bool {
+        {
+            let pending = self.pending_commands.read().await;
+            match pending.get(&chunk.command_id) {
+                Some(cmd) if cmd.client_id == client_id && !cmd.status.is_finished() => {}
+                _ => return false,
+            }
+        }
+
+        let mut outputs = self.outputs.write().await;
+        let output = outputs.entry(chunk.command_id.clone()).or_default();
+        if output.chunks.last().is_some_and(|last| chunk.seq <= last.seq) {
+            return false;
+        }
+        if output.bytes + chunk.data.len() > MAX_OUTPUT_BYTES {
+            if !output.truncated {
+                tracing::warn!("Output of command {} exceeds {} bytes, dropping further chunks", chunk.command_id, MAX_OUTPUT_BYTES);
+                output.truncated = true;
+            }
+            return false;
+        }
+
+        output.bytes += chunk.data.len();
+        output.chunks.push(chunk);
+        self.changes.send_replace(());
+        true
+    }
+
+    // 从第 cursor 个分块开始读取已收到的输出，同时返回输出是否因超限被截断
+    pub async fn output_since(&self, command_id: &str, cursor: usize) -> (Vec<CommandOutputChunk>, bool) {
+        let outputs = self.outputs.read().await;
+        match outputs.get(command_id) {
+            Some(output) => (output.chunks.iter().skip(cursor).cloned().collect(), output.truncated),
+            None => (Vec::new(), false),
+        }
+    }
+
+    // 订阅输出与状态变化
+    pub fn subscribe_changes(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    // 命令结束后不再接收输出，只保留最近结束的若干份供稍后查看
+    async fn finish_outputs(&self, command_ids: &[String]) {
+        let mut outputs = self.outputs.write().await;
+        let now = SystemTime::now();
+        for command_id in command_ids {
+            if let Some(output) = outputs.get_mut(command_id) {
+                output.finished_at = Some(now);
+            }
+        }
+
+        let mut finished: Vec<(String, SystemTime)> = outputs.iter()
+            .filter_map(|(id, output)| output.finished_at.map(|at| (id.clone(), at)))
+            .collect();
+        if finished.len() > MAX_FINISHED_OUTPUTS {
+            finished.sort_by_key(|(_, at)| *at);
+            for (id, _) in &finished[..finished.len() - MAX_FINISHED_OUTPUTS] {
+                outputs.remove(id);
+            }
         }
+        drop(outputs);
+
+        self.changes.send_replace(());
     }
 
-    // 存储命令执行结果
-    pub async fn store_result(&self, result: CommandResult) {
+    // 存储命令执行结果；只接受下发给该客户端、尚未返回结果的命令
+    pub async fn store_result(&self, result: CommandResult) -> StoreOutcome {
         let command_id = result.command_id.clone();
         
         // 从待执行列表中移除
         {
             let mut pending = self.pending_commands.write().await;
-            pending.remove(&command_id);
+            match pending.get(&command_id) {
+                Some(cmd) if cmd.client_id == result.client_id => {
+                    pending.remove(&command_id);
+                }
+                Some(_) => return StoreOutcome::OtherClient,
+                None => {
+                    let results = self.completed_results.read().await;
+                    return match results.get(&command_id) {
+                        Some(stored) if stored.client_id == result.client_id => StoreOutcome::Duplicate,
+                        Some(_) => StoreOutcome::OtherClient,
+                        None => StoreOutcome::Unknown,
+                    };
+                }
+            }
         }
 
         // 添加到完成结果中
@@ -108,10 +279,14 @@ impl CommandResultsManager {
             results.insert(command_id.clone(), result);
         }
 
+        self.finish_outputs(std::slice::from_ref(&command_id)).await;
+
         tracing::info!("Stored result for command: {}", command_id);
+        StoreOutcome::Stored
     }
 
     // 获取命令结果
+    #[allow(dead_code)]
     pub async fn get_result(&self, command_id: &str) -> Option<CommandResult> {
         let results = self.completed_results.read().await;
         results.get(command_id).cloned()
@@ -131,7 +306,13 @@ impl CommandResultsManager {
         {
             let results = self.completed_results.read().await;
             if let Some(result) = results.get(command_id) {
-                return Some(CommandStatus::Completed(result.clone()));
+                return Some(if result.timed_out {
+                    CommandStatus::TimedOut(result.clone())
+                } else if result.cancelled {
+                    CommandStatus::Cancelled(result.clone())
+                } else {
+                    CommandStatus::Completed(result.clone())
+                });
             }
         }
 
@@ -147,12 +328,13 @@ impl CommandResultsManager {
             .collect();
         
         // 按接收时间排序，最新的在前
-        client_results.sort_by(|a, b| b.received_at.cmp(&a.received_at));
+        client_results.sort_by_key(|r| std::cmp::Reverse(r.received_at));
         client_results.truncate(limit);
         client_results
     }
 
     // 清理过期的待执行命令
+    #[allow(dead_code)]
     pub async fn cleanup_expired_commands(&self, timeout_duration: Duration) {
         let mut pending = self.pending_commands.write().await;
         let now = SystemTime::now();
@@ -160,21 +342,24 @@ impl CommandResultsManager {
         let mut expired_ids = Vec::new();
         
         for (id, cmd) in pending.iter_mut() {
-            if let Ok(elapsed) = now.duration_since(cmd.created_at) {
-                if elapsed > timeout_duration {
+            if let Ok(elapsed) = now.duration_since(cmd.created_at)
+                && elapsed > timeout_duration {
                     expired_ids.push(id.clone());
                     cmd.status = CommandStatus::Timeout;
                 }
-            }
         }
 
-        for id in expired_ids {
-            pending.remove(&id);
+        for id in &expired_ids {
+            pending.remove(id);
             tracing::warn!("Command {} timed out and was removed", id);
         }
+        drop(pending);
+
+        self.finish_outputs(&expired_ids).await;
     }
 
     // 获取统计信息
+    #[allow(dead_code)]
     pub async fn get_stats(&self) -> (usize, usize) {
         let pending = self.pending_commands.read().await;
         let completed = self.completed_results.read().await;
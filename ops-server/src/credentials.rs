@@ -0,0 +1,226 @@
+// 客户端凭据：每个客户端使用独立的认证密钥，首次连接时凭一次性注册令牌换取；
+// 密钥可在线轮换（客户端确认前新旧密钥都有效）或吊销，全部状态保存在 JSON 文件中
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use ops_common::file_transfer::sha256_hex;
+use ops_common::tcp_auth::generate_secret;
+use serde::{Deserialize, Serialize};
+
+/// 未指定有效期时注册令牌的有效期
+pub const DEFAULT_ENROLLMENT_TTL: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClientCredential {
+    secret: String,
+    // 已下发给客户端、等待确认的新密钥
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_secret: Option<String>,
+    created_at: SystemTime,
+    #[serde(default)]
+    rotated_at: Option<SystemTime>,
+    #[serde(default)]
+    revoked_at: Option<SystemTime>,
+}
+
+/// 注册令牌只保存 SHA-256，文件泄露也无法用于注册
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EnrollmentToken {
+    // 设置后只能用于注册该 client_id
+    client_id: Option<String>,
+    expires_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CredentialStore {
+    clients: HashMap<String, ClientCredential>,
+    enrollment_tokens: HashMap<String, EnrollmentToken>, // 令牌 SHA-256 -> 令牌信息
+}
+
+/// 客户端凭据的状态，不含密钥
+#[derive(Debug, Clone, Serialize)]
+pub struct CredentialSummary {
+    pub client_id: String,
+    pub created_at: SystemTime,
+    pub rotated_at: Option<SystemTime>,
+    pub rotation_pending: bool,
+    pub revoked_at: Option<SystemTime>,
+}
+
+/// 凭据登记表；未指定文件时只保存在内存中
+#[derive(Default)]
+pub struct CredentialRegistry {
+    path: Option<PathBuf>,
+    store: CredentialStore,
+}
+
+impl CredentialRegistry {
+    /// 从文件加载，文件不存在时为空
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let store = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("凭据文件格式错误: {}", e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => CredentialStore::default(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path: Some(path), store })
+    }
+
+    /// 客户端当前有效的密钥；未注册时返回 None，已吊销时返回空列表
+    pub fn secrets_for(&self, client_id: &str) -> Option<Vec<String>> {
+        let credential = self.store.clients.get(client_id)?;
+        if credential.revoked_at.is_some() {
+            return Some(Vec::new());
+        }
+        Some(credential.pending_secret.iter().chain([&credential.secret]).cloned().collect())
+    }
+
+    pub fn is_revoked(&self, client_id: &str) -> bool {
+        self.store.clients.get(client_id).is_some_and(|credential| credential.revoked_at.is_some())
+    }
+
+    pub fn list(&self) -> Vec<CredentialSummary> {
+        let mut list: Vec<_> = self.store.clients.iter().map(|(client_id, credential)| CredentialSummary {
+            client_id: client_id.clone(),
+            created_at: credential.created_at,
+            rotated_at: credential.rotated_at,
+            rotation_pending: credential.pending_secret.is_some(),
+            revoked_at: credential.revoked_at,
+        }).collect();
+        list.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        list
+    }
+
+    /// 签发一次性注册令牌，可限定只能注册指定的 client_id
+    pub fn issue_enrollment_token(&mut self, client_id: Option<String>, ttl: Duration) -> Result<String, String> {
+        let token = generate_secret();
+        let expires_at = SystemTime::now() + ttl;
+        self.update(|store| {
+            store.enrollment_tokens.insert(sha256_hex(token.as_bytes()), EnrollmentToken { client_id, expires_at });
+            Ok(())
+        })?;
+        Ok(token)
+    }
+
+    /// 凭注册令牌为客户端签发密钥，令牌随即作废。已有有效凭据的客户端不能重新注册，
+    /// 已吊销的客户端只能用限定了其 client_id 的令牌重新注册
+    pub fn enroll(&mut self, client_id: &str, token: &str) -> Result<String, String> {
+        let secret = generate_secret();
+        self.update(|store| {
+            let token = store.enrollment_tokens
+                .remove(&sha256_hex(token.as_bytes()))
+                .filter(|token| token.expires_at > SystemTime::now())
+                .ok_or("注册令牌无效或已过期")?;
+            if token.client_id.as_deref().is_some_and(|bound| bound != client_id) {
+                return Err(format!("注册令牌不能用于客户端 {}", client_id));
+            }
+            match store.clients.get(client_id) {
+                Some(credential) if credential.revoked_at.is_none() => {
+                    return Err(format!("客户端 {} 已注册", client_id));
+                }
+                Some(_) if token.client_id.is_none() => {
+                    return Err(format!("客户端 {} 已被吊销，需要使用限定该客户端的注册令牌", client_id));
+                }
+                _ => {}
+            }
+            store.clients.insert(client_id.to_string(), ClientCredential {
+                secret: secret.clone(),
+                pending_secret: None,
+                created_at: SystemTime::now(),
+                rotated_at: None,
+                revoked_at: None,
+            });
+            Ok(())
+        })?;
+        Ok(secret)
+    }
+
+    /// 为通过旧版共享密钥认证的客户端建立凭据并生成新密钥，客户端确认后不再接受共享密钥
+    pub fn migrate(&mut self, client_id: &str, shared_secret: &str) -> Result<String, String> {
+        let secret = generate_secret();
+        self.update(|store| {
+            if store.clients.contains_key(client_id) {
+                return Err(format!("客户端 {} 已有凭据", client_id));
+            }
+            store.clients.insert(client_id.to_string(), ClientCredential {
+                secret: shared_secret.to_string(),
+                pending_secret: Some(secret.clone()),
+                created_at: SystemTime::now(),
+                rotated_at: None,
+                revoked_at: None,
+            });
+            Ok(())
+        })?;
+        Ok(secret)
+    }
+
+    /// 生成新密钥，客户端确认前新旧密钥都有效
+    pub fn begin_rotation(&mut self, client_id: &str) -> Result<String, String> {
+        let secret = generate_secret();
+        self.update(|store| {
+            let credential = store.clients
+                .get_mut(client_id)
+                .filter(|credential| credential.revoked_at.is_none())
+                .ok_or_else(|| format!("客户端 {} 没有有效凭据", client_id))?;
+            credential.pending_secret = Some(secret.clone());
+            Ok(())
+        })?;
+        Ok(secret)
+    }
+
+    /// 客户端已保存新密钥，停用旧密钥；没有待确认的密钥时返回 false
+    pub fn confirm_rotation(&mut self, client_id: &str) -> Result<bool, String> {
+        self.update(|store| {
+            let Some(credential) = store.clients.get_mut(client_id) else {
+                return Ok(false);
+            };
+            let Some(secret) = credential.pending_secret.take() else {
+                return Ok(false);
+            };
+            credential.secret = secret;
+            credential.rotated_at = Some(SystemTime::now());
+            Ok(true)
+        })
+    }
+
+    /// 吊销客户端凭据，之后该客户端无法认证；未注册时返回 false
+    pub fn revoke(&mut self, client_id: &str) -> Result<bool, String> {
+        self.update(|store| {
+            let Some(credential) = store.clients.get_mut(client_id) else {
+                return Ok(false);
+            };
+            credential.pending_secret = None;
+            credential.revoked_at.get_or_insert_with(SystemTime::now);
+            Ok(true)
+        })
+    }
+
+    /// 在副本上修改并落盘，成功后才替换内存中的状态
+    fn update<T>(&mut self, change: impl FnOnce(&mut CredentialStore) -> Result<T, String>) -> Result<T, String> {
+        let mut store = self.store.clone();
+        let now = SystemTime::now();
+        store.enrollment_tokens.retain(|_, token| token.expires_at > now);
+        let result = change(&mut store)?;
+        if let Some(path) = &self.path {
+            save_json(path, &store).map_err(|e| format!("保存凭据文件 {} 失败: {}", path.display(), e))?;
+        }
+        self.store = store;
+        Ok(result)
+    }
+}
+
+/// 以 JSON 格式原子地写入文件：先写临时文件再改名，文件权限 0600
+pub(crate) fn save_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let content = serde_json::to_vec_pretty(value)?;
+    let temp = path.with_extension("tmp");
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp)?;
+    file.write_all(&content)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+}
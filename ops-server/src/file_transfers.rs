@@ -0,0 +1,208 @@
+// 文件推送记录：每次推送对应一个 transfer_id，按客户端记录发送和写入结果；
+// 文件读取：把客户端回传的分块校验后转发给等待下载的 HTTP 请求
+
+use std::collections::HashMap;
+use std::time::SystemTime;
+use ops_common::file_transfer::Checksum;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+/// 最多保留的推送记录数，超出时删除最早的记录
+const MAX_TRANSFERS: usize = 100;
+
+/// 单个客户端的推送状态
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum TransferStatus {
+    Sending,               // 正在发送分块
+    Sent,                  // 已全部发出，等待客户端结果
+    Completed(String),     // 客户端校验并写入成功
+    Failed(String),        // 发送失败或客户端拒绝
+}
+
+impl TransferStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, TransferStatus::Completed(_) | TransferStatus::Failed(_))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileTransfer {
+    pub transfer_id: String,
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    pub created_at: SystemTime,
+    pub clients: HashMap<String, TransferStatus>,
+}
+
+#[derive(Default)]
+pub struct FileTransfers {
+    transfers: HashMap<String, FileTransfer>,
+}
+
+impl FileTransfers {
+    pub fn create(&mut self, path: String, size: u64, sha256: String, clients: HashMap<String, TransferStatus>) -> String {
+        if self.transfers.len() >= MAX_TRANSFERS
+            && let Some(oldest) = self.transfers.values().min_by_key(|t| t.created_at).map(|t| t.transfer_id.clone()) {
+            self.transfers.remove(&oldest);
+        }
+
+        let transfer_id = uuid::Uuid::new_v4().to_string();
+        self.transfers.insert(transfer_id.clone(), FileTransfer {
+            transfer_id: transfer_id.clone(),
+            path,
+            size,
+            sha256,
+            created_at: SystemTime::now(),
+            clients,
+        });
+        transfer_id
+    }
+
+    pub fn get(&self, transfer_id: &str) -> Option<&FileTransfer> {
+        self.transfers.get(transfer_id)
+    }
+
+    /// 分块全部进入发送队列；客户端结果可能已先到达，此时保留结果
+    pub fn mark_sent(&mut self, transfer_id: &str, client_id: &str) {
+        if let Some(status) = self.status_mut(transfer_id, client_id)
+            && *status == TransferStatus::Sending {
+            *status = TransferStatus::Sent;
+        }
+    }
+
+    /// 记录最终结果，已结束的状态不再改变；返回该客户端是否属于这次推送
+    pub fn finish(&mut self, transfer_id: &str, client_id: &str, result: Result<String, String>) -> bool {
+        let Some(status) = self.status_mut(transfer_id, client_id) else {
+            return false;
+        };
+        if !status.is_finished() {
+            *status = match result {
+                Ok(message) => TransferStatus::Completed(message),
+                Err(message) => TransferStatus::Failed(message),
+            };
+        }
+        true
+    }
+
+    /// 客户端断开时把其未完成的推送标记为失败
+    pub fn fail_client(&mut self, client_id: &str, reason: &str) {
+        for transfer in self.transfers.values_mut() {
+            if let Some(status) = transfer.clients.get_mut(client_id)
+                && !status.is_finished() {
+                *status = TransferStatus::Failed(reason.to_string());
+            }
+        }
+    }
+
+    fn status_mut(&mut self, transfer_id: &str, client_id: &str) -> Option<&mut TransferStatus> {
+        self.transfers.get_mut(transfer_id)?.clients.get_mut(client_id)
+    }
+}
+
+/// 客户端回传文件过程中的事件
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetchEvent {
+    /// 开始回传：文件总大小和实际读取的区间
+    Start { file_size: u64, offset: u64, length: u64 },
+    Chunk { offset: u64, data: Vec<u8> },
+    /// 全部内容已收到且校验通过
+    End { sha256: String },
+    Failed(String),
+}
+
+/// 一个进行中的文件读取
+struct FileFetch {
+    client_id: String,
+    max_size: u64,
+    events: mpsc::UnboundedSender<FetchEvent>,
+    // Start 之后为 (下一个分块的偏移, 区间结束位置)
+    expected: Option<(u64, u64)>,
+    checksum: Checksum,
+}
+
+impl FileFetch {
+    /// 校验客户端发来的事件，不合法时转换为失败事件
+    fn check(&mut self, event: FetchEvent) -> FetchEvent {
+        match (&event, self.expected) {
+            (FetchEvent::Start { length, .. }, None) if *length > self.max_size => {
+                FetchEvent::Failed(format!("文件大小 {} 超过服务端上限 {}", length, self.max_size))
+            }
+            (FetchEvent::Start { offset, length, .. }, None) => {
+                self.expected = Some((*offset, offset + length));
+                event
+            }
+            (FetchEvent::Chunk { offset, data }, Some((next, end))) => {
+                if *offset != next || next + data.len() as u64 > end {
+                    return FetchEvent::Failed(format!("分块顺序错误: 期望偏移 {}，收到 {}", next, offset));
+                }
+                self.checksum.update(data);
+                self.expected = Some((next + data.len() as u64, end));
+                event
+            }
+            (FetchEvent::End { sha256 }, Some((next, end))) => {
+                if next != end {
+                    return FetchEvent::Failed(format!("内容不完整: 收到 {} 字节后结束", next));
+                }
+                let checksum = std::mem::take(&mut self.checksum).finish();
+                if !checksum.eq_ignore_ascii_case(sha256) {
+                    return FetchEvent::Failed(format!("SHA-256 校验失败: 客户端 {}，实际 {}", sha256, checksum));
+                }
+                event
+            }
+            (FetchEvent::Failed(_), _) => event,
+            _ => FetchEvent::Failed("客户端回传的消息顺序错误".to_string()),
+        }
+    }
+}
+
+/// 进行中的文件读取，按 fetch_id 索引
+#[derive(Default)]
+pub struct FileFetches {
+    fetches: HashMap<String, FileFetch>,
+}
+
+impl FileFetches {
+    /// 登记一次读取，返回 fetch_id 和接收事件的队列；回传内容不超过 max_size
+    pub fn open(&mut self, client_id: &str, max_size: u64) -> (String, mpsc::UnboundedReceiver<FetchEvent>) {
+        let fetch_id = uuid::Uuid::new_v4().to_string();
+        let (events, receiver) = mpsc::unbounded_channel();
+        self.fetches.insert(fetch_id.clone(), FileFetch {
+            client_id: client_id.to_string(),
+            max_size,
+            events,
+            expected: None,
+            checksum: Checksum::new(),
+        });
+        (fetch_id, receiver)
+    }
+
+    pub fn remove(&mut self, fetch_id: &str) {
+        self.fetches.remove(fetch_id);
+    }
+
+    /// 转发客户端的事件；只接受发起读取的客户端，返回该读取是否存在
+    pub fn route(&mut self, client_id: &str, fetch_id: &str, event: FetchEvent) -> bool {
+        let Some(fetch) = self.fetches.get_mut(fetch_id).filter(|fetch| fetch.client_id == client_id) else {
+            return false;
+        };
+        let event = fetch.check(event);
+        let finished = matches!(event, FetchEvent::End { .. } | FetchEvent::Failed(_));
+        // 下载请求已经结束时不再转发
+        if fetch.events.send(event).is_err() || finished {
+            self.fetches.remove(fetch_id);
+        }
+        true
+    }
+
+    /// 客户端断开时结束其上的所有读取
+    pub fn fail_client(&mut self, client_id: &str, reason: &str) {
+        self.fetches.retain(|_, fetch| {
+            if fetch.client_id != client_id {
+                return true;
+            }
+            let _ = fetch.events.send(FetchEvent::Failed(reason.to_string()));
+            false
+        });
+    }
+}
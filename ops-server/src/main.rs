@@ -1,6 +1,5 @@
 use tokio::net::TcpListener;
-use std::collections::HashMap;
-use std::time::{SystemTime, Duration};
+use std::time::{Duration, SystemTime};
 use std::process;
 use std::net::SocketAddr;
 use tracing::{info, error, warn};
@@ -12,18 +11,27 @@ use tracing_subscriber::{
     fmt::writer::MakeWriterExt
 };
 use tracing_appender::{rolling, non_blocking};
+use clap::{Parser, Subcommand};
 
 mod web;
 mod tcp_services;
 mod shared_data_handle;
+mod sessions;
 mod middleware;
 mod command_results;
+mod audit;
+mod auth_bans;
+mod file_transfers;
+mod ca;
+mod approvals;
+mod credentials;
+mod signing_key;
 
 use crate::shared_data_handle::{SharedDataHandle, SharedData};
 use crate::tcp_services::handle_socket;
 use crate::middleware::AuthConfig;
 
-use ops_common::{ClientInfo, config::ServerConfig};
+use ops_common::{ClientInfo, codec::FrameCodec, command_signing::CommandSigner, config::ServerConfig, tls};
 
 #[cfg(test)]
 mod tests;
@@ -107,16 +115,40 @@ async fn launch_tcp_server(shared_data: SharedDataHandle, config: ServerConfig)
         e
     })?;
     
-    info!("TCP server listening on {}", addr);
+    info!("TCP server listening on {} (max frame size: {} bytes)", addr, config.max_frame_size);
+    let codec = FrameCodec::new(config.max_frame_size);
+
+    let tls_acceptor = if config.tls_enabled {
+        let (Some(cert_file), Some(key_file)) = (&config.tls_cert_file, &config.tls_key_file) else {
+            return Err("启用 TLS 时必须配置 tls_cert_file 和 tls_key_file".into());
+        };
+        let acceptor = tls::build_acceptor(cert_file, key_file, config.tls_client_ca_file.as_deref())?;
+        info!("TLS enabled for TCP server (cert: {})", cert_file);
+        if let Some(client_ca_file) = &config.tls_client_ca_file {
+            info!("Mutual TLS enabled, client certificates must be issued by {} and match client_id", client_ca_file);
+        }
+        Some(acceptor)
+    } else {
+        warn!("TLS disabled, agent traffic on {} is unencrypted", addr);
+        None
+    };
 
     loop {
         match listener.accept().await {
             Ok((stream, client_addr)) => {
                 info!("New client connection from: {}", client_addr);
                 let shared_data = shared_data.clone();
+                let tls_acceptor = tls_acceptor.clone();
                 
                 tokio::spawn(async move {
-                    if let Err(e) = handle_socket::handle_client_connection(stream, shared_data).await {
+                    let (stream, peer_identity) = match handle_socket::accept_stream(stream, tls_acceptor.as_ref()).await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("TLS handshake with {} failed: {}", client_addr, e);
+                            return;
+                        }
+                    };
+                    if let Err(e) = handle_socket::handle_client_connection(stream, client_addr, peer_identity, shared_data, codec).await {
                         error!("Client connection error from {}: {}", client_addr, e);
                     }
                 });
@@ -129,8 +161,42 @@ async fn launch_tcp_server(shared_data: SharedDataHandle, config: ServerConfig)
     }
 }
 
+#[derive(Parser, Debug)]
+#[command(name = "ops-server")]
+#[command(about = "OPS系统服务端")]
+#[command(version = "0.1.0")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 本地 CA 与证书管理
+    #[command(subcommand)]
+    Ca(ca::CaCommand),
+    /// 命令签名密钥管理
+    #[command(subcommand)]
+    SigningKey(signing_key::SigningKeyCommand),
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    // 证书和密钥管理子命令不启动服务
+    if let Some(command) = args.command {
+        let result = match command {
+            Command::Ca(command) => ca::run(command),
+            Command::SigningKey(command) => signing_key::run(command),
+        };
+        if let Err(e) = result {
+            eprintln!("错误: {}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
     // 初始化日志配置
     setup_logging();
 
@@ -138,7 +204,63 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = ServerConfig::from_env();
     info!("Server starting with config: TCP={}, HTTP={}", config.tcp_address(), config.http_address());
 
-    let shared_data = SharedDataHandle::new(SharedData::new(config.max_connections));
+    let mut data = SharedData::new(config.max_connections);
+    data.sessions = sessions::SessionRegistry::new(Duration::from_secs(config.resume_token_ttl_secs));
+    data.terminal_users = config.terminal_users.clone();
+    data.client_groups = config.client_groups.clone();
+    data.max_push_file_size = config.max_push_file_size;
+    data.max_fetch_file_size = config.max_fetch_file_size;
+    data.tcp_auth_enabled = config.tcp_auth_enabled;
+    data.tcp_auth_secret = config.tcp_auth_secret.clone();
+    data.credentials = match credentials::CredentialRegistry::load(&config.credentials_file) {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            error!("无法加载凭据文件 {}: {}", config.credentials_file, e);
+            process::exit(1);
+        }
+    };
+    data.audit = match audit::AuditLog::open(&config.audit_log_file) {
+        Ok(audit) => audit,
+        Err(e) => {
+            error!("无法打开审计日志 {}: {}", config.audit_log_file, e);
+            process::exit(1);
+        }
+    };
+    data.require_client_approval = config.require_client_approval;
+    data.approvals = match approvals::ClientApprovals::load(&config.approvals_file) {
+        Ok(approvals) => approvals,
+        Err(e) => {
+            error!("无法加载审批文件 {}: {}", config.approvals_file, e);
+            process::exit(1);
+        }
+    };
+    if let Some(key_file) = &config.command_signing_key_file {
+        match CommandSigner::load(key_file) {
+            Ok(signer) => {
+                info!("Signing commands with key {} (public key: {})", key_file, signer.public_key());
+                data.command_signer = Some(signer);
+            }
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    data.command_signature_ttl = Duration::from_secs(config.command_signature_ttl_secs);
+    data.command_policy = match config.command_policy() {
+        Ok(policy) => policy,
+        Err(e) => {
+            error!("无法加载命令校验策略: {}", e);
+            process::exit(1);
+        }
+    };
+    info!("Command policy {} (script dirs: {:?})", data.command_policy.version(), data.command_policy.scripts.allowed_dirs);
+    data.auth_bans = auth_bans::AuthBans::new(
+        config.auth_max_failures,
+        Duration::from_secs(config.auth_failure_window_secs),
+        Duration::from_secs(config.auth_ban_secs),
+    );
+    let shared_data = SharedDataHandle::new(data);
     let cleanup_data = shared_data.clone();
     let socket_data = shared_data.clone();
     let web_data = shared_data.clone();
@@ -173,6 +295,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             for client_id in &expired_clients {
                 data.remove_client_connection(client_id).await;
             }
+            data.approvals.prune_pending(Duration::from_secs(client_timeout));
+            data.auth_nonces.prune();
+            data.auth_bans.prune();
             
             let after_count = data.client_data.len();
             if before_count != after_count {
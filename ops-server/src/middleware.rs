@@ -11,9 +11,12 @@ use axum::{
 use std::net::SocketAddr;
 use tracing::{warn, debug, info};
 use ops_common::security::validate_auth_header;
-use crate::shared_data_handle::SharedDataHandle;
 use crate::web::handlers::SessionStore;
 
+/// 通过会话认证的登录用户，由认证中间件放入请求扩展；Token 认证的请求没有该扩展
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser(pub String);
+
 #[derive(Clone)]
 pub struct AuthConfig {
     pub token: Option<String>,
@@ -38,21 +41,21 @@ impl AuthConfig {
 
 pub async fn auth_middleware(
     State(auth_config): State<AuthConfig>,
-    request: Request<Body>,
+    mut request: Request<Body>,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    let headers = request.headers();
-    
     // 首先尝试基于Session的认证
-    if let Some(session_store) = &auth_config.session_store {
-        if let Some(session_id) = extract_session_from_headers(headers) {
+    if let Some(session_store) = &auth_config.session_store
+        && let Some(session_id) = extract_session_from_headers(request.headers()) {
             // 检查Session是否有效（1小时内）
-            if session_store.is_session_valid(&session_id, std::time::Duration::from_secs(3600)).await {
+            if let Some(user) = session_store.session_user(&session_id, std::time::Duration::from_secs(3600)).await {
                 debug!("Session authentication successful");
+                request.extensions_mut().insert(AuthenticatedUser(user));
                 return Ok(next.run(request).await);
             }
         }
-    }
+
+    let headers = request.headers();
     
     // 回退到基于Token的认证（如果启用）
     if auth_config.enabled {
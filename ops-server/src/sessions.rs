@@ -0,0 +1,113 @@
+// 可恢复会话：认证成功后签发续连令牌，客户端短暂断线后凭令牌恢复会话，无需重新认证
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use ops_common::tcp_auth::{constant_time_compare, generate_secret};
+use uuid::Uuid;
+
+/// 默认续连令牌有效期：连接断开后 60 秒
+pub const DEFAULT_RESUME_TTL: Duration = Duration::from_secs(60);
+
+struct ResumableSession {
+    client_id: String,
+    token: String,
+    // 当前承载会话的连接，断开后为 None
+    connection_id: Option<String>,
+    disconnected_at: Option<Instant>,
+}
+
+/// 恢复成功的会话
+#[derive(Debug, PartialEq)]
+pub struct ResumedSession {
+    pub session_id: String,
+    /// 轮换后的新令牌，旧令牌随即失效
+    pub token: String,
+    /// 会话原先所在、尚未报告断开的连接（如半开连接），由新连接接管后应关闭
+    pub replaced_connection: Option<String>,
+}
+
+/// 会话登记表，按 session_id 索引
+pub struct SessionRegistry {
+    sessions: HashMap<String, ResumableSession>,
+    ttl: Duration,
+}
+
+impl Default for SessionRegistry {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESUME_TTL)
+    }
+}
+
+impl SessionRegistry {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            ttl,
+        }
+    }
+
+    /// 断开后令牌的有效期
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    fn new_token() -> String {
+        generate_secret()
+    }
+
+    /// 为刚认证的连接创建会话，返回 (session_id, token)
+    pub fn open(&mut self, client_id: &str, connection_id: &str) -> (String, String) {
+        let session_id = Uuid::new_v4().to_string();
+        let token = Self::new_token();
+        self.sessions.insert(session_id.clone(), ResumableSession {
+            client_id: client_id.to_string(),
+            token: token.clone(),
+            connection_id: Some(connection_id.to_string()),
+            disconnected_at: None,
+        });
+        (session_id, token)
+    }
+
+    /// 凭令牌把会话恢复到新连接上并轮换令牌，令牌无效或已过期时返回 None。
+    /// 服务端可能还没发现旧连接已经断开（半开连接），会话仍在旧连接上时同样由新连接接管
+    pub fn resume(&mut self, client_id: &str, token: &str, connection_id: &str) -> Option<ResumedSession> {
+        let ttl = self.ttl;
+        let (session_id, session) = self.sessions.iter_mut().find(|(_, session)| {
+            session.client_id == client_id && constant_time_compare(&session.token, token)
+        })?;
+
+        if session.disconnected_at.is_some_and(|at| at.elapsed() >= ttl) {
+            return None;
+        }
+
+        session.token = Self::new_token();
+        let replaced_connection = session.connection_id.replace(connection_id.to_string());
+        session.disconnected_at = None;
+        Some(ResumedSession {
+            session_id: session_id.clone(),
+            token: session.token.clone(),
+            replaced_connection,
+        })
+    }
+
+    /// 连接断开时开始计时；会话已经恢复到其他连接上时不做处理
+    pub fn detach(&mut self, session_id: &str, connection_id: &str) {
+        if let Some(session) = self.sessions.get_mut(session_id)
+            && session.connection_id.as_deref() == Some(connection_id) {
+            session.connection_id = None;
+            session.disconnected_at = Some(Instant::now());
+        }
+    }
+
+    /// 会话断开已超过有效期时移除并返回 true
+    pub fn remove_if_expired(&mut self, session_id: &str) -> bool {
+        let expired = self.sessions
+            .get(session_id)
+            .and_then(|session| session.disconnected_at)
+            .is_some_and(|at| at.elapsed() >= self.ttl);
+        if expired {
+            self.sessions.remove(session_id);
+        }
+        expired
+    }
+}
@@ -1,10 +1,21 @@
 use std::sync::Arc;
-use tokio::io::AsyncWriteExt;
-use tokio::sync::{ Mutex, MutexGuard };
-use tokio::net::TcpStream;
+use tokio::sync::{ Mutex, MutexGuard, Notify, mpsc };
 use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+use ops_common::command_policy::CommandPolicy;
+use ops_common::command_signing::{CommandSignature, CommandSigner, SignedRequest};
+use ops_common::tcp_auth::NonceCache;
+use ops_common::file_transfer::FILE_CHUNK_SIZE;
+use ops_common::protocol::{Capability, Envelope, FetchRange, FilePushStart, HelloAck, ReconnectReport, command_signed_content, file_fetch_signed_content, log_tail_signed_content};
 use crate::ClientInfo;
+use crate::approvals::ClientApprovals;
+use crate::audit::AuditLog;
+use crate::auth_bans::AuthBans;
 use crate::command_results::CommandResultsManager;
+use crate::credentials::CredentialRegistry;
+use crate::file_transfers::{FetchEvent, FileFetches, FileTransfers, TransferStatus};
+use crate::sessions::SessionRegistry;
+use crate::tcp_services::handle_socket::{OutboundSender, send_message};
 
 #[derive(Clone)]
 pub struct SharedDataHandle(Arc<Mutex<SharedData>>);
@@ -23,13 +34,106 @@ impl SharedDataHandle {
     }
 }
 
+/// 一个已注册客户端的连接及握手时协商出的协议参数
+pub struct ClientConnection {
+    pub sender: OutboundSender,
+    pub connection_id: String,
+    pub session_id: Option<String>,
+    pub protocol_version: u32,
+    pub capabilities: Vec<Capability>,
+    // 通知连接的读循环退出
+    closer: Arc<Notify>,
+}
+
+impl ClientConnection {
+    pub fn new(sender: OutboundSender, connection_id: String, session_id: Option<String>, negotiated: HelloAck, closer: Arc<Notify>) -> Self {
+        Self {
+            sender,
+            connection_id,
+            session_id,
+            protocol_version: negotiated.protocol_version,
+            capabilities: negotiated.capabilities,
+            closer,
+        }
+    }
+
+    /// 通知客户端原因并让连接的读循环退出；读循环还没开始等待时，下次读取前退出
+    pub fn close(&self, reason: &str) {
+        let _ = send_message(&self.sender, Envelope::ConnectionRejected { reason: reason.to_string() });
+        self.closer.notify_one();
+    }
+}
+
+/// 客户端的重连记录，按 client_id 保存，连接注销后仍保留
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ReconnectHistory {
+    pub count: u64,
+    pub last_report: ReconnectReport,
+    pub last_reported_at: SystemTime,
+}
+
+/// 推送给浏览器终端的事件
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TerminalEvent {
+    Output { data: String },
+    Closed { reason: Option<String> },
+}
+
+/// 一个打开中的终端会话：所在客户端和通往浏览器 WebSocket 的队列
+pub struct TerminalBridge {
+    pub client_id: String,
+    pub events: mpsc::Sender<TerminalEvent>,
+}
+
+/// 终端事件队列容量，浏览器跟不上时丢弃输出
+const TERMINAL_EVENT_QUEUE: usize = 1024;
+
+/// 推送给浏览器的日志跟踪事件
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LogTailEvent {
+    Lines { data: String },
+    Ended { reason: String },
+}
+
+/// 一个跟踪中的日志：所在客户端和通往浏览器 SSE 的队列
+pub struct LogTailBridge {
+    pub client_id: String,
+    pub events: mpsc::Sender<LogTailEvent>,
+}
+
+/// 日志事件队列容量，浏览器跟不上时丢弃日志
+const LOG_TAIL_EVENT_QUEUE: usize = 1024;
+
 #[derive(Default)]
 pub struct SharedData {
     pub client_data: HashMap<String, ClientInfo>,
-    pub client_connections: HashMap<String, Arc<Mutex<TcpStream>>>,
+    pub client_connections: HashMap<String, ClientConnection>,
     pub max_connections: usize,
     pub connection_count: usize,
     pub command_results: CommandResultsManager,
+    pub reconnect_history: HashMap<String, ReconnectHistory>,
+    pub sessions: SessionRegistry,
+    pub terminal_users: Vec<String>, // 允许使用 Web 终端的用户
+    pub terminals: HashMap<String, TerminalBridge>,
+    pub log_tails: HashMap<String, LogTailBridge>,
+    pub audit: AuditLog,
+    pub client_groups: HashMap<String, Vec<String>>, // 客户端分组，用于批量推送文件
+    pub max_push_file_size: usize,
+    pub file_transfers: FileTransfers,
+    pub max_fetch_file_size: u64,
+    pub file_fetches: FileFetches,
+    pub tcp_auth_enabled: bool,
+    pub tcp_auth_secret: Option<String>, // 旧版共享密钥，只对没有凭据的客户端有效
+    pub credentials: CredentialRegistry,
+    pub require_client_approval: bool,
+    pub approvals: ClientApprovals,
+    pub command_signer: Option<CommandSigner>, // 配置后对下发的命令签名
+    pub command_signature_ttl: Duration,
+    pub auth_nonces: NonceCache, // 已签发、尚未使用的认证质询
+    pub auth_bans: AuthBans, // 按来源 IP 统计认证失败并临时封禁
+    pub command_policy: CommandPolicy, // 下发给客户端的命令校验策略
 }
 
 impl SharedData {
@@ -40,8 +144,43 @@ impl SharedData {
             max_connections,
             connection_count: 0,
             command_results: CommandResultsManager::new(1000), // 最多存储1000个结果
+            reconnect_history: HashMap::new(),
+            sessions: SessionRegistry::default(),
+            terminal_users: Vec::new(),
+            terminals: HashMap::new(),
+            log_tails: HashMap::new(),
+            audit: AuditLog::default(),
+            client_groups: HashMap::new(),
+            max_push_file_size: 64 * 1024 * 1024,
+            file_transfers: FileTransfers::default(),
+            max_fetch_file_size: 64 * 1024 * 1024,
+            file_fetches: FileFetches::default(),
+            tcp_auth_enabled: false,
+            tcp_auth_secret: None,
+            credentials: CredentialRegistry::default(),
+            require_client_approval: false,
+            approvals: ClientApprovals::default(),
+            command_signer: None,
+            command_signature_ttl: Duration::from_secs(300),
+            auth_nonces: NonceCache::default(),
+            auth_bans: AuthBans::default(),
+            command_policy: CommandPolicy::builtin(),
         }
     }
+
+    /// 配置了签名私钥时为发往 client_id 的请求签名
+    pub fn sign_request(&self, kind: SignedRequest, request_id: &str, client_id: &str, content: &str) -> Option<CommandSignature> {
+        self.command_signer
+            .as_ref()
+            .map(|signer| signer.sign_request(kind, request_id, client_id, content, self.command_signature_ttl))
+    }
+
+    /// 认证客户端时可接受的密钥：已注册的客户端只用其凭据，否则退回旧版共享密钥
+    pub fn auth_secrets(&self, client_id: &str) -> Vec<String> {
+        self.credentials
+            .secrets_for(client_id)
+            .unwrap_or_else(|| self.tcp_auth_secret.iter().cloned().collect())
+    }
 }
 
 impl SharedData {
@@ -49,7 +188,7 @@ impl SharedData {
     pub async fn add_client_connection(
         &mut self,
         client_id: String,
-        stream: Arc<Mutex<TcpStream>>
+        connection: ClientConnection
     ) -> Result<(), String> {
         // 检查连接数限制
         if self.connection_count >= self.max_connections && !self.client_connections.contains_key(&client_id) {
@@ -61,7 +200,7 @@ impl SharedData {
             self.connection_count += 1;
         }
 
-        self.client_connections.insert(client_id, stream);
+        self.client_connections.insert(client_id, connection);
         Ok(())
     }
 
@@ -72,6 +211,40 @@ impl SharedData {
         }
     }
 
+    // 仅当登记的仍是指定连接时才移除，避免旧连接断开时误删同一客户端的新连接
+    pub async fn remove_client_connection_if(&mut self, client_id: &str, connection_id: &str) {
+        let is_current = self.client_connections
+            .get(client_id)
+            .is_some_and(|connection| connection.connection_id == connection_id);
+        if is_current {
+            self.remove_client_connection(client_id).await;
+        }
+    }
+
+    // 会话断开超过续连有效期后移除，并把其中未完成的命令标记为失败
+    pub async fn expire_session(&mut self, session_id: &str) {
+        if !self.sessions.remove_if_expired(session_id) {
+            return;
+        }
+        let failed = self.command_results
+            .fail_session_commands(session_id, "客户端断开且未在有效期内恢复会话")
+            .await;
+        tracing::info!("Session {} expired, {} pending command(s) failed", session_id, failed);
+    }
+
+    // 记录客户端上报的重连
+    pub fn record_reconnect(&mut self, report: ReconnectReport) {
+        let now = SystemTime::now();
+        self.reconnect_history
+            .entry(report.client_id.clone())
+            .and_modify(|history| {
+                history.count += 1;
+                history.last_report = report.clone();
+                history.last_reported_at = now;
+            })
+            .or_insert(ReconnectHistory { count: 1, last_report: report, last_reported_at: now });
+    }
+
     // 广播消息给所有连接的客户端
     pub async fn broadcast_message(
         &self,
@@ -79,20 +252,13 @@ impl SharedData {
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         println!("当前连接数: {}", self.client_connections.len());
         
-        // 构建带有消息类型的广播消息
-        let broadcast_message = format!("BROADCAST::{}\n", message);
+        let broadcast_message = Envelope::Broadcast { message: message.to_string() };
         
-        for (id, stream) in &self.client_connections {
-            let mut stream = stream.lock().await;
-            if let Err(e) = stream.write_all(broadcast_message.as_bytes()).await {
+        for (id, connection) in &self.client_connections {
+            if let Err(e) = send_message(&connection.sender, broadcast_message.clone()) {
                 eprintln!("发送消息到客户端 {} 失败: {}", id, e);
             } else {
-                // 确保数据被发送
-                if let Err(flush_err) = stream.flush().await {
-                    eprintln!("刷新数据到客户端 {} 失败: {}", id, flush_err);
-                } else {
-                    println!("广播消息已发送到客户端: {}", id);
-                }
+                println!("广播消息已发送到客户端: {}", id);
             }
         }
         Ok(())
@@ -104,28 +270,37 @@ impl SharedData {
         client_id: &str,
         command: &str
     ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        if let Some(stream) = self.client_connections.get(client_id) {
+        self.send_command_with_timeout(client_id, command, None).await
+    }
+
+    /// 下发命令并指定执行超时；None 表示使用客户端的默认值，客户端会按自身上限截断
+    pub async fn send_command_with_timeout(
+        &self,
+        client_id: &str,
+        command: &str,
+        timeout_secs: Option<u64>
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(connection) = self.client_connections.get(client_id) {
             // 创建命令请求并获取命令ID
-            let command_id = self.command_results.create_command(client_id.to_string(), command.to_string()).await;
+            let command_id = self.command_results
+                .create_command(client_id.to_string(), command.to_string(), connection.session_id.clone())
+                .await;
             
             // 发送带有命令ID的命令
-            let command_with_id = format!("CMD:{}::{}\n", command_id, command);
+            let signature = self.sign_request(SignedRequest::Command, &command_id, client_id, &command_signed_content(command, timeout_secs));
+            let request = Envelope::CommandRequest {
+                command_id: command_id.clone(),
+                command: command.to_string(),
+                timeout_secs,
+                signature,
+            };
             
-            tracing::debug!("Preparing to send command to client {}: {}", client_id, command_with_id.trim());
+            tracing::debug!("Preparing to send command to client {}: {:?}", client_id, request);
             
-            let mut stream_guard = stream.lock().await;
-            match stream_guard.write_all(command_with_id.as_bytes()).await {
+            match send_message(&connection.sender, request) {
                 Ok(_) => {
-                    // 确保数据被发送
-                    if let Err(flush_err) = stream_guard.flush().await {
-                        tracing::error!("Failed to flush command to client {}: {}", client_id, flush_err);
-                        return Err(flush_err.into());
-                    }
-                    
-                    // 标记命令为执行中
-                    self.command_results.mark_executing(&command_id).await;
-                    
-                    tracing::info!("Command {} sent to client {} successfully", command_id, client_id);
+                    // 保持 Pending，直到客户端回复 command_accepted / command_rejected
+                    tracing::info!("Command {} queued for client {}", command_id, client_id);
                     Ok(command_id)
                 }
                 Err(write_err) => {
@@ -138,4 +313,288 @@ impl SharedData {
             Err("客户端未连接".into())
         }
     }
+
+    /// 用户是否有权限打开 Web 终端
+    pub fn can_use_terminal(&self, user: &str) -> bool {
+        self.terminal_users.iter().any(|allowed| allowed == user)
+    }
+
+    /// 在客户端上打开终端，返回会话ID和接收终端事件的队列
+    pub fn open_terminal(
+        &mut self,
+        client_id: &str,
+        cols: u16,
+        rows: u16
+    ) -> Result<(String, mpsc::Receiver<TerminalEvent>), String> {
+        let connection = self.client_connections
+            .get(client_id)
+            .ok_or("客户端未连接")?;
+        if !connection.capabilities.contains(&Capability::Terminal) {
+            return Err("客户端未启用终端功能".to_string());
+        }
+
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let signature = self.sign_request(SignedRequest::TerminalOpen, &session_id, client_id, "");
+        send_message(&connection.sender, Envelope::TerminalOpen { session_id: session_id.clone(), cols, rows, signature })
+            .map_err(|e| e.to_string())?;
+
+        let (events, receiver) = mpsc::channel(TERMINAL_EVENT_QUEUE);
+        self.terminals.insert(session_id.clone(), TerminalBridge { client_id: client_id.to_string(), events });
+        Ok((session_id, receiver))
+    }
+
+    /// 把浏览器的输入或窗口调整转发给终端所在的客户端
+    pub fn send_to_terminal(&self, session_id: &str, message: Envelope) -> Result<(), String> {
+        let bridge = self.terminals.get(session_id).ok_or("终端会话已结束")?;
+        let connection = self.client_connections
+            .get(&bridge.client_id)
+            .ok_or("客户端未连接")?;
+        send_message(&connection.sender, message).map_err(|e| e.to_string())
+    }
+
+    /// 浏览器断开时关闭终端，通知客户端结束 shell
+    pub fn close_terminal(&mut self, session_id: &str) {
+        if let Some(bridge) = self.terminals.remove(session_id)
+            && let Some(connection) = self.client_connections.get(&bridge.client_id) {
+            let _ = send_message(&connection.sender, Envelope::TerminalClose { session_id: session_id.to_string() });
+        }
+    }
+
+    /// 把客户端的终端事件转发给浏览器；只接受终端所在客户端发来的事件
+    pub fn route_terminal_event(&mut self, client_id: &str, session_id: &str, event: TerminalEvent) -> bool {
+        let Some(bridge) = self.terminals.get(session_id).filter(|bridge| bridge.client_id == client_id) else {
+            return false;
+        };
+        let closed = matches!(event, TerminalEvent::Closed { .. });
+        if bridge.events.try_send(event).is_err() {
+            tracing::warn!("Terminal {} event queue full, dropping output", session_id);
+        }
+        if closed {
+            self.terminals.remove(session_id);
+        }
+        true
+    }
+
+    /// 客户端连接断开时结束其上的所有终端
+    pub fn close_client_terminals(&mut self, client_id: &str) {
+        self.terminals.retain(|_, bridge| {
+            if bridge.client_id != client_id {
+                return true;
+            }
+            let _ = bridge.events.try_send(TerminalEvent::Closed { reason: Some("客户端连接已断开".to_string()) });
+            false
+        });
+    }
+
+    /// 要求客户端跟踪应用日志，返回跟踪ID和接收日志事件的队列
+    pub fn open_log_tail(
+        &mut self,
+        client_id: &str,
+        app: &str,
+        file: &str,
+        lines: u64
+    ) -> Result<(String, mpsc::Receiver<LogTailEvent>), String> {
+        let connection = self.client_connections
+            .get(client_id)
+            .ok_or("客户端未连接")?;
+        if !connection.capabilities.contains(&Capability::LogTail) {
+            return Err("客户端不支持日志跟踪".to_string());
+        }
+
+        let tail_id = uuid::Uuid::new_v4().to_string();
+        let request = Envelope::LogTailStart {
+            tail_id: tail_id.clone(),
+            app: app.to_string(),
+            file: file.to_string(),
+            lines,
+            signature: self.sign_request(SignedRequest::LogTail, &tail_id, client_id, &log_tail_signed_content(app, file, lines)),
+        };
+        send_message(&connection.sender, request).map_err(|e| e.to_string())?;
+
+        let (events, receiver) = mpsc::channel(LOG_TAIL_EVENT_QUEUE);
+        self.log_tails.insert(tail_id.clone(), LogTailBridge { client_id: client_id.to_string(), events });
+        Ok((tail_id, receiver))
+    }
+
+    /// 浏览器关闭订阅时通知客户端停止跟踪
+    pub fn close_log_tail(&mut self, tail_id: &str) {
+        if let Some(bridge) = self.log_tails.remove(tail_id)
+            && let Some(connection) = self.client_connections.get(&bridge.client_id) {
+            let _ = send_message(&connection.sender, Envelope::LogTailStop { tail_id: tail_id.to_string() });
+        }
+    }
+
+    /// 把客户端回传的日志转发给浏览器；只接受跟踪所在客户端发来的事件
+    pub fn route_log_tail_event(&mut self, client_id: &str, tail_id: &str, event: LogTailEvent) -> bool {
+        let Some(bridge) = self.log_tails.get(tail_id).filter(|bridge| bridge.client_id == client_id) else {
+            return false;
+        };
+        let ended = matches!(event, LogTailEvent::Ended { .. });
+        if bridge.events.try_send(event).is_err() {
+            tracing::warn!("Log tail {} event queue full, dropping lines", tail_id);
+        }
+        if ended {
+            self.log_tails.remove(tail_id);
+        }
+        true
+    }
+
+    /// 客户端连接断开时结束其上的所有日志跟踪
+    pub fn close_client_log_tails(&mut self, client_id: &str) {
+        self.log_tails.retain(|_, bridge| {
+            if bridge.client_id != client_id {
+                return true;
+            }
+            let _ = bridge.events.try_send(LogTailEvent::Ended { reason: "客户端连接已断开".to_string() });
+            false
+        });
+    }
+
+    /// 解析推送目标：单个客户端或配置中的分组，二者只能指定一个
+    pub fn resolve_targets(&self, client_id: Option<&str>, group: Option<&str>) -> Result<Vec<String>, String> {
+        match (client_id, group) {
+            (Some(client_id), None) => Ok(vec![client_id.to_string()]),
+            (None, Some(group)) => self.client_groups
+                .get(group)
+                .filter(|members| !members.is_empty())
+                .cloned()
+                .ok_or_else(|| format!("未知或空的分组: {}", group)),
+            _ => Err("必须且只能指定 client_id 或 group 之一".to_string()),
+        }
+    }
+
+    /// 登记一次文件推送，返回 transfer_id 和可以发送的连接；
+    /// 未连接或不支持文件传输的客户端直接记为失败
+    pub fn begin_file_push(
+        &mut self,
+        targets: &[String],
+        path: &str,
+        size: u64,
+        sha256: &str
+    ) -> (String, Vec<(String, OutboundSender)>) {
+        let mut statuses = HashMap::new();
+        let mut senders = Vec::new();
+        for client_id in targets {
+            let status = match self.client_connections.get(client_id) {
+                None => TransferStatus::Failed("客户端未连接".to_string()),
+                Some(connection) if !connection.capabilities.contains(&Capability::FileTransfer) => {
+                    TransferStatus::Failed("客户端未启用文件推送".to_string())
+                }
+                Some(connection) => {
+                    senders.push((client_id.clone(), connection.sender.clone()));
+                    TransferStatus::Sending
+                }
+            };
+            statuses.insert(client_id.clone(), status);
+        }
+        let transfer_id = self.file_transfers.create(path.to_string(), size, sha256.to_string(), statuses);
+        (transfer_id, senders)
+    }
+
+    /// 要求客户端读取文件，返回 fetch_id 和接收回传内容的队列
+    pub fn open_file_fetch(
+        &mut self,
+        client_id: &str,
+        path: &str,
+        range: FetchRange
+    ) -> Result<(String, mpsc::UnboundedReceiver<FetchEvent>), String> {
+        let connection = self.client_connections
+            .get(client_id)
+            .ok_or("客户端未连接")?;
+        if !connection.capabilities.contains(&Capability::FileFetch) {
+            return Err("客户端未启用文件读取".to_string());
+        }
+
+        let (fetch_id, events) = self.file_fetches.open(client_id, self.max_fetch_file_size);
+        let signature = self.sign_request(SignedRequest::FileFetch, &fetch_id, client_id, &file_fetch_signed_content(path, &range));
+        let request = Envelope::FileFetchRequest { fetch_id: fetch_id.clone(), path: path.to_string(), range, signature };
+        if let Err(e) = send_message(&connection.sender, request) {
+            self.file_fetches.remove(&fetch_id);
+            return Err(e.to_string());
+        }
+        Ok((fetch_id, events))
+    }
+
+    /// 为在线客户端生成新密钥并下发，客户端确认后旧密钥失效
+    pub fn rotate_credential(&mut self, client_id: &str) -> Result<(), String> {
+        let connection = self.client_connections
+            .get(client_id)
+            .ok_or("客户端未连接")?;
+        if !connection.capabilities.contains(&Capability::CredentialRotation) {
+            return Err("客户端不支持密钥轮换".to_string());
+        }
+        let secret = self.credentials.begin_rotation(client_id)?;
+        send_message(&connection.sender, Envelope::CredentialUpdate { secret }).map_err(|e| e.to_string())
+    }
+
+    /// 吊销客户端凭据并断开其连接；客户端没有凭据时返回 false
+    pub async fn revoke_credential(&mut self, client_id: &str) -> Result<bool, String> {
+        if !self.credentials.revoke(client_id)? {
+            return Ok(false);
+        }
+        if let Some(connection) = self.client_connections.get(client_id) {
+            let _ = send_message(&connection.sender, Envelope::ConnectionRejected { reason: "客户端凭据已被吊销".to_string() });
+        }
+        self.remove_client_connection(client_id).await;
+        Ok(true)
+    }
+
+    /// 拒绝待审批的客户端并通知其断开；不在待审批列表中时返回 false
+    pub fn reject_client(&mut self, client_id: &str) -> Result<bool, String> {
+        let Some(pending) = self.approvals.reject(client_id)? else {
+            return Ok(false);
+        };
+        if let Some(sender) = pending.sender() {
+            let _ = send_message(sender, Envelope::ConnectionRejected { reason: "客户端未获批准".to_string() });
+        }
+        Ok(true)
+    }
+
+    /// 要求客户端取消正在执行的命令，结果仍通过 command_response 返回
+    pub async fn cancel_command(&self, command_id: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let command = self.command_results
+            .get_pending_command(command_id)
+            .await
+            .filter(|command| !command.status.is_finished())
+            .ok_or("命令不存在或已结束")?;
+
+        let connection = self.client_connections
+            .get(&command.client_id)
+            .ok_or("客户端未连接")?;
+
+        send_message(&connection.sender, Envelope::CancelCommand { command_id: command_id.to_string() })?;
+        tracing::info!("Cancel requested for command {} on client {}", command_id, command.client_id);
+        Ok(())
+    }
+}
+
+/// 按顺序把文件分块写入客户端的发送队列，队列满时等待而不是丢弃
+pub async fn push_file_to_client(
+    shared_data: SharedDataHandle,
+    client_id: String,
+    sender: OutboundSender,
+    start: FilePushStart,
+    content: Arc<Vec<u8>>
+) {
+    let transfer_id = start.transfer_id.clone();
+    let mut result = sender.send(Envelope::FilePushStart(start)).await;
+    for (index, chunk) in content.chunks(FILE_CHUNK_SIZE).enumerate() {
+        if result.is_err() {
+            break;
+        }
+        result = sender.send(Envelope::FilePushChunk {
+            transfer_id: transfer_id.clone(),
+            offset: (index * FILE_CHUNK_SIZE) as u64,
+            data: chunk.to_vec(),
+        }).await;
+    }
+
+    let mut data = shared_data.lock().await;
+    match result {
+        Ok(()) => data.file_transfers.mark_sent(&transfer_id, &client_id),
+        Err(_) => {
+            tracing::warn!("Connection to {} closed while pushing file {}", client_id, transfer_id);
+            data.file_transfers.finish(&transfer_id, &client_id, Err("客户端连接已关闭".to_string()));
+        }
+    }
 }
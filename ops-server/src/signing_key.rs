@@ -0,0 +1,55 @@
+// ops-server signing-key 子命令：生成命令签名私钥，输出客户端需要固定的公钥
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use clap::Subcommand;
+use ops_common::command_signing::CommandSigner;
+
+type SigningKeyResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Subcommand, Debug)]
+pub enum SigningKeyCommand {
+    /// 生成 Ed25519 签名私钥（PKCS#8 PEM）并输出公钥
+    Generate {
+        /// 私钥输出文件
+        #[arg(long, default_value = "command-signing-key.pem")]
+        out: PathBuf,
+        /// 覆盖已存在的文件
+        #[arg(long)]
+        force: bool,
+    },
+    /// 输出私钥对应的公钥
+    Show {
+        /// 私钥文件
+        #[arg(long, default_value = "command-signing-key.pem")]
+        key: PathBuf,
+    },
+}
+
+pub fn run(command: SigningKeyCommand) -> SigningKeyResult<()> {
+    match command {
+        SigningKeyCommand::Generate { out, force } => {
+            if out.exists() && !force {
+                return Err(format!("{} 已存在，使用 --force 覆盖", out.display()).into());
+            }
+            let pem = CommandSigner::generate_pem()?;
+            let signer = CommandSigner::from_pem(&pem)?;
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&out)?;
+            file.write_all(pem.as_bytes())?;
+            println!("签名私钥: {}（请妥善保管，不要分发到客户端）", out.display());
+            println!("服务端配置: OPS_COMMAND_SIGNING_KEY_FILE={}", out.display());
+            println!("客户端配置: OPS_COMMAND_SIGNING_PUBLIC_KEY={}", signer.public_key());
+        }
+        SigningKeyCommand::Show { key } => {
+            println!("{}", CommandSigner::load(&key)?.public_key());
+        }
+    }
+    Ok(())
+}
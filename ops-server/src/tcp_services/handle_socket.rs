@@ -1,112 +1,313 @@
-use std::time::SystemTime;
-use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::TcpStream };
-use crate::shared_data_handle::{ SharedDataHandle };
-use ops_common::{ClientInfo, tcp_auth::{TcpAuthMessage, TcpAuthenticator}};
+use std::time::{Duration, SystemTime};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use serde::{ Deserialize, Serialize };
+use tokio::sync::{Notify, mpsc};
+use std::net::{IpAddr, SocketAddr};
+use crate::approvals::Admission;
+use crate::file_transfers::FetchEvent;
+use crate::shared_data_handle::{ ClientConnection, LogTailEvent, SharedDataHandle, TerminalEvent };
+use ops_common::{ClientInfo, codec::{FrameCodec, FrameReader, FrameTooLarge, FrameWriter, FramedStream}, tls::{BoxedStream, PeerIdentity, TlsAcceptor}, command_signing::SignedRequest, protocol::{Capability, Envelope, Hello, HelloAck, ResumeRequest, command_policy_signed_content}, tcp_auth::{TcpAuthMessage, TcpAuthenticator}};
 use tracing::{info, error, warn, debug};
-use crate::command_results::CommandResult;
-use std::collections::HashMap;
-
-// 新增：定义消息类型枚举
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "data_type")] // 使用 data_type 字段作为区分枚举的依据
-enum Message {
-    #[serde(rename = "client_info")]
-    ClientInfo {
-        client_id: String,
-        system_info: ops_common::HostInfo,
-        version_info: Vec<ops_common::VersionInfo>,
-        app_info: Vec<ops_common::AppInfo>,
-        last_seen: SystemTime,
-    },
-    #[serde(rename = "command_response")]
-    CommandResponse {
-        command_id: String,
-        client_id: String,
-        command: String,
-        output: String,
-        error_output: String,
-        exit_code: i32,
-        executed_at: SystemTime,
-    },
-    #[serde(rename = "auth_challenge")]
-    AuthChallenge {
-        nonce: String,
-        timestamp: u64,
-    },
-    #[serde(rename = "auth_response")]
-    AuthResponse {
-        client_id: String,
-        nonce: String,
-        response_hash: String,
-        timestamp: u64,
-    },
-    #[serde(rename = "auth_result")]
-    AuthResult {
-        success: bool,
-        message: String,
-    },
-}
+use crate::command_results::{CommandResult, StoreOutcome};
 
 // 连接状态枚举
 #[derive(Debug, Clone, PartialEq)]
 enum ConnectionState {
     Connected,        // 刚连接，等待认证
     Authenticated,    // 已认证，可以正常通信
-    AuthFailed,       // 认证失败
 }
 
-// 客户端连接信息
-#[derive(Debug, Clone)]
-struct ClientConnection {
-    stream: Arc<Mutex<TcpStream>>,
-    state: ConnectionState,
-    challenge_nonce: Option<String>,
-    challenge_timestamp: Option<u64>,
-}
+/// 客户端连接的发送队列，写任务独占连接的写端
+pub type OutboundSender = mpsc::Sender<Envelope>;
 
+/// 每个客户端发送队列的容量，队列满时新消息直接返回错误而不是阻塞调用方
+const OUTBOUND_QUEUE_CAPACITY: usize = 256;
 
-/// 从流中读取数据 - 简单读取直到获得完整消息
-async fn read_line_from_stream(stream: Arc<Mutex<tokio::net::TcpStream>>) -> std::io::Result<Vec<u8>> {
-    let mut stream = stream.lock().await;
-    let mut line_buffer = Vec::new();
-    let mut byte_buffer = [0u8; 1];
-    
-    loop {
-        let n = stream.read(&mut byte_buffer).await?;
-        if n == 0 {
-            if line_buffer.is_empty() {
-                return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "连接已关闭"));
-            } else {
-                // 返回未以换行结尾的数据
-                break;
+type ConnectionReader = FrameReader<ReadHalf<BoxedStream>>;
+
+/// 启动写任务：按顺序发送队列中的消息，所有发送端释放后关闭写方向
+fn spawn_writer(mut writer: FrameWriter<WriteHalf<BoxedStream>>, peer_addr: String) -> OutboundSender {
+    let (sender, mut receiver) = mpsc::channel::<Envelope>(OUTBOUND_QUEUE_CAPACITY);
+
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            if let Err(e) = writer.write_envelope(&message).await {
+                warn!("Failed to write to {}: {}", peer_addr, e);
+                return;
             }
+            debug!("Message sent to {}: {:?}", peer_addr, message);
         }
-        
-        let byte = byte_buffer[0];
-        if byte == b'\n' {
-            // 找到换行符，返回一行数据
-            break;
-        } else if byte != b'\r' {
-            // 忽略回车符，添加其他字符
-            line_buffer.push(byte);
+        let _ = writer.shutdown().await;
+        debug!("Writer for {} stopped", peer_addr);
+    });
+
+    sender
+}
+
+/// TLS 握手的最长时间
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 完成 TLS 握手（若启用），返回统一的传输流以及 mTLS 下客户端证书中的身份
+pub async fn accept_stream(
+    stream: TcpStream,
+    tls_acceptor: Option<&TlsAcceptor>
+) -> std::io::Result<(BoxedStream, Option<PeerIdentity>)> {
+    let Some(acceptor) = tls_acceptor else {
+        return Ok((Box::new(stream), None));
+    };
+
+    let tls_stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS 握手超时"))??;
+
+    let peer_identity = match tls_stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()) {
+        Some(cert) => Some(PeerIdentity::from_certificate(cert)?),
+        None => None,
+    };
+    Ok((Box::new(tls_stream), peer_identity))
+}
+
+/// mTLS 下检查客户端声明的 client_id 与证书身份一致，不一致时通知客户端并返回错误
+fn verify_claimed_identity(
+    outbound: &OutboundSender,
+    peer_identity: Option<&PeerIdentity>,
+    claimed_client_id: &str,
+    peer_addr: &str
+) -> std::io::Result<()> {
+    let Some(identity) = peer_identity else {
+        return Ok(());
+    };
+    if identity.matches(claimed_client_id) {
+        return Ok(());
+    }
+
+    warn!("Client {} claimed client_id {} but certificate identifies {:?}", peer_addr, claimed_client_id, identity.names);
+    let reason = format!("client_id {} 与客户端证书身份不一致", claimed_client_id);
+    let _ = send_message(outbound, Envelope::ConnectionRejected { reason: reason.clone() });
+    Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, reason))
+}
+
+/// 连接已通过认证或续连确定 client_id 后，拒绝消息中声明的其他身份并断开连接
+fn verify_bound_identity(
+    outbound: &OutboundSender,
+    ids: &ConnectionIds,
+    claimed_client_id: &str,
+    peer_addr: &str
+) -> std::io::Result<()> {
+    if !ids.identity_bound || ids.client_id == claimed_client_id {
+        return Ok(());
+    }
+
+    warn!("Client {} authenticated from {} claimed client_id {}, closing connection", ids.client_id, peer_addr, claimed_client_id);
+    let reason = format!("client_id {} 与认证身份 {} 不一致", claimed_client_id, ids.client_id);
+    let _ = send_message(outbound, Envelope::ConnectionRejected { reason: reason.clone() });
+    Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, reason))
+}
+
+/// 握手阶段等待客户端 hello 的最长时间
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 等待客户端的 hello 并完成协议协商，不兼容时通知客户端后返回错误；
+/// 协商结果由调用方在处理续连请求后发送
+async fn negotiate_protocol(
+    reader: &mut ConnectionReader,
+    outbound: &OutboundSender,
+    peer_addr: &str
+) -> std::io::Result<(HelloAck, Hello)> {
+    let read_result = tokio::time::timeout(HELLO_TIMEOUT, reader.read_envelope()).await;
+
+    let hello = match read_result {
+        Ok(Ok(Envelope::Hello(hello))) => hello,
+        Ok(Ok(other)) => {
+            let reason = "握手失败: 连接后的第一个消息必须是 hello".to_string();
+            warn!("Expected hello from {}, got {:?}", peer_addr, other);
+            let _ = send_message(outbound, Envelope::ConnectionRejected { reason: reason.clone() });
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, reason));
+        }
+        Ok(Err(e)) => return Err(e),
+        Err(_) => {
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "等待 hello 超时"));
+        }
+    };
+
+    match Hello::local().negotiate(&hello) {
+        Ok(ack) => {
+            info!("Negotiated protocol v{} with {} (agent {}, capabilities: {:?})",
+                  ack.protocol_version, peer_addr, hello.agent_version, ack.capabilities);
+            Ok((ack, hello))
+        }
+        Err(reason) => {
+            warn!("Rejecting {} (agent {}): {}", peer_addr, hello.agent_version, reason);
+            let _ = send_message(outbound, Envelope::ConnectionRejected { reason: reason.clone() });
+            Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, reason))
+        }
+    }
+}
+
+/// 为已认证的连接创建可恢复会话，并把续连令牌发给客户端
+async fn open_session(
+    shared_data: &SharedDataHandle,
+    outbound: &OutboundSender,
+    client_id: &str,
+    connection_id: &str
+) -> std::io::Result<String> {
+    let (session_id, token, ttl) = {
+        let mut data = shared_data.lock().await;
+        let (session_id, token) = data.sessions.open(client_id, connection_id);
+        (session_id, token, data.sessions.ttl())
+    };
+    send_message(outbound, Envelope::SessionToken { token, expires_in_secs: ttl.as_secs() })?;
+    Ok(session_id)
+}
+
+/// 凭续连令牌恢复会话并登记新连接，成功时返回 session_id
+async fn resume_session(
+    shared_data: &SharedDataHandle,
+    outbound: &OutboundSender,
+    request: &ResumeRequest,
+    ids: &ConnectionIds,
+    negotiated: &HelloAck
+) -> std::io::Result<Option<String>> {
+    let mut data = shared_data.lock().await;
+    // 凭据已吊销或尚未获批准的客户端不能恢复会话
+    if data.credentials.is_revoked(&request.client_id)
+        || (data.require_client_approval && data.approvals.status(&request.client_id) != Admission::Approved) {
+        return Ok(None);
+    }
+    let Some(resumed) = data.sessions.resume(&request.client_id, &request.token, &ids.connection_id) else {
+        return Ok(None);
+    };
+    let (session_id, token) = (resumed.session_id, resumed.token);
+
+    // 会话还在旧连接上：客户端已经重连，旧连接多半是服务端没有察觉断开的半开连接，关闭它
+    if let Some(replaced) = resumed.replaced_connection
+        && let Some(stale) = data.client_connections.get(&request.client_id)
+        && stale.connection_id == replaced {
+        warn!("Client {} resumed its session while connection {} was still registered, closing the stale connection", request.client_id, replaced);
+        stale.close("会话已在新连接上恢复");
+    }
+
+    let mut ack = negotiated.clone();
+    ack.resumed = true;
+    let connection = ClientConnection::new(outbound.clone(), ids.connection_id.clone(), Some(session_id.clone()), ack, ids.closer.clone());
+    if let Err(e) = data.add_client_connection(request.client_id.clone(), connection).await {
+        let _ = send_message(outbound, Envelope::ConnectionRejected { reason: e.clone() });
+        return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e));
+    }
+
+    let ttl = data.sessions.ttl();
+    drop(data);
+    send_message(outbound, Envelope::HelloAck(HelloAck { resumed: true, ..negotiated.clone() }))?;
+    send_message(outbound, Envelope::SessionToken { token, expires_in_secs: ttl.as_secs() })?;
+    Ok(Some(session_id))
+}
+
+/// 向协商了 command_policy 的客户端下发命令校验策略，配置了签名私钥时一并签名
+async fn push_command_policy(
+    shared_data: &SharedDataHandle,
+    outbound: &OutboundSender,
+    negotiated: &HelloAck,
+    client_id: &str
+) -> std::io::Result<()> {
+    if !negotiated.supports(&Capability::CommandPolicy) {
+        return Ok(());
+    }
+    let policy_id = uuid::Uuid::new_v4().to_string();
+    let (policy, signature) = {
+        let data = shared_data.lock().await;
+        let policy = data.command_policy.clone();
+        let signature = data.sign_request(SignedRequest::CommandPolicy, &policy_id, client_id, &command_policy_signed_content(&policy));
+        (policy, signature)
+    };
+    send_message(outbound, Envelope::CommandPolicy { policy_id, policy, signature })
+}
+
+/// 校验认证响应：nonce 必须是本连接尚未使用的质询，再用该客户端的密钥校验响应哈希
+async fn verify_auth_response(
+    shared_data: &SharedDataHandle,
+    response: &TcpAuthMessage,
+    connection_id: &str
+) -> Result<(), String> {
+    let TcpAuthMessage::Response { client_id, nonce, .. } = response else {
+        return Err("不是认证响应".to_string());
+    };
+    let mut data = shared_data.lock().await;
+    let timestamp = data.auth_nonces.consume(nonce, connection_id)?;
+    let secrets = data.auth_secrets(client_id);
+    match TcpAuthenticator::verify_response(response, nonce, timestamp, |_| secrets.clone()) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("响应校验失败".to_string()),
+        Err(e) => Err(format!("响应校验出错: {}", e)),
+    }
+}
+
+/// 记录一次认证失败，同一 IP 失败次数过多时临时封禁
+async fn record_auth_failure(shared_data: &SharedDataHandle, peer_ip: IpAddr, client_id: &str, reason: &str) {
+    let banned = shared_data.lock().await.auth_bans.record_failure(peer_ip, Some(client_id), reason);
+    if let Some(duration) = banned {
+        warn!("Banning {} for {}s after repeated authentication failures (last client_id: {})",
+              peer_ip, duration.as_secs(), client_id);
+    }
+}
+
+/// 凭一次性注册令牌为客户端签发密钥；失败时通知客户端并断开连接
+async fn enroll_client(
+    shared_data: &SharedDataHandle,
+    outbound: &OutboundSender,
+    client_id: &str,
+    token: &str,
+    peer_addr: &str
+) -> std::io::Result<()> {
+    let result = shared_data.lock().await.credentials.enroll(client_id, token);
+    match result {
+        Ok(secret) => {
+            info!("Client {} enrolled from {}", client_id, peer_addr);
+            send_message(outbound, Envelope::Auth(TcpAuthMessage::Enrolled { secret }))
+        }
+        Err(reason) => {
+            warn!("Enrollment of client {} from {} failed: {}", client_id, peer_addr, reason);
+            let _ = send_message(outbound, Envelope::Auth(TcpAuthenticator::create_failure_result(&reason)));
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, reason))
         }
     }
-    
-    debug!("Read line: {} bytes", line_buffer.len());
-    Ok(line_buffer)
 }
 
-// /// 解析客户端发送的 JSON 数据
-// fn parse_client_data(data: &[u8]) -> Result<ClientInfo, serde_json::Error> {
-//     serde_json::from_slice(data)
-// }
+/// 没有凭据的客户端通过共享密钥认证后，为其建立凭据并下发独立密钥
+async fn migrate_legacy_client(shared_data: &SharedDataHandle, outbound: &OutboundSender, client_id: &str) {
+    let mut data = shared_data.lock().await;
+    let Some(shared_secret) = data.tcp_auth_secret.clone() else { return };
+    if data.credentials.secrets_for(client_id).is_some() {
+        return;
+    }
+    match data.credentials.migrate(client_id, &shared_secret) {
+        Ok(secret) => {
+            info!("Issuing a dedicated credential to client {} authenticated with the shared secret", client_id);
+            if let Err(e) = send_message(outbound, Envelope::CredentialUpdate { secret }) {
+                warn!("Failed to send credential update to {}: {}", client_id, e);
+            }
+        }
+        Err(e) => error!("Failed to create credential for client {}: {}", client_id, e),
+    }
+}
 
-/// 解析客户端发送的 JSON 数据
-fn parse_client_data(data: &[u8]) -> Result<Message, serde_json::Error> {
-    serde_json::from_slice(data)
+/// 按连接认证出的 client_id 检查审批状态；未启用审批时所有客户端都视为已批准
+async fn admit_client(
+    shared_data: &SharedDataHandle,
+    client_id: &str,
+    client_info: &ClientInfo,
+    outbound: &OutboundSender,
+    peer_addr: &str
+) -> Admission {
+    let mut data = shared_data.lock().await;
+    if !data.require_client_approval {
+        return Admission::Approved;
+    }
+    let admission = data.approvals.admit(client_id, client_info, peer_addr, outbound);
+    if admission == Admission::Pending {
+        debug!("Client {} from {} is waiting for approval", client_id, peer_addr);
+    }
+    admission
 }
 
 /// 更新共享内存中的客户端信息
@@ -123,73 +324,152 @@ async fn update_shared_data(
     Ok(())
 }
 
-/// 向客户端发送 ACK
-async fn send_ack(stream: &mut Arc<Mutex<TcpStream>>) -> std::io::Result<()> {
-    // stream.lock().await.write_all(b"ACK").await
-    stream.lock().await.write_all(b"ACK\n").await
+/// 把客户端回传的文件内容转发给等待下载的请求
+async fn route_fetch_event(shared_data: &SharedDataHandle, client_id: &str, fetch_id: &str, event: FetchEvent) {
+    if !shared_data.lock().await.file_fetches.route(client_id, fetch_id, event) {
+        debug!("Ignoring event of unknown file fetch {} from {}", fetch_id, client_id);
+    }
 }
 
-/// 向客户端发送消息
-async fn send_message(stream: &Arc<Mutex<TcpStream>>, message: &Message) -> std::io::Result<()> {
-    let json_data = serde_json::to_vec(message)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-    
-    let mut stream_guard = stream.lock().await;
-    stream_guard.write_all(&json_data).await?;
-    stream_guard.write_all(b"\n").await?; // 添加换行符作为消息分隔符
-    stream_guard.flush().await?;
-    
-    debug!("Message sent: {} bytes", json_data.len());
-    Ok(())
+/// 把消息放入客户端的发送队列，不等待网络写入完成
+pub fn send_message(outbound: &OutboundSender, message: Envelope) -> std::io::Result<()> {
+    outbound.try_send(message).map_err(|e| match e {
+        mpsc::error::TrySendError::Full(_) => {
+            std::io::Error::new(std::io::ErrorKind::WouldBlock, "客户端发送队列已满")
+        }
+        mpsc::error::TrySendError::Closed(_) => {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "客户端连接已关闭")
+        }
+    })
 }
 
-// /// 向客户端发送任意消息
-// async fn send_message_to_client(
-//     stream: &mut Arc<Mutex<TcpStream>>,
-//     message: &[u8]
-// ) -> std::io::Result<()> {
-//     stream.lock().await.write_all(message).await
-// }
-
 /// 主函数：处理客户端连接
 pub async fn handle_client_connection(
-    stream: tokio::net::TcpStream, // 客户端连接的流
-    shared_data: SharedDataHandle // 共享的数据结构
+    stream: BoxedStream, // 客户端连接的流（明文或 TLS）
+    peer_addr: SocketAddr, // 客户端地址
+    peer_identity: Option<PeerIdentity>, // mTLS 下客户端证书中的身份
+    shared_data: SharedDataHandle, // 共享的数据结构
+    codec: FrameCodec // 帧编解码配置
 ) -> std::io::Result<()> {
-    let peer_addr = stream.peer_addr()
-        .map(|addr| addr.to_string())
-        .unwrap_or_else(|_| "unknown".to_string());
-    
-    let mut client_id = String::new();
-    let mut connection_state = ConnectionState::Connected;
-    let mut challenge_nonce: Option<String> = None;
-    let mut challenge_timestamp: Option<u64> = None;
-    
+    let peer_ip = peer_addr.ip();
+    let peer_addr = peer_addr.to_string();
     info!("Handling client connection from: {}", peer_addr);
 
-    // 将 stream 包装为 Arc<Mutex<_>> 以便多处借用
-    let stream = Arc::new(Mutex::new(stream));
+    // 读写分离：读循环独占读端，写任务独占写端，其他地方只通过发送队列发消息
+    let (mut reader, writer) = FramedStream::new(stream, codec).split();
+    let outbound = spawn_writer(writer, peer_addr.clone());
+
+    // 认证失败次数过多的 IP 在封禁期内直接拒绝
+    let banned_until = shared_data.lock().await.auth_bans.banned_until(peer_ip);
+    if let Some(until) = banned_until {
+        let remaining = until.duration_since(SystemTime::now()).unwrap_or_default().as_secs();
+        warn!("Refusing connection from banned address {} ({}s remaining)", peer_ip, remaining);
+        let reason = format!("认证失败次数过多，请在 {} 秒后重试", remaining);
+        let _ = send_message(&outbound, Envelope::ConnectionRejected { reason: reason.clone() });
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, reason));
+    }
+    let mut ids = ConnectionIds {
+        connection_id: uuid::Uuid::new_v4().to_string(),
+        client_id: String::new(),
+        identity_bound: false,
+        session_id: None,
+        closer: Arc::new(Notify::new()),
+    };
+
+    let result = serve_connection(
+        &mut reader, &outbound, &mut ids, peer_identity.as_ref(), &shared_data, &peer_addr, peer_ip,
+    ).await;
+
+    // 连接结束时注销；若该客户端已经通过新连接重新注册，则保留新连接
+    let ConnectionIds { connection_id, client_id, session_id, .. } = ids;
+    let mut data = shared_data.lock().await;
+    data.auth_nonces.forget_connection(&connection_id);
+    if !client_id.is_empty() {
+        data.remove_client_connection_if(&client_id, &connection_id).await;
+        // 终端不随会话恢复，连接断开后直接结束
+        if !data.client_connections.contains_key(&client_id) {
+            data.close_client_terminals(&client_id);
+            data.file_transfers.fail_client(&client_id, "客户端连接已断开");
+            data.file_fetches.fail_client(&client_id, "客户端连接已断开");
+            data.close_client_log_tails(&client_id);
+        }
+    }
+
+    // 会话保留一段时间等待客户端凭令牌恢复，过期后其中未完成的命令标记为失败
+    if let Some(session_id) = session_id {
+        data.sessions.detach(&session_id, &connection_id);
+        let ttl = data.sessions.ttl();
+        let shared_data = shared_data.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ttl).await;
+            shared_data.lock().await.expire_session(&session_id).await;
+        });
+    }
+    result
+}
+
+/// 连接的标识：本连接 ID、识别出的 client_id 以及所属会话
+struct ConnectionIds {
+    connection_id: String,
+    client_id: String,
+    // client_id 由认证或续连确定，之后不能再通过客户端消息更改
+    identity_bound: bool,
+    session_id: Option<String>,
+    // 会话被其他连接接管时通知读循环退出
+    closer: Arc<Notify>,
+}
+
+/// 连接的读循环：握手、认证，然后处理客户端消息直到连接断开
+async fn serve_connection(
+    reader: &mut ConnectionReader,
+    outbound: &OutboundSender,
+    ids: &mut ConnectionIds,
+    peer_identity: Option<&PeerIdentity>,
+    shared_data: &SharedDataHandle,
+    peer_addr: &str,
+    peer_ip: IpAddr
+) -> std::io::Result<()> {
+    let mut connection_state = ConnectionState::Connected;
+    let mut challenge_nonce: Option<String> = None;
+    // 客户端在本连接上登记后下发一次策略，之后才会收到命令
+    let mut policy_sent = false;
     
-    // 创建认证器
-    let tcp_auth_secret = std::env::var("OPS_TCP_AUTH_SECRET")
-        .unwrap_or_else(|_| "default-tcp-secret-key".to_string());
-    let authenticator = TcpAuthenticator::new(tcp_auth_secret);
+    // 先协商协议版本和功能，再进入认证
+    let (negotiated, hello) = negotiate_protocol(reader, outbound, peer_addr).await?;
+
+    // 客户端携带续连令牌时尝试恢复会话，成功则跳过认证质询
+    if let Some(request) = &hello.resume {
+        verify_claimed_identity(outbound, peer_identity, &request.client_id, peer_addr)?;
+        ids.session_id = resume_session(shared_data, outbound, request, ids, &negotiated).await?;
+        if ids.session_id.is_some() {
+            info!("Client {} resumed its session from {}", request.client_id, peer_addr);
+            ids.client_id = request.client_id.clone();
+            ids.identity_bound = true;
+            connection_state = ConnectionState::Authenticated;
+            push_command_policy(shared_data, outbound, &negotiated, &ids.client_id).await?;
+            policy_sent = true;
+        } else {
+            info!("Resume token from {} is invalid or expired, falling back to authentication", peer_addr);
+        }
+    }
+    if ids.session_id.is_none() {
+        send_message(outbound, Envelope::HelloAck(negotiated.clone()))?;
+    }
     
     // 如果启用了TCP认证，先发送认证质询
-    let tcp_auth_enabled = std::env::var("OPS_TCP_AUTH_ENABLED")
-        .map(|v| v.to_lowercase() == "true" || v == "1")
-        .unwrap_or(false);
+    let tcp_auth_enabled = shared_data.lock().await.tcp_auth_enabled;
         
-    if tcp_auth_enabled {
+    if connection_state == ConnectionState::Authenticated {
+        // 会话已恢复
+    } else if tcp_auth_enabled {
         info!("TCP authentication enabled, sending challenge to {}", peer_addr);
-        let challenge = TcpAuthenticator::generate_challenge();
+        // 质询登记到本连接，响应只能在本连接上使用一次
+        let challenge = shared_data.lock().await.auth_nonces.issue(&ids.connection_id);
         
-        if let TcpAuthMessage::Challenge { nonce, timestamp } = challenge {
+        if let TcpAuthMessage::Challenge { nonce, .. } = &challenge {
             challenge_nonce = Some(nonce.clone());
-            challenge_timestamp = Some(timestamp);
             
-            let challenge_msg = Message::AuthChallenge { nonce, timestamp };
-            if let Err(e) = send_message(&stream, &challenge_msg).await {
+            if let Err(e) = send_message(outbound, Envelope::Auth(challenge)) {
                 error!("Failed to send authentication challenge to {}: {}", peer_addr, e);
                 return Err(e);
             }
@@ -204,48 +484,67 @@ pub async fn handle_client_connection(
     loop {
         debug!("Waiting for data from client: {}", peer_addr);
 
-        // 1. 读取一行数据（以换行符分割）
-        let data = match read_line_from_stream(Arc::clone(&stream)).await {
+        // 1. 读取一个完整帧；会话被新连接接管时不再等待
+        let frame = tokio::select! {
+            frame = reader.read_frame() => frame,
+            _ = ids.closer.notified() => {
+                info!("Session of client {} was taken over by a new connection, closing connection from {}", ids.client_id, peer_addr);
+                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "会话已在新连接上恢复"));
+            }
+        };
+        let data = match frame {
             Ok(data) => data,
             Err(e) => {
-                warn!("Failed to read data from {}: {}", peer_addr, e);
-                {
-                    let mut data = shared_data.lock().await;
-                    data.remove_client_connection(&client_id).await;
+                if FrameTooLarge::is_frame_too_large(&e) {
+                    // 超长帧之后的字节流已无法对齐，只能断开连接
+                    error!("Closing connection from {}: {}", peer_addr, e);
+                } else {
+                    warn!("Failed to read data from {}: {}", peer_addr, e);
                 }
                 return Err(e);
             }
         };
-        
-        // 跳过空行
-        if data.is_empty() {
-            debug!("Received empty line from {}, continuing...", peer_addr);
-            continue;
-        }
-
-
-                // 添加调试信息：显示接收到的原始数据
-        let data_str = String::from_utf8_lossy(&data);
-        debug!("Raw data from {}: {}", peer_addr, data_str);
-
-        // 检查是否是回环命令数据（服务器发送给客户端的命令被错误读取）
-        if data_str.trim().starts_with("CMD:") {
-            warn!("Server read loop detected command data meant for client: {}", data_str.trim());
-            continue;
-        }
 
         // 解析消息
-        let message = match parse_client_data(&data) {
+        let message = match Envelope::decode(&data) {
             Ok(msg) => msg,
             Err(e) => {
                 error!("Failed to parse data from {}: {}", peer_addr, e);
-                error!("Raw data that failed to parse: {}", data_str);
+                error!("Raw data that failed to parse: {}", String::from_utf8_lossy(&data));
                 continue;
             }
         };
 
+        // 认证后凭据被吊销的客户端立即断开
+        if tcp_auth_enabled
+            && connection_state == ConnectionState::Authenticated
+            && shared_data.lock().await.credentials.is_revoked(&ids.client_id) {
+            warn!("Credential of client {} was revoked, closing connection from {}", ids.client_id, peer_addr);
+            let _ = send_message(outbound, Envelope::ConnectionRejected { reason: "客户端凭据已被吊销".to_string() });
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "客户端凭据已被吊销"));
+        }
+
         match message {
-            Message::AuthResponse { client_id: auth_client_id, nonce, response_hash, timestamp } => {
+            Envelope::Auth(TcpAuthMessage::Enroll { client_id, token }) => {
+                if !tcp_auth_enabled || challenge_nonce.is_none() || connection_state == ConnectionState::Authenticated {
+                    warn!("Received unexpected enrollment request from {}", peer_addr);
+                    continue;
+                }
+                verify_claimed_identity(outbound, peer_identity, &client_id, peer_addr)?;
+                if let Err(e) = enroll_client(shared_data, outbound, &client_id, &token, peer_addr).await {
+                    record_auth_failure(shared_data, peer_ip, &client_id, &format!("注册失败: {}", e)).await;
+                    return Err(e);
+                }
+            }
+            Envelope::Auth(auth_msg) => {
+                // 质询和结果消息不应该从客户端接收
+                let TcpAuthMessage::Response { client_id: auth_client_id, .. } = &auth_msg else {
+                    warn!("Received unexpected auth message type from client {}", peer_addr);
+                    continue;
+                };
+                let auth_client_id = auth_client_id.clone();
+                verify_claimed_identity(outbound, peer_identity, &auth_client_id, peer_addr)?;
+
                 if !tcp_auth_enabled {
                     warn!("Received auth response but authentication is disabled from {}", peer_addr);
                     continue;
@@ -253,56 +552,37 @@ pub async fn handle_client_connection(
                 
                 info!("Received auth response from: {} (ID: {})", peer_addr, auth_client_id);
                 
-                // 验证认证响应
-                let auth_msg = TcpAuthMessage::Response {
-                    client_id: auth_client_id.clone(),
-                    nonce: nonce.clone(),
-                    response_hash,
-                    timestamp,
-                };
+                // 验证认证响应：已注册的客户端使用各自的密钥
+                let verification = verify_auth_response(shared_data, &auth_msg, &ids.connection_id).await;
                 
-                let is_valid = match (&challenge_nonce, &challenge_timestamp) {
-                    (Some(orig_nonce), Some(orig_timestamp)) => {
-                        match authenticator.verify_response(&auth_msg, orig_nonce, *orig_timestamp) {
-                            Ok(valid) => valid,
-                            Err(e) => {
-                                error!("Authentication verification error for {}: {}", peer_addr, e);
-                                false
-                            }
-                        }
-                    }
-                    _ => {
-                        error!("No challenge issued but received auth response from {}", peer_addr);
-                        false
-                    }
-                };
-                
-                if is_valid {
+                if verification.is_ok() {
                     info!("Authentication successful for client {} from {}", auth_client_id, peer_addr);
+                    shared_data.lock().await.auth_bans.record_success(peer_ip);
                     connection_state = ConnectionState::Authenticated;
-                    client_id = auth_client_id;
+                    ids.client_id = auth_client_id;
+                    ids.identity_bound = true;
                     
                     // 发送认证成功消息
-                    let success_msg = Message::AuthResult {
-                        success: true,
-                        message: "Authentication successful".to_string(),
-                    };
-                    
-                    if let Err(e) = send_message(&stream, &success_msg).await {
+                    let success_msg = Envelope::Auth(TcpAuthenticator::create_success_result());
+                    if let Err(e) = send_message(outbound, success_msg) {
                         error!("Failed to send auth success message to {}: {}", peer_addr, e);
                         return Err(e);
                     }
-                } else {
-                    warn!("Authentication failed for client {} from {}", auth_client_id, peer_addr);
-                    connection_state = ConnectionState::AuthFailed;
+
+                    // 签发续连令牌，短暂断线后可凭令牌恢复会话
+                    ids.session_id = Some(open_session(shared_data, outbound, &ids.client_id, &ids.connection_id).await?);
+
+                    // 通过旧版共享密钥认证的客户端自动迁移到独立密钥
+                    if negotiated.capabilities.contains(&Capability::CredentialRotation) {
+                        migrate_legacy_client(shared_data, outbound, &ids.client_id).await;
+                    }
+                } else if let Err(reason) = verification {
+                    warn!("Authentication failed for client {} from {}: {}", auth_client_id, peer_addr, reason);
+                    record_auth_failure(shared_data, peer_ip, &auth_client_id, &reason).await;
                     
                     // 发送认证失败消息
-                    let failure_msg = Message::AuthResult {
-                        success: false,
-                        message: "Authentication failed".to_string(),
-                    };
-                    
-                    if let Err(e) = send_message(&stream, &failure_msg).await {
+                    let failure_msg = Envelope::Auth(TcpAuthenticator::create_failure_result("Authentication failed"));
+                    if let Err(e) = send_message(outbound, failure_msg) {
                         error!("Failed to send auth failure message to {}: {}", peer_addr, e);
                     }
                     
@@ -313,56 +593,63 @@ pub async fn handle_client_connection(
                     ));
                 }
             }
-            Message::ClientInfo { client_id: msg_client_id, system_info, version_info, app_info, last_seen } => {
+            Envelope::ClientInfo(client_info) => {
                 // 检查连接是否已认证
                 if tcp_auth_enabled && connection_state != ConnectionState::Authenticated {
                     warn!("Received client info before authentication from {}", peer_addr);
                     continue;
                 }
                 
-                info!("Received client info from: {} (ID: {})", peer_addr, msg_client_id);
-                client_id = msg_client_id.clone();
+                info!("Received client info from: {} (ID: {})", peer_addr, client_info.client_id);
+                verify_claimed_identity(outbound, peer_identity, &client_info.client_id, peer_addr)?;
+                // 只有未启用认证时才从上报信息中获取 client_id
+                verify_bound_identity(outbound, ids, &client_info.client_id, peer_addr)?;
+                ids.client_id = client_info.client_id.clone();
+
+                // 需要审批时，未批准的客户端只进入待审批列表，不登记连接
+                match admit_client(shared_data, &ids.client_id, &client_info, outbound, peer_addr).await {
+                    Admission::Approved => {}
+                    Admission::Pending => {
+                        send_message(outbound, Envelope::Ack)?;
+                        continue;
+                    }
+                    Admission::Rejected => {
+                        warn!("Refusing rejected client {} from {}", ids.client_id, peer_addr);
+                        let _ = send_message(outbound, Envelope::ConnectionRejected { reason: "客户端未获批准".to_string() });
+                        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "客户端未获批准"));
+                    }
+                }
 
                 // 添加连接到共享数据
                 {
                     let mut data = shared_data.lock().await;
-                    if let Err(e) = data.add_client_connection(client_id.clone(), Arc::clone(&stream)).await {
-                        error!("Failed to add client connection {}: {}", client_id, e);
+                    if let Err(e) = data.add_client_connection(
+                        ids.client_id.clone(),
+                        ClientConnection::new(outbound.clone(), ids.connection_id.clone(), ids.session_id.clone(), negotiated.clone(), ids.closer.clone()),
+                    ).await {
+                        error!("Failed to add client connection {}: {}", ids.client_id, e);
                         // 发送拒绝连接的消息
-                        let _ = stream.lock().await.write_all(b"CONNECTION_REJECTED: Too many connections").await;
+                        let _ = send_message(outbound, Envelope::ConnectionRejected { reason: e.clone() });
                         return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e));
                     }
                 }
-
-                // 重构 ClientInfo 结构
-                let client_info = ClientInfo {
-                    client_id: msg_client_id,
-                    system_info,
-                    version_info,
-                    app_info,
-                    last_seen,
-                };
+                if !policy_sent {
+                    push_command_policy(shared_data, outbound, &negotiated, &ids.client_id).await?;
+                    policy_sent = true;
+                }
 
                 // 更新共享数据
-                if let Err(e) = update_shared_data(&shared_data, client_info).await {
-                    error!("Failed to update shared data for {}: {}", client_id, e);
+                if let Err(e) = update_shared_data(shared_data, client_info).await {
+                    error!("Failed to update shared data for {}: {}", ids.client_id, e);
                 }
 
                 // 发送 ACK
-                if let Err(e) = send_ack(&mut Arc::clone(&stream)).await {
-                    error!("Failed to send ACK to {}: {}", client_id, e);
+                if let Err(e) = send_message(outbound, Envelope::Ack) {
+                    error!("Failed to send ACK to {}: {}", ids.client_id, e);
                     return Err(e);
                 }
             }
-            Message::CommandResponse { 
-                command_id, 
-                client_id: resp_client_id, 
-                command, 
-                output, 
-                error_output, 
-                exit_code, 
-                executed_at 
-            } => {
+            Envelope::CommandResponse(response) => {
                 // 检查连接是否已认证
                 if tcp_auth_enabled && connection_state != ConnectionState::Authenticated {
                     warn!("Received command response before authentication from {}", peer_addr);
@@ -370,78 +657,171 @@ pub async fn handle_client_connection(
                 }
                 
                 info!("Received command response from client {}: command_id={}, exit_code={}", 
-                      resp_client_id, command_id, exit_code);
+                      response.client_id, response.command_id, response.exit_code);
+                verify_claimed_identity(outbound, peer_identity, &response.client_id, peer_addr)?;
+                verify_bound_identity(outbound, ids, &response.client_id, peer_addr)?;
                 
                 // 创建命令结果对象
                 let command_result = CommandResult {
-                    command_id,
-                    client_id: resp_client_id,
-                    command,
-                    output,
-                    error_output,
-                    exit_code,
-                    executed_at,
+                    command_id: response.command_id,
+                    client_id: response.client_id,
+                    command: response.command,
+                    output: response.output,
+                    error_output: response.error_output,
+                    exit_code: response.exit_code,
+                    executed_at: response.executed_at,
                     received_at: SystemTime::now(),
+                    cancelled: response.cancelled,
+                    timed_out: response.timed_out,
                 };
                 
-                // 存储命令结果
-                let mut data = shared_data.lock().await;
-                data.command_results.store_result(command_result).await;
-            }
-            Message::AuthChallenge { .. } | Message::AuthResult { .. } => {
-                // 这些消息类型不应该从客户端接收
-                warn!("Received unexpected auth message type from client {}", peer_addr);
-            }
-        }
-
-
-
-
-
-
-
-        // // 2. 解析数据
-        // let client_data = match parse_client_data(&data) {
-        //     Ok(data) => data,
-        //     Err(e) => {
-        //         eprintln!("解析数据失败: {}", e);
-        //         continue;
-        //     }
-        // };
-
-        // client_id = client_data.client_id.clone();
+                // 只接受本连接客户端自己的命令结果
+                let command_id = command_result.command_id.clone();
+                if command_result.client_id != ids.client_id {
+                    warn!("Client {} sent a result for client {} from {}, ignoring",
+                          ids.client_id, command_result.client_id, peer_addr);
+                    continue;
+                }
 
-        // // 3. 添加连接到共享数据
-        // {
-        //     // let mut data = shared_data.lock().await;
-        //     // data.add_client_connection(client_id.clone(), Arc::clone(&stream)).await;
-        //     // if client_id.is_empty() {
-        //     // println!("新增客户端连接: {}", client_id.clone());
-        //     let mut data = shared_data.lock().await;
-        //     data.add_client_connection(client_id.clone(), Arc::clone(&stream)).await;
-        //     // }
-        // }
+                // 存储命令结果；客户端重连后会重发未确认的结果，按 command_id 去重。
+                // 服务端不认识的命令同样确认，否则结果会一直留在客户端发件箱中
+                match shared_data.lock().await.command_results.store_result(command_result).await {
+                    StoreOutcome::Stored => {}
+                    StoreOutcome::Duplicate => debug!("Ignoring duplicate result for command {}", command_id),
+                    StoreOutcome::Unknown => {
+                        info!("Acknowledging result for unknown command {} from {} without storing it", command_id, peer_addr);
+                    }
+                    StoreOutcome::OtherClient => {
+                        warn!("Ignoring result for command {} of another client from {}", command_id, peer_addr);
+                        continue;
+                    }
+                }
 
-        // // 4. 更新共享数据
-        // if let Err(e) = update_shared_data(&shared_data, client_data).await {
-        //     eprintln!("更新共享数据失败: {}", e);
-        // }
+                // 确认后客户端从发件箱中删除该结果
+                if let Err(e) = send_message(outbound, Envelope::ResultAck { command_id }) {
+                    warn!("Failed to acknowledge command result from {}: {}", peer_addr, e);
+                }
+            }
+            Envelope::CommandAccepted { command_id } | Envelope::CommandRejected { command_id, .. }
+                if tcp_auth_enabled && connection_state != ConnectionState::Authenticated => {
+                warn!("Received acknowledgement for command {} before authentication from {}", command_id, peer_addr);
+            }
+            Envelope::CommandAccepted { command_id } => {
+                let data = shared_data.lock().await;
+                if !data.command_results.mark_delivered(&command_id, &ids.client_id).await {
+                    debug!("Ignoring acceptance of unknown or settled command {} from {}", command_id, peer_addr);
+                }
+            }
+            Envelope::CommandRejected { command_id, reason } => {
+                warn!("Client {} rejected command {}: {}", ids.client_id, command_id, reason);
+                let data = shared_data.lock().await;
+                if !data.command_results.mark_rejected(&command_id, &ids.client_id, reason).await {
+                    debug!("Ignoring rejection of unknown or settled command {} from {}", command_id, peer_addr);
+                }
+            }
+            Envelope::CommandOutput(chunk) => {
+                if tcp_auth_enabled && connection_state != ConnectionState::Authenticated {
+                    warn!("Received command output before authentication from {}", peer_addr);
+                    continue;
+                }
 
-        // // 5. 发送 ACK
-        // if let Err(e) = send_ack(&mut Arc::clone(&stream)).await {
-        //     eprintln!("发送 ACK 失败: {}", e);
-        //     return Ok(());
-        // }
+                let data = shared_data.lock().await;
+                let (command_id, seq) = (chunk.command_id.clone(), chunk.seq);
+                if !data.command_results.append_output(&ids.client_id, chunk).await {
+                    debug!("Ignoring output chunk {} of unknown or settled command {} from {}", seq, command_id, peer_addr);
+                }
+            }
+            Envelope::TerminalOutput { session_id, .. } | Envelope::TerminalClosed { session_id, .. }
+                if tcp_auth_enabled && connection_state != ConnectionState::Authenticated => {
+                warn!("Received terminal event for session {} before authentication from {}", session_id, peer_addr);
+            }
+            Envelope::TerminalOutput { session_id, data } => {
+                let mut shared = shared_data.lock().await;
+                if !shared.route_terminal_event(&ids.client_id, &session_id, TerminalEvent::Output { data }) {
+                    debug!("Ignoring output of unknown terminal {} from {}", session_id, peer_addr);
+                }
+            }
+            Envelope::TerminalClosed { session_id, reason } => {
+                info!("Terminal {} on client {} closed (reason: {})", session_id, ids.client_id, reason.as_deref().unwrap_or("-"));
+                let mut shared = shared_data.lock().await;
+                shared.route_terminal_event(&ids.client_id, &session_id, TerminalEvent::Closed { reason });
+            }
+            Envelope::LogTailData { tail_id, .. } | Envelope::LogTailEnded { tail_id, .. }
+                if tcp_auth_enabled && connection_state != ConnectionState::Authenticated => {
+                warn!("Received log tail {} before authentication from {}", tail_id, peer_addr);
+            }
+            Envelope::LogTailData { tail_id, data } => {
+                let mut shared = shared_data.lock().await;
+                if !shared.route_log_tail_event(&ids.client_id, &tail_id, LogTailEvent::Lines { data }) {
+                    debug!("Ignoring lines of unknown log tail {} from {}", tail_id, peer_addr);
+                }
+            }
+            Envelope::LogTailEnded { tail_id, reason } => {
+                info!("Log tail {} on client {} ended: {}", tail_id, ids.client_id, reason);
+                let mut shared = shared_data.lock().await;
+                shared.route_log_tail_event(&ids.client_id, &tail_id, LogTailEvent::Ended { reason });
+            }
+            Envelope::FilePushResult { transfer_id, .. } if tcp_auth_enabled && connection_state != ConnectionState::Authenticated => {
+                warn!("Received file transfer result {} before authentication from {}", transfer_id, peer_addr);
+            }
+            Envelope::FilePushResult { transfer_id, success, message } => {
+                info!("File transfer {} on client {}: success={} ({})", transfer_id, ids.client_id, success, message);
+                let result = if success { Ok(message) } else { Err(message) };
+                if !shared_data.lock().await.file_transfers.finish(&transfer_id, &ids.client_id, result) {
+                    debug!("Ignoring result of unknown file transfer {} from {}", transfer_id, peer_addr);
+                }
+            }
+            Envelope::FileFetchStart { fetch_id, .. }
+            | Envelope::FileFetchChunk { fetch_id, .. }
+            | Envelope::FileFetchEnd { fetch_id, .. }
+            | Envelope::FileFetchFailed { fetch_id, .. }
+                if tcp_auth_enabled && connection_state != ConnectionState::Authenticated => {
+                warn!("Received file fetch {} before authentication from {}", fetch_id, peer_addr);
+            }
+            Envelope::FileFetchStart { fetch_id, file_size, offset, length } => {
+                route_fetch_event(shared_data, &ids.client_id, &fetch_id, FetchEvent::Start { file_size, offset, length }).await;
+            }
+            Envelope::FileFetchChunk { fetch_id, offset, data } => {
+                route_fetch_event(shared_data, &ids.client_id, &fetch_id, FetchEvent::Chunk { offset, data }).await;
+            }
+            Envelope::FileFetchEnd { fetch_id, sha256 } => {
+                info!("File fetch {} from client {} finished", fetch_id, ids.client_id);
+                route_fetch_event(shared_data, &ids.client_id, &fetch_id, FetchEvent::End { sha256 }).await;
+            }
+            Envelope::FileFetchFailed { fetch_id, reason } => {
+                warn!("File fetch {} on client {} failed: {}", fetch_id, ids.client_id, reason);
+                route_fetch_event(shared_data, &ids.client_id, &fetch_id, FetchEvent::Failed(reason)).await;
+            }
+            Envelope::CredentialUpdateAck => {
+                if connection_state != ConnectionState::Authenticated || ids.client_id.is_empty() {
+                    warn!("Received credential update ack before authentication from {}", peer_addr);
+                    continue;
+                }
+                match shared_data.lock().await.credentials.confirm_rotation(&ids.client_id) {
+                    Ok(true) => info!("Client {} switched to its new credential", ids.client_id),
+                    Ok(false) => debug!("Ignoring credential update ack without pending rotation from {}", ids.client_id),
+                    Err(e) => error!("Failed to confirm credential rotation for {}: {}", ids.client_id, e),
+                }
+            }
+            Envelope::ReconnectReport(report) => {
+                // 检查连接是否已认证
+                if tcp_auth_enabled && connection_state != ConnectionState::Authenticated {
+                    warn!("Received reconnect report before authentication from {}", peer_addr);
+                    continue;
+                }
 
-        // 示例：发送自定义消息给客户端
-        // let custom_message = b"Hello from server!";
-        // if let Err(e) = send_message_to_client(&mut Arc::clone(&stream), custom_message).await {
-        //     eprintln!("发送自定义消息失败: {}", e);
-        // }
+                verify_claimed_identity(outbound, peer_identity, &report.client_id, peer_addr)?;
+                verify_bound_identity(outbound, ids, &report.client_id, peer_addr)?;
+                info!("Client {} reconnected from {} after {} attempt(s), {}s offline (reason: {}, last error: {})",
+                      report.client_id, peer_addr, report.attempts, report.downtime_secs, report.reason,
+                      report.last_error.as_deref().unwrap_or("-"));
 
-        // {
-        //     let mut data = shared_data.lock().await;
-        //     data.remove_client_connection(&client_id).await;
-        // }
+                shared_data.lock().await.record_reconnect(report);
+            }
+            other => {
+                // 这些消息类型不应该从客户端接收
+                warn!("Received unexpected message from client {}: {:?}", peer_addr, other);
+            }
+        }
     }
 }
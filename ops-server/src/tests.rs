@@ -1,139 +1,1842 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::shared_data_handle::{SharedDataHandle, SharedData};
-    use crate::middleware::AuthConfig;
-    use axum::{
-        body::Body,
-        http::{Request, StatusCode},
-    };
-    use axum_test::TestServer;
-    use ops_common::config::ServerConfig;
-    use serde_json::json;
-
-    fn create_test_shared_data() -> SharedDataHandle {
-        SharedDataHandle::new(SharedData::new(100))
-    }
-
-    #[tokio::test]
-    async fn test_health_check() {
-        let shared_data = create_test_shared_data();
-        let auth_config = AuthConfig::new(None);
-        let app = crate::web::routes::routes(shared_data, auth_config);
-        let server = TestServer::new(app).unwrap();
-
-        let response = server.get("/health").await;
-        
-        response.assert_status(StatusCode::OK);
-        let json: serde_json::Value = response.json();
-        assert_eq!(json["status"], "healthy");
-        assert_eq!(json["clients_count"], 0);
-    }
-
-    #[tokio::test]
-    async fn test_auth_middleware_without_token() {
-        let shared_data = create_test_shared_data();
-        let auth_config = AuthConfig::new(None); // 认证未启用
-        let app = crate::web::routes::routes(shared_data, auth_config);
-        let server = TestServer::new(app).unwrap();
-
-        let response = server.get("/api/clients").await;
-        response.assert_status(StatusCode::OK);
-    }
-
-    #[tokio::test]
-    async fn test_auth_middleware_with_valid_token() {
-        let shared_data = create_test_shared_data();
-        let auth_config = AuthConfig::new(Some("test-token".to_string()));
-        let app = crate::web::routes::routes(shared_data, auth_config);
-        let server = TestServer::new(app).unwrap();
-
-        let response = server
-            .get("/api/clients")
-            .add_header("Authorization", "Bearer test-token")
-            .await;
-        
-        response.assert_status(StatusCode::OK);
-    }
-
-    #[tokio::test]
-    async fn test_auth_middleware_with_invalid_token() {
-        let shared_data = create_test_shared_data();
-        let auth_config = AuthConfig::new(Some("test-token".to_string()));
-        let app = crate::web::routes::routes(shared_data, auth_config);
-        let server = TestServer::new(app).unwrap();
-
-        let response = server
-            .get("/api/clients")
-            .add_header("Authorization", "Bearer wrong-token")
-            .await;
-        
-        response.assert_status(StatusCode::UNAUTHORIZED);
-    }
-
-    #[tokio::test]
-    async fn test_auth_middleware_missing_header() {
-        let shared_data = create_test_shared_data();
-        let auth_config = AuthConfig::new(Some("test-token".to_string()));
-        let app = crate::web::routes::routes(shared_data, auth_config);
-        let server = TestServer::new(app).unwrap();
-
-        let response = server.get("/api/clients").await;
-        response.assert_status(StatusCode::UNAUTHORIZED);
-    }
-
-    #[tokio::test]
-    async fn test_broadcast_message() {
-        let shared_data = create_test_shared_data();
-        let auth_config = AuthConfig::new(None);
-        let app = crate::web::routes::routes(shared_data, auth_config);
-        let server = TestServer::new(app).unwrap();
-
-        let payload = json!({
-            "message": "Test broadcast message"
-        });
-
-        let response = server
-            .post("/api/send-message")
-            .json(&payload)
-            .await;
-
-        response.assert_status(StatusCode::OK);
-        let body = response.text();
-        assert!(body.contains("消息已广播"));
-    }
-
-    #[tokio::test]
-    async fn test_send_command() {
-        let shared_data = create_test_shared_data();
-        let auth_config = AuthConfig::new(None);
-        let app = crate::web::routes::routes(shared_data, auth_config);
-        let server = TestServer::new(app).unwrap();
-
-        let payload = json!({
-            "client_id": "test-client",
-            "command": "echo hello"
-        });
-
-        let response = server
-            .post("/api/send-command")
-            .json(&payload)
-            .await;
-
-        // 应该返回错误，因为客户端不存在
-        response.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
-    #[tokio::test]
-    async fn test_cors_headers() {
-        let shared_data = create_test_shared_data();
-        let auth_config = AuthConfig::new(None);
-        let app = crate::web::routes::routes(shared_data, auth_config);
-        let server = TestServer::new(app).unwrap();
-
-        let response = server.get("/health").await;
-        
-        response.assert_status(StatusCode::OK);
-        assert!(response.headers().get("access-control-allow-origin").is_some());
-    }
-}
\ No newline at end of file
+use crate::shared_data_handle::{SharedDataHandle, SharedData};
+use crate::middleware::AuthConfig;
+use axum::extract::connect_info::MockConnectInfo;
+use axum::http::StatusCode;
+use axum_test::TestServer;
+use serde_json::json;
+use std::net::SocketAddr;
+use ops_common::{ClientInfo, HostInfo};
+use ops_common::config::ServerConfig;
+use ops_common::codec::{FrameCodec, FramedStream};
+use ops_common::protocol::{Envelope, Hello, PROTOCOL_VERSION};
+use ops_common::tls::{self, BoxedStream, TlsAcceptor};
+use crate::tcp_services::handle_socket;
+
+fn create_test_shared_data() -> SharedDataHandle {
+    SharedDataHandle::new(SharedData::new(100))
+}
+
+// 访问日志中间件依赖 ConnectInfo，测试中使用固定的模拟地址
+fn create_test_server(shared_data: SharedDataHandle, auth_config: AuthConfig) -> TestServer {
+    let (app, _session_store) = crate::web::routes::routes(shared_data, auth_config);
+    let app = app.layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+    TestServer::builder().save_cookies().build(app).unwrap()
+}
+
+// 使用默认管理员账号登录，后续请求携带会话 Cookie
+async fn login(server: &TestServer) {
+    let response = server
+        .post("/api/login")
+        .json(&json!({ "username": "admin", "password": "admin123" }))
+        .await;
+    response.assert_status(StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_health_check() {
+    let shared_data = create_test_shared_data();
+    let auth_config = AuthConfig::new(None);
+    let server = create_test_server(shared_data, auth_config);
+
+    let response = server.get("/health").await;
+    
+    response.assert_status(StatusCode::OK);
+    let json: serde_json::Value = response.json();
+    assert_eq!(json["status"], "healthy");
+    assert_eq!(json["clients_count"], 0);
+}
+
+#[tokio::test]
+async fn test_auth_middleware_without_token() {
+    let shared_data = create_test_shared_data();
+    let auth_config = AuthConfig::new(None); // 未配置Token时只能通过会话访问
+    let server = create_test_server(shared_data, auth_config);
+
+    let response = server.get("/api/clients").await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+
+    login(&server).await;
+    let response = server.get("/api/clients").await;
+    response.assert_status(StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_auth_middleware_with_valid_token() {
+    let shared_data = create_test_shared_data();
+    let auth_config = AuthConfig::new(Some("test-token".to_string()));
+    let server = create_test_server(shared_data, auth_config);
+
+    let response = server
+        .get("/api/clients")
+        .add_header("Authorization", "Bearer test-token")
+        .await;
+    
+    response.assert_status(StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_auth_middleware_with_invalid_token() {
+    let shared_data = create_test_shared_data();
+    let auth_config = AuthConfig::new(Some("test-token".to_string()));
+    let server = create_test_server(shared_data, auth_config);
+
+    let response = server
+        .get("/api/clients")
+        .add_header("Authorization", "Bearer wrong-token")
+        .await;
+    
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_auth_middleware_missing_header() {
+    let shared_data = create_test_shared_data();
+    let auth_config = AuthConfig::new(Some("test-token".to_string()));
+    let server = create_test_server(shared_data, auth_config);
+
+    let response = server.get("/api/clients").await;
+    response.assert_status(StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_broadcast_message() {
+    let shared_data = create_test_shared_data();
+    let auth_config = AuthConfig::new(None);
+    let server = create_test_server(shared_data, auth_config);
+    login(&server).await;
+
+    let payload = json!({
+        "message": "Test broadcast message"
+    });
+
+    let response = server
+        .post("/api/send-message")
+        .json(&payload)
+        .await;
+
+    response.assert_status(StatusCode::OK);
+    let body = response.text();
+    assert!(body.contains("消息已广播"));
+}
+
+#[tokio::test]
+async fn test_send_command() {
+    let shared_data = create_test_shared_data();
+    let auth_config = AuthConfig::new(None);
+    let server = create_test_server(shared_data, auth_config);
+    login(&server).await;
+
+    let payload = json!({
+        "client_id": "test-client",
+        "command": "echo hello"
+    });
+
+    let response = server
+        .post("/api/send-command")
+        .json(&payload)
+        .await;
+
+    // 应该返回错误，因为客户端不存在
+    response.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+}
+
+#[tokio::test]
+async fn test_cors_headers() {
+    let shared_data = create_test_shared_data();
+    let auth_config = AuthConfig::new(None);
+    let server = create_test_server(shared_data, auth_config);
+
+    let response = server.get("/health").await;
+    
+    response.assert_status(StatusCode::OK);
+    assert!(response.headers().get("access-control-allow-origin").is_some());
+}
+
+// 在随机端口上启动 TCP 服务，每个连接交给 handle_client_connection 处理
+async fn spawn_tcp_server(shared_data: SharedDataHandle) -> SocketAddr {
+    spawn_tcp_server_with_tls(shared_data, None).await
+}
+
+async fn spawn_tcp_server_with_tls(shared_data: SharedDataHandle, tls_acceptor: Option<TlsAcceptor>) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, peer_addr)) = listener.accept().await {
+            let shared_data = shared_data.clone();
+            let tls_acceptor = tls_acceptor.clone();
+            tokio::spawn(async move {
+                let Ok((stream, peer_identity)) = handle_socket::accept_stream(stream, tls_acceptor.as_ref()).await else {
+                    return;
+                };
+                let _ = handle_socket::handle_client_connection(
+                    stream, peer_addr, peer_identity, shared_data, FrameCodec::default(),
+                ).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn connect_agent(addr: SocketAddr) -> FramedStream<BoxedStream> {
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    FramedStream::new(Box::new(stream), FrameCodec::default())
+}
+
+// 在临时目录中生成 CA、localhost 服务端证书和 client-1 客户端证书
+struct TestPki {
+    ca_file: String,
+    cert_file: String,
+    key_file: String,
+    client_cert_file: String,
+    client_key_file: String,
+}
+
+fn write_test_pki(dir: &std::path::Path) -> TestPki {
+    let ca = crate::ca::generate_ca("Test CA", 1).unwrap();
+    let server = crate::ca::issue_server_cert(&ca, &["localhost".to_string()], 1).unwrap();
+    let client = crate::ca::issue_client_cert(&ca, "client-1", 1).unwrap();
+
+    let write = |name: &str, pem: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, pem).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    TestPki {
+        ca_file: write("ca.pem", &ca.cert_pem),
+        cert_file: write("server.pem", &server.cert_pem),
+        key_file: write("server-key.pem", &server.key_pem),
+        client_cert_file: write("client-1.pem", &client.cert_pem),
+        client_key_file: write("client-1-key.pem", &client.key_pem),
+    }
+}
+
+// 使用 client-1 证书通过 mTLS 连接并完成 hello
+async fn connect_mtls_agent(addr: SocketAddr, pki: &TestPki) -> FramedStream<BoxedStream> {
+    let connector = tls::build_connector(
+        &pki.ca_file,
+        Some((pki.client_cert_file.as_str(), pki.client_key_file.as_str())),
+    ).unwrap();
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let tls_stream = connector.connect(tls::server_name("localhost").unwrap(), tcp).await.unwrap();
+    let mut agent = FramedStream::new(Box::new(tls_stream) as BoxedStream, FrameCodec::default());
+
+    agent.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::HelloAck(_)));
+    agent
+}
+
+fn sample_client_info(client_id: &str) -> ClientInfo {
+    ClientInfo {
+        client_id: client_id.to_string(),
+        system_info: HostInfo::default(),
+        version_info: Vec::new(),
+        app_info: Vec::new(),
+        last_seen: std::time::SystemTime::now(),
+        command_queue: Default::default(),
+        command_policy: Default::default(),
+        apps_base_dir: Some("/opt/apps".to_string()),
+    }
+}
+
+#[tokio::test]
+async fn test_negotiated_version_in_client_list() {
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    let mut agent = connect_agent(addr).await;
+    agent.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    match agent.read_envelope().await.unwrap() {
+        Envelope::HelloAck(ack) => assert_eq!(ack.protocol_version, PROTOCOL_VERSION),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-1"))).await.unwrap();
+    expect_registered(&mut agent).await;
+
+    let server = create_test_server(shared_data, AuthConfig::new(None));
+    login(&server).await;
+    let response = server.get("/api/clients").await;
+    response.assert_status(StatusCode::OK);
+    let json: serde_json::Value = response.json();
+    assert_eq!(json["clients"]["client-1"]["protocol_version"], PROTOCOL_VERSION);
+    assert_eq!(json["clients"]["client-1"]["client_id"], "client-1");
+}
+
+#[tokio::test]
+async fn test_command_policy_pushed_and_version_reported() {
+    let shared_data = create_test_shared_data();
+    let config = ServerConfig {
+        allowed_script_dirs: vec!["/srv/ops-scripts".to_string()],
+        ..Default::default()
+    };
+    let server_policy = config.command_policy().unwrap();
+    shared_data.lock().await.command_policy = server_policy.clone();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    let mut agent = connect_agent(addr).await;
+    agent.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::HelloAck(_)));
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-1"))).await.unwrap();
+    match agent.read_envelope().await.unwrap() {
+        Envelope::CommandPolicy { policy, .. } => {
+            assert_eq!(policy.scripts.allowed_dirs, vec!["/srv/ops-scripts"]);
+            assert_eq!(policy.version(), server_policy.version());
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::Ack));
+
+    // 策略只在登记时下发一次
+    let mut info = sample_client_info("client-1");
+    info.command_policy.version = Some(server_policy.version());
+    agent.write_envelope(&Envelope::ClientInfo(info)).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::Ack));
+
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+    let json: serde_json::Value = server.get("/api/clients").await.json();
+    assert_eq!(json["command_policy_version"], server_policy.version());
+    assert_eq!(json["clients"]["client-1"]["command_policy"]["version"], server_policy.version());
+    assert_eq!(json["clients"]["client-1"]["policy_current"], true);
+
+    // 服务端策略变化后，仍在执行旧策略的客户端标记为非当前
+    shared_data.lock().await.command_policy = ops_common::command_policy::CommandPolicy::builtin();
+    let json: serde_json::Value = server.get("/api/clients").await.json();
+    assert_eq!(json["clients"]["client-1"]["policy_current"], false);
+}
+
+#[tokio::test]
+async fn test_incompatible_hello_rejected() {
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data).await;
+
+    let mut agent = connect_agent(addr).await;
+    let hello = Hello {
+        protocol_version: PROTOCOL_VERSION + 2,
+        min_protocol_version: PROTOCOL_VERSION + 1,
+        capabilities: Vec::new(),
+        agent_version: "9.0.0".to_string(),
+        resume: None,
+    };
+    agent.write_envelope(&Envelope::Hello(hello)).await.unwrap();
+
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+    assert!(agent.read_envelope().await.is_err(), "server should close the connection");
+}
+
+#[tokio::test]
+async fn test_tls_handshake_and_hello() {
+    let pki_dir = tempfile::tempdir().unwrap();
+    let pki = write_test_pki(pki_dir.path());
+
+    let acceptor = tls::build_acceptor(&pki.cert_file, &pki.key_file, None).unwrap();
+    let addr = spawn_tcp_server_with_tls(create_test_shared_data(), Some(acceptor)).await;
+
+    let connector = tls::build_connector(&pki.ca_file, None).unwrap();
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let tls_stream = connector.connect(tls::server_name("localhost").unwrap(), tcp).await.unwrap();
+    let mut agent = FramedStream::new(Box::new(tls_stream) as BoxedStream, FrameCodec::default());
+
+    agent.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::HelloAck(_)));
+}
+
+#[tokio::test]
+async fn test_tls_rejects_untrusted_server() {
+    let pki_dir = tempfile::tempdir().unwrap();
+    let pki = write_test_pki(pki_dir.path());
+
+    // 客户端信任另一个 CA，应当拒绝服务端证书
+    let other_dir = tempfile::tempdir().unwrap();
+    let other_pki = write_test_pki(other_dir.path());
+
+    let acceptor = tls::build_acceptor(&pki.cert_file, &pki.key_file, None).unwrap();
+    let addr = spawn_tcp_server_with_tls(create_test_shared_data(), Some(acceptor)).await;
+
+    let connector = tls::build_connector(&other_pki.ca_file, None).unwrap();
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    assert!(connector.connect(tls::server_name("localhost").unwrap(), tcp).await.is_err());
+}
+
+#[tokio::test]
+async fn test_mtls_accepts_matching_client_id() {
+    let pki_dir = tempfile::tempdir().unwrap();
+    let pki = write_test_pki(pki_dir.path());
+    let shared_data = create_test_shared_data();
+
+    let acceptor = tls::build_acceptor(&pki.cert_file, &pki.key_file, Some(&pki.ca_file)).unwrap();
+    let addr = spawn_tcp_server_with_tls(shared_data.clone(), Some(acceptor)).await;
+
+    let mut agent = connect_mtls_agent(addr, &pki).await;
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-1"))).await.unwrap();
+    expect_registered(&mut agent).await;
+    assert!(shared_data.lock().await.client_connections.contains_key("client-1"));
+}
+
+#[tokio::test]
+async fn test_mtls_rejects_impersonation() {
+    let pki_dir = tempfile::tempdir().unwrap();
+    let pki = write_test_pki(pki_dir.path());
+    let shared_data = create_test_shared_data();
+
+    let acceptor = tls::build_acceptor(&pki.cert_file, &pki.key_file, Some(&pki.ca_file)).unwrap();
+    let addr = spawn_tcp_server_with_tls(shared_data.clone(), Some(acceptor)).await;
+
+    // 持有 client-1 证书却声称自己是 client-2
+    let mut agent = connect_mtls_agent(addr, &pki).await;
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-2"))).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+    assert!(agent.read_envelope().await.is_err(), "server should close the connection");
+    assert!(!shared_data.lock().await.client_connections.contains_key("client-2"));
+}
+
+#[tokio::test]
+async fn test_mtls_requires_client_certificate() {
+    let pki_dir = tempfile::tempdir().unwrap();
+    let pki = write_test_pki(pki_dir.path());
+
+    let acceptor = tls::build_acceptor(&pki.cert_file, &pki.key_file, Some(&pki.ca_file)).unwrap();
+    let addr = spawn_tcp_server_with_tls(create_test_shared_data(), Some(acceptor)).await;
+
+    // TLS 1.3 下客户端证书在握手完成后才被校验，失败体现在首次读取上
+    let connector = tls::build_connector(&pki.ca_file, None).unwrap();
+    let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let Ok(tls_stream) = connector.connect(tls::server_name("localhost").unwrap(), tcp).await else {
+        return;
+    };
+    let mut agent = FramedStream::new(Box::new(tls_stream) as BoxedStream, FrameCodec::default());
+    let _ = agent.write_envelope(&Envelope::Hello(Hello::local())).await;
+    assert!(agent.read_envelope().await.is_err());
+}
+
+// 客户端在连接上首次登记时先收到命令校验策略，再收到 ACK
+async fn expect_registered(agent: &mut FramedStream<BoxedStream>) {
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::CommandPolicy { .. }));
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::Ack));
+}
+
+// 完成 hello 并注册 client_id
+async fn register_agent(addr: SocketAddr, client_id: &str) -> FramedStream<BoxedStream> {
+    let mut agent = connect_agent(addr).await;
+    agent.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::HelloAck(_)));
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info(client_id))).await.unwrap();
+    expect_registered(&mut agent).await;
+    agent
+}
+
+#[tokio::test]
+async fn test_command_sent_while_read_loop_waits() {
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+
+    // 服务端读循环此时阻塞在读取上，命令经发送队列仍能立即送达
+    let command_id = tokio::time::timeout(
+        std::time::Duration::from_secs(1),
+        async { shared_data.lock().await.send_command_to_client("client-1", "uptime").await.unwrap() },
+    ).await.expect("send_command_to_client should not wait for the read loop");
+
+    match agent.read_envelope().await.unwrap() {
+        Envelope::CommandRequest { command_id: received, command, .. } => {
+            assert_eq!(received, command_id);
+            assert_eq!(command, "uptime");
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_stale_connection_does_not_remove_replacement() {
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    let old_agent = register_agent(addr, "client-1").await;
+    let mut new_agent = register_agent(addr, "client-1").await;
+
+    // 旧连接断开后，新连接的注册应保留
+    drop(old_agent);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(shared_data.lock().await.client_connections.contains_key("client-1"));
+
+    shared_data.lock().await.send_command_to_client("client-1", "uptime").await.unwrap();
+    assert!(matches!(new_agent.read_envelope().await.unwrap(), Envelope::CommandRequest { .. }));
+
+    // 新连接断开后注销
+    drop(new_agent);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(!shared_data.lock().await.client_connections.contains_key("client-1"));
+}
+
+#[tokio::test]
+async fn test_reconnect_report_in_client_list() {
+    use ops_common::protocol::ReconnectReport;
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+
+    agent.write_envelope(&Envelope::ReconnectReport(ReconnectReport {
+        client_id: "client-1".to_string(),
+        attempts: 3,
+        downtime_secs: 12,
+        reason: "连接已关闭".to_string(),
+        last_error: Some("Connection refused".to_string()),
+        resumed: false,
+    })).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let server = create_test_server(shared_data, AuthConfig::new(None));
+    login(&server).await;
+    let json: serde_json::Value = server.get("/api/clients").await.json();
+    let reconnects = &json["clients"]["client-1"]["reconnects"];
+    assert_eq!(reconnects["count"], 1);
+    assert_eq!(reconnects["last_report"]["attempts"], 3);
+}
+
+#[tokio::test]
+async fn test_resume_token_rotates_and_expires() {
+    use crate::sessions::SessionRegistry;
+
+    let mut sessions = SessionRegistry::new(std::time::Duration::from_millis(50));
+    let (session_id, token) = sessions.open("client-1", "conn-1");
+    sessions.detach(&session_id, "conn-1");
+
+    // 令牌只能由签发时的客户端使用，恢复后轮换
+    assert_eq!(sessions.resume("client-2", &token, "conn-2"), None);
+    let resumed = sessions.resume("client-1", &token, "conn-2").unwrap();
+    assert_eq!(resumed.session_id, session_id);
+    assert_eq!(resumed.replaced_connection, None);
+    assert_ne!(resumed.token, token);
+    assert_eq!(sessions.resume("client-1", &token, "conn-3"), None);
+    let new_token = resumed.token;
+
+    // 旧连接迟到的断开通知不影响已恢复到新连接上的会话
+    sessions.detach(&session_id, "conn-1");
+    tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+    assert!(!sessions.remove_if_expired(&session_id));
+
+    sessions.detach(&session_id, "conn-2");
+    tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+    assert_eq!(sessions.resume("client-1", &new_token, "conn-3"), None);
+    assert!(sessions.remove_if_expired(&session_id));
+}
+
+#[tokio::test]
+async fn test_resume_takes_over_session_from_stale_connection() {
+    use crate::sessions::SessionRegistry;
+
+    let mut sessions = SessionRegistry::new(std::time::Duration::from_millis(50));
+    let (session_id, token) = sessions.open("client-1", "conn-1");
+
+    // 服务端还没发现 conn-1 断开时，凭有效令牌同样可以恢复，并报告被接管的连接
+    let resumed = sessions.resume("client-1", &token, "conn-2").unwrap();
+    assert_eq!(resumed.session_id, session_id);
+    assert_eq!(resumed.replaced_connection.as_deref(), Some("conn-1"));
+    assert_eq!(sessions.resume("client-1", &token, "conn-3"), None);
+
+    // 旧连接随后关闭时不影响会话
+    sessions.detach(&session_id, "conn-1");
+    tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+    assert!(!sessions.remove_if_expired(&session_id));
+}
+
+#[tokio::test]
+async fn test_expired_session_fails_pending_commands() {
+    use crate::command_results::CommandStatus;
+    use crate::sessions::SessionRegistry;
+
+    let mut data = SharedData::new(10);
+    data.sessions = SessionRegistry::new(std::time::Duration::ZERO);
+    let (session_id, _) = data.sessions.open("client-1", "conn-1");
+    let in_session = data.command_results
+        .create_command("client-1".to_string(), "uptime".to_string(), Some(session_id.clone()))
+        .await;
+    let other = data.command_results
+        .create_command("client-2".to_string(), "uptime".to_string(), None)
+        .await;
+
+    data.sessions.detach(&session_id, "conn-1");
+    data.expire_session(&session_id).await;
+
+    assert!(matches!(data.command_results.get_command_status(&in_session).await, Some(CommandStatus::Failed(_))));
+    assert!(matches!(data.command_results.get_command_status(&other).await, Some(CommandStatus::Pending)));
+}
+
+#[tokio::test]
+async fn test_resume_session_skips_authentication() {
+    use ops_common::protocol::ResumeRequest;
+
+    let shared_data = create_test_shared_data();
+    let (session_id, token) = shared_data.lock().await.sessions.open("client-1", "old-connection");
+    shared_data.lock().await.sessions.detach(&session_id, "old-connection");
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    // 无效令牌：正常协商，不恢复会话
+    let mut agent = connect_agent(addr).await;
+    let mut hello = Hello::local();
+    hello.resume = Some(ResumeRequest { client_id: "client-1".to_string(), token: "bogus".to_string() });
+    agent.write_envelope(&Envelope::Hello(hello)).await.unwrap();
+    match agent.read_envelope().await.unwrap() {
+        Envelope::HelloAck(ack) => assert!(!ack.resumed),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    // 有效令牌：恢复会话，登记连接并轮换令牌
+    let mut agent = connect_agent(addr).await;
+    let mut hello = Hello::local();
+    hello.resume = Some(ResumeRequest { client_id: "client-1".to_string(), token: token.clone() });
+    agent.write_envelope(&Envelope::Hello(hello)).await.unwrap();
+    match agent.read_envelope().await.unwrap() {
+        Envelope::HelloAck(ack) => assert!(ack.resumed),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+    let new_token = match agent.read_envelope().await.unwrap() {
+        Envelope::SessionToken { token: new_token, .. } => new_token,
+        other => panic!("Unexpected envelope: {:?}", other),
+    };
+    assert_ne!(new_token, token);
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::CommandPolicy { .. }));
+
+    // 已轮换的旧令牌不能再使用
+    let mut replay = connect_agent(addr).await;
+    let mut hello = Hello::local();
+    hello.resume = Some(ResumeRequest { client_id: "client-1".to_string(), token });
+    replay.write_envelope(&Envelope::Hello(hello)).await.unwrap();
+    match replay.read_envelope().await.unwrap() {
+        Envelope::HelloAck(ack) => assert!(!ack.resumed),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    let data = shared_data.lock().await;
+    let connection = data.client_connections.get("client-1").expect("resumed connection registered");
+    assert_eq!(connection.session_id.as_deref(), Some(session_id.as_str()));
+    drop(data);
+    drop(agent);
+}
+
+#[tokio::test]
+async fn test_resume_while_stale_connection_registered() {
+    use ops_common::protocol::ResumeRequest;
+
+    let shared_data = create_test_shared_data();
+    let (session_id, token) = shared_data.lock().await.sessions.open("client-1", "initial");
+    shared_data.lock().await.sessions.detach(&session_id, "initial");
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    async fn resume(addr: SocketAddr, token: String) -> (FramedStream<BoxedStream>, String) {
+        let mut agent = connect_agent(addr).await;
+        let mut hello = Hello::local();
+        hello.resume = Some(ResumeRequest { client_id: "client-1".to_string(), token });
+        agent.write_envelope(&Envelope::Hello(hello)).await.unwrap();
+        match agent.read_envelope().await.unwrap() {
+            Envelope::HelloAck(ack) => assert!(ack.resumed),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+        let token = match agent.read_envelope().await.unwrap() {
+            Envelope::SessionToken { token, .. } => token,
+            other => panic!("Unexpected envelope: {:?}", other),
+        };
+        assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::CommandPolicy { .. }));
+        (agent, token)
+    }
+
+    // 第一条连接恢复会话后不再收发数据，模拟服务端没有察觉的半开连接
+    let (mut stale, token) = resume(addr, token).await;
+    let stale_connection = shared_data.lock().await.client_connections["client-1"].connection_id.clone();
+
+    // 客户端重连时凭最新令牌接管会话，旧连接被关闭
+    let (agent, _) = resume(addr, token).await;
+    match stale.read_envelope().await.unwrap() {
+        Envelope::ConnectionRejected { reason } => assert!(reason.contains("新连接")),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+    assert!(tokio::time::timeout(std::time::Duration::from_secs(5), stale.read_envelope()).await.unwrap().is_err());
+
+    let data = shared_data.lock().await;
+    let connection = data.client_connections.get("client-1").expect("resumed connection registered");
+    assert_ne!(connection.connection_id, stale_connection);
+    assert_eq!(connection.session_id.as_deref(), Some(session_id.as_str()));
+    drop(data);
+    drop(agent);
+}
+
+#[tokio::test]
+async fn test_duplicate_command_result_deduplicated() {
+    use ops_common::protocol::CommandResponse;
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let command_id = shared_data.lock().await.send_command_to_client("client-1", "uptime").await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::CommandRequest { .. }));
+
+    let response = |output: &str| CommandResponse {
+        command_id: command_id.clone(),
+        client_id: "client-1".to_string(),
+        command: "uptime".to_string(),
+        output: output.to_string(),
+        error_output: String::new(),
+        exit_code: 0,
+        executed_at: std::time::SystemTime::now(),
+        cancelled: false,
+        timed_out: false,
+    };
+
+    // 重发的结果同样会被确认，但只保存第一份
+    for output in ["first", "replayed"] {
+        agent.write_envelope(&Envelope::CommandResponse(response(output))).await.unwrap();
+        match agent.read_envelope().await.unwrap() {
+            Envelope::ResultAck { command_id: acked } => assert_eq!(acked, command_id),
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+
+    let stored = shared_data.lock().await.command_results.get_result(&command_id).await.unwrap();
+    assert_eq!(stored.output, "first");
+}
+
+#[tokio::test]
+async fn test_command_result_from_other_client_ignored() {
+    use crate::command_results::CommandStatus;
+    use ops_common::protocol::CommandResponse;
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut target = register_agent(addr, "client-1").await;
+    let mut intruder = register_agent(addr, "client-2").await;
+    let command_id = shared_data.lock().await.send_command_to_client("client-1", "uptime").await.unwrap();
+    assert!(matches!(target.read_envelope().await.unwrap(), Envelope::CommandRequest { .. }));
+
+    let response = |client_id: &str, output: &str| CommandResponse {
+        command_id: command_id.clone(),
+        client_id: client_id.to_string(),
+        command: "uptime".to_string(),
+        output: output.to_string(),
+        error_output: String::new(),
+        exit_code: 0,
+        executed_at: std::time::SystemTime::now(),
+        cancelled: false,
+        timed_out: false,
+    };
+
+    // 冒充目标客户端或以自己的身份回传别人的命令结果，都不会保存也不会确认
+    intruder.write_envelope(&Envelope::CommandResponse(response("client-1", "forged"))).await.unwrap();
+    intruder.write_envelope(&Envelope::CommandResponse(response("client-2", "forged"))).await.unwrap();
+    let no_ack = tokio::time::timeout(std::time::Duration::from_millis(200), intruder.read_envelope()).await;
+    assert!(no_ack.is_err(), "forged results must not be acknowledged");
+    assert!(matches!(
+        shared_data.lock().await.command_results.get_command_status(&command_id).await,
+        Some(CommandStatus::Pending)
+    ));
+
+    // 真正的结果照常保存
+    target.write_envelope(&Envelope::CommandResponse(response("client-1", "genuine"))).await.unwrap();
+    assert!(matches!(target.read_envelope().await.unwrap(), Envelope::ResultAck { .. }));
+    let stored = shared_data.lock().await.command_results.get_result(&command_id).await.unwrap();
+    assert_eq!(stored.output, "genuine");
+}
+
+#[tokio::test]
+async fn test_result_replayed_after_server_restart_acknowledged() {
+    use ops_common::protocol::CommandResponse;
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let command_id = shared_data.lock().await.send_command_to_client("client-1", "uptime").await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::CommandRequest { .. }));
+    drop(agent);
+
+    // 服务端重启后不再认识该命令，客户端重连后重发的结果仍被确认，以便从发件箱中删除
+    let restarted = create_test_shared_data();
+    let addr = spawn_tcp_server(restarted.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    agent.write_envelope(&Envelope::CommandResponse(CommandResponse {
+        command_id: command_id.clone(),
+        client_id: "client-1".to_string(),
+        command: "uptime".to_string(),
+        output: "replayed".to_string(),
+        error_output: String::new(),
+        exit_code: 0,
+        executed_at: std::time::SystemTime::now(),
+        cancelled: false,
+        timed_out: false,
+    })).await.unwrap();
+    match agent.read_envelope().await.unwrap() {
+        Envelope::ResultAck { command_id: acked } => assert_eq!(acked, command_id),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+    assert!(restarted.lock().await.command_results.get_result(&command_id).await.is_none());
+}
+
+#[tokio::test]
+async fn test_command_acceptance_and_rejection() {
+    use crate::command_results::CommandStatus;
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+
+    let accepted = shared_data.lock().await.send_command_to_client("client-1", "uptime").await.unwrap();
+    let rejected = shared_data.lock().await.send_command_to_client("client-1", "rm -rf /").await.unwrap();
+    let status = |id: String| {
+        let shared_data = shared_data.clone();
+        async move { shared_data.lock().await.command_results.get_command_status(&id).await }
+    };
+
+    // 下发后未确认前保持 Pending
+    assert!(matches!(status(accepted.clone()).await, Some(CommandStatus::Pending)));
+
+    agent.write_envelope(&Envelope::CommandAccepted { command_id: accepted.clone() }).await.unwrap();
+    agent.write_envelope(&Envelope::CommandRejected {
+        command_id: rejected.clone(),
+        reason: "危险命令".to_string(),
+    }).await.unwrap();
+    // 重复的确认不会覆盖已有状态
+    agent.write_envelope(&Envelope::CommandAccepted { command_id: rejected.clone() }).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    assert!(matches!(status(accepted).await, Some(CommandStatus::Delivered)));
+    match status(rejected).await {
+        Some(CommandStatus::Rejected(reason)) => assert_eq!(reason, "危险命令"),
+        other => panic!("Unexpected status: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_command_stream_follows_output_until_done() {
+    use ops_common::protocol::{CommandOutputChunk, CommandResponse, OutputStream};
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    let command_id = shared_data.lock().await.send_command_to_client("client-1", "uptime").await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::CommandRequest { .. }));
+
+    let chunk = |seq: u64, stream: OutputStream, data: &str| Envelope::CommandOutput(CommandOutputChunk {
+        command_id: command_id.clone(),
+        seq,
+        stream,
+        data: data.to_string(),
+    });
+
+    // 浏览器先订阅，客户端随后才开始输出
+    let agent_task = async {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        agent.write_envelope(&Envelope::CommandAccepted { command_id: command_id.clone() }).await.unwrap();
+        agent.write_envelope(&chunk(0, OutputStream::Stdout, "line 1\n")).await.unwrap();
+        agent.write_envelope(&chunk(1, OutputStream::Stderr, "warning\n")).await.unwrap();
+        // 重复的分块被丢弃
+        agent.write_envelope(&chunk(1, OutputStream::Stderr, "warning\n")).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        agent.write_envelope(&Envelope::CommandResponse(CommandResponse {
+            command_id: command_id.clone(),
+            client_id: "client-1".to_string(),
+            command: "uptime".to_string(),
+            output: "line 1\n".to_string(),
+            error_output: "warning\n".to_string(),
+            exit_code: 0,
+            executed_at: std::time::SystemTime::now(),
+            cancelled: false,
+            timed_out: false,
+        })).await.unwrap();
+        assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::ResultAck { .. }));
+    };
+    let stream_request = server.get("/api/command-stream").add_query_param("command_id", &command_id);
+
+    let (response, _) = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        async { tokio::join!(stream_request, agent_task) },
+    ).await.expect("stream should end once the command completes");
+
+    response.assert_status(StatusCode::OK);
+    let body = response.text();
+    assert_eq!(body.matches("event: output").count(), 2);
+    assert!(body.contains(r#""data":"line 1\n""#));
+    assert!(body.contains(r#""stream":"stderr""#));
+
+    let done = body.split("event: done\ndata: ").nth(1).expect("done event");
+    let done: serde_json::Value = serde_json::from_str(done.lines().next().unwrap()).unwrap();
+    assert_eq!(done["status"]["Completed"]["exit_code"], 0);
+    assert_eq!(done["truncated"], false);
+
+    // 未知命令返回 404
+    server.get("/api/command-stream").add_query_param("command_id", "missing").await
+        .assert_status(StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_manage_service_uses_client_apps_dir() {
+    use ops_common::security::{CommandValidator, ValidationResult};
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    // 命令在客户端上报的应用目录中执行，并能通过默认策略
+    let validator = CommandValidator::new();
+    for action in ["Start", "Stop", "Restart", "Status"] {
+        server.post("/api/manage-service")
+            .json(&json!({ "client_id": "client-1", "app_name": "demo", "action": action }))
+            .await
+            .assert_status(StatusCode::OK);
+        match agent.read_envelope().await.unwrap() {
+            Envelope::CommandRequest { command, .. } => {
+                assert!(command.starts_with("cd /opt/apps/demo && "), "{}", command);
+                if let ValidationResult::Blocked { reason } = validator.validate(&command) {
+                    panic!("{} blocked: {}", command, reason);
+                }
+            }
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+    server.post("/api/update-app")
+        .json(&json!({ "client_id": "client-1", "app_name": "demo", "version": "1.2.0" }))
+        .await
+        .assert_status(StatusCode::OK);
+    match agent.read_envelope().await.unwrap() {
+        Envelope::CommandRequest { command, .. } => assert_eq!(command, "cd /opt/apps/demo && bash demo.sh update 1.2.0"),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    // 应用名和版本号不能夹带 Shell 语法
+    server.post("/api/manage-service")
+        .json(&json!({ "client_id": "client-1", "app_name": "demo; reboot", "action": "Start" }))
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+    server.post("/api/manage-service")
+        .json(&json!({ "client_id": "client-1", "app_name": "..", "action": "Start" }))
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+    server.post("/api/update-app")
+        .json(&json!({ "client_id": "client-1", "app_name": "demo", "version": "1.0 $(id)" }))
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_cancel_command_forwards_to_client() {
+    use crate::command_results::CommandStatus;
+    use ops_common::protocol::CommandResponse;
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    let command_id = shared_data.lock().await.send_command_to_client("client-1", "top").await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::CommandRequest { .. }));
+
+    server.post("/api/cancel-command").json(&json!({ "command_id": command_id })).await
+        .assert_status(StatusCode::OK);
+    match agent.read_envelope().await.unwrap() {
+        Envelope::CancelCommand { command_id: cancelled } => assert_eq!(cancelled, command_id),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    // 客户端结束进程后带 cancelled 标记返回已有输出
+    agent.write_envelope(&Envelope::CommandResponse(CommandResponse {
+        command_id: command_id.clone(),
+        client_id: "client-1".to_string(),
+        command: "top".to_string(),
+        output: "partial".to_string(),
+        error_output: String::new(),
+        exit_code: -1,
+        executed_at: std::time::SystemTime::now(),
+        cancelled: true,
+        timed_out: false,
+    })).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::ResultAck { .. }));
+
+    let status = shared_data.lock().await.command_results.get_command_status(&command_id).await;
+    match status {
+        Some(CommandStatus::Cancelled(result)) => assert_eq!(result.output, "partial"),
+        other => panic!("Unexpected status: {:?}", other),
+    }
+
+    // 已结束或不存在的命令不能取消
+    server.post("/api/cancel-command").json(&json!({ "command_id": command_id })).await
+        .assert_status(StatusCode::CONFLICT);
+    server.post("/api/cancel-command").json(&json!({ "command_id": "missing" })).await
+        .assert_status(StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_send_command_with_timeout_and_timed_out_result() {
+    use crate::command_results::CommandStatus;
+    use ops_common::protocol::CommandResponse;
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    let response = server.post("/api/send-command")
+        .json(&json!({ "client_id": "client-1", "command": "top", "timeout_secs": 30 }))
+        .await;
+    response.assert_status(StatusCode::OK);
+    let command_id = response.json::<serde_json::Value>()["command_id"].as_str().unwrap().to_string();
+
+    match agent.read_envelope().await.unwrap() {
+        Envelope::CommandRequest { timeout_secs, .. } => assert_eq!(timeout_secs, Some(30)),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    agent.write_envelope(&Envelope::CommandResponse(CommandResponse {
+        command_id: command_id.clone(),
+        client_id: "client-1".to_string(),
+        command: "top".to_string(),
+        output: "partial".to_string(),
+        error_output: String::new(),
+        exit_code: 124,
+        executed_at: std::time::SystemTime::now(),
+        cancelled: false,
+        timed_out: true,
+    })).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::ResultAck { .. }));
+
+    let status = shared_data.lock().await.command_results.get_command_status(&command_id).await;
+    match status {
+        Some(CommandStatus::TimedOut(result)) => assert_eq!(result.output, "partial"),
+        other => panic!("Unexpected status: {:?}", other),
+    }
+}
+
+// WebSocket 需要真实的 HTTP 连接
+fn create_http_test_server(shared_data: SharedDataHandle) -> TestServer {
+    let (app, _session_store) = crate::web::routes::routes(shared_data, AuthConfig::new(None));
+    let app = app.layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+    TestServer::builder().http_transport().save_cookies().build(app).unwrap()
+}
+
+#[tokio::test]
+async fn test_terminal_requires_permission() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let audit_file = temp_dir.path().join("audit.log");
+    let shared_data = create_test_shared_data();
+    shared_data.lock().await.audit = crate::audit::AuditLog::open(&audit_file).unwrap();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let _agent = register_agent(addr, "client-1").await;
+    let server = create_http_test_server(shared_data.clone());
+    login(&server).await;
+
+    // 默认没有任何用户可以使用终端
+    server.get_websocket("/api/terminal?client_id=client-1").await
+        .assert_status(StatusCode::FORBIDDEN);
+    let audit = std::fs::read_to_string(&audit_file).unwrap();
+    assert!(audit.contains("\"event\":\"terminal_denied\""));
+    assert!(audit.contains("\"user\":\"admin\""));
+    assert!(shared_data.lock().await.terminals.is_empty());
+}
+
+#[tokio::test]
+async fn test_terminal_proxies_agent_pty_and_audits_session() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let audit_file = temp_dir.path().join("audit.log");
+    let shared_data = create_test_shared_data();
+    {
+        let mut data = shared_data.lock().await;
+        data.terminal_users = vec!["admin".to_string()];
+        data.audit = crate::audit::AuditLog::open(&audit_file).unwrap();
+    }
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let server = create_http_test_server(shared_data.clone());
+    login(&server).await;
+
+    let mut ws = server.get_websocket("/api/terminal?client_id=client-1&cols=120&rows=40").await
+        .into_websocket().await;
+    let session_id = match agent.read_envelope().await.unwrap() {
+        Envelope::TerminalOpen { session_id, cols, rows, .. } => {
+            assert_eq!((cols, rows), (120, 40));
+            session_id
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    };
+
+    agent.write_envelope(&Envelope::TerminalOutput { session_id: session_id.clone(), data: "$ ".to_string() }).await.unwrap();
+    assert_eq!(ws.receive_json::<serde_json::Value>().await, json!({ "type": "output", "data": "$ " }));
+
+    ws.send_json(&json!({ "type": "input", "data": "uptimx\u{7f}e\r" })).await;
+    match agent.read_envelope().await.unwrap() {
+        Envelope::TerminalInput { session_id: id, data } => {
+            assert_eq!(id, session_id);
+            assert_eq!(data, "uptimx\u{7f}e\r");
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+    ws.send_json(&json!({ "type": "resize", "cols": 100, "rows": 30 })).await;
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::TerminalResize { cols: 100, rows: 30, .. }));
+
+    // 浏览器关闭后服务端通知客户端结束 shell
+    ws.close().await;
+    match agent.read_envelope().await.unwrap() {
+        Envelope::TerminalClose { session_id: id } => assert_eq!(id, session_id),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    let records: Vec<serde_json::Value> = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            let audit = std::fs::read_to_string(&audit_file).unwrap_or_default();
+            if audit.contains("terminal_close") {
+                break audit.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+    }).await.expect("terminal close should be audited");
+
+    let events: Vec<&str> = records.iter().map(|record| record["event"].as_str().unwrap()).collect();
+    assert_eq!(events, ["terminal_open", "terminal_input", "terminal_close"]);
+    assert!(records.iter().all(|record| record["user"] == "admin" && record["session_id"] == session_id));
+    assert_eq!(records[1]["detail"], "uptime");
+    assert!(shared_data.lock().await.terminals.is_empty());
+}
+
+#[tokio::test]
+async fn test_push_file_sends_verified_chunks_and_tracks_result() {
+    use axum_test::multipart::{MultipartForm, Part};
+    use ops_common::file_transfer::sha256_hex;
+
+    let shared_data = create_test_shared_data();
+    shared_data.lock().await.client_groups.insert("web".to_string(), vec!["client-1".to_string(), "offline".to_string()]);
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i % 256) as u8).collect();
+    let form = MultipartForm::new()
+        .add_part("file", Part::bytes(content.clone()).file_name("app.conf"))
+        .add_text("group", "web")
+        .add_text("path", "/etc/app/app.conf")
+        .add_text("mode", "640");
+    let response = server.post("/api/push-file").multipart(form).await;
+    response.assert_status(StatusCode::OK);
+    let body: serde_json::Value = response.json();
+    let transfer_id = body["transfer_id"].as_str().unwrap().to_string();
+    assert_eq!(body["sha256"], sha256_hex(&content));
+    assert_eq!(body["clients"]["offline"], json!({ "Failed": "客户端未连接" }));
+
+    // 客户端按顺序收到元信息和分块，拼接后校验一致
+    let start = match agent.read_envelope().await.unwrap() {
+        Envelope::FilePushStart(start) => start,
+        other => panic!("Unexpected envelope: {:?}", other),
+    };
+    assert_eq!(start.path, "/etc/app/app.conf");
+    assert_eq!(start.mode, Some(0o640));
+    let mut received = Vec::new();
+    while received.len() < start.size as usize {
+        match agent.read_envelope().await.unwrap() {
+            Envelope::FilePushChunk { offset, data, .. } => {
+                assert_eq!(offset, received.len() as u64);
+                received.extend_from_slice(&data);
+            }
+            other => panic!("Unexpected envelope: {:?}", other),
+        }
+    }
+    assert_eq!(sha256_hex(&received), start.sha256);
+
+    agent.write_envelope(&Envelope::FilePushResult {
+        transfer_id: transfer_id.clone(),
+        success: true,
+        message: "已写入 /etc/app/app.conf".to_string(),
+    }).await.unwrap();
+
+    let status = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            let body: serde_json::Value = server.get("/api/file-transfer")
+                .add_query_param("transfer_id", &transfer_id)
+                .await
+                .json();
+            if body["clients"]["client-1"].get("Completed").is_some() {
+                break body;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+    }).await.expect("transfer should complete");
+    assert_eq!(status["size"], 100_000);
+
+    // 目标必须明确，分组必须存在
+    let form = MultipartForm::new()
+        .add_part("file", Part::bytes(b"x".to_vec()))
+        .add_text("group", "missing")
+        .add_text("path", "/etc/app/x");
+    server.post("/api/push-file").multipart(form).await.assert_status(StatusCode::BAD_REQUEST);
+    let form = MultipartForm::new()
+        .add_part("file", Part::bytes(b"x".to_vec()))
+        .add_text("client_id", "client-1")
+        .add_text("path", "relative/x");
+    server.post("/api/push-file").multipart(form).await.assert_status(StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_push_file_rejects_oversized_upload() {
+    use axum_test::multipart::{MultipartForm, Part};
+
+    let shared_data = create_test_shared_data();
+    shared_data.lock().await.max_push_file_size = 1024;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    let form = MultipartForm::new()
+        .add_part("file", Part::bytes(vec![0u8; 4096]))
+        .add_text("client_id", "client-1")
+        .add_text("path", "/etc/app/big");
+    server.post("/api/push-file").multipart(form).await.assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn test_fetch_file_streams_client_chunks_as_download() {
+    use ops_common::file_transfer::{FILE_CHUNK_SIZE, sha256_hex};
+    use ops_common::protocol::FetchRange;
+
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    let content: Vec<u8> = (0..150_000u32).map(|i| (i % 251) as u8).collect();
+    let request = server.get("/api/fetch-file")
+        .add_query_param("client_id", "client-1")
+        .add_query_param("path", "/var/log/app/app.log")
+        .add_query_param("tail_lines", 100);
+
+    // 模拟客户端：按请求分块回传
+    let agent_side = async {
+        let fetch_id = match agent.read_envelope().await.unwrap() {
+            Envelope::FileFetchRequest { fetch_id, path, range, .. } => {
+                assert_eq!(path, "/var/log/app/app.log");
+                assert_eq!(range, FetchRange::Tail { lines: 100 });
+                fetch_id
+            }
+            other => panic!("Unexpected envelope: {:?}", other),
+        };
+        agent.write_envelope(&Envelope::FileFetchStart {
+            fetch_id: fetch_id.clone(),
+            file_size: 200_000,
+            offset: 50_000,
+            length: content.len() as u64,
+        }).await.unwrap();
+        for (index, chunk) in content.chunks(FILE_CHUNK_SIZE).enumerate() {
+            agent.write_envelope(&Envelope::FileFetchChunk {
+                fetch_id: fetch_id.clone(),
+                offset: 50_000 + (index * FILE_CHUNK_SIZE) as u64,
+                data: chunk.to_vec(),
+            }).await.unwrap();
+        }
+        agent.write_envelope(&Envelope::FileFetchEnd { fetch_id, sha256: sha256_hex(&content) }).await.unwrap();
+    };
+
+    let (response, _) = tokio::join!(request, agent_side);
+    response.assert_status(StatusCode::OK);
+    assert_eq!(response.header("content-disposition"), "attachment; filename=\"app.log\"");
+    assert_eq!(response.header("x-file-size"), "200000");
+    assert_eq!(response.as_bytes().to_vec(), content);
+
+    // 客户端报告失败时返回原因
+    let request = server.get("/api/fetch-file")
+        .add_query_param("client_id", "client-1")
+        .add_query_param("path", "/etc/shadow");
+    let agent_side = async {
+        let Envelope::FileFetchRequest { fetch_id, .. } = agent.read_envelope().await.unwrap() else {
+            panic!("expected file fetch request");
+        };
+        agent.write_envelope(&Envelope::FileFetchFailed {
+            fetch_id,
+            reason: "路径不在允许的目录中".to_string(),
+        }).await.unwrap();
+    };
+    let (response, _) = tokio::join!(request, agent_side);
+    response.assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(response.text().contains("允许的目录"));
+
+    // 参数冲突或客户端不存在
+    server.get("/api/fetch-file")
+        .add_query_param("client_id", "client-1")
+        .add_query_param("path", "/var/log/app/app.log")
+        .add_query_param("tail_lines", 10)
+        .add_query_param("offset", 0)
+        .await
+        .assert_status(StatusCode::BAD_REQUEST);
+    server.get("/api/fetch-file")
+        .add_query_param("client_id", "offline")
+        .add_query_param("path", "/var/log/app/app.log")
+        .await
+        .assert_status(StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn test_file_fetch_rejects_checksum_mismatch_and_oversize() {
+    use crate::file_transfers::{FetchEvent, FileFetches};
+
+    let mut fetches = FileFetches::default();
+    let (fetch_id, mut events) = fetches.open("client-1", 1024);
+    assert!(fetches.route("client-1", &fetch_id, FetchEvent::Start { file_size: 10, offset: 0, length: 5 }));
+    // 其他客户端不能冒充回传
+    assert!(!fetches.route("client-2", &fetch_id, FetchEvent::Chunk { offset: 0, data: b"evil!".to_vec() }));
+    fetches.route("client-1", &fetch_id, FetchEvent::Chunk { offset: 0, data: b"hello".to_vec() });
+    fetches.route("client-1", &fetch_id, FetchEvent::End { sha256: "0".repeat(64) });
+
+    assert!(matches!(events.recv().await, Some(FetchEvent::Start { .. })));
+    assert!(matches!(events.recv().await, Some(FetchEvent::Chunk { .. })));
+    match events.recv().await {
+        Some(FetchEvent::Failed(reason)) => assert!(reason.contains("SHA-256")),
+        other => panic!("Unexpected event: {:?}", other),
+    }
+    assert!(!fetches.route("client-1", &fetch_id, FetchEvent::End { sha256: String::new() }));
+
+    let (fetch_id, mut events) = fetches.open("client-1", 1024);
+    fetches.route("client-1", &fetch_id, FetchEvent::Start { file_size: 4096, offset: 0, length: 4096 });
+    match events.recv().await {
+        Some(FetchEvent::Failed(reason)) => assert!(reason.contains("超过服务端上限")),
+        other => panic!("Unexpected event: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_log_tail_streams_lines_over_sse() {
+    let shared_data = create_test_shared_data();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let mut agent = register_agent(addr, "client-1").await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    let request = server.get("/api/log-tail")
+        .add_query_param("client_id", "client-1")
+        .add_query_param("app", "web")
+        .add_query_param("file", "logs/app.log")
+        .add_query_param("lines", 50);
+    let agent_task = async {
+        let tail_id = match agent.read_envelope().await.unwrap() {
+            Envelope::LogTailStart { tail_id, app, file, lines, .. } => {
+                assert_eq!((app.as_str(), file.as_str(), lines), ("web", "logs/app.log", 50));
+                tail_id
+            }
+            other => panic!("Unexpected envelope: {:?}", other),
+        };
+        agent.write_envelope(&Envelope::LogTailData { tail_id: tail_id.clone(), data: "started\n".to_string() }).await.unwrap();
+        agent.write_envelope(&Envelope::LogTailEnded { tail_id, reason: "读取日志失败".to_string() }).await.unwrap();
+    };
+
+    let (response, _) = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        async { tokio::join!(request, agent_task) },
+    ).await.expect("stream should end once the client ends the tail");
+    response.assert_status(StatusCode::OK);
+    let body = response.text();
+    assert!(body.contains("event: lines\ndata: {\"type\":\"lines\",\"data\":\"started\\n\"}"), "{}", body);
+    assert!(body.contains("event: ended"));
+    assert!(shared_data.lock().await.log_tails.is_empty());
+
+    // 浏览器关闭订阅时通知客户端停止
+    let (tail_id, events) = shared_data.lock().await.open_log_tail("client-1", "web", "logs/app.log", 10).unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::LogTailStart { .. }));
+    drop(events);
+    shared_data.lock().await.close_log_tail(&tail_id);
+    match agent.read_envelope().await.unwrap() {
+        Envelope::LogTailStop { tail_id: stopped } => assert_eq!(stopped, tail_id),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    server.get("/api/log-tail")
+        .add_query_param("client_id", "offline")
+        .add_query_param("app", "web")
+        .add_query_param("file", "logs/app.log")
+        .await
+        .assert_status(StatusCode::CONFLICT);
+}
+
+#[test]
+fn test_credential_registry_enroll_rotate_revoke_persisted() {
+    use crate::credentials::CredentialRegistry;
+    use std::time::Duration;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("credentials.json");
+    let mut registry = CredentialRegistry::load(&path).unwrap();
+    assert!(registry.secrets_for("client-1").is_none());
+
+    // 注册令牌只能使用一次，限定 client_id 的令牌不能注册其他客户端
+    let token = registry.issue_enrollment_token(None, Duration::from_secs(60)).unwrap();
+    let secret = registry.enroll("client-1", &token).unwrap();
+    assert!(registry.enroll("client-2", &token).is_err());
+    let bound = registry.issue_enrollment_token(Some("client-3".to_string()), Duration::from_secs(60)).unwrap();
+    assert!(registry.enroll("client-2", &bound).is_err());
+    let expired = registry.issue_enrollment_token(None, Duration::ZERO).unwrap();
+    assert!(registry.enroll("client-2", &expired).is_err());
+    // 已注册的客户端不能被其他令牌持有者重新注册
+    let another = registry.issue_enrollment_token(None, Duration::from_secs(60)).unwrap();
+    assert!(registry.enroll("client-1", &another).is_err());
+    assert_eq!(registry.secrets_for("client-1"), Some(vec![secret.clone()]));
+
+    // 轮换期间新旧密钥都有效，确认后只保留新密钥
+    let rotated = registry.begin_rotation("client-1").unwrap();
+    assert_eq!(registry.secrets_for("client-1"), Some(vec![rotated.clone(), secret.clone()]));
+    assert!(registry.confirm_rotation("client-1").unwrap());
+    assert!(!registry.confirm_rotation("client-1").unwrap());
+
+    // 重新加载后状态保持，文件中不保存明文令牌
+    let mut registry = CredentialRegistry::load(&path).unwrap();
+    assert_eq!(registry.secrets_for("client-1"), Some(vec![rotated]));
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert!(!content.contains(&another));
+    use std::os::unix::fs::PermissionsExt;
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // 吊销后没有有效密钥，只能用限定该客户端的令牌重新注册
+    assert!(registry.revoke("client-1").unwrap());
+    assert!(!registry.revoke("unknown").unwrap());
+    assert!(registry.is_revoked("client-1"));
+    assert_eq!(registry.secrets_for("client-1"), Some(Vec::new()));
+    assert!(registry.begin_rotation("client-1").is_err());
+    assert!(registry.enroll("client-1", &another).is_err());
+    let reenroll = registry.issue_enrollment_token(Some("client-1".to_string()), Duration::from_secs(60)).unwrap();
+    registry.enroll("client-1", &reenroll).unwrap();
+    assert!(!registry.is_revoked("client-1"));
+}
+
+// 启用 TCP 认证后完成 hello，返回收到的质询
+async fn connect_for_auth(addr: SocketAddr) -> (FramedStream<BoxedStream>, String, u64) {
+    use ops_common::tcp_auth::TcpAuthMessage;
+
+    let mut agent = connect_agent(addr).await;
+    agent.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::HelloAck(_)));
+    match agent.read_envelope().await.unwrap() {
+        Envelope::Auth(TcpAuthMessage::Challenge { nonce, timestamp }) => (agent, nonce, timestamp),
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+}
+
+async fn respond_to_challenge(agent: &mut FramedStream<BoxedStream>, client_id: &str, secret: &str, nonce: String, timestamp: u64) -> bool {
+    use ops_common::tcp_auth::{TcpAuthMessage, TcpAuthenticator};
+
+    let response = TcpAuthenticator::new(secret.to_string())
+        .generate_response(client_id.to_string(), nonce, timestamp)
+        .unwrap();
+    agent.write_envelope(&Envelope::Auth(response)).await.unwrap();
+    match agent.read_envelope().await.unwrap() {
+        Envelope::Auth(TcpAuthMessage::AuthResult { success, .. }) => success,
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_enrollment_issues_per_client_secret_and_revocation_disconnects() {
+    use ops_common::tcp_auth::TcpAuthMessage;
+
+    let shared_data = create_test_shared_data();
+    {
+        let mut data = shared_data.lock().await;
+        data.tcp_auth_enabled = true;
+        data.tcp_auth_secret = Some("legacy-secret".to_string());
+    }
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    let response = server.post("/api/enrollment-tokens").json(&json!({ "client_id": "client-1" })).await;
+    response.assert_status(StatusCode::OK);
+    let token = response.json::<serde_json::Value>()["token"].as_str().unwrap().to_string();
+
+    // 凭注册令牌换取独立密钥，再用它回应质询
+    let (mut agent, nonce, timestamp) = connect_for_auth(addr).await;
+    agent.write_envelope(&Envelope::Auth(TcpAuthMessage::Enroll { client_id: "client-1".to_string(), token: token.clone() })).await.unwrap();
+    let secret = match agent.read_envelope().await.unwrap() {
+        Envelope::Auth(TcpAuthMessage::Enrolled { secret }) => secret,
+        other => panic!("Unexpected envelope: {:?}", other),
+    };
+    assert!(respond_to_challenge(&mut agent, "client-1", &secret, nonce, timestamp).await);
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::SessionToken { .. }));
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-1"))).await.unwrap();
+    expect_registered(&mut agent).await;
+
+    // 已注册的客户端不再接受共享密钥，令牌也不能再次使用
+    let (mut other, nonce, timestamp) = connect_for_auth(addr).await;
+    assert!(!respond_to_challenge(&mut other, "client-1", "legacy-secret", nonce, timestamp).await);
+    let (mut other, _, _) = connect_for_auth(addr).await;
+    other.write_envelope(&Envelope::Auth(TcpAuthMessage::Enroll { client_id: "client-1".to_string(), token })).await.unwrap();
+    assert!(matches!(other.read_envelope().await.unwrap(), Envelope::Auth(TcpAuthMessage::AuthResult { success: false, .. })));
+
+    // 轮换：客户端确认后旧密钥失效
+    server.post("/api/credentials/rotate").json(&json!({ "client_id": "client-1" })).await
+        .assert_status(StatusCode::OK);
+    let rotated = match agent.read_envelope().await.unwrap() {
+        Envelope::CredentialUpdate { secret } => secret,
+        other => panic!("Unexpected envelope: {:?}", other),
+    };
+    agent.write_envelope(&Envelope::CredentialUpdateAck).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(shared_data.lock().await.auth_secrets("client-1"), vec![rotated.clone()]);
+    let credentials = server.get("/api/credentials").await.json::<serde_json::Value>();
+    assert_eq!(credentials[0]["client_id"], "client-1");
+    assert_eq!(credentials[0]["rotation_pending"], false);
+    assert!(!credentials.to_string().contains(&rotated));
+
+    // 吊销后立即断开连接，之后无法再认证
+    server.post("/api/credentials/revoke").json(&json!({ "client_id": "client-1" })).await
+        .assert_status(StatusCode::OK);
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+    assert!(!shared_data.lock().await.client_connections.contains_key("client-1"));
+    let (mut agent, nonce, timestamp) = connect_for_auth(addr).await;
+    assert!(!respond_to_challenge(&mut agent, "client-1", &rotated, nonce, timestamp).await);
+
+    server.post("/api/credentials/revoke").json(&json!({ "client_id": "unknown" })).await
+        .assert_status(StatusCode::NOT_FOUND);
+    server.post("/api/credentials/rotate").json(&json!({ "client_id": "client-1" })).await
+        .assert_status(StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn test_shared_secret_client_migrated_to_own_credential() {
+    let shared_data = create_test_shared_data();
+    {
+        let mut data = shared_data.lock().await;
+        data.tcp_auth_enabled = true;
+        data.tcp_auth_secret = Some("legacy-secret".to_string());
+    }
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    let (mut agent, nonce, timestamp) = connect_for_auth(addr).await;
+    assert!(respond_to_challenge(&mut agent, "client-1", "legacy-secret", nonce, timestamp).await);
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::SessionToken { .. }));
+    let secret = match agent.read_envelope().await.unwrap() {
+        Envelope::CredentialUpdate { secret } => secret,
+        other => panic!("Unexpected envelope: {:?}", other),
+    };
+    // 确认前共享密钥仍然有效，确认后只接受新密钥
+    assert_eq!(shared_data.lock().await.auth_secrets("client-1").len(), 2);
+    agent.write_envelope(&Envelope::CredentialUpdateAck).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(shared_data.lock().await.auth_secrets("client-1"), vec![secret]);
+    assert_eq!(shared_data.lock().await.auth_secrets("client-2"), vec!["legacy-secret".to_string()]);
+}
+
+// 启用 TCP 认证，并为每个客户端直接注册独立密钥
+async fn enable_auth_with_credentials(shared_data: &SharedDataHandle, client_ids: &[&str]) -> Vec<String> {
+    let mut data = shared_data.lock().await;
+    data.tcp_auth_enabled = true;
+    client_ids.iter().map(|client_id| {
+        let token = data.credentials.issue_enrollment_token(None, std::time::Duration::from_secs(60)).unwrap();
+        data.credentials.enroll(client_id, &token).unwrap()
+    }).collect()
+}
+
+// 用独立密钥完成认证，但不上报客户端信息
+async fn authenticate_agent(addr: SocketAddr, client_id: &str, secret: &str) -> FramedStream<BoxedStream> {
+    let (mut agent, nonce, timestamp) = connect_for_auth(addr).await;
+    assert!(respond_to_challenge(&mut agent, client_id, secret, nonce, timestamp).await);
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::SessionToken { .. }));
+    agent
+}
+
+#[tokio::test]
+async fn test_authenticated_client_cannot_claim_another_client_id() {
+    use ops_common::protocol::CommandResponse;
+
+    let shared_data = create_test_shared_data();
+    let secrets = enable_auth_with_credentials(&shared_data, &["client-1", "client-2"]).await;
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    let mut victim = authenticate_agent(addr, "client-2", &secrets[1]).await;
+    victim.write_envelope(&Envelope::ClientInfo(sample_client_info("client-2"))).await.unwrap();
+    expect_registered(&mut victim).await;
+
+    // 以 client-1 认证的连接冒充 client-2 上报信息，被拒绝并断开
+    let mut attacker = authenticate_agent(addr, "client-1", &secrets[0]).await;
+    attacker.write_envelope(&Envelope::ClientInfo(sample_client_info("client-2"))).await.unwrap();
+    assert!(matches!(attacker.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+    assert!(attacker.read_envelope().await.is_err());
+
+    // 冒充 client-2 回传命令结果同样断开
+    let mut attacker = authenticate_agent(addr, "client-1", &secrets[0]).await;
+    attacker.write_envelope(&Envelope::CommandResponse(CommandResponse {
+        command_id: "command-1".to_string(),
+        client_id: "client-2".to_string(),
+        command: "uptime".to_string(),
+        output: String::new(),
+        error_output: String::new(),
+        exit_code: 0,
+        executed_at: std::time::SystemTime::now(),
+        cancelled: false,
+        timed_out: false,
+    })).await.unwrap();
+    assert!(matches!(attacker.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+
+    // client-2 的连接不受影响，命令仍发给它
+    shared_data.lock().await.send_command_to_client("client-2", "uptime").await.unwrap();
+    assert!(matches!(victim.read_envelope().await.unwrap(), Envelope::CommandRequest { .. }));
+}
+
+#[tokio::test]
+async fn test_rotation_ack_and_revocation_bound_to_authenticated_client() {
+    let shared_data = create_test_shared_data();
+    let secrets = enable_auth_with_credentials(&shared_data, &["client-1", "client-2"]).await;
+    shared_data.lock().await.credentials.begin_rotation("client-2").unwrap();
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    // 不能冒充 client-2 确认它的密钥轮换
+    let mut attacker = authenticate_agent(addr, "client-1", &secrets[0]).await;
+    attacker.write_envelope(&Envelope::ClientInfo(sample_client_info("client-2"))).await.unwrap();
+    assert!(matches!(attacker.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+    let mut attacker = authenticate_agent(addr, "client-1", &secrets[0]).await;
+    attacker.write_envelope(&Envelope::CredentialUpdateAck).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert_eq!(shared_data.lock().await.auth_secrets("client-2").len(), 2);
+
+    // 凭据被吊销后不能换用其他 client_id 继续工作
+    shared_data.lock().await.credentials.revoke("client-1").unwrap();
+    attacker.write_envelope(&Envelope::ClientInfo(sample_client_info("client-2"))).await.unwrap();
+    assert!(matches!(attacker.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+    assert!(attacker.read_envelope().await.is_err());
+    assert!(!shared_data.lock().await.client_connections.contains_key("client-2"));
+}
+
+#[test]
+fn test_client_approvals_persisted() {
+    use crate::approvals::{Admission, ClientApprovals};
+    use std::time::Duration;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("approvals.json");
+    let mut approvals = ClientApprovals::load(&path).unwrap();
+    let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+
+    // 只能审批待审批列表中的客户端
+    assert!(!approvals.approve("client-1").unwrap());
+    assert_eq!(approvals.admit("client-1", &sample_client_info("client-1"), "127.0.0.1:1", &sender), Admission::Pending);
+    assert_eq!(approvals.admit("client-2", &sample_client_info("client-2"), "127.0.0.1:2", &sender), Admission::Pending);
+    assert_eq!(approvals.pending().len(), 2);
+    assert!(approvals.approve("client-1").unwrap());
+    assert!(approvals.reject("client-2").unwrap().is_some());
+    assert!(approvals.pending().is_empty());
+
+    // 重新加载后审批结果保持，待审批列表不保存
+    let mut approvals = ClientApprovals::load(&path).unwrap();
+    assert_eq!(approvals.admit("client-1", &sample_client_info("client-1"), "127.0.0.1:1", &sender), Admission::Approved);
+    assert_eq!(approvals.admit("client-2", &sample_client_info("client-2"), "127.0.0.1:2", &sender), Admission::Rejected);
+    assert_eq!(approvals.admit("client-3", &sample_client_info("client-3"), "127.0.0.1:3", &sender), Admission::Pending);
+    assert_eq!(approvals.pending().len(), 1);
+    approvals.prune_pending(Duration::ZERO);
+    assert!(approvals.pending().is_empty());
+}
+
+#[tokio::test]
+async fn test_unknown_client_waits_for_approval() {
+    let shared_data = create_test_shared_data();
+    shared_data.lock().await.require_client_approval = true;
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    let mut agent = connect_agent(addr).await;
+    agent.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::HelloAck(_)));
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-1"))).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::Ack));
+
+    // 待审批的客户端不在客户端列表中，也不能接收命令
+    let pending = server.get("/api/pending-clients").await.json::<serde_json::Value>();
+    assert_eq!(pending["require_approval"], true);
+    assert_eq!(pending["clients"][0]["client_id"], "client-1");
+    assert!(server.get("/api/clients").await.json::<serde_json::Value>()["clients"].as_object().unwrap().is_empty());
+    server.post("/api/send-command").json(&json!({ "client_id": "client-1", "command": "uptime" })).await
+        .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+
+    // 批准后在下一次心跳时加入
+    server.post("/api/pending-clients/approve").json(&json!({ "client_id": "client-1" })).await
+        .assert_status(StatusCode::OK);
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-1"))).await.unwrap();
+    expect_registered(&mut agent).await;
+    assert!(shared_data.lock().await.client_connections.contains_key("client-1"));
+    assert!(server.get("/api/pending-clients").await.json::<serde_json::Value>()["clients"].as_array().unwrap().is_empty());
+
+    // 拒绝后立即断开，之后再连接也会被拒绝
+    let mut other = connect_agent(addr).await;
+    other.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(other.read_envelope().await.unwrap(), Envelope::HelloAck(_)));
+    other.write_envelope(&Envelope::ClientInfo(sample_client_info("client-2"))).await.unwrap();
+    assert!(matches!(other.read_envelope().await.unwrap(), Envelope::Ack));
+    server.post("/api/pending-clients/reject").json(&json!({ "client_id": "client-2" })).await
+        .assert_status(StatusCode::OK);
+    assert!(matches!(other.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+
+    let mut other = connect_agent(addr).await;
+    other.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(other.read_envelope().await.unwrap(), Envelope::HelloAck(_)));
+    other.write_envelope(&Envelope::ClientInfo(sample_client_info("client-2"))).await.unwrap();
+    assert!(matches!(other.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+    assert!(!shared_data.lock().await.client_connections.contains_key("client-2"));
+
+    server.post("/api/pending-clients/approve").json(&json!({ "client_id": "client-2" })).await
+        .assert_status(StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_approval_checked_against_authenticated_client_id() {
+    let shared_data = create_test_shared_data();
+    let secrets = enable_auth_with_credentials(&shared_data, &["client-1", "client-2"]).await;
+    {
+        let mut data = shared_data.lock().await;
+        data.require_client_approval = true;
+        let (sender, _receiver) = tokio::sync::mpsc::channel(1);
+        data.approvals.admit("client-1", &sample_client_info("client-1"), "127.0.0.1:1", &sender);
+        assert!(data.approvals.approve("client-1").unwrap());
+    }
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    // 未获批准的 client-2 声明已批准的 client-1 不能绕过审批
+    let mut agent = authenticate_agent(addr, "client-2", &secrets[1]).await;
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-1"))).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+    assert!(!shared_data.lock().await.client_connections.contains_key("client-1"));
+
+    // 以自己的身份上报时进入待审批列表
+    let mut agent = authenticate_agent(addr, "client-2", &secrets[1]).await;
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-2"))).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::Ack));
+    let pending = shared_data.lock().await.approvals.pending();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].client_id, "client-2");
+    assert!(shared_data.lock().await.client_connections.is_empty());
+}
+
+#[tokio::test]
+async fn test_commands_signed_when_signing_key_configured() {
+    use ops_common::command_signing::{CommandSigner, CommandVerifier, SignedRequest};
+
+    let shared_data = create_test_shared_data();
+    let signer = CommandSigner::from_pem(&CommandSigner::generate_pem().unwrap()).unwrap();
+    let mut verifier = CommandVerifier::new(&signer.public_key()).unwrap();
+    shared_data.lock().await.command_signer = Some(signer);
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+
+    let mut agent = connect_agent(addr).await;
+    agent.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::HelloAck(_)));
+    agent.write_envelope(&Envelope::ClientInfo(sample_client_info("client-1"))).await.unwrap();
+
+    // 下发的命令策略同样签名
+    match agent.read_envelope().await.unwrap() {
+        Envelope::CommandPolicy { policy_id, policy, signature } => {
+            let content = ops_common::protocol::command_policy_signed_content(&policy);
+            assert!(verifier.verify_request(SignedRequest::CommandPolicy, &policy_id, "client-1", &content, signature.as_ref()).is_ok());
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+    assert!(matches!(agent.read_envelope().await.unwrap(), Envelope::Ack));
+
+    shared_data.lock().await.send_command_to_client("client-1", "uptime").await.unwrap();
+    match agent.read_envelope().await.unwrap() {
+        Envelope::CommandRequest { command_id, command, timeout_secs, signature } => {
+            assert!(verifier.verify(&command_id, "client-1", &command, timeout_secs, signature.as_ref()).is_ok());
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+
+    // 打开终端的请求同样签名
+    shared_data.lock().await.open_terminal("client-1", 80, 24).unwrap();
+    match agent.read_envelope().await.unwrap() {
+        Envelope::TerminalOpen { session_id, signature, .. } => {
+            assert!(verifier.verify_request(SignedRequest::TerminalOpen, &session_id, "client-1", "", signature.as_ref()).is_ok());
+        }
+        other => panic!("Unexpected envelope: {:?}", other),
+    }
+}
+
+#[test]
+fn test_auth_bans_after_repeated_failures() {
+    use crate::auth_bans::AuthBans;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    let mut bans = AuthBans::new(3, Duration::from_secs(60), Duration::from_secs(60));
+
+    // 成功认证清零计数
+    assert_eq!(bans.record_failure(ip, Some("client-1"), "bad"), None);
+    assert_eq!(bans.record_failure(ip, Some("client-1"), "bad"), None);
+    bans.record_success(ip);
+    assert_eq!(bans.record_failure(ip, Some("client-1"), "bad"), None);
+    assert_eq!(bans.record_failure(ip, Some("client-1"), "bad"), None);
+    assert_eq!(bans.record_failure(ip, Some("client-1"), "bad"), Some(Duration::from_secs(60)));
+    assert!(bans.banned_until(ip).is_some());
+    assert!(bans.banned_until(other).is_none());
+
+    bans.record_failure(other, None, "bad");
+    let list = bans.list();
+    assert_eq!(list[0].ip, ip);
+    assert_eq!(list[0].total_failures, 5);
+    assert_eq!(list[0].bans, 1);
+
+    assert!(bans.unban(ip));
+    assert!(!bans.unban(ip));
+    assert!(bans.banned_until(ip).is_none());
+
+    // 计数窗口结束后记录被清理
+    let mut bans = AuthBans::new(3, Duration::ZERO, Duration::from_secs(60));
+    bans.record_failure(ip, None, "bad");
+    std::thread::sleep(Duration::from_millis(5));
+    bans.prune();
+    assert!(bans.list().is_empty());
+}
+
+#[tokio::test]
+async fn test_replayed_auth_response_rejected_and_source_banned() {
+    use crate::auth_bans::AuthBans;
+    use ops_common::tcp_auth::{TcpAuthMessage, TcpAuthenticator};
+    use std::time::Duration;
+
+    let shared_data = create_test_shared_data();
+    {
+        let mut data = shared_data.lock().await;
+        data.tcp_auth_enabled = true;
+        data.tcp_auth_secret = Some("legacy-secret".to_string());
+        data.auth_bans = AuthBans::new(2, Duration::from_secs(60), Duration::from_secs(60));
+    }
+    let addr = spawn_tcp_server(shared_data.clone()).await;
+    let server = create_test_server(shared_data.clone(), AuthConfig::new(None));
+    login(&server).await;
+
+    // 截获的响应不能在另一个连接上使用
+    let (_victim, nonce, timestamp) = connect_for_auth(addr).await;
+    let captured = TcpAuthenticator::new("legacy-secret".to_string())
+        .generate_response("client-1".to_string(), nonce, timestamp)
+        .unwrap();
+    let (mut attacker, _, _) = connect_for_auth(addr).await;
+    attacker.write_envelope(&Envelope::Auth(captured)).await.unwrap();
+    assert!(matches!(attacker.read_envelope().await.unwrap(), Envelope::Auth(TcpAuthMessage::AuthResult { success: false, .. })));
+
+    // 第二次失败后该 IP 被封禁，新连接直接被拒绝
+    let (mut attacker, nonce, timestamp) = connect_for_auth(addr).await;
+    assert!(!respond_to_challenge(&mut attacker, "client-1", "wrong-secret", nonce, timestamp).await);
+    let mut banned = connect_agent(addr).await;
+    banned.write_envelope(&Envelope::Hello(Hello::local())).await.unwrap();
+    assert!(matches!(banned.read_envelope().await.unwrap(), Envelope::ConnectionRejected { .. }));
+
+    let bans = server.get("/api/auth-bans").await.json::<serde_json::Value>();
+    assert_eq!(bans["records"][0]["ip"], "127.0.0.1");
+    assert_eq!(bans["records"][0]["total_failures"], 2);
+    assert_eq!(bans["records"][0]["last_client_id"], "client-1");
+    assert!(!bans["records"][0]["banned_until"].is_null());
+
+    // 管理员解除封禁后可以正常认证
+    server.post("/api/auth-bans/unban").json(&json!({ "ip": "127.0.0.1" })).await
+        .assert_status(StatusCode::OK);
+    server.post("/api/auth-bans/unban").json(&json!({ "ip": "127.0.0.1" })).await
+        .assert_status(StatusCode::NOT_FOUND);
+    server.post("/api/auth-bans/unban").json(&json!({ "ip": "not-an-ip" })).await
+        .assert_status(StatusCode::BAD_REQUEST);
+    let (mut agent, nonce, timestamp) = connect_for_auth(addr).await;
+    assert!(respond_to_challenge(&mut agent, "client-1", "legacy-secret", nonce, timestamp).await);
+}
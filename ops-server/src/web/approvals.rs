@@ -0,0 +1,72 @@
+// 新客户端审批：查看待审批列表，批准或拒绝客户端加入
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use crate::SharedDataHandle;
+use crate::approvals::PendingClient;
+
+#[derive(Serialize)]
+pub struct PendingClientsResponse {
+    pub require_approval: bool,
+    pub clients: Vec<PendingClient>,
+}
+
+// 列出等待审批的客户端
+pub async fn list_pending_clients(State(shared_data): State<SharedDataHandle>) -> Json<PendingClientsResponse> {
+    let data = shared_data.lock().await;
+    Json(PendingClientsResponse {
+        require_approval: data.require_client_approval,
+        clients: data.approvals.pending(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ApprovalRequest {
+    pub client_id: String,
+}
+
+#[derive(Serialize)]
+pub struct ApprovalResponse {
+    pub client_id: String,
+    pub message: String,
+}
+
+// 批准客户端，客户端下次上报信息时加入
+pub async fn approve_client(
+    State(shared_data): State<SharedDataHandle>,
+    Json(payload): Json<ApprovalRequest>
+) -> Result<Json<ApprovalResponse>, (StatusCode, String)> {
+    match shared_data.lock().await.approvals.approve(&payload.client_id) {
+        Ok(true) => {
+            tracing::info!("Approved client {}", payload.client_id);
+            Ok(Json(ApprovalResponse {
+                client_id: payload.client_id,
+                message: "已批准".to_string(),
+            }))
+        }
+        Ok(false) => Err((StatusCode::NOT_FOUND, "客户端不在待审批列表中".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
+
+// 拒绝客户端并断开其连接，之后该客户端不能再加入
+pub async fn reject_client(
+    State(shared_data): State<SharedDataHandle>,
+    Json(payload): Json<ApprovalRequest>
+) -> Result<Json<ApprovalResponse>, (StatusCode, String)> {
+    match shared_data.lock().await.reject_client(&payload.client_id) {
+        Ok(true) => {
+            tracing::warn!("Rejected client {}", payload.client_id);
+            Ok(Json(ApprovalResponse {
+                client_id: payload.client_id,
+                message: "已拒绝".to_string(),
+            }))
+        }
+        Ok(false) => Err((StatusCode::NOT_FOUND, "客户端不在待审批列表中".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
@@ -0,0 +1,52 @@
+// TCP 认证失败统计：查看各来源 IP 的失败次数和封禁状态，手动解除封禁
+
+use std::net::IpAddr;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use crate::SharedDataHandle;
+use crate::auth_bans::AuthFailureRecord;
+
+#[derive(Serialize)]
+pub struct AuthBansResponse {
+    pub records: Vec<AuthFailureRecord>,
+    /// 已签发、尚未使用的认证质询数
+    pub pending_challenges: usize,
+}
+
+// 列出认证失败记录，封禁中的 IP 在前
+pub async fn list_auth_bans(State(shared_data): State<SharedDataHandle>) -> Json<AuthBansResponse> {
+    let data = shared_data.lock().await;
+    Json(AuthBansResponse {
+        records: data.auth_bans.list(),
+        pending_challenges: data.auth_nonces.len(),
+    })
+}
+
+#[derive(Deserialize)]
+pub struct UnbanRequest {
+    pub ip: String,
+}
+
+#[derive(Serialize)]
+pub struct UnbanResponse {
+    pub ip: IpAddr,
+    pub message: String,
+}
+
+// 解除 IP 的封禁并清空其失败计数
+pub async fn unban(
+    State(shared_data): State<SharedDataHandle>,
+    Json(payload): Json<UnbanRequest>
+) -> Result<Json<UnbanResponse>, (StatusCode, String)> {
+    let ip: IpAddr = payload.ip.trim().parse()
+        .map_err(|_| (StatusCode::BAD_REQUEST, format!("无效的 IP 地址: {}", payload.ip)))?;
+    if !shared_data.lock().await.auth_bans.unban(ip) {
+        return Err((StatusCode::NOT_FOUND, "该 IP 没有认证失败记录".to_string()));
+    }
+    tracing::info!("Cleared authentication failures of {}", ip);
+    Ok(Json(UnbanResponse { ip, message: "已解除封禁".to_string() }))
+}
@@ -0,0 +1,92 @@
+// 客户端凭据管理：查看凭据状态、签发注册令牌、轮换和吊销客户端密钥
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::Json,
+};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use crate::SharedDataHandle;
+use crate::credentials::{CredentialSummary, DEFAULT_ENROLLMENT_TTL};
+
+// 列出已注册客户端的凭据状态（不含密钥）
+pub async fn list_credentials(State(shared_data): State<SharedDataHandle>) -> Json<Vec<CredentialSummary>> {
+    Json(shared_data.lock().await.credentials.list())
+}
+
+#[derive(Deserialize)]
+pub struct EnrollmentTokenRequest {
+    /// 设置后令牌只能用于注册该客户端
+    pub client_id: Option<String>,
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct EnrollmentTokenResponse {
+    pub token: String,
+    pub client_id: Option<String>,
+    pub expires_at: SystemTime,
+}
+
+// 签发一次性注册令牌，令牌只在响应中出现一次
+pub async fn create_enrollment_token(
+    State(shared_data): State<SharedDataHandle>,
+    Json(payload): Json<EnrollmentTokenRequest>
+) -> Result<Json<EnrollmentTokenResponse>, (StatusCode, String)> {
+    let ttl = payload.ttl_secs.map(Duration::from_secs).unwrap_or(DEFAULT_ENROLLMENT_TTL);
+    let token = shared_data.lock().await
+        .credentials
+        .issue_enrollment_token(payload.client_id.clone(), ttl)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    tracing::info!("Issued enrollment token (client: {}, valid for {}s)", payload.client_id.as_deref().unwrap_or("any"), ttl.as_secs());
+    Ok(Json(EnrollmentTokenResponse {
+        token,
+        client_id: payload.client_id,
+        expires_at: SystemTime::now() + ttl,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct CredentialRequest {
+    pub client_id: String,
+}
+
+#[derive(Serialize)]
+pub struct CredentialResponse {
+    pub client_id: String,
+    pub message: String,
+}
+
+// 轮换在线客户端的密钥，客户端确认保存后旧密钥失效
+pub async fn rotate_credential(
+    State(shared_data): State<SharedDataHandle>,
+    Json(payload): Json<CredentialRequest>
+) -> Result<Json<CredentialResponse>, (StatusCode, String)> {
+    shared_data.lock().await
+        .rotate_credential(&payload.client_id)
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+    tracing::info!("Rotating credential of client {}", payload.client_id);
+    Ok(Json(CredentialResponse {
+        client_id: payload.client_id,
+        message: "已下发新密钥，等待客户端确认".to_string(),
+    }))
+}
+
+// 吊销客户端凭据并断开其连接
+pub async fn revoke_credential(
+    State(shared_data): State<SharedDataHandle>,
+    Json(payload): Json<CredentialRequest>
+) -> Result<Json<CredentialResponse>, (StatusCode, String)> {
+    match shared_data.lock().await.revoke_credential(&payload.client_id).await {
+        Ok(true) => {
+            tracing::warn!("Revoked credential of client {}", payload.client_id);
+            Ok(Json(CredentialResponse {
+                client_id: payload.client_id,
+                message: "凭据已吊销".to_string(),
+            }))
+        }
+        Ok(false) => Err((StatusCode::NOT_FOUND, "客户端没有凭据".to_string())),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e)),
+    }
+}
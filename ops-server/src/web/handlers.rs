@@ -1,10 +1,14 @@
-use axum::{ Json, extract::{ State, Path, Query }, http::StatusCode,response::{ Html, IntoResponse, Response }, };
-use std::collections::HashMap;
+use axum::{ Json, body::{Body, Bytes}, extract::{ Multipart, State, Query }, http::{StatusCode, header}, response::{ Html, IntoResponse, Response, sse::{Event, KeepAlive, Sse} }, };
+use futures_util::stream::{self, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
 use serde::{ Deserialize, Serialize };
 use std::time::{SystemTime, Duration};
 use crate::{ ClientInfo, SharedDataHandle };
-use crate::command_results::{CommandResult, CommandStatus};
-use ops_common::security::{CommandValidator, PredefinedCommand};
+use crate::shared_data_handle::{ReconnectHistory, push_file_to_client};
+use crate::file_transfers::{FetchEvent, FileTransfer, TransferStatus};
+use crate::command_results::{CommandResult, CommandResultsManager, CommandStatus};
+use ops_common::{command_signing::SignedRequest, config::DEFAULT_APPS_BASE_DIR, file_transfer::sha256_hex, protocol::{Capability, FetchRange, FilePushStart}, security::{CommandValidator, PredefinedCommand}};
 use axum::http::header::{SET_COOKIE, HeaderMap};
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -12,7 +16,22 @@ use uuid::Uuid;
 
 #[derive(serde::Serialize)]
 pub struct ClientResponse {
-    pub clients: HashMap<String, ClientInfo>,
+    pub clients: HashMap<String, ClientEntry>,
+    /// 服务端当前下发的命令校验策略版本
+    pub command_policy_version: String,
+}
+
+/// 客户端列表条目：心跳信息加上连接时协商出的协议版本（未连接时为空）
+#[derive(serde::Serialize)]
+pub struct ClientEntry {
+    #[serde(flatten)]
+    pub info: ClientInfo,
+    pub protocol_version: Option<u32>,
+    pub capabilities: Vec<Capability>,
+    /// 客户端上报的重连记录
+    pub reconnects: Option<ReconnectHistory>,
+    /// 客户端执行的是否为服务端当前的命令校验策略
+    pub policy_current: bool,
 }
 
 // 新增：广播消息请求结构体
@@ -26,6 +45,9 @@ pub struct BroadcastMessage {
 pub struct CommandRequest {
     pub client_id: String,
     pub command: String,
+    /// 可选的执行超时秒数
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
 }
 
 // 新增：广播消息处理
@@ -62,7 +84,7 @@ pub async fn send_command(
     match shared_data
         .lock()
         .await
-        .send_command_to_client(&payload.client_id, &payload.command)
+        .send_command_with_timeout(&payload.client_id, &payload.command, payload.timeout_secs)
         .await
     {
         Ok(command_id) => {
@@ -78,6 +100,38 @@ pub async fn send_command(
     }
 }
 
+// 取消正在执行的命令
+#[derive(Deserialize)]
+pub struct CancelCommandRequest {
+    pub command_id: String,
+}
+
+pub async fn cancel_command(
+    State(shared_data): State<SharedDataHandle>,
+    Json(payload): Json<CancelCommandRequest>
+) -> Result<Json<CommandExecuteResponse>, (StatusCode, String)> {
+    let data = shared_data.lock().await;
+
+    match data.command_results.get_command_status(&payload.command_id).await {
+        None => return Err((StatusCode::NOT_FOUND, "Command not found".to_string())),
+        Some(status) if status.is_finished() => {
+            return Err((StatusCode::CONFLICT, "命令已结束，无法取消".to_string()));
+        }
+        Some(_) => {}
+    }
+
+    match data.cancel_command(&payload.command_id).await {
+        Ok(()) => Ok(Json(CommandExecuteResponse {
+            command_id: payload.command_id,
+            message: "已发送取消请求".to_string(),
+        })),
+        Err(e) => {
+            tracing::error!("Failed to cancel command {}: {}", payload.command_id, e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        }
+    }
+}
+
 // 获取命令执行结果
 #[derive(Deserialize)]
 pub struct CommandStatusQuery {
@@ -96,6 +150,282 @@ pub async fn get_command_result(
     }
 }
 
+/// 输出流结束时的 done 事件；命令已被清理时 status 为空
+#[derive(Serialize)]
+struct CommandStreamDone {
+    status: Option<CommandStatus>,
+    truncated: bool,
+}
+
+struct CommandStreamState {
+    results: CommandResultsManager,
+    command_id: String,
+    cursor: usize,
+    changes: tokio::sync::watch::Receiver<()>,
+    queued: VecDeque<Event>,
+    done: bool,
+}
+
+pub(crate) fn json_event<T: Serialize>(name: &str, value: &T) -> Event {
+    Event::default()
+        .event(name)
+        .json_data(value)
+        .unwrap_or_else(|_| Event::default().event(name))
+}
+
+// 以 SSE 推送命令的实时输出：每个分块一个 output 事件，命令结束后发送 done 事件
+pub async fn command_stream(
+    State(shared_data): State<SharedDataHandle>,
+    Query(params): Query<CommandStatusQuery>
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let results = shared_data.lock().await.command_results.clone();
+    if results.get_command_status(&params.command_id).await.is_none() {
+        return Err((StatusCode::NOT_FOUND, "Command not found".to_string()));
+    }
+
+    let state = CommandStreamState {
+        changes: results.subscribe_changes(),
+        results,
+        command_id: params.command_id,
+        cursor: 0,
+        queued: VecDeque::new(),
+        done: false,
+    };
+
+    let events = stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(event) = state.queued.pop_front() {
+                return Some((Ok(event), state));
+            }
+            if state.done {
+                return None;
+            }
+
+            state.changes.borrow_and_update();
+            // 先取状态再取输出：结果总在全部输出之后到达，状态已结束时读到的输出就是完整的
+            let status = state.results.get_command_status(&state.command_id).await;
+            let (chunks, truncated) = state.results.output_since(&state.command_id, state.cursor).await;
+            state.cursor += chunks.len();
+            state.queued.extend(chunks.iter().map(|chunk| json_event("output", chunk)));
+
+            let finished = status.as_ref().is_none_or(|status| status.is_finished());
+            if finished && state.queued.is_empty() {
+                state.queued.push_back(json_event("done", &CommandStreamDone { status, truncated }));
+                state.done = true;
+            } else if state.queued.is_empty() {
+                // 定期重新检查，即使错过通知也不会一直挂起
+                let _ = tokio::time::timeout(Duration::from_secs(15), state.changes.changed()).await;
+            }
+        }
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Serialize)]
+pub struct PushFileResponse {
+    pub transfer_id: String,
+    pub sha256: String,
+    pub clients: HashMap<String, TransferStatus>,
+}
+
+/// multipart 表单中的推送参数
+#[derive(Default)]
+struct PushFileForm {
+    content: Option<Vec<u8>>,
+    client_id: Option<String>,
+    group: Option<String>,
+    path: Option<String>,
+    mode: Option<u32>,
+}
+
+async fn read_push_file_form(mut multipart: Multipart, max_size: usize) -> Result<PushFileForm, (StatusCode, String)> {
+    let bad_request = |e: String| (StatusCode::BAD_REQUEST, e);
+    let mut form = PushFileForm::default();
+
+    while let Some(mut field) = multipart.next_field().await.map_err(|e| bad_request(e.to_string()))? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "file" {
+            // 边读边检查大小，超过上限立即拒绝
+            let mut content = Vec::new();
+            while let Some(chunk) = field.chunk().await.map_err(|e| bad_request(e.to_string()))? {
+                if content.len() + chunk.len() > max_size {
+                    return Err((StatusCode::PAYLOAD_TOO_LARGE, format!("文件超过大小上限 {} 字节", max_size)));
+                }
+                content.extend_from_slice(&chunk);
+            }
+            form.content = Some(content);
+            continue;
+        }
+
+        let value = field.text().await.map_err(|e| bad_request(e.to_string()))?;
+        let value = value.trim().to_string();
+        if value.is_empty() {
+            continue;
+        }
+        match name.as_str() {
+            "client_id" => form.client_id = Some(value),
+            "group" => form.group = Some(value),
+            "path" => form.path = Some(value),
+            "mode" => {
+                let mode = u32::from_str_radix(&value, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or_else(|| bad_request(format!("无效的文件权限: {}", value)))?;
+                form.mode = Some(mode);
+            }
+            _ => {}
+        }
+    }
+    Ok(form)
+}
+
+// 推送文件到客户端或分组：表单字段 file、client_id 或 group、path、可选的八进制 mode
+pub async fn push_file(
+    State(shared_data): State<SharedDataHandle>,
+    multipart: Multipart
+) -> Result<Json<PushFileResponse>, (StatusCode, String)> {
+    let max_size = shared_data.lock().await.max_push_file_size;
+    let form = read_push_file_form(multipart, max_size).await?;
+
+    let content = form.content.ok_or((StatusCode::BAD_REQUEST, "缺少文件".to_string()))?;
+    let path = form.path
+        .filter(|path| path.starts_with('/'))
+        .ok_or((StatusCode::BAD_REQUEST, "path 必须是绝对路径".to_string()))?;
+    let sha256 = sha256_hex(&content);
+
+    let mut data = shared_data.lock().await;
+    let targets = data
+        .resolve_targets(form.client_id.as_deref(), form.group.as_deref())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let (transfer_id, senders) = data.begin_file_push(&targets, &path, content.len() as u64, &sha256);
+    let clients = data.file_transfers
+        .get(&transfer_id)
+        .map(|transfer| transfer.clients.clone())
+        .unwrap_or_default();
+    // 签名包含 client_id，每个客户端各自签名
+    let starts: Vec<_> = senders.into_iter().map(|(client_id, sender)| {
+        let mut start = FilePushStart {
+            transfer_id: transfer_id.clone(),
+            path: path.clone(),
+            size: content.len() as u64,
+            sha256: sha256.clone(),
+            mode: form.mode,
+            signature: None,
+        };
+        start.signature = data.sign_request(SignedRequest::FilePush, &transfer_id, &client_id, &start.signed_content());
+        (client_id, sender, start)
+    }).collect();
+    drop(data);
+
+    tracing::info!("Pushing {} ({} bytes, sha256 {}) to {} client(s) as transfer {}",
+                   path, content.len(), sha256, starts.len(), transfer_id);
+    let content = Arc::new(content);
+    for (client_id, sender, start) in starts {
+        tokio::spawn(push_file_to_client(shared_data.clone(), client_id, sender, start, Arc::clone(&content)));
+    }
+
+    Ok(Json(PushFileResponse { transfer_id, sha256, clients }))
+}
+
+#[derive(Deserialize)]
+pub struct FileTransferQuery {
+    pub transfer_id: String,
+}
+
+// 查询文件推送在各客户端上的结果
+pub async fn get_file_transfer(
+    State(shared_data): State<SharedDataHandle>,
+    Query(params): Query<FileTransferQuery>
+) -> Result<Json<FileTransfer>, (StatusCode, String)> {
+    shared_data.lock().await
+        .file_transfers
+        .get(&params.transfer_id)
+        .cloned()
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Transfer not found".to_string()))
+}
+
+/// 等待客户端开始回传文件的时限
+const FETCH_START_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub struct FetchFileQuery {
+    pub client_id: String,
+    pub path: String,
+    pub offset: Option<u64>,
+    pub length: Option<u64>,
+    pub tail_lines: Option<u64>,
+}
+
+/// 下载结束（包括浏览器中途断开）时注销读取
+struct FetchGuard {
+    shared_data: SharedDataHandle,
+    fetch_id: String,
+}
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        let shared_data = self.shared_data.clone();
+        let fetch_id = std::mem::take(&mut self.fetch_id);
+        tokio::spawn(async move { shared_data.lock().await.file_fetches.remove(&fetch_id) });
+    }
+}
+
+// 从客户端读取文件并作为下载返回：默认整个文件，可指定 offset/length 字节范围或最后 tail_lines 行
+pub async fn fetch_file(
+    State(shared_data): State<SharedDataHandle>,
+    Query(params): Query<FetchFileQuery>
+) -> Result<Response, (StatusCode, String)> {
+    let range = match (params.tail_lines, params.offset, params.length) {
+        (None, None, None) => FetchRange::Whole,
+        (Some(lines), None, None) => FetchRange::Tail { lines },
+        (None, offset, length) => FetchRange::Bytes { offset: offset.unwrap_or(0), length },
+        _ => return Err((StatusCode::BAD_REQUEST, "tail_lines 不能与 offset/length 同时使用".to_string())),
+    };
+    if !params.path.starts_with('/') {
+        return Err((StatusCode::BAD_REQUEST, "path 必须是绝对路径".to_string()));
+    }
+
+    let (fetch_id, mut events) = shared_data.lock().await
+        .open_file_fetch(&params.client_id, &params.path, range)
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+    let guard = FetchGuard { shared_data: shared_data.clone(), fetch_id };
+
+    let (file_size, offset, length) = match tokio::time::timeout(FETCH_START_TIMEOUT, events.recv()).await {
+        Ok(Some(FetchEvent::Start { file_size, offset, length })) => (file_size, offset, length),
+        Ok(Some(FetchEvent::Failed(reason))) => return Err((StatusCode::UNPROCESSABLE_ENTITY, reason)),
+        Ok(_) => return Err((StatusCode::BAD_GATEWAY, "客户端没有返回文件".to_string())),
+        Err(_) => return Err((StatusCode::GATEWAY_TIMEOUT, "等待客户端响应超时".to_string())),
+    };
+    tracing::info!("Fetching {} from {} ({} of {} bytes from offset {})",
+                   params.path, params.client_id, length, file_size, offset);
+
+    // 客户端中途失败或校验不通过时以错误结束响应，浏览器不会得到不完整的文件
+    let body = stream::unfold(Some((events, guard)), |state| async move {
+        let (mut events, guard) = state?;
+        match events.recv().await {
+            Some(FetchEvent::Chunk { data, .. }) => Some((Ok(Bytes::from(data)), Some((events, guard)))),
+            Some(FetchEvent::End { .. }) => None,
+            Some(FetchEvent::Failed(reason)) => Some((Err(std::io::Error::other(reason)), None)),
+            Some(FetchEvent::Start { .. }) | None => Some((Err(std::io::Error::other("文件读取已中断")), None)),
+        }
+    });
+
+    let file_name = std::path::Path::new(&params.path)
+        .file_name()
+        .map(|name| name.to_string_lossy().replace(['"', '\\'], "_"))
+        .unwrap_or_else(|| "download".to_string());
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name))
+        .header(header::CONTENT_LENGTH, length)
+        .header("X-File-Size", file_size)
+        .header("X-Fetch-Offset", offset)
+        .body(Body::from_stream(body))
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
 // 获取客户端的命令历史
 #[derive(Deserialize)]
 pub struct ClientHistoryQuery {
@@ -122,18 +452,29 @@ pub async fn list_clients(
     
     // 限制返回的客户端数量，避免大量数据传输
     const MAX_CLIENTS: usize = 100;
+    let command_policy_version = data.command_policy.version();
     
-    let clients: HashMap<String, ClientInfo> = data.client_data
+    let clients: HashMap<String, ClientEntry> = data.client_data
         .iter()
         .take(MAX_CLIENTS)
-        .map(|(k, v)| (k.clone(), v.clone()))
+        .map(|(k, v)| {
+            let connection = data.client_connections.get(k);
+            let entry = ClientEntry {
+                info: v.clone(),
+                protocol_version: connection.map(|c| c.protocol_version),
+                capabilities: connection.map(|c| c.capabilities.clone()).unwrap_or_default(),
+                reconnects: data.reconnect_history.get(k).cloned(),
+                policy_current: v.command_policy.version.as_ref() == Some(&command_policy_version),
+            };
+            (k.clone(), entry)
+        })
         .collect();
     
     if data.client_data.len() > MAX_CLIENTS {
         tracing::warn!("Truncated client list from {} to {} entries", data.client_data.len(), MAX_CLIENTS);
     }
     
-    Ok(Json(ClientResponse { clients }))
+    Ok(Json(ClientResponse { clients, command_policy_version }))
 }
 
 // 返回前端页面 index.html
@@ -193,27 +534,56 @@ pub struct UpdateRequest {
     pub version: String,
 }
 
+/// 应用名和版本号会拼进 Shell 命令，只允许字母、数字和 `._-`
+fn validate_app_token(field: &str, value: &str) -> Result<(), (StatusCode, String)> {
+    let valid = !matches!(value, "" | "." | "..")
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if valid {
+        Ok(())
+    } else {
+        Err((StatusCode::BAD_REQUEST, format!("{} 只能包含字母、数字和 ._-: {}", field, value)))
+    }
+}
+
+/// 应用在客户端上的目录：客户端上报的 apps_base_dir 下的应用名，旧版本客户端未上报时使用默认目录
+async fn client_app_dir(shared_data: &SharedDataHandle, client_id: &str, app_name: &str) -> Result<String, (StatusCode, String)> {
+    validate_app_token("app_name", app_name)?;
+    let data = shared_data.lock().await;
+    let info = data.client_data.get(client_id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("客户端不存在: {}", client_id)))?;
+    let base = info.apps_base_dir.as_deref().unwrap_or(DEFAULT_APPS_BASE_DIR);
+    let valid = base.starts_with('/')
+        && base.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'))
+        && !base.split('/').any(|part| part == "..");
+    if !valid {
+        return Err((StatusCode::BAD_REQUEST, format!("客户端上报的应用目录不能用于生成命令: {}", base)));
+    }
+    Ok(format!("{}/{}", base.trim_end_matches('/'), app_name))
+}
+
 // 服务管理端点
 pub async fn manage_service(
     State(shared_data): State<SharedDataHandle>,
     Json(payload): Json<ServiceManagementRequest>
 ) -> Result<Json<CommandExecuteResponse>, (StatusCode, String)> {
+    let app = &payload.app_name;
+    let dir = client_app_dir(&shared_data, &payload.client_id, app).await?;
     let command = match payload.action {
         ServiceAction::Start => {
             // 启动服务：执行应用目录下的脚本文件
-            format!("cd /tmp/apps/{} && bash {}.sh start", payload.app_name, payload.app_name)
+            format!("cd {dir} && bash {app}.sh start")
         },
         ServiceAction::Stop => {
-            // 停止服务：杀死PID文件中的进程
-            format!("cd /tmp/apps/{} && if [ -f {}.pid ]; then kill $(cat {}.pid) && rm -f {}.pid; else echo 'Service is not running'; fi", payload.app_name, payload.app_name, payload.app_name, payload.app_name)
+            // 停止服务：由应用脚本结束进程；命令策略不允许 kill 无法确定的 PID
+            format!("cd {dir} && bash {app}.sh stop")
         },
         ServiceAction::Restart => {
             // 重启服务：先停止再启动
-            format!("cd /tmp/apps/{} && (if [ -f {}.pid ]; then kill $(cat {}.pid) && rm -f {}.pid; fi) && sleep 1 && bash {}.sh start", payload.app_name, payload.app_name, payload.app_name, payload.app_name, payload.app_name)
+            format!("cd {dir} && (bash {app}.sh stop || true) && sleep 1 && bash {app}.sh start")
         },
         ServiceAction::Status => {
             // 检查状态：查看PID文件和进程状态
-            format!("cd /tmp/apps/{} && if [ -f {}.pid ]; then pid=$(cat {}.pid); if ps -p $pid > /dev/null 2>&1; then echo 'Service is running (PID: '$pid')'; else echo 'PID file exists but process is not running'; fi; else echo 'Service is not running'; fi", payload.app_name, payload.app_name, payload.app_name)
+            format!("cd {dir} && if [ -f {app}.pid ]; then pid=$(cat {app}.pid); if ps -p $pid > /dev/null 2>&1; then echo 'Service is running (PID: '$pid')'; else echo 'PID file exists but process is not running'; fi; else echo 'Service is not running'; fi")
         },
     };
 
@@ -241,7 +611,9 @@ pub async fn update_app(
     State(shared_data): State<SharedDataHandle>,
     Json(payload): Json<UpdateRequest>
 ) -> Result<Json<CommandExecuteResponse>, (StatusCode, String)> {
-    let command = format!("cd /tmp/apps/{} && bash {}.sh update {}", payload.app_name, payload.app_name, payload.version);
+    validate_app_token("version", &payload.version)?;
+    let dir = client_app_dir(&shared_data, &payload.client_id, &payload.app_name).await?;
+    let command = format!("cd {} && bash {}.sh update {}", dir, payload.app_name, payload.version);
 
     match shared_data
         .lock()
@@ -371,16 +743,15 @@ impl SessionStore {
     
     // 检查会话是否存在且有效
     pub async fn is_session_valid(&self, session_id: &str, max_age: Duration) -> bool {
-        if let Some(session) = self.sessions.read().await.get(session_id) {
-            let now = SystemTime::now();
-            if let Ok(elapsed) = now.duration_since(session.last_accessed) {
-                elapsed < max_age
-            } else {
-                false
-            }
-        } else {
-            false
-        }
+        self.session_user(session_id, max_age).await.is_some()
+    }
+
+    // 会话有效时返回登录的用户
+    pub async fn session_user(&self, session_id: &str, max_age: Duration) -> Option<String> {
+        let sessions = self.sessions.read().await;
+        let session = sessions.get(session_id)?;
+        let elapsed = SystemTime::now().duration_since(session.last_accessed).ok()?;
+        (elapsed < max_age).then(|| session.user_id.clone())
     }
 }
 
@@ -502,6 +873,11 @@ pub async fn check_auth(
         if session_store.is_session_valid(&session_id, SESSION_TIMEOUT).await {
             // 更新最后访问时间（延长会话）
             if let Some(session) = session_store.get_session(&session_id).await {
+                tracing::debug!(
+                    "Session of user {} is valid (age {:?})",
+                    session.user_id,
+                    session.created_at.elapsed().unwrap_or_default()
+                );
                 return Json(LoginResponse {
                     success: true,
                     message: "已认证".to_string(),
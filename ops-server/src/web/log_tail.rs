@@ -0,0 +1,68 @@
+// 应用日志跟踪：浏览器通过 SSE 订阅，客户端持续回传日志新增的行，浏览器断开后通知客户端停止
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::stream::{self, Stream};
+use serde::Deserialize;
+use std::convert::Infallible;
+use crate::SharedDataHandle;
+use crate::shared_data_handle::LogTailEvent;
+use crate::web::handlers::json_event;
+
+/// 开始跟踪时最多回传的历史行数
+const MAX_INITIAL_LINES: u64 = 10_000;
+
+#[derive(Deserialize)]
+pub struct LogTailQuery {
+    pub client_id: String,
+    pub app: String,
+    /// 应用目录内的日志文件相对路径
+    pub file: String,
+    #[serde(default = "default_lines")]
+    pub lines: u64,
+}
+
+fn default_lines() -> u64 {
+    100
+}
+
+/// 订阅结束（包括浏览器断开）时停止客户端上的跟踪
+struct LogTailGuard {
+    shared_data: SharedDataHandle,
+    tail_id: String,
+}
+
+impl Drop for LogTailGuard {
+    fn drop(&mut self) {
+        let shared_data = self.shared_data.clone();
+        let tail_id = std::mem::take(&mut self.tail_id);
+        tokio::spawn(async move { shared_data.lock().await.close_log_tail(&tail_id) });
+    }
+}
+
+// 以 SSE 推送应用日志：每批新增的行一个 lines 事件，客户端结束跟踪时发送 ended 事件
+pub async fn log_tail(
+    State(shared_data): State<SharedDataHandle>,
+    Query(query): Query<LogTailQuery>
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let lines = query.lines.min(MAX_INITIAL_LINES);
+    let (tail_id, events) = shared_data.lock().await
+        .open_log_tail(&query.client_id, &query.app, &query.file, lines)
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+    tracing::info!("Following {} of app {} on {} as tail {}", query.file, query.app, query.client_id, tail_id);
+
+    let guard = LogTailGuard { shared_data: shared_data.clone(), tail_id };
+    let stream = stream::unfold(Some((events, guard)), |state| async move {
+        let (mut events, guard) = state?;
+        let event = events.recv().await.unwrap_or(LogTailEvent::Ended { reason: "日志跟踪已结束".to_string() });
+        match event {
+            LogTailEvent::Lines { .. } => Some((Ok(json_event("lines", &event)), Some((events, guard)))),
+            LogTailEvent::Ended { .. } => Some((Ok(json_event("ended", &event)), None)),
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
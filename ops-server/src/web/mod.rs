@@ -1,2 +1,7 @@
+pub mod approvals;
+pub mod auth_bans;
+pub mod credentials;
 pub mod handlers;
-pub mod routes;
\ No newline at end of file
+pub mod log_tail;
+pub mod routes;
+pub mod terminal;
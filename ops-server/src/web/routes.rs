@@ -9,10 +9,11 @@
 // }
 use axum::{
     Router, 
+    extract::DefaultBodyLimit,
     routing::{get, post},
     middleware,
 };
-use crate::{web::handlers, SharedDataHandle, middleware::{auth_middleware, cors_middleware, web_logging_middleware, AuthConfig}};
+use crate::{web::{approvals, auth_bans, credentials, handlers, log_tail, terminal}, SharedDataHandle, middleware::{auth_middleware, cors_middleware, web_logging_middleware, AuthConfig}};
 use crate::web::handlers::SessionStore;
 
 pub fn routes(shared_data: SharedDataHandle, auth_config: AuthConfig) -> (Router, SessionStore) {
@@ -40,7 +41,23 @@ pub fn routes(shared_data: SharedDataHandle, auth_config: AuthConfig) -> (Router
         .route("/api/clients", get(handlers::list_clients))
         .route("/api/send-message", post(handlers::broadcast_message))
         .route("/api/send-command", post(handlers::send_command))
+        .route("/api/cancel-command", post(handlers::cancel_command))
         .route("/api/command-result", get(handlers::get_command_result))
+        .route("/api/command-stream", get(handlers::command_stream))
+        .route("/api/terminal", get(terminal::terminal))
+        .route("/api/push-file", post(handlers::push_file).layer(DefaultBodyLimit::disable()))
+        .route("/api/file-transfer", get(handlers::get_file_transfer))
+        .route("/api/fetch-file", get(handlers::fetch_file))
+        .route("/api/log-tail", get(log_tail::log_tail))
+        .route("/api/auth-bans", get(auth_bans::list_auth_bans))
+        .route("/api/auth-bans/unban", post(auth_bans::unban))
+        .route("/api/pending-clients", get(approvals::list_pending_clients))
+        .route("/api/pending-clients/approve", post(approvals::approve_client))
+        .route("/api/pending-clients/reject", post(approvals::reject_client))
+        .route("/api/credentials", get(credentials::list_credentials))
+        .route("/api/enrollment-tokens", post(credentials::create_enrollment_token))
+        .route("/api/credentials/rotate", post(credentials::rotate_credential))
+        .route("/api/credentials/revoke", post(credentials::revoke_credential))
         .route("/api/client-history", get(handlers::get_client_command_history))
         .route("/api/predefined-commands", get(handlers::get_predefined_commands))
         .route("/api/apps", get(handlers::get_apps_info))
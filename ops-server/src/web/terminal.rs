@@ -0,0 +1,174 @@
+// Web 终端：浏览器通过 WebSocket 连接，服务端经客户端的 TCP 连接转发到客户端上的 PTY
+
+use axum::{
+    Extension,
+    extract::{Query, State, ws::{Message, WebSocket, WebSocketUpgrade}},
+    http::StatusCode,
+    response::Response,
+};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use ops_common::protocol::Envelope;
+use crate::SharedDataHandle;
+use crate::audit::{AuditLog, AuditRecord};
+use crate::middleware::AuthenticatedUser;
+use crate::shared_data_handle::TerminalEvent;
+
+/// 审计日志中单行输入的长度上限，超过后分段记录
+const MAX_AUDIT_LINE: usize = 4096;
+
+#[derive(Deserialize)]
+pub struct TerminalQuery {
+    pub client_id: String,
+    #[serde(default = "default_cols")]
+    pub cols: u16,
+    #[serde(default = "default_rows")]
+    pub rows: u16,
+}
+
+fn default_cols() -> u16 {
+    80
+}
+
+fn default_rows() -> u16 {
+    24
+}
+
+/// 浏览器发来的终端消息
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TerminalClientMessage {
+    Input { data: String },
+    Resize { cols: u16, rows: u16 },
+}
+
+/// 一个终端会话的标识，用于转发和审计
+struct TerminalContext {
+    shared_data: SharedDataHandle,
+    audit: AuditLog,
+    user: String,
+    client_id: String,
+    session_id: String,
+}
+
+impl TerminalContext {
+    fn audit(&self, event: &str, detail: Option<String>) {
+        self.audit.record(AuditRecord::new(&self.user, &self.client_id, &self.session_id, event, detail));
+    }
+}
+
+// 打开终端的 WebSocket 端点，只有 terminal_users 中的登录用户可以使用
+pub async fn terminal(
+    State(shared_data): State<SharedDataHandle>,
+    user: Option<Extension<AuthenticatedUser>>,
+    Query(query): Query<TerminalQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, (StatusCode, String)> {
+    let user = user.map(|Extension(AuthenticatedUser(user))| user);
+
+    let mut data = shared_data.lock().await;
+    let audit = data.audit.clone();
+    let user = match user {
+        Some(user) if data.can_use_terminal(&user) => user,
+        user => {
+            let user = user.unwrap_or_else(|| "-".to_string());
+            audit.record(AuditRecord::new(&user, &query.client_id, "-", "terminal_denied", None));
+            return Err((StatusCode::FORBIDDEN, "没有使用终端的权限".to_string()));
+        }
+    };
+
+    let (session_id, events) = data
+        .open_terminal(&query.client_id, query.cols, query.rows)
+        .map_err(|e| (StatusCode::CONFLICT, e))?;
+    drop(data);
+
+    let context = TerminalContext {
+        shared_data: shared_data.clone(),
+        audit,
+        user,
+        client_id: query.client_id,
+        session_id: session_id.clone(),
+    };
+    context.audit("terminal_open", Some(format!("{}x{}", query.cols, query.rows)));
+
+    Ok(ws
+        .on_failed_upgrade(move |e| {
+            tracing::warn!("Terminal {} WebSocket upgrade failed: {}", session_id, e);
+            tokio::spawn(async move { shared_data.lock().await.close_terminal(&session_id) });
+        })
+        .on_upgrade(move |socket| run_terminal(socket, context, events)))
+}
+
+async fn run_terminal(mut socket: WebSocket, context: TerminalContext, mut events: mpsc::Receiver<TerminalEvent>) {
+    let mut line = String::new();
+
+    let reason = loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    break "终端会话已结束".to_string();
+                };
+                let text = serde_json::to_string(&event).unwrap_or_default();
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    break "浏览器连接已断开".to_string();
+                }
+                if let TerminalEvent::Closed { reason } = event {
+                    break reason.unwrap_or_else(|| "shell 已退出".to_string());
+                }
+            }
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break "浏览器连接已断开".to_string(),
+                    Some(Ok(_)) => continue,
+                };
+                let envelope = match serde_json::from_str::<TerminalClientMessage>(&text) {
+                    Ok(TerminalClientMessage::Input { data }) => {
+                        audit_input(&context, &mut line, &data);
+                        Envelope::TerminalInput { session_id: context.session_id.clone(), data }
+                    }
+                    Ok(TerminalClientMessage::Resize { cols, rows }) => {
+                        Envelope::TerminalResize { session_id: context.session_id.clone(), cols, rows }
+                    }
+                    Err(e) => {
+                        tracing::warn!("Invalid terminal message for {}: {}", context.session_id, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = context.shared_data.lock().await.send_to_terminal(&context.session_id, envelope) {
+                    break e;
+                }
+            }
+        }
+    };
+
+    if !line.is_empty() {
+        context.audit("terminal_input", Some(line));
+    }
+    context.shared_data.lock().await.close_terminal(&context.session_id);
+    context.audit("terminal_close", Some(reason));
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+// 按行记录输入：回车时写一条审计记录，退格删除上一个字符，其他控制字符忽略
+fn audit_input(context: &TerminalContext, line: &mut String, data: &str) {
+    for c in data.chars() {
+        match c {
+            '\r' | '\n' => {
+                if !line.is_empty() {
+                    context.audit("terminal_input", Some(std::mem::take(line)));
+                }
+            }
+            '\u{7f}' | '\u{8}' => {
+                line.pop();
+            }
+            c if c.is_control() => {}
+            c => {
+                line.push(c);
+                if line.len() >= MAX_AUDIT_LINE {
+                    context.audit("terminal_input", Some(std::mem::take(line)));
+                }
+            }
+        }
+    }
+}
@@ -4,7 +4,7 @@
 rm -f /tmp/client_id.txt /tmp/client_commands.log
 
 # 创建必要的目录
-mkdir -p /tmp/apps
+mkdir -p /opt/apps
 
 echo "=== 构建项目 ==="
 cargo build --release || exit 1